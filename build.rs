@@ -1,7 +1,18 @@
 fn main() {
     let mut config = prost_build::Config::new();
     config.bytes(&["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
+    config.type_attribute(
+        ".",
+        "#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]",
+    );
+    // prost 生成的 enum 已经 derive 了 PartialOrd，这里覆盖掉上面的规则
+    let enum_attribute = "#[derive(serde::Serialize, serde::Deserialize)]";
+    config.type_attribute(".api.MergeOp", enum_attribute);
+    config.type_attribute(".api.Format", enum_attribute);
+    config.type_attribute(".api.ReturnMode", enum_attribute);
+    config.type_attribute(".api.ModifyOp", enum_attribute);
+    config.type_attribute(".api.ChangeOp", enum_attribute);
+    config.type_attribute(".api.AggOp", enum_attribute);
     config
         .out_dir("src/pb")
         .compile_protos(&["api.proto"], &["proto/"])
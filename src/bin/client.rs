@@ -2,8 +2,9 @@ use std::time::Duration;
 
 use anyhow::Result;
 use futures::StreamExt;
-use simplekv::{CommandRequest, KvError, ProstClientStream, TlsClientConnector, YamuxCtrl};
-use tokio::net::TcpStream;
+use simplekv::{
+    connect, CommandRequest, KvError, ProstClientStream, TlsClientConnector, YamuxCtrl,
+};
 use tokio::time;
 use tokio_util::compat::Compat;
 use tracing::info;
@@ -15,7 +16,7 @@ async fn main() -> Result<()> {
     let addr = "127.0.0.1:6000";
     let ca_cert = include_str!("../../fixtures/ca.cert");
     let connector = TlsClientConnector::new("demo.simplekv.cc", None, Some(ca_cert))?;
-    let stream = TcpStream::connect(addr).await?;
+    let stream = connect(addr).await?;
     let stream = connector.connect(stream).await?;
 
     // 打开一个 stream
@@ -1,8 +1,14 @@
+use std::sync::Arc;
+use std::{env, fs};
+
 use anyhow::Result;
-use simplekv::{MemTable, ProstServerStream, Service, ServiceInner, TlsServerAcceptor, YamuxCtrl};
+use simplekv::{
+    MemTable, ProstServerStream, Service, ServiceInner, Storage, TenantServices, TlsServerAcceptor,
+    YamuxCtrl,
+};
 use tokio::net::TcpListener;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::info;
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -12,19 +18,67 @@ async fn main() -> Result<()> {
     let server_cert = include_str!("../../fixtures/server.cert");
     let server_key = include_str!("../../fixtures/server.key");
 
-    let acceptor = TlsServerAcceptor::new(server_cert, server_key, None)?;
+    // 设置了 KV_CLIENT_CA 时要求客户端证书，证书的 CN 就是租户，每个租户只能访问自己的 table
+    let client_ca = env::var("KV_CLIENT_CA")
+        .ok()
+        .map(fs::read_to_string)
+        .transpose()?;
+    let acceptor = TlsServerAcceptor::new(server_cert, server_key, client_ca.as_deref())?;
+
+    // 设置了 KV_MAX_TABLES 时限制 table 的个数，避免客户端无限地创建 table
+    let mut store = MemTable::new();
+    if let Ok(n) = env::var("KV_MAX_TABLES") {
+        store = store.with_max_tables(n.parse()?);
+        info!("At most {} tables", n);
+    }
 
-    let service: Service = ServiceInner::new(MemTable::new()).into();
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening on {}", addr);
+    match client_ca {
+        None => {
+            let service: Service = ServiceInner::new(store).into();
+            serve(listener, acceptor, move |_cn| Some(service.clone())).await
+        }
+        Some(_) => {
+            let tenants = TenantServices::new(store, |store| ServiceInner::new(store).into());
+            serve(listener, acceptor, move |cn| match cn {
+                Some(cn) => tenants
+                    .get(&cn)
+                    .map_err(|e| warn!("Invalid tenant {:?}: {}", cn, e))
+                    .ok(),
+                None => None,
+            })
+            .await
+        }
+    }
+}
+
+/// 接受连接，service_for 根据客户端证书的 CN 选择连接使用的 Service，返回 None 时断开连接
+async fn serve<Store, F>(
+    listener: TcpListener,
+    acceptor: TlsServerAcceptor,
+    service_for: F,
+) -> Result<()>
+where
+    Store: Storage + Send + Sync + 'static,
+    F: Fn(Option<String>) -> Option<Service<Store>> + Send + Sync + 'static,
+{
+    let service_for = Arc::new(service_for);
     loop {
         let tls = acceptor.clone();
         let (stream, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
 
-        let svc = service.clone();
+        let service_for = service_for.clone();
         tokio::spawn(async move {
             let stream = tls.accept(stream).await.unwrap();
+            let svc = match service_for(TlsServerAcceptor::peer_common_name(&stream)) {
+                Some(svc) => svc,
+                None => {
+                    warn!("Client {:?} rejected", addr);
+                    return;
+                }
+            };
             YamuxCtrl::new_server(stream, None, move |stream| {
                 let svc1 = svc.clone();
                 async move {
@@ -14,18 +14,106 @@ pub enum KvError {
     StorageError(&'static str, String, String, String),
     #[error("Certificate parse error: error to load {0} {0}")]
     CertifcateParseError(&'static str, &'static str),
+    #[error("Invalid value for table: {0}, key: {1}. Error: {2}")]
+    ValidationFailed(String, String, String),
+    #[error("Fenced out: token {0} is lower than {1}")]
+    FencedOut(u64, u64),
+    #[error("Server is behind: required version {0}, current version {1}")]
+    VersionBehind(u64, u64),
+    #[error("Failed to connect to {0}: {1}")]
+    ConnectError(String, String),
+    #[error("Response is too large: {0} bytes exceeds the limit of {1} bytes, please paginate (e.g. Hkeyspage + Hmget)")]
+    ResponseTooLarge(usize, usize),
+    #[error("Table already exists: {0}")]
+    TableExists(String),
+    #[error("Too many tables: cannot create more than {0} tables")]
+    TooManyTables(usize),
+    #[error("Command is disabled: {0}")]
+    CommandDisabled(String),
+    #[error("Timed out after {0} ms")]
+    Timeout(u64),
+    #[error("Storage is read-only")]
+    ReadOnly,
+    #[error("Certificate pinning failed: server certificate fingerprint mismatch")]
+    CertPinMismatch,
 
     #[error("Failed to encode protobuf message")]
     EncodeError(#[from] prost::EncodeError),
     #[error("Failed to decode protobuf message")]
     DecodeError(#[from] prost::DecodeError),
+    #[error("Failed to encode/decode JSON")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Failed to read CSV")]
+    CsvError(#[from] csv::Error),
     #[error("Failed to access sled db")]
     SledError(#[from] sled::Error),
     #[error("I/O error")]
     IoError(#[from] std::io::Error),
     #[error("TLS error")]
     TlsError(#[from] tokio_rustls::rustls::TLSError),
+    #[error("Yamux error")]
+    YamuxError(#[from] yamux::ConnectionError),
 
     #[error("Internal error: {0}")]
     Internal(String),
 }
+
+impl KvError {
+    /// 这个错误是否值得重试：连接、传输层的错误、超时和服务器暂时落后的错误重试之后可能成功，
+    /// 数据本身或者命令本身的错误重试多少次都一样
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            KvError::IoError(_)
+                | KvError::YamuxError(_)
+                | KvError::ConnectError(..)
+                | KvError::VersionBehind(..)
+                | KvError::Timeout(_)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn transport_errors_should_be_retryable() {
+        let errors = [
+            KvError::IoError(Error::new(ErrorKind::ConnectionReset, "reset")),
+            KvError::YamuxError(yamux::ConnectionError::Closed),
+            KvError::ConnectError("127.0.0.1:6000".into(), "refused".into()),
+            KvError::VersionBehind(2, 1),
+            KvError::Timeout(100),
+        ];
+        for e in errors {
+            assert!(e.is_retryable(), "{:?} should be retryable", e);
+        }
+    }
+
+    #[test]
+    fn domain_errors_should_not_be_retryable() {
+        let errors = [
+            KvError::NotFound("table t1, key k1".into()),
+            KvError::FrameError,
+            KvError::InvalidCommand("bad".into()),
+            KvError::ConvertError("v1".into(), "Integer"),
+            KvError::StorageError("hget", "t1".into(), "k1".into(), "oops".into()),
+            KvError::CertifcateParseError("server", "cert"),
+            KvError::ValidationFailed("t1".into(), "k1".into(), "too long".into()),
+            KvError::FencedOut(1, 2),
+            KvError::ResponseTooLarge(2048, 1024),
+            KvError::TooManyTables(16),
+            KvError::CommandDisabled("hdrain".into()),
+            KvError::ReadOnly,
+            KvError::CertPinMismatch,
+            KvError::DecodeError(prost::DecodeError::new("bad frame")),
+            KvError::JsonError(serde_json::from_str::<i64>("x").unwrap_err()),
+            KvError::Internal("oops".into()),
+        ];
+        for e in errors {
+            assert!(!e.is_retryable(), "{:?} should not be retryable", e);
+        }
+    }
+}
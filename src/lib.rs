@@ -4,6 +4,9 @@ mod pb;
 mod service;
 mod storage;
 
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 pub use error::KvError;
 pub use network::*;
 pub use pb::api::*;
@@ -0,0 +1,151 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+use crate::{CommandRequest, CommandResponse, KvError, ProstClientStream};
+
+type Pending = (
+    CommandRequest,
+    oneshot::Sender<Result<CommandResponse, KvError>>,
+);
+
+/// 批量发送的配置
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// 攒够这么多个命令就立刻发送
+    pub max_batch_size: usize,
+    /// 第一个命令最多等待这么久就发送，不管攒了多少个
+    pub max_delay: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 64,
+            max_delay: Duration::from_millis(5),
+        }
+    }
+}
+
+/// 把命令攒成一批，用 pipeline 的方式一起发送的客户端
+///
+/// 实际的发送在后台任务中进行，所有的 BatchingClient 都 drop 之后后台任务会退出
+#[derive(Clone)]
+pub struct BatchingClient {
+    tx: mpsc::UnboundedSender<Pending>,
+    batches: Arc<AtomicU64>,
+}
+
+impl BatchingClient {
+    pub fn new<S>(client: ProstClientStream<S>, config: BatchConfig) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let batches = Arc::new(AtomicU64::new(0));
+        tokio::spawn(run(client, rx, config, batches.clone()));
+        Self { tx, batches }
+    }
+
+    /// 把命令放入队列，返回的 receiver 在命令所在的批次发送完成之后得到响应
+    pub fn enqueue(
+        &self,
+        cmd: CommandRequest,
+    ) -> oneshot::Receiver<Result<CommandResponse, KvError>> {
+        let (tx, rx) = oneshot::channel();
+        if let Err(mpsc::error::SendError((_cmd, tx))) = self.tx.send((cmd, tx)) {
+            let _ = tx.send(Err(KvError::Internal("Batching client is closed".into())));
+        }
+        rx
+    }
+
+    /// 已经发送了多少个批次
+    pub fn batches(&self) -> u64 {
+        self.batches.load(Ordering::Relaxed)
+    }
+}
+
+async fn run<S>(
+    mut client: ProstClientStream<S>,
+    mut rx: mpsc::UnboundedReceiver<Pending>,
+    config: BatchConfig,
+    batches: Arc<AtomicU64>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let max_batch_size = config.max_batch_size.max(1);
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::sleep(config.max_delay);
+        tokio::pin!(deadline);
+        while batch.len() < max_batch_size {
+            tokio::select! {
+                pending = rx.recv() => match pending {
+                    Some(v) => batch.push(v),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+
+        let (cmds, senders): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        batches.fetch_add(1, Ordering::Relaxed);
+        match client.execute_batch(&cmds).await {
+            Ok(responses) => {
+                for (tx, res) in senders.into_iter().zip(responses) {
+                    let _ = tx.send(Ok(res));
+                }
+            }
+            Err(e) => {
+                // 连接出错之后后面的命令也发不出去了，通知所有等待的调用者
+                warn!("Failed to execute batch: {:?}", e);
+                for tx in senders {
+                    let _ = tx.send(Err(KvError::Internal(e.to_string())));
+                }
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{start_test_server, test_client};
+    use crate::{assert_res_ok, Value};
+
+    #[tokio::test]
+    async fn batching_client_should_work() -> anyhow::Result<()> {
+        let (addr, _server) = start_test_server().await?;
+        let client = test_client(addr).await?;
+        let config = BatchConfig {
+            max_batch_size: 10,
+            max_delay: Duration::from_millis(100),
+        };
+        let client = BatchingClient::new(client, config);
+
+        let receivers: Vec<_> = (0..25)
+            .map(|i| client.enqueue(CommandRequest::new_hset("t1", format!("k{}", i), i.into())))
+            .collect();
+        for rx in receivers {
+            assert_res_ok(&rx.await??, &[Value::default()], &[]);
+        }
+        // 两个满的批次，剩下的 5 个等到超时之后发送
+        assert_eq!(client.batches(), 3);
+
+        let receivers: Vec<_> = (0..25)
+            .map(|i| client.enqueue(CommandRequest::new_hget("t1", format!("k{}", i))))
+            .collect();
+        for (i, rx) in receivers.into_iter().enumerate() {
+            assert_res_ok(&rx.await??, &[(i as i64).into()], &[]);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time;
+use tokio_rustls::client::TlsStream;
+
+use crate::{KvError, TlsClientConnector};
+
+/// happy eyeballs 中相邻两次连接尝试之间的间隔（RFC 8305 推荐 250ms）
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// 解析 addr（"host:port"）之后依次连接每个地址，返回第一个连接成功的 TCP stream
+///
+/// 解析或者连接失败时返回带有 addr 的 ConnectError，而不是底层的 I/O 错误
+pub async fn connect(addr: &str) -> Result<TcpStream, KvError> {
+    let error = |e: std::io::Error| KvError::ConnectError(addr.into(), e.to_string());
+    let addrs = lookup_host(addr).await.map_err(error)?;
+
+    let mut last_error = KvError::ConnectError(addr.into(), "no address resolved".into());
+    for resolved in addrs {
+        match TcpStream::connect(resolved).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = error(e),
+        }
+    }
+    Err(last_error)
+}
+
+/// 解析 host 得到所有的地址，用 happy eyeballs 的方式并发连接，返回第一个建立好的 TLS stream
+pub async fn connect_resolved(
+    host: &str,
+    port: u16,
+    connector: &TlsClientConnector,
+) -> Result<TlsStream<TcpStream>, KvError> {
+    let addrs: Vec<_> = lookup_host((host, port)).await?.collect();
+    connect_addrs(&addrs, connector).await
+}
+
+/// 依次每隔 ATTEMPT_DELAY 发起一个连接，不等之前的连接失败，谁先完成 TLS 握手就用谁；
+/// 正在进行的连接失败时马上发起下一个，不用等到 ATTEMPT_DELAY
+///
+/// 所有的连接都失败时返回最后一个错误
+pub async fn connect_addrs(
+    addrs: &[SocketAddr],
+    connector: &TlsClientConnector,
+) -> Result<TlsStream<TcpStream>, KvError> {
+    let connect = |addr: SocketAddr| async move {
+        let stream = TcpStream::connect(addr).await?;
+        connector.connect(stream).await
+    };
+
+    let mut addrs = addrs.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    attempts.extend(addrs.next().map(connect));
+    let mut last_error = KvError::Internal("No address to connect".into());
+    while !attempts.is_empty() {
+        let delay = time::sleep(ATTEMPT_DELAY);
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                // 返回之后 attempts 被 drop，其他还没完成的连接也就放弃了
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    last_error = e;
+                    attempts.extend(addrs.next().map(connect));
+                }
+            },
+            _ = delay, if addrs.len() > 0 => attempts.extend(addrs.next().map(connect)),
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tls::tls_utils::{tls_acceptor, tls_connector};
+    use anyhow::Result;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connect_addrs_should_use_live_address() -> Result<()> {
+        // 只 bind 不 accept：TCP 可以连上，但 TLS 握手永远不会完成
+        let dead = TcpListener::bind("127.0.0.1:0").await?;
+        let live = start_server().await?;
+
+        let connector = tls_connector(false)?;
+        let addrs = [dead.local_addr()?, live];
+        let mut stream =
+            time::timeout(Duration::from_secs(1), connect_addrs(&addrs, &connector)).await??;
+
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_addrs_should_fail_if_all_addresses_fail() -> Result<()> {
+        // 拿到一个端口后马上释放，连接会被拒绝
+        let addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+
+        let connector = tls_connector(false)?;
+        let result = connect_addrs(&[addr], &connector).await;
+        assert!(matches!(result, Err(KvError::IoError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_addrs_should_try_next_address_when_one_fails() -> Result<()> {
+        // 第一个地址马上被拒绝，不用等 ATTEMPT_DELAY 就开始连接下一个
+        let refused = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut addrs = vec![refused; 8];
+        addrs.push(start_server().await?);
+
+        let connector = tls_connector(false)?;
+        let connecting = connect_addrs(&addrs, &connector);
+        time::timeout(ATTEMPT_DELAY * 4, connecting).await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_to_closed_port_should_return_descriptive_error() -> Result<()> {
+        let addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let addr = addr.to_string();
+
+        let err = connect(&addr).await.unwrap_err();
+        assert!(matches!(&err, KvError::ConnectError(target, _) if *target == addr));
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Failed to connect to {}", addr)));
+
+        // 没有端口，无法解析
+        let err = connect("localhost").await.unwrap_err();
+        assert!(matches!(err, KvError::ConnectError(target, _) if target == "localhost"));
+
+        Ok(())
+    }
+
+    async fn start_server() -> Result<SocketAddr> {
+        let acceptor = tls_acceptor(false)?;
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = echo.local_addr()?;
+
+        tokio::spawn(async move {
+            let (stream, _) = echo.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut buf = [0; 12];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        Ok(addr)
+    }
+}
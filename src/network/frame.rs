@@ -5,6 +5,7 @@ use crate::{CommandRequest, CommandResponse, KvError};
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
 
@@ -63,6 +64,32 @@ where
         }
     }
 
+    /// 把一个 Message encode 成 payload 是 JSON 的 frame，不做压缩
+    fn encode_json_frame(&self, buf: &mut BytesMut) -> Result<(), KvError>
+    where
+        Self: Serialize,
+    {
+        let payload = serde_json::to_vec(self)?;
+        if payload.len() >= MAX_FRAME {
+            return Err(KvError::FrameError);
+        }
+
+        buf.put_u32(payload.len() as _);
+        buf.put_slice(&payload);
+        Ok(())
+    }
+
+    /// 把一个完整的 JSON frame decode 成一个 Message
+    fn decode_json_frame(buf: &mut BytesMut) -> Result<Self, KvError>
+    where
+        Self: DeserializeOwned,
+    {
+        let (len, _compressed) = decode_header(buf.get_u32() as usize);
+        let msg = serde_json::from_slice(&buf[..len])?;
+        buf.advance(len);
+        Ok(msg)
+    }
+
     /// 把一个完整的 frame decode 成一个 Message
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
         // 先取 4 字节，从中拿出长度和 compression bit
@@ -96,6 +123,14 @@ fn decode_header(header: usize) -> (usize, bool) {
     (len, compressed)
 }
 
+/// buf 开头的 frame 一共有多少字节（包括长度），buf 里还不够一个长度时返回 None
+pub(crate) fn frame_len(buf: &[u8]) -> Option<usize> {
+    let header = buf.get(..LEN_LEN)?;
+    let header = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+    let (len, _compressed) = decode_header(header);
+    Some(LEN_LEN + len)
+}
+
 /// 从 stream 中读取一个完整的 frame
 pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
 where
@@ -123,6 +158,23 @@ mod tests {
     use crate::Value;
     use bytes::Bytes;
 
+    #[test]
+    fn command_response_json_encode_decode_should_work() {
+        let mut buf = BytesMut::new();
+
+        let values: Vec<Value> = vec![1.into(), "hello".into(), b"data".into()];
+        let res: CommandResponse = values.into();
+        res.encode_json_frame(&mut buf).unwrap();
+
+        // payload 是可以直接阅读的 JSON
+        assert!(std::str::from_utf8(&buf[LEN_LEN..])
+            .unwrap()
+            .contains("hello"));
+
+        let res1 = CommandResponse::decode_json_frame(&mut buf).unwrap();
+        assert_eq!(res, res1);
+    }
+
     #[test]
     fn command_request_encode_decode_should_work() {
         let mut buf = BytesMut::new();
@@ -0,0 +1,171 @@
+use std::io::Read;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio::task;
+use tracing::info;
+
+use crate::{CommandRequest, KvError, Kvpair, ProstClientStream};
+
+/// 每多少行用一个 Hmset 写入服务器
+const IMPORT_BATCH_SIZE: usize = 500;
+
+/// 把 CSV/TSV 的每一行导入成 table 中的一个 kv pair，value 按 string 写入
+pub struct CsvImport {
+    table: String,
+    key_col: usize,
+    value_col: usize,
+    delimiter: u8,
+    has_headers: bool,
+}
+
+/// 导入的结果
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// 成功导入的行数
+    pub imported: usize,
+    /// 出错被跳过的行：(行号，错误信息)，行号从 1 开始，包括 header
+    pub errors: Vec<(u64, String)>,
+}
+
+impl CsvImport {
+    /// 缺省使用逗号分隔，第一行是 header
+    pub fn new(table: impl Into<String>, key_col: usize, value_col: usize) -> Self {
+        Self {
+            table: table.into(),
+            key_col,
+            value_col,
+            delimiter: b',',
+            has_headers: true,
+        }
+    }
+
+    /// 设置分隔符，比如 TSV 使用 b'\t'
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// 设置第一行是否是 header，header 不会被导入
+    pub fn with_headers(mut self, has_headers: bool) -> Self {
+        self.has_headers = has_headers;
+        self
+    }
+
+    /// 从 reader 中读取数据，分批写入服务器
+    /// 格式不对的行会被跳过并记录在 ImportReport 中，读取或者写入失败则直接返回错误
+    ///
+    /// reader 是同步的，读取和解析放在 spawn_blocking 的线程里，不会阻塞 tokio 的 worker
+    pub async fn import<S>(
+        &self,
+        client: &mut ProstClientStream<S>,
+        reader: impl Read + Send + 'static,
+    ) -> Result<ImportReport, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // 多缓存一批，写入服务器的同时可以解析下一批
+        let (tx, mut rx) = mpsc::channel(2);
+        let (key_col, value_col) = (self.key_col, self.value_col);
+        let reader = csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .has_headers(self.has_headers)
+            .from_reader(reader);
+        let parsing = task::spawn_blocking(move || parse_batches(reader, key_col, value_col, tx));
+
+        let mut report = ImportReport::default();
+        while let Some(batch) = rx.recv().await {
+            let mut batch = batch?;
+            report.errors.append(&mut batch.errors);
+            self.flush(client, &mut batch.pairs, &mut report).await?;
+        }
+        parsing
+            .await
+            .map_err(|e| KvError::Internal(format!("CSV parser panicked: {}", e)))?;
+
+        Ok(report)
+    }
+
+    async fn flush<S>(
+        &self,
+        client: &mut ProstClientStream<S>,
+        batch: &mut Vec<Kvpair>,
+        report: &mut ImportReport,
+    ) -> Result<(), KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let n = batch.len();
+        let cmd = CommandRequest::new_hmset(&self.table, std::mem::take(batch));
+        let res = client.execute_unary(&cmd).await?;
+        if res.status != 200 {
+            return Err(KvError::Internal(format!(
+                "Failed to import into {}: {}",
+                self.table, res.message
+            )));
+        }
+
+        report.imported += n;
+        info!(
+            "Imported {} rows into {}, {} rows skipped",
+            report.imported,
+            self.table,
+            report.errors.len()
+        );
+        Ok(())
+    }
+}
+
+/// 解析出来的一批数据：要写入的 kv pair 和被跳过的行
+#[derive(Default)]
+struct Batch {
+    pairs: Vec<Kvpair>,
+    errors: Vec<(u64, String)>,
+}
+
+/// 在阻塞的线程里解析 CSV，每 IMPORT_BATCH_SIZE 个 kv pair 发送一批；读取出错时发送错误并停止
+///
+/// 接收的一方已经出错返回时发送会失败，这时也停止解析
+fn parse_batches<R: Read>(
+    mut reader: csv::Reader<R>,
+    key_col: usize,
+    value_col: usize,
+    tx: mpsc::Sender<Result<Batch, KvError>>,
+) {
+    let mut batch = Batch::default();
+    for record in reader.records() {
+        let record = match record {
+            Ok(v) => v,
+            Err(e) if e.is_io_error() => {
+                let _ = tx.blocking_send(Err(e.into()));
+                return;
+            }
+            Err(e) => {
+                let line = e.position().map(|p| p.line()).unwrap_or_default();
+                batch.errors.push((line, e.to_string()));
+                continue;
+            }
+        };
+
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        match (record.get(key_col), record.get(value_col)) {
+            (Some(key), Some(value)) => batch.pairs.push(Kvpair::new(key, value.into())),
+            _ => {
+                let msg = format!("Row has only {} columns", record.len());
+                batch.errors.push((line, msg));
+                continue;
+            }
+        }
+
+        if batch.pairs.len() == IMPORT_BATCH_SIZE
+            && tx.blocking_send(Ok(std::mem::take(&mut batch))).is_err()
+        {
+            return;
+        }
+    }
+    let _ = tx.blocking_send(Ok(batch));
+}
@@ -1,49 +1,188 @@
+mod batch;
+mod connect;
 mod frame;
+mod import;
 mod multiplex;
+mod multiplexed;
+mod recent;
+mod script;
+mod session;
 mod stream;
 mod stream_result;
 mod tls;
 
+pub use batch::{BatchConfig, BatchingClient};
+pub use connect::{connect, connect_addrs, connect_resolved};
 pub use frame::{read_frame, FrameCoder};
+pub use import::{CsvImport, ImportReport};
 pub use multiplex::YamuxCtrl;
+pub use multiplexed::MultiplexedClient;
+pub use recent::{RecentRequest, RecentRequests, DEFAULT_RECENT_CAPACITY};
+pub use script::{parse_command, run_script, ScriptReport};
+pub use session::Session;
 pub use stream::ProstStream;
 pub use stream_result::StreamResult;
 pub use tls::{TlsClientConnector, TlsServerAcceptor};
 
-use crate::{CommandRequest, CommandResponse, KvError, Service};
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, KvError, MemTable, Service,
+    Storage, Value,
+};
 use futures::{SinkExt, StreamExt};
+use http::StatusCode;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
 use tracing::info;
 
+/// 一个连接上最多同时执行多少个带 request_id 的命令，包括已经执行完、还没有发送的；
+/// 超过之后暂停读取新的命令，直到有命令的响应发送出去
+const MULTIPLEX_CAPACITY: usize = 128;
+
+/// 带 request_id 的命令的 task 交回来发送的内容
+enum Multiplexed {
+    /// 响应的一个 frame
+    Frame(Arc<CommandResponse>),
+    /// 命令的所有 frame 都交回来了，带上命令的名字和状态码
+    Done(&'static str, u32),
+}
+
+/// wait_for 轮询的最短和最长间隔
+const WAIT_MIN_INTERVAL: Duration = Duration::from_millis(10);
+const WAIT_MAX_INTERVAL: Duration = Duration::from_millis(200);
+
 /// 处理服务器端的某个 accept 下来的 socket 的读写
-pub struct ProstServerStream<S> {
+pub struct ProstServerStream<S, Store = MemTable> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
-    service: Service,
+    service: Service<Store>,
+    recent: RecentRequests,
 }
 
 /// 处理客户端 socket 的读写
 pub struct ProstClientStream<S> {
     inner: ProstStream<S, CommandResponse, CommandRequest>,
+    session: Option<Session>,
+    ack_window: u32,
 }
 
-impl<S> ProstServerStream<S>
+impl<S, Store> ProstServerStream<S, Store>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    Store: Storage + Send + Sync + 'static,
 {
-    pub fn new(stream: S, service: Service) -> Self {
+    pub fn new(stream: S, service: Service<Store>) -> Self {
         Self {
             inner: ProstStream::new(stream),
             service,
+            recent: RecentRequests::new(DEFAULT_RECENT_CAPACITY),
         }
     }
 
+    /// 设置最多记录最近多少个请求
+    pub fn with_recent_capacity(mut self, capacity: usize) -> Self {
+        self.recent = RecentRequests::new(capacity);
+        self
+    }
+
     pub async fn process(mut self) -> Result<(), KvError> {
+        // 带 request_id 的命令在单独的 task 里执行，每生成一个 frame 就交回来发送；
+        // channel 满了之后 task 等待发送，不会在内存里堆积整个响应
+        let (done_tx, mut done_rx) = mpsc::channel(MULTIPLEX_CAPACITY);
+        let mut in_flight = 0;
         let stream = &mut self.inner;
-        while let Some(Ok(cmd)) = stream.next().await {
+        loop {
+            let cmd = tokio::select! {
+                cmd = stream.next(), if in_flight < MULTIPLEX_CAPACITY => match cmd {
+                    Some(Ok(cmd)) => cmd,
+                    _ => break,
+                },
+                Some(msg) = done_rx.recv() => {
+                    match msg {
+                        Multiplexed::Frame(data) => stream.send(&data).await.unwrap(),
+                        Multiplexed::Done(name, status) => {
+                            in_flight -= 1;
+                            self.recent.record(name, status);
+                        }
+                    }
+                    continue;
+                }
+            };
+
             info!("Got a new command: {:?}", cmd);
+            let name = cmd.name();
+            // Hello 和 Recent 处理的是连接本身，不需要交给 service
+            let data = match &cmd.request_data {
+                Some(RequestData::Hello(param)) => {
+                    stream.set_out_format(param.response_format());
+                    Some(CommandResponse::ok())
+                }
+                Some(RequestData::Recent(param)) => Some(self.recent.to_response(param.n as usize)),
+                // 带 request_id 的命令执行完才发送，订阅的响应没有尽头，只能在连接上直接执行
+                Some(RequestData::Subscribe(_)) if cmd.request_id != 0 => {
+                    Some(KvError::InvalidCommand("Subscribe can't have a request_id".into()).into())
+                }
+                _ => None,
+            };
+            if let Some(mut data) = data {
+                data.request_id = cmd.request_id;
+                stream.send(&data).await.unwrap();
+                self.recent.record(name, data.status);
+                continue;
+            }
+
+            if cmd.request_id != 0 {
+                let id = cmd.request_id;
+                let service = self.service.clone();
+                let done_tx = done_tx.clone();
+                in_flight += 1;
+                tokio::spawn(async move {
+                    let mut frames = service.execute(cmd);
+                    let mut status = None;
+                    while let Some(mut data) = frames.next().await {
+                        status.get_or_insert(data.status);
+                        Arc::make_mut(&mut data).request_id = id;
+                        // 连接已经断开的话就不用发了
+                        if done_tx.send(Multiplexed::Frame(data)).await.is_err() {
+                            return;
+                        }
+                    }
+                    let done = Multiplexed::Done(name, status.unwrap_or_default());
+                    let _ = done_tx.send(done).await;
+                });
+                continue;
+            }
+
+            let window = cmd.ack_window as usize;
             let mut res = self.service.execute(cmd);
+            let mut status = None;
+            let mut unacked = 0;
             while let Some(data) = res.next().await {
+                status.get_or_insert(data.status);
                 stream.send(&data).await.unwrap();
+                unacked += 1;
+                // 发完一个窗口之后等客户端确认，慢的客户端不会让发送的数据无限堆积
+                if window > 0 && unacked >= window && data.continued {
+                    match stream.next().await {
+                        Some(Ok(CommandRequest {
+                            request_data: Some(RequestData::Ack(_)),
+                            ..
+                        })) => unacked = 0,
+                        _ => return Err(KvError::Internal("Expected an ack from client".into())),
+                    }
+                }
+            }
+            self.recent.record(name, status.unwrap_or_default());
+        }
+
+        // 客户端不再发送命令之后，把还在执行的命令的响应发完
+        drop(done_tx);
+        while let Some(msg) = done_rx.recv().await {
+            match msg {
+                Multiplexed::Frame(data) => stream.send(&data).await.unwrap(),
+                Multiplexed::Done(name, status) => self.recent.record(name, status),
             }
         }
         // info!("Client {:?} disconnected", self.addr);
@@ -58,22 +197,131 @@ where
     pub fn new(stream: S) -> Self {
         Self {
             inner: ProstStream::new(stream),
+            session: None,
+            ack_window: 0,
         }
     }
 
+    /// 加入一个会话，同一个会话的连接之间保证 read-your-writes
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// 拆成多个 frame 的响应每收到 n 个 frame 确认一次，服务器最多领先客户端 n 个 frame
+    pub fn with_ack_window(mut self, n: u32) -> Self {
+        self.ack_window = n;
+        self
+    }
+
     pub async fn execute_unary(
         &mut self,
         cmd: &CommandRequest,
     ) -> Result<CommandResponse, KvError> {
-        let stream = &mut self.inner;
-        stream.send(cmd).await?;
+        let cmd = self.prepare(cmd, self.ack_window);
+        self.inner.send(&cmd).await?;
+        self.read_response(cmd.ack_window).await
+    }
+
+    /// 加上会话和流控的字段，得到真正发送的命令
+    fn prepare<'a>(&self, cmd: &'a CommandRequest, ack_window: u32) -> Cow<'a, CommandRequest> {
+        if self.session.is_none() && ack_window == 0 {
+            return Cow::Borrowed(cmd);
+        }
+        let cmd = match &self.session {
+            Some(session) => session.prepare(cmd),
+            None => cmd.clone(),
+        };
+        Cow::Owned(cmd.with_ack_window(ack_window))
+    }
+
+    /// 读取一个完整的响应，被拆成多个 frame 的响应会被拼接起来
+    async fn read_response(&mut self, ack_window: u32) -> Result<CommandResponse, KvError> {
+        let mut res = self.read_frame().await?;
+        let mut unacked = 1;
+        while res.continued {
+            if ack_window > 0 && unacked >= ack_window {
+                self.inner.send(&CommandRequest::new_ack()).await?;
+                unacked = 0;
+            }
+            let next = self.read_frame().await?;
+            unacked += 1;
+            res.merge_chunk(next);
+        }
+        if let Some(session) = &self.session {
+            session.observe(&res);
+        }
+        Ok(res)
+    }
 
-        match stream.next().await {
+    async fn read_frame(&mut self) -> Result<CommandResponse, KvError> {
+        match self.inner.next().await {
             Some(v) => v,
             None => Err(KvError::Internal("Didn't get any response".into())),
         }
     }
 
+    /// 用 pipeline 的方式发送一组命令：先把所有命令发出去，再按顺序读取每个命令的响应
+    ///
+    /// pipeline 中的命令不做流控：服务器等待 Ack 的时候会先读到后面的命令
+    pub async fn execute_batch(
+        &mut self,
+        cmds: &[CommandRequest],
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        for cmd in cmds {
+            let cmd = self.prepare(cmd, 0);
+            self.inner.feed(&cmd).await?;
+        }
+        self.inner.flush().await?;
+
+        let mut responses = Vec::with_capacity(cmds.len());
+        for _ in cmds {
+            responses.push(self.read_response(0).await?);
+        }
+        Ok(responses)
+    }
+
+    /// 等待 table 中 key 的 value 等于 target，返回是否在 timeout 之内等到了
+    ///
+    /// 服务器没有 watch 的机制，这里用 Hget 轮询，轮询的间隔从 10ms 开始逐渐增加到 200ms
+    pub async fn wait_for(
+        &mut self,
+        table: &str,
+        key: &str,
+        target: Value,
+        timeout: Duration,
+    ) -> Result<bool, KvError> {
+        let deadline = Instant::now() + timeout;
+        let mut interval = WAIT_MIN_INTERVAL;
+        let cmd = CommandRequest::new_hget(table, key);
+        loop {
+            let res = self.execute_unary(&cmd).await?;
+            if res.status == StatusCode::OK.as_u16() as u32 && res.values.first() == Some(&target) {
+                return Ok(true);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            time::sleep(interval.min(deadline - now)).await;
+            interval = (interval * 2).min(WAIT_MAX_INTERVAL);
+        }
+    }
+
+    /// 把 CSV（逗号分隔，第一行是 header）导入到 table 中，需要 TSV 之类的格式时使用 CsvImport
+    pub async fn import_csv(
+        &mut self,
+        reader: impl std::io::Read + Send + 'static,
+        table: &str,
+        key_col: usize,
+        value_col: usize,
+    ) -> Result<ImportReport, KvError> {
+        CsvImport::new(table, key_col, value_col)
+            .import(self, reader)
+            .await
+    }
+
     pub async fn execute_streaming(self, cmd: &CommandRequest) -> Result<StreamResult, KvError> {
         let mut stream = self.inner;
 
@@ -141,9 +389,14 @@ mod tests {
     use std::net::SocketAddr;
 
     use super::*;
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use crate::{assert_res_error, assert_res_ok, Format, Kvpair, MemTable, ServiceInner, Value};
     use anyhow::Result;
-    use bytes::Bytes;
+    use bytes::{Bytes, BytesMut};
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncWriteExt, ReadBuf};
     use tokio::net::{TcpListener, TcpStream};
 
     #[tokio::test]
@@ -198,6 +451,293 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn recent_should_return_latest_requests() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        client
+            .execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        client
+            .execute_unary(&CommandRequest::new_hget("t1", "k2"))
+            .await?;
+
+        let res = client
+            .execute_unary(&CommandRequest::new_recent(10))
+            .await?;
+        assert_eq!(res.status, 200);
+        let recent: Vec<_> = res
+            .values
+            .into_iter()
+            .map(|v| {
+                let s = String::try_from(v).unwrap();
+                s.split(' ').skip(1).collect::<Vec<_>>().join(" ")
+            })
+            .collect();
+        assert_eq!(recent, vec!["hset 200", "hget 404"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_csv_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        let data = "id,name,city\n1,tyr,\"Beijing, China\"\n2,broken\n3,\"say \"\"hi\"\"\",Paris\n";
+        let report = client.import_csv(data.as_bytes(), "users", 1, 2).await?;
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, 3);
+
+        let cmd = CommandRequest::new_hmget("users", vec!["tyr".into(), "say \"hi\"".into()]);
+        let res = client.execute_unary(&cmd).await?;
+        assert_res_ok(&res, &["Beijing, China".into(), "Paris".into()], &[]);
+
+        // TSV 没有 header
+        let data = "k1\tv1\nk2\tv2\n";
+        let report = CsvImport::new("tsv", 0, 1)
+            .with_delimiter(b'\t')
+            .with_headers(false)
+            .import(&mut client, data.as_bytes())
+            .await?;
+        assert_eq!(
+            report,
+            ImportReport {
+                imported: 2,
+                errors: vec![]
+            }
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunked_hgetall_should_be_reassembled() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_max_pairs_per_frame(1000)
+            .into();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            ProstServerStream::new(stream, service).process().await
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let pairs: Vec<_> = (0..5000)
+            .map(|i| Kvpair::new(format!("k{}", i), i.into()))
+            .collect();
+        client
+            .execute_unary(&CommandRequest::new_hmset("t1", pairs))
+            .await?;
+
+        // 5000 个 pair 分成 5 个 frame 返回，客户端拼接成一个响应
+        let res = client
+            .execute_unary(&CommandRequest::new_hgetall("t1"))
+            .await?;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.pairs.len(), 5000);
+        assert!(!res.continued);
+
+        // 后面的请求不受影响
+        let res = client
+            .execute_unary(&CommandRequest::new_hget("t1", "k42"))
+            .await?;
+        assert_res_ok(&res, &[42.into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slow_client_should_bound_unacked_frames() -> anyhow::Result<()> {
+        // duplex 的缓冲区足够放下整个响应，能限制服务器领先多少的只有流控
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024 * 1024);
+        let flight = Arc::new(InFlight::default());
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_max_pairs_per_frame(100)
+            .into();
+        let server = Metered::new(server_io, flight.clone(), None);
+        tokio::spawn(ProstServerStream::new(server, service).process());
+
+        // 客户端每次读 1KB 之前先睡 1ms
+        let delay = Some(Duration::from_millis(1));
+        let client = Metered::new(client_io, flight.clone(), delay);
+        let mut client = ProstClientStream::new(client).with_ack_window(2);
+        let pairs: Vec<_> = (0..2000)
+            .map(|i| Kvpair::new(format!("k{}", i), i.into()))
+            .collect();
+        client
+            .execute_unary(&CommandRequest::new_hmset("t1", pairs))
+            .await?;
+
+        // 2000 个 pair 分成 20 个 frame，服务器最多领先客户端 2 个 frame
+        let res = client
+            .execute_unary(&CommandRequest::new_hgetall("t1"))
+            .await?;
+        assert_eq!(res.pairs.len(), 2000);
+        let written = flight.written.load(Ordering::SeqCst);
+        let max = flight.max_unread.load(Ordering::SeqCst);
+        assert!(max * 5 < written, "max unread {} of {}", max, written);
+
+        // 流控结束之后连接还可以继续使用
+        let res = client
+            .execute_unary(&CommandRequest::new_hget("t1", "k42"))
+            .await?;
+        assert_res_ok(&res, &[42.into()], &[]);
+
+        Ok(())
+    }
+
+    /// 服务器写出了多少字节、客户端读了多少字节，以及写出但还没有被读走的最大字节数
+    #[derive(Default)]
+    struct InFlight {
+        written: AtomicUsize,
+        read: AtomicUsize,
+        max_unread: AtomicUsize,
+    }
+
+    /// 服务器端不设置 delay，只统计写出的字节；客户端设置 delay，每次最多读 1KB
+    struct Metered<S> {
+        inner: S,
+        flight: Arc<InFlight>,
+        delay: Option<Duration>,
+    }
+
+    impl<S> Metered<S> {
+        fn new(inner: S, flight: Arc<InFlight>, delay: Option<Duration>) -> Self {
+            Self {
+                inner,
+                flight,
+                delay,
+            }
+        }
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = &mut *self;
+            let delay = match this.delay {
+                Some(delay) => delay,
+                None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+            };
+
+            // 模拟一个处理得很慢的客户端，每次读之前都先停一下
+            std::thread::sleep(delay);
+            let mut limited = ReadBuf::new(buf.initialize_unfilled_to(buf.remaining().min(1024)));
+            futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+            let n = limited.filled().len();
+            buf.advance(n);
+            this.flight.read.fetch_add(n, Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let this = &mut *self;
+            let n = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+            if this.delay.is_none() {
+                let written = this.flight.written.fetch_add(n, Ordering::SeqCst) + n;
+                let unread = written - this.flight.read.load(Ordering::SeqCst);
+                this.flight.max_unread.fetch_max(unread, Ordering::SeqCst);
+            }
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn hello_with_json_format_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        // 用最原始的方式收发 frame，模拟一个只懂 JSON 的客户端
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hello(Format::Json).encode_frame(&mut buf)?;
+        CommandRequest::new_hset("t1", "k1", "v1".into()).encode_frame(&mut buf)?;
+        stream.write_all(&buf).await?;
+
+        // 写操作的响应带上了写入之后的版本号
+        let hset_res = CommandResponse {
+            version: 1,
+            ..Value::default().into()
+        };
+        for expected in [CommandResponse::ok(), hset_res] {
+            let mut buf = BytesMut::new();
+            read_frame(&mut stream, &mut buf).await?;
+            let res = CommandResponse::decode_json_frame(&mut buf)?;
+            assert_eq!(res, expected);
+        }
+
+        // 别的连接还是使用 protobuf
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let res = client
+            .execute_unary(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_res_error(&res, 404, "Not found");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_for_should_unblock_when_key_reaches_target() -> Result<()> {
+        let (addr, _server) = crate::testing::start_test_server().await?;
+        let mut waiter = crate::testing::test_client(addr).await?;
+        let mut setter = crate::testing::test_client(addr).await?;
+
+        let mut handle = tokio::spawn(async move {
+            waiter
+                .wait_for("locks", "l1", "ready".into(), Duration::from_secs(5))
+                .await
+        });
+
+        // key 先被设置成别的值，waiter 不能被唤醒
+        let cmd = CommandRequest::new_hset("locks", "l1", "pending".into());
+        setter.execute_unary(&cmd).await?;
+        let waited = time::timeout(Duration::from_millis(50), &mut handle).await;
+        assert!(waited.is_err());
+
+        let cmd = CommandRequest::new_hset("locks", "l1", "ready".into());
+        setter.execute_unary(&cmd).await?;
+        assert!(time::timeout(Duration::from_secs(1), handle).await???);
+
+        // 等不到的时候超时返回 false
+        let start = Instant::now();
+        let matched = setter
+            .wait_for("locks", "l1", "never".into(), Duration::from_millis(100))
+            .await?;
+        assert!(!matched);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        Ok(())
+    }
+
     async fn start_server() -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
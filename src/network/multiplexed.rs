@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+use super::{ProstClientStream, ProstStream, Session};
+use crate::{CommandRequest, CommandResponse, KvError};
+
+/// 最多有多少个命令在排队等待发送
+const PENDING_CAPACITY: usize = 128;
+
+type Reply = oneshot::Sender<Result<CommandResponse, KvError>>;
+
+/// 在一个连接上同时执行多个命令的客户端，由 ProstClientStream::into_multiplexed() 生成
+///
+/// 每个命令都带上一个 request_id，服务器并发执行这些命令，响应按执行完成的顺序返回，
+/// 后台的 task 根据响应的 request_id 把它交给对应的调用者。
+/// 和 YamuxCtrl 不同，它不需要为每个并发的命令打开一个新的 stream。
+/// MultiplexedClient 可以随便 clone，所有的 clone 共用同一个连接
+#[derive(Clone)]
+pub struct MultiplexedClient {
+    tx: mpsc::Sender<(CommandRequest, Reply)>,
+}
+
+impl<S> ProstClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// 转换成可以同时执行多个命令的客户端，之前设置的 Session 继续有效
+    pub fn into_multiplexed(self) -> MultiplexedClient {
+        let (tx, rx) = mpsc::channel(PENDING_CAPACITY);
+        tokio::spawn(run(self.inner, rx, self.session));
+        MultiplexedClient { tx }
+    }
+}
+
+impl MultiplexedClient {
+    /// 执行一个命令，等待它的响应；可以同时调用多次
+    pub async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send((cmd.clone(), reply))
+            .await
+            .map_err(|_| connection_closed())?;
+        rx.await.map_err(|_| connection_closed())?
+    }
+}
+
+fn connection_closed() -> KvError {
+    KvError::Internal("Connection closed".into())
+}
+
+/// 后台的 task：发送排队的命令，读取响应，按 request_id 交给等待的调用者
+async fn run<S>(
+    mut stream: ProstStream<S, CommandResponse, CommandRequest>,
+    mut rx: mpsc::Receiver<(CommandRequest, Reply)>,
+    session: Option<Session>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut next_id = 0;
+    // 还没有收到完整响应的命令，以及已经收到的那部分 frame
+    let mut waiting: HashMap<u64, (Reply, Option<CommandResponse>)> = HashMap::new();
+    let mut open = true;
+
+    while open || !waiting.is_empty() {
+        tokio::select! {
+            pending = rx.recv(), if open => {
+                let (cmd, reply) = match pending {
+                    Some(v) => v,
+                    // 所有的 MultiplexedClient 都 drop 了，等收完已经发出去的命令的响应再退出
+                    None => {
+                        open = false;
+                        continue;
+                    }
+                };
+
+                next_id += 1;
+                let mut cmd = match &session {
+                    Some(session) => session.prepare(&cmd),
+                    None => cmd,
+                };
+                cmd.request_id = next_id;
+                cmd.ack_window = 0;
+                match stream.send(&cmd).await {
+                    Ok(_) => {
+                        waiting.insert(next_id, (reply, None));
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+            frame = stream.next(), if !waiting.is_empty() => {
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    _ => break,
+                };
+
+                let id = frame.request_id;
+                let partial = match waiting.get_mut(&id) {
+                    Some((_reply, partial)) => partial,
+                    None => {
+                        warn!("Got a response for unknown request {}", id);
+                        continue;
+                    }
+                };
+                let res = match partial.take() {
+                    Some(mut res) => {
+                        res.merge_chunk(frame);
+                        res
+                    }
+                    None => frame,
+                };
+                if res.continued {
+                    *partial = Some(res);
+                    continue;
+                }
+
+                if let Some(session) = &session {
+                    session.observe(&res);
+                }
+                if let Some((reply, _)) = waiting.remove(&id) {
+                    let _ = reply.send(Ok(res));
+                }
+            }
+        }
+    }
+
+    // 连接断开了，还在等待的调用者都会收到错误
+    for (_id, (reply, _)) in waiting {
+        let _ = reply.send(Err(connection_closed()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, Kvpair, MemTable, ProstServerStream, Service, ServiceInner};
+    use anyhow::Result;
+    use futures::future;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn concurrent_requests_should_get_their_own_responses() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_max_pairs_per_frame(10)
+            .into();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            ProstServerStream::new(stream, service).process().await
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).into_multiplexed();
+        let pairs: Vec<_> = (0..50)
+            .map(|i| Kvpair::new(format!("k{}", i), i.into()))
+            .collect();
+        let cmd = CommandRequest::new_hmset("t1", pairs);
+        client.execute(&cmd).await?;
+
+        // 50 个命令同时在一个连接上等待响应，每个都拿到自己的 value
+        let results = future::join_all((0..50).map(|i| {
+            let client = client.clone();
+            async move {
+                let cmd = CommandRequest::new_hget("t1", format!("k{}", i));
+                (i, client.execute(&cmd).await)
+            }
+        }))
+        .await;
+        for (i, res) in results {
+            assert_res_ok(&res?, &[i.into()], &[]);
+        }
+
+        // 拆成多个 frame 的响应也能正确拼接
+        let res = client.execute(&CommandRequest::new_hgetall("t1")).await?;
+        assert_eq!(res.pairs.len(), 50);
+        assert!(!res.continued);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multiplexed_requests_should_be_bounded() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            ProstServerStream::new(stream, service).process().await
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).into_multiplexed();
+        // 超过上限的命令等前面的命令发送之后再执行，都能拿到响应
+        let n = super::super::MULTIPLEX_CAPACITY * 3;
+        let results = future::join_all((0..n).map(|i| {
+            let client = client.clone();
+            async move {
+                let cmd = CommandRequest::new_hset("t1", format!("k{}", i), (i as i64).into());
+                client.execute(&cmd).await
+            }
+        }))
+        .await;
+        for res in results {
+            assert_eq!(res?.status, 200);
+        }
+
+        // 订阅不会结束，不能带 request_id
+        let res = client
+            .execute(&CommandRequest::new_subscribe("lobby"))
+            .await?;
+        assert_eq!(res.status, 400);
+        let res = client
+            .execute(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_res_ok(&res, &[1.into()], &[]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+
+use crate::{Clock, CommandResponse, SystemClock, Value};
+
+/// 每个连接缺省记录最近多少个请求
+pub const DEFAULT_RECENT_CAPACITY: usize = 64;
+
+/// 连接上处理过的一个请求
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentRequest {
+    /// 命令的名字
+    pub command: &'static str,
+    /// 处理完成的时间（unix 时间戳，毫秒）
+    pub timestamp: u64,
+    /// 响应的状态码
+    pub status: u32,
+}
+
+/// 记录一个连接最近处理过的请求，超过容量之后最早的请求会被丢弃
+#[derive(Debug)]
+pub struct RecentRequests {
+    capacity: usize,
+    entries: VecDeque<RecentRequest>,
+}
+
+impl RecentRequests {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// 记录一个处理完的请求
+    pub fn record(&mut self, command: &'static str, status: u32) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(RecentRequest {
+            command,
+            timestamp: SystemClock.now(),
+            status,
+        });
+    }
+
+    /// 最近的 n 个请求，按处理的先后顺序排列
+    pub fn latest(&self, n: usize) -> impl Iterator<Item = &RecentRequest> {
+        self.entries
+            .iter()
+            .skip(self.entries.len().saturating_sub(n))
+    }
+
+    /// 把最近的 n 个请求转换成 Recent 命令的响应，每个请求是一个 "<timestamp> <command> <status>" 的 string
+    pub fn to_response(&self, n: usize) -> CommandResponse {
+        self.latest(n)
+            .map(|r| Value::from(format!("{} {} {}", r.timestamp, r.command, r.status)))
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_requests_should_evict_old_entries() {
+        let mut recent = RecentRequests::new(2);
+        recent.record("hset", 200);
+        recent.record("hget", 404);
+        recent.record("hdel", 200);
+
+        let commands: Vec<_> = recent.latest(10).map(|r| (r.command, r.status)).collect();
+        assert_eq!(commands, vec![("hget", 404), ("hdel", 200)]);
+
+        let commands: Vec<_> = recent.latest(1).map(|r| r.command).collect();
+        assert_eq!(commands, vec!["hdel"]);
+    }
+}
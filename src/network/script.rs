@@ -0,0 +1,239 @@
+use std::path::Path;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{CommandRequest, CommandResponse, KvError, Kvpair, ProstClientStream, Value};
+
+/// 执行脚本的结果
+#[derive(Debug, Default)]
+pub struct ScriptReport {
+    /// 每一行命令的结果：(行号，响应)，行号从 1 开始，空行和注释不在其中
+    /// 解析失败的行也有一个 400 的响应
+    pub results: Vec<(usize, CommandResponse)>,
+}
+
+impl ScriptReport {
+    /// 失败的行数
+    pub fn failed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, res)| res.status != 200)
+            .count()
+    }
+}
+
+/// 逐行执行文件中的命令，比如 `hset table key value`，空行和以 # 开头的行会被忽略
+///
+/// 某一行解析或者执行失败时，stop_on_error 为 true 则不再执行后面的行；
+/// 读取文件或者发送命令失败则直接返回错误
+pub async fn run_script<S>(
+    client: &mut ProstClientStream<S>,
+    path: impl AsRef<Path>,
+    stop_on_error: bool,
+) -> Result<ScriptReport, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let content = tokio::fs::read_to_string(path).await?;
+    let mut report = ScriptReport::default();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let res = match parse_command(line) {
+            Ok(cmd) => client.execute_unary(&cmd).await?,
+            Err(e) => e.into(),
+        };
+        let failed = res.status != 200;
+        report.results.push((i + 1, res));
+        if failed && stop_on_error {
+            break;
+        }
+    }
+    Ok(report)
+}
+
+/// 把一行文本解析成命令，参数之间用空格分隔，带空格的参数可以用双引号括起来
+///
+/// value 能解析成整数、浮点数或者 true/false 时按对应的类型写入，否则是 string
+pub fn parse_command(line: &str) -> Result<CommandRequest, KvError> {
+    let args = split_args(line)?;
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (name.to_lowercase(), args),
+        None => return Err(KvError::InvalidCommand("Empty command".into())),
+    };
+
+    let arity = |n: usize| match args.len() == n {
+        true => Ok(()),
+        false => Err(KvError::InvalidCommand(format!(
+            "{} takes {} arguments, got {}",
+            name,
+            n,
+            args.len()
+        ))),
+    };
+    let at_least = |n: usize| match args.len() >= n {
+        true => Ok(()),
+        false => Err(KvError::InvalidCommand(format!(
+            "{} takes at least {} arguments, got {}",
+            name,
+            n,
+            args.len()
+        ))),
+    };
+
+    let cmd = match name.as_str() {
+        "hget" => arity(2).map(|_| CommandRequest::new_hget(&args[0], &args[1]))?,
+        "hgetall" => arity(1).map(|_| CommandRequest::new_hgetall(&args[0]))?,
+        "hset" => {
+            arity(3)?;
+            CommandRequest::new_hset(&args[0], &args[1], parse_value(&args[2]))
+        }
+        "hdel" => arity(2).map(|_| CommandRequest::new_hdel(&args[0], &args[1]))?,
+        "hexist" => arity(2).map(|_| CommandRequest::new_hexist(&args[0], &args[1]))?,
+        "hmget" => at_least(2).map(|_| CommandRequest::new_hmget(&args[0], args[1..].to_vec()))?,
+        "hmdel" => at_least(2).map(|_| CommandRequest::new_hmdel(&args[0], args[1..].to_vec()))?,
+        "hmexist" => {
+            at_least(2)?;
+            CommandRequest::new_hmexist(&args[0], args[1..].to_vec())
+        }
+        "hmset" => {
+            // hmset table k1 v1 k2 v2 ...
+            if args.len() < 3 || args.len() % 2 == 0 {
+                let msg = "hmset takes a table and key value pairs".into();
+                return Err(KvError::InvalidCommand(msg));
+            }
+            let pairs = args[1..]
+                .chunks(2)
+                .map(|kv| Kvpair::new(&kv[0], parse_value(&kv[1])))
+                .collect();
+            CommandRequest::new_hmset(&args[0], pairs)
+        }
+        _ => return Err(KvError::InvalidCommand(format!("Unknown command {}", name))),
+    };
+    Ok(cmd)
+}
+
+/// 整数、浮点数和 true/false 转换成对应类型的 value，其余的都是字符串
+///
+/// inf、NaN 之类 f64 能解析的特殊值不当作数字，按字符串保存
+fn parse_value(s: &str) -> Value {
+    if let Ok(i) = s.parse::<i64>() {
+        return i.into();
+    }
+    if let Some(f) = s.parse::<f64>().ok().filter(|f| f.is_finite()) {
+        return f.into();
+    }
+    match s {
+        "true" => true.into(),
+        "false" => false.into(),
+        _ => s.into(),
+    }
+}
+
+/// 按空格拆分参数，双引号中的空格不拆分，\" 和 \\ 转义成 " 和 \
+fn split_args(line: &str) -> Result<Vec<String>, KvError> {
+    let mut args = Vec::new();
+    let mut chars = line.chars();
+    let mut current: Option<String> = None;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => arg.push(c),
+                            None => break,
+                        },
+                        Some(c) => arg.push(c),
+                        None => {
+                            let msg = format!("Unterminated quote in: {}", line);
+                            return Err(KvError::InvalidCommand(msg));
+                        }
+                    }
+                }
+            }
+            c if c.is_whitespace() => args.extend(current.take()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_res_ok;
+    use crate::testing::{start_test_server, test_client};
+    use std::io::Write;
+
+    #[test]
+    fn parse_command_should_work() {
+        let cmd = parse_command(r#"hset t1 k1 "hello world""#).unwrap();
+        assert_eq!(
+            cmd,
+            CommandRequest::new_hset("t1", "k1", "hello world".into())
+        );
+
+        let cmd = parse_command("HMSET t1 a 1 b 2.5").unwrap();
+        let pairs = vec![Kvpair::new("a", 1.into()), Kvpair::new("b", 2.5.into())];
+        assert_eq!(cmd, CommandRequest::new_hmset("t1", pairs));
+
+        // 不是有限的数字时按字符串处理
+        let cmd = parse_command("hmset t1 a inf b NaN c -Infinity").unwrap();
+        let pairs = vec![
+            Kvpair::new("a", "inf".into()),
+            Kvpair::new("b", "NaN".into()),
+            Kvpair::new("c", "-Infinity".into()),
+        ];
+        assert_eq!(cmd, CommandRequest::new_hmset("t1", pairs));
+
+        assert!(parse_command("hget t1").is_err());
+        assert!(parse_command("hset t1 k1 \"v1").is_err());
+        assert!(parse_command("nope t1").is_err());
+    }
+
+    #[tokio::test]
+    async fn run_script_should_work() -> anyhow::Result<()> {
+        let (addr, _server) = start_test_server().await?;
+        let mut client = test_client(addr).await?;
+
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "# 初始化数据")?;
+        writeln!(file, "hset t1 k1 v1")?;
+        writeln!(file)?;
+        writeln!(file, "hmset t1 k2 2 k3 \"v 3\"")?;
+        writeln!(file, "hget t1")?;
+        writeln!(file, "hdel t1 k1")?;
+
+        // 出错之后继续执行
+        let report = run_script(&mut client, file.path(), false).await?;
+        let lines: Vec<_> = report.results.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [2, 4, 5, 6]);
+        assert_eq!(report.failed(), 1);
+
+        let res = client
+            .execute_unary(&CommandRequest::new_hgetall("t1"))
+            .await?;
+        let pairs = &[Kvpair::new("k2", 2.into()), Kvpair::new("k3", "v 3".into())];
+        assert_res_ok(&res, &[], pairs);
+
+        // 出错之后停止执行，k1 不会被删除
+        client
+            .execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        let report = run_script(&mut client, file.path(), true).await?;
+        assert_eq!(report.results.len(), 3);
+        let res = client
+            .execute_unary(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+}
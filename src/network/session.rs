@@ -0,0 +1,87 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::{CommandRequest, CommandResponse};
+
+/// 跨连接的 read-your-writes 会话
+///
+/// 记录这个会话见过的最大的服务器版本号，同一个 Session clone 给多个连接之后，
+/// 在任何一个连接上发出的命令都会要求服务器的版本号不小于这个值，
+/// 这样在一个连接上写入的数据，在另一个连接上一定能读到
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    version: Arc<AtomicU64>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 会话见过的最大的版本号
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// 记录响应中的版本号
+    pub fn observe(&self, res: &CommandResponse) {
+        self.version.fetch_max(res.version, Ordering::SeqCst);
+    }
+
+    /// 给命令加上会话要求的最小版本号
+    pub fn prepare(&self, cmd: &CommandRequest) -> CommandRequest {
+        let min_version = cmd.min_version.max(self.version());
+        cmd.clone().with_min_version(min_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok,
+        testing::{start_test_server, test_client},
+        Value,
+    };
+
+    #[tokio::test]
+    async fn write_should_be_visible_on_another_connection() -> anyhow::Result<()> {
+        let (addr, _server) = start_test_server().await?;
+        let session = Session::new();
+        let mut client1 = test_client(addr).await?.with_session(session.clone());
+        let mut client2 = test_client(addr).await?.with_session(session.clone());
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client1.execute_unary(&cmd).await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        assert_eq!(session.version(), 1);
+
+        // 在另一个连接上读取，会带上第一个连接写入之后的版本号
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert_eq!(session.prepare(&cmd).min_version, 1);
+        let res = client2.execute_unary(&cmd).await?;
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_behind_session_should_fail() -> anyhow::Result<()> {
+        let (addr, _server) = start_test_server().await?;
+        let session = Session::new();
+        // 模拟会话在别的服务器上见过更新的版本
+        session.observe(&CommandResponse {
+            version: 10,
+            ..Default::default()
+        });
+
+        let mut client = test_client(addr).await?.with_session(session);
+        let res = client
+            .execute_unary(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, 503);
+        Ok(())
+    }
+}
@@ -1,13 +1,18 @@
-use bytes::BytesMut;
-use futures::{ready, FutureExt, Sink, Stream};
+use bytes::{buf::UninitSlice, BufMut, BytesMut};
+use futures::{ready, Sink, Stream};
 use std::{
+    io,
     marker::PhantomData,
+    mem::MaybeUninit,
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use crate::{read_frame, FrameCoder, KvError};
+use serde::Serialize;
+
+use super::frame::{frame_len, LEN_LEN};
+use crate::{Format, FrameCoder, KvError};
 
 /// 处理 KV server prost frame 的 stream
 pub struct ProstStream<S, In, Out> {
@@ -19,6 +24,8 @@ pub struct ProstStream<S, In, Out> {
     written: usize,
     // 读缓存
     rbuf: BytesMut,
+    // 发送数据时使用的编码格式
+    out_format: Format,
 
     // 类型占位符
     _in: PhantomData<In>,
@@ -34,22 +41,26 @@ where
     /// 当调用 next() 时，得到 Result<In, KvError>
     type Item = Result<In, KvError>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // 上一次调用结束后 rbuf 应该为空
-        assert!(self.rbuf.is_empty());
-
-        // 从 rbuf 中分离出 rest（摆脱对 self 的引用）
-        let mut rest = self.rbuf.split_off(0);
-
-        // 使用 read_frame 来获取数据
-        let fut = read_frame(&mut self.stream, &mut rest);
-        ready!(Box::pin(fut).poll_unpin(cx))?;
-
-        // 拿到一个 frame 的数据，把 buffer 合并回去
-        self.rbuf.unsplit(rest);
-
-        // 调用 decode_frame 获取解包后的数据
-        Poll::Ready(Some(In::decode_frame(&mut self.rbuf)))
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            // rbuf 里已经有一个完整的 frame 了，就把它拆出来 decode
+            match frame_len(&this.rbuf) {
+                Some(len) if this.rbuf.len() >= len => {
+                    let mut frame = this.rbuf.split_to(len);
+                    return Poll::Ready(Some(In::decode_frame(&mut frame)));
+                }
+                Some(len) => this.rbuf.reserve(len - this.rbuf.len()),
+                None => this.rbuf.reserve(LEN_LEN),
+            }
+
+            // 读到的数据都留在 rbuf 里，poll 被中途放弃（比如在 select! 里）时读到一半的 frame 不会丢失
+            let n = ready!(poll_read_buf(&mut this.stream, cx, &mut this.rbuf))?;
+            if n == 0 {
+                let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+                return Poll::Ready(Some(Err(e.into())));
+            }
+        }
     }
 }
 
@@ -58,7 +69,7 @@ impl<S, In, Out> Sink<&Out> for ProstStream<S, In, Out>
 where
     S: AsyncRead + AsyncWrite + Unpin,
     In: Unpin + Send,
-    Out: Unpin + Send + FrameCoder,
+    Out: Unpin + Send + FrameCoder + Serialize,
 {
     /// 如果发送出错，会返回 KvError
     type Error = KvError;
@@ -69,7 +80,10 @@ where
 
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        item.encode_frame(&mut this.wbuf)?;
+        match this.out_format {
+            Format::Protobuf => item.encode_frame(&mut this.wbuf)?,
+            Format::Json => item.encode_json_frame(&mut this.wbuf)?,
+        }
 
         Ok(())
     }
@@ -102,6 +116,26 @@ where
     }
 }
 
+/// 从 stream 中读取数据，追加到 buf 后面已经 reserve 好的空间里，返回读到的字节数
+fn poll_read_buf<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    cx: &mut Context<'_>,
+    buf: &mut BytesMut,
+) -> Poll<io::Result<usize>> {
+    let n = {
+        let dst = buf.chunk_mut();
+        // UninitSlice 和 [MaybeUninit<u8>] 的内存布局是一样的，ReadBuf 只会往里面写入数据
+        let dst = unsafe { &mut *(dst as *mut UninitSlice as *mut [MaybeUninit<u8>]) };
+        let mut read_buf = ReadBuf::uninit(dst);
+        ready!(Pin::new(stream).poll_read(cx, &mut read_buf))?;
+        read_buf.filled().len()
+    };
+
+    // 前 n 个字节已经被 poll_read 初始化了
+    unsafe { buf.advance_mut(n) };
+    Poll::Ready(Ok(n))
+}
+
 // 一般来说，如果我们的 Stream 是 Unpin，最好实现一下
 // Unpin 不像 Send/Sync 会自动实现
 impl<S, In, Out> Unpin for ProstStream<S, In, Out> where S: Unpin {}
@@ -117,10 +151,16 @@ where
             written: 0,
             wbuf: BytesMut::new(),
             rbuf: BytesMut::new(),
+            out_format: Format::Protobuf,
             _in: PhantomData::default(),
             _out: PhantomData::default(),
         }
     }
+
+    /// 设置之后发送数据时使用的编码格式
+    pub fn set_out_format(&mut self, format: Format) {
+        self.out_format = format;
+    }
 }
 
 #[cfg(test)]
@@ -1,8 +1,9 @@
 use std::io::Cursor;
 use std::sync::Arc;
 
+use ring::digest::{digest, SHA256};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig};
+use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig, Session};
 use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore};
 use tokio_rustls::webpki::DNSNameRef;
 use tokio_rustls::TlsConnector;
@@ -26,6 +27,8 @@ pub struct TlsServerAcceptor {
 pub struct TlsClientConnector {
     pub config: Arc<ClientConfig>,
     pub domain: Arc<String>,
+    /// 固定的服务器证书 SHA256 指纹，设置后服务器证书必须与之匹配
+    pinned_cert: Option<[u8; 32]>,
 }
 
 impl TlsClientConnector {
@@ -60,9 +63,16 @@ impl TlsClientConnector {
         Ok(Self {
             config: Arc::new(config),
             domain: Arc::new(domain.into()),
+            pinned_cert: None,
         })
     }
 
+    /// 固定服务器证书的 SHA256 指纹，即便 CA 被攻破，也无法用别的证书冒充服务器
+    pub fn with_pinned_cert(mut self, sha256: [u8; 32]) -> Self {
+        self.pinned_cert = Some(sha256);
+        self
+    }
+
     /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
     pub async fn connect<S>(&self, stream: S) -> Result<ClientTlsStream<S>, KvError>
     where
@@ -75,6 +85,15 @@ impl TlsClientConnector {
             .connect(dns, stream)
             .await?;
 
+        // 握手完成后，在发送任何数据之前检查服务器证书的指纹
+        if let Some(pinned) = &self.pinned_cert {
+            let certs = stream.get_ref().1.get_peer_certificates();
+            match certs.as_ref().and_then(|certs| certs.first()) {
+                Some(cert) if digest(&SHA256, &cert.0).as_ref() == pinned => {}
+                _ => return Err(KvError::CertPinMismatch),
+            }
+        }
+
         Ok(stream)
     }
 }
@@ -119,6 +138,19 @@ impl TlsServerAcceptor {
         let acceptor = TlsAcceptor::from(self.inner.clone());
         Ok(acceptor.accept(stream).await?)
     }
+
+    /// 客户端证书中的 CN，没有客户端证书或者证书中没有 CN 时返回 None
+    pub fn peer_common_name<S>(stream: &ServerTlsStream<S>) -> Option<String> {
+        let (_, session) = stream.get_ref();
+        let certs = session.get_peer_certificates()?;
+        common_name(certs.first()?)
+    }
+}
+
+fn common_name(cert: &Certificate) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(|v| v.to_owned())
 }
 
 fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
@@ -153,9 +185,9 @@ pub mod tls_utils {
     use crate::{KvError, TlsClientConnector, TlsServerAcceptor};
 
     const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
-    const CLIENT_CERT: &str = include_str!("../../fixtures/client.cert");
+    pub const CLIENT_CERT: &str = include_str!("../../fixtures/client.cert");
     const CLIENT_KEY: &str = include_str!("../../fixtures/client.key");
-    const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
+    pub const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
     const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
 
     pub fn tls_connector(client_cert: bool) -> Result<TlsClientConnector, KvError> {
@@ -179,9 +211,12 @@ pub mod tls_utils {
 
 #[cfg(test)]
 mod tests {
-    use super::tls_utils::tls_acceptor;
+    use super::tls_utils::{tls_acceptor, CLIENT_CERT, SERVER_CERT};
+    use super::{common_name, load_certs};
     use crate::network::tls::tls_utils::tls_connector;
+    use crate::KvError;
     use anyhow::Result;
+    use ring::digest::{digest, SHA256};
     use std::net::SocketAddr;
     use std::sync::Arc;
     use tokio::{
@@ -217,6 +252,44 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn tls_with_pinned_cert_should_work() -> Result<()> {
+        let addr = start_server(false).await?;
+
+        let cert = load_certs(SERVER_CERT)?.remove(0);
+        let mut pin = [0u8; 32];
+        pin.copy_from_slice(digest(&SHA256, &cert.0).as_ref());
+        let connector = tls_connector(false)?.with_pinned_cert(pin);
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_mismatched_pin_should_not_work() -> Result<()> {
+        let addr = start_server(false).await?;
+
+        let connector = tls_connector(false)?.with_pinned_cert([0u8; 32]);
+        let stream = TcpStream::connect(addr).await?;
+        let result = connector.connect(stream).await;
+
+        assert!(matches!(result, Err(KvError::CertPinMismatch)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn common_name_should_work() -> Result<()> {
+        let cert = load_certs(CLIENT_CERT)?.remove(0);
+        assert_eq!(common_name(&cert).as_deref(), Some("awesome-device-id"));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn tls_with_bad_domain_should_not_work() -> Result<()> {
         let addr = start_server(false).await?;
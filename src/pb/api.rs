@@ -1,13 +1,33 @@
 /// 来自客户端的命令请求
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12")]
+    /// 命令之外的字段从 100 开始编号，给新的命令留出空间
+    /// 为 true 时，写操作的数据落盘之后才返回
+    #[prost(bool, tag="100")]
+    pub durable: bool,
+    /// 写操作的 fencing token，比服务器见过的最大的 token 小的写操作会被拒绝，0 代表不检查。
+    /// 检查和写入是原子的，后台的 Hdelprefix 只在启动任务的时候检查
+    #[prost(uint64, tag="101")]
+    pub fencing_token: u64,
+    /// 要求服务器的版本号至少是 min_version，用于跨连接的 read-your-writes，0 代表不检查
+    #[prost(uint64, tag="102")]
+    pub min_version: u64,
+    /// 拆成多个 frame 的响应的流控窗口：服务器每发送 ack_window 个 frame 之后，
+    /// 要等到客户端发来 Ack 才继续发送，0 代表不做流控
+    #[prost(uint32, tag="103")]
+    pub ack_window: u32,
+    /// 不为 0 时服务器会和别的命令并发执行这个命令，响应的 request_id 和它相同，
+    /// 这样一个连接上可以同时有多个等待响应的命令，见 MultiplexedClient；
+    /// 这种命令不做流控，Subscribe 这样不会结束的命令也不能带 request_id
+    #[prost(uint64, tag="104")]
+    pub request_id: u64,
+    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87, 88")]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
 pub mod command_request {
-    #[derive(PartialOrd)]
+    #[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum RequestData {
         #[prost(message, tag="1")]
@@ -34,10 +54,162 @@ pub mod command_request {
         Unsubscribe(super::Unsubscribe),
         #[prost(message, tag="12")]
         Publish(super::Publish),
+        #[prost(message, tag="13")]
+        Compact(super::Compact),
+        #[prost(message, tag="14")]
+        Hgetrange(super::Hgetrange),
+        #[prost(message, tag="15")]
+        Hmerge(super::Hmerge),
+        #[prost(message, tag="16")]
+        Hcount(super::Hcount),
+        #[prost(message, tag="17")]
+        Hmsetnx(super::Hmsetnx),
+        #[prost(message, tag="18")]
+        Hmexistbits(super::Hmexistbits),
+        #[prost(message, tag="19")]
+        Hexpiretable(super::Hexpiretable),
+        #[prost(message, tag="20")]
+        Hvals(super::Hvals),
+        #[prost(message, tag="21")]
+        Hdelprefix(super::Hdelprefix),
+        #[prost(message, tag="22")]
+        Jobstatus(super::Jobstatus),
+        #[prost(message, tag="23")]
+        Hmatchhash(super::Hmatchhash),
+        #[prost(message, tag="24")]
+        Hkeyspage(super::Hkeyspage),
+        #[prost(message, tag="25")]
+        Recent(super::Recent),
+        #[prost(message, tag="26")]
+        Hdrain(super::Hdrain),
+        #[prost(message, tag="27")]
+        Hpatch(super::Hpatch),
+        #[prost(message, tag="28")]
+        Holdest(super::Holdest),
+        #[prost(message, tag="29")]
+        Hnewest(super::Hnewest),
+        #[prost(message, tag="30")]
+        Hello(super::Hello),
+        #[prost(message, tag="31")]
+        Backendinfo(super::Backendinfo),
+        #[prost(message, tag="32")]
+        Hrotate(super::Hrotate),
+        #[prost(message, tag="33")]
+        Hhistory(super::Hhistory),
+        #[prost(message, tag="34")]
+        Lockmany(super::Lockmany),
+        #[prost(message, tag="35")]
+        Slowest(super::Slowest),
+        #[prost(message, tag="36")]
+        Hmdelif(super::Hmdelif),
+        #[prost(message, tag="37")]
+        Hgettouch(super::Hgettouch),
+        #[prost(message, tag="38")]
+        Htablehash(super::Htablehash),
+        #[prost(message, tag="39")]
+        Hincrex(super::Hincrex),
+        #[prost(message, tag="40")]
+        Hreapexpired(super::Hreapexpired),
+        #[prost(message, tag="41")]
+        Hgetreset(super::Hgetreset),
+        #[prost(message, tag="42")]
+        Lpushcap(super::Lpushcap),
+        #[prost(message, tag="43")]
+        Eval(super::Eval),
+        #[prost(message, tag="44")]
+        Tablesizes(super::Tablesizes),
+        #[prost(message, tag="45")]
+        Sunionstore(super::Sunionstore),
+        #[prost(message, tag="46")]
+        Sinterstore(super::Sinterstore),
+        #[prost(message, tag="47")]
+        Pfadd(super::Pfadd),
+        #[prost(message, tag="48")]
+        Pfcount(super::Pfcount),
+        #[prost(message, tag="49")]
+        Hreplace(super::Hreplace),
+        #[prost(message, tag="50")]
+        Setbit(super::Setbit),
+        #[prost(message, tag="51")]
+        Getbit(super::Getbit),
+        #[prost(message, tag="52")]
+        Hexpiregt(super::Hexpiregt),
+        #[prost(message, tag="53")]
+        Serverinfo(super::Serverinfo),
+        #[prost(message, tag="54")]
+        Lpopn(super::Lpopn),
+        #[prost(message, tag="55")]
+        Hsetkeepttl(super::Hsetkeepttl),
+        #[prost(message, tag="56")]
+        Hsetct(super::Hsetct),
+        #[prost(message, tag="57")]
+        Hgetct(super::Hgetct),
+        #[prost(message, tag="58")]
+        Trename(super::Trename),
+        #[prost(message, tag="59")]
+        Ack(super::Ack),
+        #[prost(message, tag="60")]
+        Hmodify(super::Hmodify),
+        #[prost(message, tag="61")]
+        Hmgetttl(super::Hmgetttl),
+        #[prost(message, tag="62")]
+        Hsetpub(super::Hsetpub),
+        #[prost(message, tag="63")]
+        Hgetifchanged(super::Hgetifchanged),
+        #[prost(message, tag="64")]
+        Hweightedrand(super::Hweightedrand),
+        #[prost(message, tag="65")]
+        Hsetifdiff(super::Hsetifdiff),
+        #[prost(message, tag="66")]
+        Hdrainprefix(super::Hdrainprefix),
+        #[prost(message, tag="67")]
+        Hmincr(super::Hmincr),
+        #[prost(message, tag="68")]
+        Lease(super::Lease),
+        #[prost(message, tag="69")]
+        Tdiff(super::Tdiff),
+        #[prost(message, tag="70")]
+        Cdc(super::Cdc),
+        #[prost(message, tag="71")]
+        Hincrclamp(super::Hincrclamp),
+        #[prost(message, tag="72")]
+        Hquantiles(super::Hquantiles),
+        #[prost(message, tag="73")]
+        Hmgetsnapshot(super::Hmgetsnapshot),
+        #[prost(message, tag="74")]
+        Harchive(super::Harchive),
+        #[prost(message, tag="75")]
+        Hsetwithlen(super::Hsetwithlen),
+        #[prost(message, tag="76")]
+        Hgetblock(super::Hgetblock),
+        #[prost(message, tag="77")]
+        Hpexpire(super::Hpexpire),
+        #[prost(message, tag="78")]
+        Hpsetex(super::Hpsetex),
+        #[prost(message, tag="79")]
+        Hgethot(super::Hgethot),
+        #[prost(message, tag="80")]
+        Hhotkeys(super::Hhotkeys),
+        #[prost(message, tag="81")]
+        Lcompact(super::Lcompact),
+        #[prost(message, tag="82")]
+        Tcreate(super::Tcreate),
+        #[prost(message, tag="83")]
+        Tconfig(super::Tconfig),
+        #[prost(message, tag="84")]
+        Verify(super::Verify),
+        #[prost(message, tag="85")]
+        Rotate(super::Rotate),
+        #[prost(message, tag="86")]
+        Getgen(super::Getgen),
+        #[prost(message, tag="87")]
+        Hagg(super::Hagg),
+        #[prost(message, tag="88")]
+        Hdiffapply(super::Hdiffapply),
     }
 }
 /// 服务器的响应
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandResponse {
     /// 状态码；复用 HTTP 2xx/4xx/5xx 状态码
@@ -52,9 +224,26 @@ pub struct CommandResponse {
     /// 成功返回的 kv pairs
     #[prost(message, repeated, tag="4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 分页返回的命令，后面是否还有更多的数据
+    #[prost(bool, tag="5")]
+    pub has_more: bool,
+    /// 一个响应太大时会被拆成多个 frame 发送，除了最后一个 frame 之外 continued 都为 true
+    /// 客户端需要把 values 和 pairs 拼接起来，作为一个完整的响应
+    #[prost(bool, tag="6")]
+    pub continued: bool,
+    /// 执行完命令之后服务器的版本号，每个成功的写操作都会让版本号加一
+    #[prost(uint64, tag="7")]
+    pub version: u64,
+    /// 对应的命令的 request_id
+    #[prost(uint64, tag="8")]
+    pub request_id: u64,
+    /// 多个 key 的命令（Hmget、Hmexist、Hmdel、Hmgetttl）中每个 key 各自的状态码，和命令中的 key 一一对应，
+    /// 用来区分 key 不存在（404）和读取这个 key 时出错（5xx），其余的命令为空
+    #[prost(uint32, repeated, tag="9")]
+    pub item_statuses: ::prost::alloc::vec::Vec<u32>,
 }
 /// 从 table 中获取一个 key，返回 value
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
     #[prost(string, tag="1")]
@@ -62,15 +251,400 @@ pub struct Hget {
     #[prost(string, tag="2")]
     pub key: ::prost::alloc::string::String,
 }
+/// 和 Hget 一样，但 key 不存在时最多等待 timeout_ms 毫秒，等到 key 被写入就返回它的 value，
+/// 超时返回 408。timeout_ms 为 0 时不等待。只有经过 Service 的写入才能唤醒等待
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetblock {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub timeout_ms: u64,
+}
+/// 获取一个 key 的 value，同时原子地把它的过期时间重置为 ttl_secs 秒之后
+/// 用于滑动过期的缓存，ttl_secs 必须大于 0
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgettouch {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub ttl_secs: u64,
+}
+/// 只有新的过期时间（ttl_secs 秒之后）比 key 现在的过期时间更晚时才设置，返回是否设置了
+/// key 不存在或者永不过期时返回 false，ttl_secs 必须大于 0
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpiregt {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub ttl_secs: u64,
+}
+/// 设置 key 在 ttl_ms 毫秒之后过期，返回是否设置了（key 不存在时为 false），ttl_ms 必须大于 0
+/// 过期时间本来就是按毫秒存储的，和按秒设置的命令可以混用
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hpexpire {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub ttl_ms: u64,
+}
+/// 原子地写入 value 并设置 ttl_ms 毫秒之后过期，返回之前的 value，ttl_ms 必须大于 0
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hpsetex {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(uint64, tag="4")]
+    pub ttl_ms: u64,
+}
 /// 从 table 中获取所有的 Kvpair
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
 }
+/// 比较 key 的 value 的哈希和客户端缓存的哈希是否一致，返回 bool
+/// 哈希是 encode 之后的 Value 的 xxh3 64 位哈希（大端字节序），见 Value::xxhash
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmatchhash {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="3")]
+    pub client_hash: ::prost::bytes::Bytes,
+}
+/// 条件读取：key 的 value 的哈希和 since_hash 不一致时返回 [value, 新的哈希]，
+/// 一致时返回 304 且不带数据，哈希的算法和 Hmatchhash 一样
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetifchanged {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="3")]
+    pub since_hash: ::prost::bytes::Bytes,
+}
+/// 从 table 中随机选出 n 个不同的 kv pair，每个 key 被选中的概率和它的数值 value 成正比
+/// value 必须是整数或者浮点数，不是正数的 key 不会被选中；table 中有别的类型的 value 时返回 400
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hweightedrand {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub n: u32,
+}
+/// 用 t-digest 估算 table 中所有数值 value 的分位数，按 qs 的顺序返回对应的浮点数
+/// value 必须是整数或者浮点数，table 中有别的类型的 value 或者 q 不在 [0, 1] 之内时返回 400，
+/// table 为空时返回 404
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hquantiles {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(double, repeated, tag="2")]
+    pub qs: ::prost::alloc::vec::Vec<f64>,
+}
+/// 在服务器上遍历一次 table，对所有的 value 做聚合，返回一个数
+/// value 必须是整数或者浮点数，table 中有别的类型的 value 时返回 400；全是整数时 SUM、MIN、MAX
+/// 返回整数，有浮点数时返回浮点数，AVG 总是返回浮点数。table 为空时 SUM 和 COUNT 返回 0，别的返回 404
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hagg {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(enumeration="AggOp", tag="2")]
+    pub op: i32,
+}
+/// 返回整个 table 的哈希，用于比较不同节点上的 table 是否一致
+/// 把所有 kv pair 按 key 排序，依次计算 encode 之后的 Kvpair 的 xxh3 64 位哈希（大端字节序）
+/// 相同内容的 table 哈希一定相同，和写入的顺序无关
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Htablehash {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 按顺序分页获取 table 中的 key，从第 offset 个 key 开始最多返回 limit 个
+/// limit 为 0 时返回 offset 之后所有的 key
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hkeyspage {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint64, tag="2")]
+    pub offset: u64,
+    #[prost(uint64, tag="3")]
+    pub limit: u64,
+}
+/// 把 value 当作 JSON，原子地应用一个 JSON merge patch（RFC 7386），返回修改后的 value
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hpatch {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub json_patch: ::prost::alloc::string::String,
+}
+/// 按写入的先后顺序，返回最早写入的 n 个 kv pair（覆盖写入也算一次写入）
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Holdest {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub n: u32,
+}
+/// 按写入的先后顺序，返回最晚写入的 n 个 kv pair，最晚写入的排在最前面
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hnewest {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub n: u32,
+}
+/// 把新的 value 插入到 key 的历史 list 的最前面，list 最多保留 keep 个 value（0 代表不限制）
+/// key 原来的过期时间会被保留，返回之前的 value
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hrotate {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(uint32, tag="4")]
+    pub keep: u32,
+}
+/// 把 value 插入到 key 的 list 的最前面，并从另一端删除多余的元素，最多保留 max 个（max 必须大于 0）
+/// key 不存在时创建一个新的 list，key 原来的过期时间会被保留，返回插入之后 list 的长度
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lpushcap {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(uint32, tag="4")]
+    pub max: u32,
+}
+/// 求 keys 的 set 的并集，原子地保存到 dest，返回结果中元素的个数
+/// 不存在的 key 当作空集合，结果为空时删除 dest
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sunionstore {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub dest: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="3")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 求 keys 的 set 的交集，原子地保存到 dest，返回结果中元素的个数
+/// 不存在的 key 当作空集合，结果为空时删除 dest
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sinterstore {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub dest: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="3")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 把 elements 加入 key 的 HyperLogLog，key 不存在时创建一个新的 HyperLogLog
+/// 返回 HyperLogLog 是否发生了变化
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Pfadd {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="3")]
+    pub elements: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 返回 key 的 HyperLogLog 估算的不同元素的个数，key 不存在时返回 0
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Pfcount {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 把 key 的 binary value 当作 bitmap，设置第 offset 位（每个字节从最高位开始数），返回之前的值
+/// key 不存在或者 bitmap 不够长时用 0 补齐
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Setbit {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub offset: u32,
+    #[prost(bool, tag="4")]
+    pub value: bool,
+}
+/// 返回 key 的 bitmap 的第 offset 位，key 不存在或者超出 bitmap 长度时返回 false
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Getbit {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub offset: u32,
+}
+/// 从 key 的 list 的最前面取出最多 count 个元素并删除它们（count 必须大于 0），
+/// list 取空之后删除 key，key 不存在时返回空的结果
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lpopn {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub count: u32,
+}
+/// 重新写入 key 的 list，释放多次 push、pop 之后多占用的空间，返回元素的个数
+/// 元素和过期时间都保持不变，key 不存在时返回 404
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lcompact {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 和 Hget 一样读取 key，同时把它的访问次数加一，key 不存在时不计数
+/// 访问次数保存在名为 "<table>#hits" 的 table 中，key 被删除之后它的访问次数不会被清除
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgethot {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 返回 Hgethot 访问次数最多的 n 个 key 和它们的访问次数，次数相同时按 key 排序
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hhotkeys {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub n: u32,
+}
+/// 轮换密钥这样的 value：写入新的 value，把版本号加一（从 1 开始），之前的 value 在 grace_ms 毫秒之内
+/// 可以用 Getgen 读到；版本号和之前的 value 保存在内部使用的 table 里，不占用 table 中的 key，
+/// 和新的 value 在同一个事务里写入。返回 [新的版本号, 之前的 value]，grace_ms 必须大于 0
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Rotate {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub new_value: ::core::option::Option<Value>,
+    #[prost(uint64, tag="4")]
+    pub grace_ms: u64,
+}
+/// 返回 [key 当前的版本号, 之前的 value]，没有被 Rotate 过的 key 的版本号是 0，
+/// 宽限期过了之后之前的 value 为空
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Getgen {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 返回 key 的历史 list，最新的 value 在最前面
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hhistory {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 取出 table 中所有的 kv pair，同时清空 table
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdrain {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 把 table 中 within_secs 秒之内就要过期的 key 移到 archive_table 中（在那里不会过期），返回移动了多少个 key
+/// 没有设置过期时间的 key 不会被移动。移动是原子的，同时进行的写入不会被覆盖或者丢失
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Harchive {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub archive_table: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub within_secs: u64,
+}
+/// 取出 table 中所有以 prefix 开头的 kv pair，同时删除它们
+/// 多个客户端同时取同一个 prefix 时，每个 kv pair 只会被其中一个取到
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdrainprefix {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// 原子地把 table 的内容整个替换成 pairs，读操作不会看到替换到一半的 table
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hreplace {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+}
+/// 从 table 中获取所有的 value（不返回 key），按 key 排序
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hvals {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
 /// 从 table 中获取一组 key，返回它们的 value
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmget {
     #[prost(string, tag="1")]
@@ -78,16 +652,265 @@ pub struct Hmget {
     #[prost(string, repeated, tag="2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 在同一时刻读取多个 table 中的一组 key，按 reads 的顺序返回它们的 value，不存在的 key 返回空 value
+/// 同时进行的写操作（比如一个事务）要么全部可见，要么全部不可见
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmgetsnapshot {
+    #[prost(message, repeated, tag="1")]
+    pub reads: ::prost::alloc::vec::Vec<TableKey>,
+}
+/// 某个 table 中的一个 key
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TableKey {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 从 table 中获取一组 key 的 value 和剩余的存活时间，每个 key 返回 [value, 秒数] 两个 value
+/// 秒数向上取整，永不过期的 key 为 -1；不存在的 key 返回 [空 value, -2]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmgetttl {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 从 table 中获取一个 key 的 value 的 [start, end) 字节区间，
+/// value 必须是 string 或 binary，超出范围的下标会被截断到 value 的长度
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetrange {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub start: u64,
+    #[prost(uint64, tag="4")]
+    pub end: u64,
+}
+/// 统计 table 中 key 的个数，prefix 不为空时只统计以 prefix 开头的 key
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hcount {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// 删除 table 中所有以 prefix 开头的 key，返回删除了多少个 key
+/// background 为 true 时在后台分批删除，立刻返回后台任务的 id
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdelprefix {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub prefix: ::prost::alloc::string::String,
+    #[prost(bool, tag="3")]
+    pub background: bool,
+}
+/// 和服务器协商连接的参数，Hello 自己的响应已经使用协商之后的格式
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hello {
+    #[prost(enumeration="Format", tag="1")]
+    pub response_format: i32,
+}
+/// 确认收到了流控窗口中的所有 frame，只能在带 ack_window 的响应中间发送，服务器不会回复
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ack {
+}
+/// 管理命令：以 kv pair 的形式返回存储后端的状态，不同的后端返回的字段不同
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Backendinfo {
+}
+/// 管理命令：以 kv pair 的形式返回所有 table 的名字和 key 的个数，不保证顺序，空的 table 不返回
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Tablesizes {
+}
+/// 管理命令：把 table from 改名为 to，to 已经存在时只有 overwrite 为 true 才会替换掉它
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Trename {
+    #[prost(string, tag="1")]
+    pub from: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub to: ::prost::alloc::string::String,
+    #[prost(bool, tag="3")]
+    pub overwrite: bool,
+}
+/// 管理命令：table 中没有任何 key 时用 pairs 初始化它，返回是否初始化了，检查和写入是原子的
+/// table 中已经有数据时什么都不做，fail_if_exists 为 true 时返回 409
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Tcreate {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    #[prost(bool, tag="3")]
+    pub fail_if_exists: bool,
+}
+/// table 的配置，保存在名为 "__tconfig" 的 table 中，修改之后只影响之后的操作
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TableOptions {
+    /// 为 true 时 SledDB 把写入的数据压缩之后再存储，已经写入的数据不受影响
+    #[prost(bool, tag="1")]
+    pub compression: bool,
+    /// 不为 0 时，SledDB 中没有过期时间的 key 在写入时使用这个过期时间（毫秒）
+    #[prost(uint64, tag="2")]
+    pub default_ttl_ms: u64,
+    /// 为 true 时，修改这个 table 的命令都像设置了 durable 一样落盘之后才返回
+    #[prost(bool, tag="3")]
+    pub durable: bool,
+}
+/// 管理命令：修改 table 的配置，options 为空时恢复缺省的配置，返回之前的配置
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Tconfig {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="2")]
+    pub options: ::core::option::Option<TableOptions>,
+}
+/// 管理命令：检查 table 中的每个 key 能否正常读取，table 为空时检查所有的 table，
+/// 有 key 无法读取时命令也不会失败。返回无法读取的 key 的总数，每个检查过的 table 一个 kv pair，
+/// value 是这个 table 中无法读取的 key 的 list
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Verify {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 比较两个 table，以 kv pair 的形式按 key 的顺序返回它们之间的差异，每个 pair 的 value 是一个 list：
+/// 只在 table_a 中的 key 为 ["only_a", a 的 value]，只在 table_b 中的为 ["only_b", b 的 value]，
+/// 两边都有但 value 不同的为 ["differ", a 的 value, b 的 value]，value 相同的 key 不返回
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Tdiff {
+    #[prost(string, tag="1")]
+    pub table_a: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub table_b: ::prost::alloc::string::String,
+}
+/// 从 op log 中按顺序读取 offset 不小于 from_offset 的修改，最多返回 limit 个（0 代表不限制），
+/// 需要存储使用 CdcStore。每个修改是一个 kv pair：key 是 offset，value 是一个 list：
+/// 写入为 ["set", table, key, value]，删除为 ["del", table, key]，可以用 CommandResponse::changes() 解析。
+/// 客户端记住处理过的最后一个 offset，下次从它加一开始读，就不会遗漏或者重复
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Cdc {
+    #[prost(uint64, tag="1")]
+    pub from_offset: u64,
+    #[prost(uint32, tag="2")]
+    pub limit: u32,
+}
+/// op log 中记录的一个修改
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Change {
+    #[prost(uint64, tag="1")]
+    pub offset: u64,
+    #[prost(enumeration="ChangeOp", tag="2")]
+    pub op: i32,
+    #[prost(string, tag="3")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="4")]
+    pub key: ::prost::alloc::string::String,
+    /// 删除时为空
+    #[prost(message, optional, tag="5")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 管理命令：以 kv pair 的形式返回服务器的信息：
+/// uptime_ms（Service 创建以来的毫秒数）、version（crate 的版本）、features（打开的 feature 的 list）
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Serverinfo {
+}
+/// 管理命令：返回启动以来最慢的 n 个命令，最慢的排在最前面
+/// 每个命令是一个 "<耗时（微秒）> <command>" 的 string
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Slowest {
+    #[prost(uint32, tag="1")]
+    pub n: u32,
+}
+/// 管理命令：返回当前连接最近处理过的 n 个请求
+/// 每个请求是一个 "<timestamp> <command> <status>" 的 string，按处理的先后顺序排列
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Recent {
+    #[prost(uint32, tag="1")]
+    pub n: u32,
+}
+/// 查询后台任务的进度，返回 [已经处理的 key 的个数, 是否已经结束]
+/// 结束的任务只保留一段时间（缺省 10 分钟），之后查询返回 404
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Jobstatus {
+    #[prost(uint64, tag="1")]
+    pub id: u64,
+}
+/// 给 table 中所有的 key 设置同样的过期时间（秒），返回设置了多少个 key
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpiretable {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint64, tag="2")]
+    pub ttl_secs: u64,
+}
+/// 统计 table 中已经过期但还没有被清理的 key 的个数，remove 为 true 时同时删除它们
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hreapexpired {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bool, tag="2")]
+    pub remove: bool,
+}
+/// 只有在所有 key 都没有被锁住（不存在或者已经过期）时，才把它们都设置成 owner，返回是否成功
+/// ttl_secs 为 0 时锁不会过期
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lockmany {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, tag="3")]
+    pub owner: ::prost::alloc::string::String,
+    #[prost(uint64, tag="4")]
+    pub ttl_secs: u64,
+}
+/// 只有在所有 key 都不存在时才写入一组 kvpair，返回是否写入
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmsetnx {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+}
 /// 返回的值
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
+    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5, 6, 7")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
 pub mod value {
-    #[derive(PartialOrd)]
+    #[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Value {
         #[prost(string, tag="1")]
@@ -100,10 +923,150 @@ pub mod value {
         Float(f64),
         #[prost(bool, tag="5")]
         Bool(bool),
+        #[prost(message, tag="6")]
+        Set(super::Values),
+        #[prost(message, tag="7")]
+        List(super::Values),
     }
 }
+/// 一组 value，用于 set、list 之类的复合类型
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Values {
+    #[prost(message, repeated, tag="1")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 存储中实际保存的数据：value 以及它的元数据
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StoredValue {
+    #[prost(message, optional, tag="1")]
+    pub value: ::core::option::Option<Value>,
+    /// 过期的 unix 时间戳（毫秒），0 代表永不过期
+    #[prost(uint64, tag="2")]
+    pub expire_at: u64,
+    /// 写入的顺序，越大代表越晚写入，0 代表还没有分配
+    #[prost(uint64, tag="3")]
+    pub seq: u64,
+    /// value 的内容类型（比如 "application/json"），空字符串代表没有设置，修改 value 时会被清除
+    #[prost(string, tag="4")]
+    pub content_type: ::prost::alloc::string::String,
+}
+/// 原子地把 key 的整数 value 加上 delta，返回相加之后的 value，用于固定窗口的限流
+/// key 不存在（或者已经过期）时初始化为 delta，并设置 ttl_secs 秒之后过期（0 代表不过期）；
+/// 之后的递增不会修改过期时间，过期之后重新开始一个新的窗口
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrex {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub delta: i64,
+    #[prost(uint64, tag="4")]
+    pub ttl_secs: u64,
+}
+/// 在一个事务里把一组 key 的整数 value 分别加上 delta，按 deltas 的顺序返回相加之后的 value
+/// 不存在的 key 从 0 开始，原来的过期时间会被保留；任何一个 key 失败（比如不是整数）都不会修改任何 key
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmincr {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub deltas: ::prost::alloc::vec::Vec<KeyDelta>,
+}
+/// 原子地把 key 的整数 value 加上 delta，结果限制在 [min, max] 之内，用于库存之类有上下限的计数器
+/// key 不存在时从 0 开始，原来的过期时间会被保留；返回 [限制之后的 value, 是否被限制了]
+/// min 大于 max 时返回 400
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrclamp {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub delta: i64,
+    #[prost(int64, tag="4")]
+    pub min: i64,
+    #[prost(int64, tag="5")]
+    pub max: i64,
+}
+/// Hmincr 中一个 key 的增量
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct KeyDelta {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="2")]
+    pub delta: i64,
+}
+/// 获取或者续约一个租约，用于选主：key 没有被持有（不存在或者已经过期）时由 owner 获得，
+/// 已经被 owner 持有时续约，两种情况都把过期时间设置为 ttl_secs 秒之后（0 代表不过期）
+/// 返回 [是否由 owner 持有, 当前的持有者]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lease {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub owner: ::prost::alloc::string::String,
+    #[prost(uint64, tag="4")]
+    pub ttl_secs: u64,
+}
+/// 原子地取出计数器的整数 value，同时把它重置为 0（保留过期时间），key 不存在时返回 0
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetreset {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 在服务器上对一个 key 原子地执行脚本，返回执行之后 key 的 value（被删除或者不存在时为空）
+/// 比如 "if value > 10 then set 0 else set value + 1"，语法见 service/script.rs
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Eval {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub script: ::prost::alloc::string::String,
+}
+/// 把 operand 原子地合并到 table 中 key 的 value 上，返回合并后的 value
+/// 如果 key 不存在，合并的结果就是 operand 本身
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmerge {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(enumeration="MergeOp", tag="3")]
+    pub op: i32,
+    #[prost(message, optional, tag="4")]
+    pub operand: ::core::option::Option<Value>,
+}
+/// 对 table 中 key 的 value 原子地执行 op，写回变换之后的 value（保留过期时间）并返回
+/// key 不存在时返回 404，op 和 value 的类型不匹配时返回 400
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmodify {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(enumeration="ModifyOp", tag="3")]
+    pub op: i32,
+}
 /// 返回的 kvpair
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Kvpair {
     #[prost(string, tag="1")]
@@ -113,44 +1076,154 @@ pub struct Kvpair {
 }
 /// 往 table 里存一个 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hset {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
     #[prost(message, optional, tag="2")]
     pub pair: ::core::option::Option<Kvpair>,
+    #[prost(enumeration="ReturnMode", tag="3")]
+    pub return_mode: i32,
+}
+/// 和 Hset 一样设置 key 的 value，返回之前的 value，同时把 [table, key, value] 发布到 topic
+/// 写入成功之后、返回响应之前发布，在它返回之前订阅了 topic 的客户端一定能收到；
+/// 同一个 topic 的写入和发布不会交错，订阅者收到的顺序和写入的顺序一致
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetpub {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(string, tag="4")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(enumeration="ReturnMode", tag="5")]
+    pub return_mode: i32,
+}
+/// 只有新的 value 和现在的不一样时才写入（和 Hset 一样会清除过期时间），返回是否写入了
+/// value 相同时不会有任何写操作，key 的过期时间和写入顺序都保持不变
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetifdiff {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 多个 key 的 Hsetifdiff：在一个事务里把 table 改成 desired 中的样子，只写入 value 和现在不一样的 key，
+/// desired 之外的 key 不受影响。按 desired 的顺序返回真正写入了的 key，value 为 [之前的 value, 写入的 value]，
+/// key 之前不存在时之前的 value 为空
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdiffapply {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub desired: ::prost::alloc::vec::Vec<Kvpair>,
+}
+/// 写入 key，返回写入之后 table 中 key 的个数，统计的结果和这次写入之间不会插入别的写操作
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetwithlen {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 和 Hset 一样设置 key 的 value，返回之前的 value，但是保留 key 原来的过期时间
+/// key 不存在时创建的 key 永不过期
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetkeepttl {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(enumeration="ReturnMode", tag="4")]
+    pub return_mode: i32,
+}
+/// 和 Hset 一样设置 key 的 value，同时给 value 打上内容类型的标签，返回之前的 value
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetct {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(string, tag="4")]
+    pub content_type: ::prost::alloc::string::String,
+    #[prost(enumeration="ReturnMode", tag="5")]
+    pub return_mode: i32,
+}
+/// 获取 key 的 value 和它的内容类型，返回 [value, content_type]，没有设置内容类型时 content_type 为空字符串
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetct {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
 }
 /// 往 table 中存一组 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmset {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
     #[prost(message, repeated, tag="2")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    #[prost(enumeration="ReturnMode", tag="3")]
+    pub return_mode: i32,
 }
 /// 从 table 中删除一个 key，返回它之前的值
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
     #[prost(string, tag="2")]
     pub key: ::prost::alloc::string::String,
+    #[prost(enumeration="ReturnMode", tag="3")]
+    pub return_mode: i32,
 }
 /// 从 table 中删除一组 key，返回它们之前的值
-#[derive(PartialOrd)]
+/// 如果 count_only 为 true，只返回实际被删除的 key 的个数，和 return_mode 为 COUNT 一样
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmdel {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
     #[prost(string, repeated, tag="2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bool, tag="3")]
+    pub count_only: bool,
+    #[prost(enumeration="ReturnMode", tag="4")]
+    pub return_mode: i32,
+}
+/// 在一个事务里删除一组 key，每个 key 只有在 value 等于 entries 里期望的 value 时才会被删除
+/// 不匹配（或者不存在）的 key 会被跳过，不影响其他 key 的删除，返回每个 key 是否被删除
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmdelif {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub entries: ::prost::alloc::vec::Vec<Kvpair>,
 }
 /// 查看 key 是否存在
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hexist {
     #[prost(string, tag="1")]
@@ -159,7 +1232,7 @@ pub struct Hexist {
     pub key: ::prost::alloc::string::String,
 }
 /// 查看一组 key 是否存在
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmexist {
     #[prost(string, tag="1")]
@@ -167,16 +1240,25 @@ pub struct Hmexist {
     #[prost(string, repeated, tag="2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 查看多个 key 是否存在，返回一个 bitmap，第 i 个 bit 代表第 i 个 key 是否存在
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmexistbits {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
 /// subscribe 到某个主题，任何发布到这个主题的数据都会被收到
 /// 成功后，第一个返回的 CommandResponse，我们返回一个唯一的 subscription id
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Subscribe {
     #[prost(string, tag="1")]
     pub topic: ::prost::alloc::string::String,
 }
 /// 取消对某个主题的订阅
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Unsubscribe {
     #[prost(string, tag="1")]
@@ -185,7 +1267,7 @@ pub struct Unsubscribe {
     pub id: u32,
 }
 /// 发布数据到某个主题
-#[derive(PartialOrd)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Publish {
     #[prost(string, tag="1")]
@@ -193,3 +1275,80 @@ pub struct Publish {
     #[prost(message, repeated, tag="2")]
     pub data: ::prost::alloc::vec::Vec<Value>,
 }
+/// 触发存储的压缩（对 SledDB 来说会删除所有过期的 key，再把数据刷到磁盘，让 sled 回收空间）
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Compact {
+}
+/// 响应的编码格式
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Format {
+    Protobuf = 0,
+    /// 方便用通用的工具调试，frame 的结构不变，payload 是 JSON
+    Json = 1,
+}
+/// 修改的类型
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ChangeOp {
+    Set = 0,
+    Del = 1,
+}
+/// 合并操作的类型
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum MergeOp {
+    /// 整数相加
+    AddInt = 0,
+    /// 追加 binary 或者 string
+    AppendBytes = 1,
+    /// 求集合的并集
+    SetUnion = 2,
+    /// 取较大值
+    Max = 3,
+    /// 取较小值
+    Min = 4,
+}
+/// Hagg 的聚合操作
+/// enum 的值在整个 package 中不能重名，加上 AGG_OP_ 前缀，生成的代码里会去掉它
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum AggOp {
+    Sum = 0,
+    Min = 1,
+    Max = 2,
+    Avg = 3,
+    /// value 的个数
+    Count = 4,
+}
+/// 对 value 本身做变换的操作
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ModifyOp {
+    /// string 转成大写
+    Upper = 0,
+    /// string 转成小写
+    Lower = 1,
+    /// 反转 string、binary 或者 list
+    Reverse = 2,
+    /// 整数或者浮点数取负
+    Negate = 3,
+}
+/// 返回之前的 value 的写操作（Hset、Hmset、Hdel、Hmdel、Hsetpub、Hsetkeepttl、Hsetct）返回什么
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ReturnMode {
+    /// 返回修改之前的 value
+    OldValue = 0,
+    /// 返回受影响的 key 的个数
+    Count = 1,
+    /// 什么都不返回
+    None = 2,
+}
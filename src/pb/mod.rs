@@ -1,116 +1,1199 @@
 pub mod api;
 
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::time::Duration;
+
 use api::{command_request::RequestData, *};
 use bytes::Bytes;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use http::StatusCode;
 use prost::Message;
 use sled::IVec;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::KvError;
+
+impl CommandRequest {
+    pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hget(Hget {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgetblock(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        timeout_ms: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetblock(Hgetblock {
+                table: table.into(),
+                key: key.into(),
+                timeout_ms,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgetifchanged(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        since_hash: impl Into<Bytes>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetifchanged(Hgetifchanged {
+                table: table.into(),
+                key: key.into(),
+                since_hash: since_hash.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hweightedrand(table: impl Into<String>, n: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Hweightedrand(Hweightedrand {
+                table: table.into(),
+                n,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hquantiles(table: impl Into<String>, qs: Vec<f64>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hquantiles(Hquantiles {
+                table: table.into(),
+                qs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgetall(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmatchhash(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        client_hash: impl Into<Bytes>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmatchhash(Hmatchhash {
+                table: table.into(),
+                key: key.into(),
+                client_hash: client_hash.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hkeyspage(table: impl Into<String>, offset: u64, limit: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hkeyspage(Hkeyspage {
+                table: table.into(),
+                offset,
+                limit,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hpatch(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        json_patch: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hpatch(Hpatch {
+                table: table.into(),
+                key: key.into(),
+                json_patch: json_patch.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_holdest(table: impl Into<String>, n: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Holdest(Holdest {
+                table: table.into(),
+                n,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hnewest(table: impl Into<String>, n: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Hnewest(Hnewest {
+                table: table.into(),
+                n,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hrotate(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        keep: u32,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hrotate(Hrotate {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value),
+                keep,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgethot(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgethot(Hgethot {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hhotkeys(table: impl Into<String>, n: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Hhotkeys(Hhotkeys {
+                table: table.into(),
+                n,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hhistory(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hhistory(Hhistory {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hdrain(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdrain(Hdrain {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hvals(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hvals(Hvals {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hset(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hset(Hset {
+                table: table.into(),
+                pair: Some(Kvpair::new(key, value)),
+                return_mode: ReturnMode::OldValue as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hsetkeepttl(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetkeepttl(Hsetkeepttl {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value.into()),
+                return_mode: ReturnMode::OldValue as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hsetct(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+        content_type: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetct(Hsetct {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value.into()),
+                content_type: content_type.into(),
+                return_mode: ReturnMode::OldValue as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgetct(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetct(Hgetct {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_trename(from: impl Into<String>, to: impl Into<String>, overwrite: bool) -> Self {
+        Self {
+            request_data: Some(RequestData::Trename(Trename {
+                from: from.into(),
+                to: to.into(),
+                overwrite,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_tcreate(table: impl Into<String>, pairs: Vec<Kvpair>, fail_if_exists: bool) -> Self {
+        Self {
+            request_data: Some(RequestData::Tcreate(Tcreate {
+                table: table.into(),
+                pairs,
+                fail_if_exists,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_tconfig(table: impl Into<String>, options: TableOptions) -> Self {
+        Self {
+            request_data: Some(RequestData::Tconfig(Tconfig {
+                table: table.into(),
+                options: Some(options),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_verify(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Verify(Verify {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_tdiff(table_a: impl Into<String>, table_b: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Tdiff(Tdiff {
+                table_a: table_a.into(),
+                table_b: table_b.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_cdc(from_offset: u64, limit: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Cdc(Cdc { from_offset, limit })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgetrange(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        start: u64,
+        end: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetrange(Hgetrange {
+                table: table.into(),
+                key: key.into(),
+                start,
+                end,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmget(Hmget {
+                table: table.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmgetsnapshot<T: Into<String>, K: Into<String>>(
+        reads: impl IntoIterator<Item = (T, K)>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmgetsnapshot(Hmgetsnapshot {
+                reads: reads
+                    .into_iter()
+                    .map(|(table, key)| TableKey {
+                        table: table.into(),
+                        key: key.into(),
+                    })
+                    .collect(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmset(table: impl Into<String>, pairs: Vec<Kvpair>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmset(Hmset {
+                table: table.into(),
+                pairs,
+                return_mode: ReturnMode::OldValue as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdel(Hdel {
+                table: table.into(),
+                key: key.into(),
+                return_mode: ReturnMode::OldValue as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmdel(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmdel(Hmdel {
+                table: table.into(),
+                keys,
+                count_only: false,
+                return_mode: ReturnMode::OldValue as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmdel_count(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmdel(Hmdel {
+                table: table.into(),
+                keys,
+                count_only: true,
+                return_mode: ReturnMode::Count as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hexist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexist(Hexist {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmexist(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmexist(Hmexist {
+                table: table.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmexistbits(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmexistbits(Hmexistbits {
+                table: table.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_subscribe(name: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe { topic: name.into() })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_unsubscribe(name: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Unsubscribe(Unsubscribe {
+                topic: name.into(),
+                id,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_publish(name: impl Into<String>, data: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Publish(Publish {
+                topic: name.into(),
+                data,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmerge(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        op: MergeOp,
+        operand: Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmerge(Hmerge {
+                table: table.into(),
+                key: key.into(),
+                op: op as _,
+                operand: Some(operand),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hcount(table: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hcount(Hcount {
+                table: table.into(),
+                prefix: prefix.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgettouch(table: impl Into<String>, key: impl Into<String>, ttl_secs: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgettouch(Hgettouch {
+                table: table.into(),
+                key: key.into(),
+                ttl_secs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_htablehash(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Htablehash(Htablehash {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrex(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        delta: i64,
+        ttl_secs: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrex(Hincrex {
+                table: table.into(),
+                key: key.into(),
+                delta,
+                ttl_secs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hreapexpired(table: impl Into<String>, remove: bool) -> Self {
+        Self {
+            request_data: Some(RequestData::Hreapexpired(Hreapexpired {
+                table: table.into(),
+                remove,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_lease(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        owner: impl Into<String>,
+        ttl_secs: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Lease(Lease {
+                table: table.into(),
+                key: key.into(),
+                owner: owner.into(),
+                ttl_secs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrclamp(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        delta: i64,
+        min: i64,
+        max: i64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrclamp(Hincrclamp {
+                table: table.into(),
+                key: key.into(),
+                delta,
+                min,
+                max,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmincr<K: Into<String>>(
+        table: impl Into<String>,
+        deltas: impl IntoIterator<Item = (K, i64)>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmincr(Hmincr {
+                table: table.into(),
+                deltas: deltas
+                    .into_iter()
+                    .map(|(key, delta)| KeyDelta {
+                        key: key.into(),
+                        delta,
+                    })
+                    .collect(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgetreset(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetreset(Hgetreset {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_lpushcap(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+        max: u32,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Lpushcap(Lpushcap {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value.into()),
+                max,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_lcompact(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Lcompact(Lcompact {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_lpopn(table: impl Into<String>, key: impl Into<String>, count: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Lpopn(Lpopn {
+                table: table.into(),
+                key: key.into(),
+                count,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_eval(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        script: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Eval(Eval {
+                table: table.into(),
+                key: key.into(),
+                script: script.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_tablesizes() -> Self {
+        Self {
+            request_data: Some(RequestData::Tablesizes(Tablesizes {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_sunionstore(
+        table: impl Into<String>,
+        dest: impl Into<String>,
+        keys: Vec<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Sunionstore(Sunionstore {
+                table: table.into(),
+                dest: dest.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_sinterstore(
+        table: impl Into<String>,
+        dest: impl Into<String>,
+        keys: Vec<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Sinterstore(Sinterstore {
+                table: table.into(),
+                dest: dest.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_pfadd(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        elements: Vec<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Pfadd(Pfadd {
+                table: table.into(),
+                key: key.into(),
+                elements,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_pfcount(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Pfcount(Pfcount {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hreplace(table: impl Into<String>, pairs: Vec<Kvpair>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hreplace(Hreplace {
+                table: table.into(),
+                pairs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_setbit(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        offset: u32,
+        value: bool,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Setbit(Setbit {
+                table: table.into(),
+                key: key.into(),
+                offset,
+                value,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_getbit(table: impl Into<String>, key: impl Into<String>, offset: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Getbit(Getbit {
+                table: table.into(),
+                key: key.into(),
+                offset,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hexpiregt(table: impl Into<String>, key: impl Into<String>, ttl_secs: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpiregt(Hexpiregt {
+                table: table.into(),
+                key: key.into(),
+                ttl_secs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hpexpire(table: impl Into<String>, key: impl Into<String>, ttl_ms: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hpexpire(Hpexpire {
+                table: table.into(),
+                key: key.into(),
+                ttl_ms,
+            })),
+            ..Default::default()
+        }
+    }
 
-use crate::KvError;
+    pub fn new_hpsetex(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+        ttl_ms: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hpsetex(Hpsetex {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value.into()),
+                ttl_ms,
+            })),
+            ..Default::default()
+        }
+    }
 
-impl CommandRequest {
-    pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
+    pub fn new_rotate(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        new_value: impl Into<Value>,
+        grace_ms: u64,
+    ) -> Self {
         Self {
-            request_data: Some(RequestData::Hget(Hget {
+            request_data: Some(RequestData::Rotate(Rotate {
                 table: table.into(),
                 key: key.into(),
+                new_value: Some(new_value.into()),
+                grace_ms,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hgetall(table: impl Into<String>) -> Self {
+    pub fn new_getgen(table: impl Into<String>, key: impl Into<String>) -> Self {
         Self {
-            request_data: Some(RequestData::Hgetall(Hgetall {
+            request_data: Some(RequestData::Getgen(Getgen {
                 table: table.into(),
+                key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hset(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+    pub fn new_hmdelif(table: impl Into<String>, entries: Vec<Kvpair>) -> Self {
         Self {
-            request_data: Some(RequestData::Hset(Hset {
+            request_data: Some(RequestData::Hmdelif(Hmdelif {
                 table: table.into(),
-                pair: Some(Kvpair::new(key, value)),
+                entries,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_lockmany(
+        table: impl Into<String>,
+        keys: Vec<String>,
+        owner: impl Into<String>,
+        ttl_secs: u64,
+    ) -> Self {
         Self {
-            request_data: Some(RequestData::Hmget(Hmget {
+            request_data: Some(RequestData::Lockmany(Lockmany {
                 table: table.into(),
                 keys,
+                owner: owner.into(),
+                ttl_secs,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmset(table: impl Into<String>, pairs: Vec<Kvpair>) -> Self {
+    pub fn new_hmsetnx(table: impl Into<String>, pairs: Vec<Kvpair>) -> Self {
         Self {
-            request_data: Some(RequestData::Hmset(Hmset {
+            request_data: Some(RequestData::Hmsetnx(Hmsetnx {
                 table: table.into(),
                 pairs,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
+    pub fn new_hdelprefix(
+        table: impl Into<String>,
+        prefix: impl Into<String>,
+        background: bool,
+    ) -> Self {
         Self {
-            request_data: Some(RequestData::Hdel(Hdel {
+            request_data: Some(RequestData::Hdelprefix(Hdelprefix {
+                table: table.into(),
+                prefix: prefix.into(),
+                background,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_jobstatus(id: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Jobstatus(Jobstatus { id })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hexpiretable(table: impl Into<String>, ttl_secs: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpiretable(Hexpiretable {
+                table: table.into(),
+                ttl_secs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_compact() -> Self {
+        Self {
+            request_data: Some(RequestData::Compact(Compact {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hello(response_format: Format) -> Self {
+        Self {
+            request_data: Some(RequestData::Hello(Hello {
+                response_format: response_format as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_backendinfo() -> Self {
+        Self {
+            request_data: Some(RequestData::Backendinfo(Backendinfo {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_serverinfo() -> Self {
+        Self {
+            request_data: Some(RequestData::Serverinfo(Serverinfo {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_slowest(n: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Slowest(Slowest { n })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hagg(table: impl Into<String>, op: AggOp) -> Self {
+        Self {
+            request_data: Some(RequestData::Hagg(Hagg {
+                table: table.into(),
+                op: op as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmodify(table: impl Into<String>, key: impl Into<String>, op: ModifyOp) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmodify(Hmodify {
                 table: table.into(),
                 key: key.into(),
+                op: op as _,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmdel(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_hmgetttl(table: impl Into<String>, keys: Vec<String>) -> Self {
         Self {
-            request_data: Some(RequestData::Hmdel(Hmdel {
+            request_data: Some(RequestData::Hmgetttl(Hmgetttl {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hexist(table: impl Into<String>, key: impl Into<String>) -> Self {
+    pub fn new_hsetpub(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+        topic: impl Into<String>,
+    ) -> Self {
         Self {
-            request_data: Some(RequestData::Hexist(Hexist {
+            request_data: Some(RequestData::Hsetpub(Hsetpub {
                 table: table.into(),
                 key: key.into(),
+                value: Some(value.into()),
+                topic: topic.into(),
+                return_mode: ReturnMode::OldValue as _,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmexist(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_hsetifdiff(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Self {
         Self {
-            request_data: Some(RequestData::Hmexist(Hmexist {
+            request_data: Some(RequestData::Hsetifdiff(Hsetifdiff {
                 table: table.into(),
-                keys,
+                key: key.into(),
+                value: Some(value.into()),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hdiffapply(table: impl Into<String>, desired: Vec<Kvpair>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdiffapply(Hdiffapply {
+                table: table.into(),
+                desired,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_subscribe(name: impl Into<String>) -> Self {
+    pub fn new_hsetwithlen(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Self {
         Self {
-            request_data: Some(RequestData::Subscribe(Subscribe { topic: name.into() })),
+            request_data: Some(RequestData::Hsetwithlen(Hsetwithlen {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value.into()),
+            })),
+            ..Default::default()
         }
     }
 
-    pub fn new_unsubscribe(name: impl Into<String>, id: u32) -> Self {
+    pub fn new_harchive(
+        table: impl Into<String>,
+        archive_table: impl Into<String>,
+        within_secs: u64,
+    ) -> Self {
         Self {
-            request_data: Some(RequestData::Unsubscribe(Unsubscribe {
-                topic: name.into(),
-                id,
+            request_data: Some(RequestData::Harchive(Harchive {
+                table: table.into(),
+                archive_table: archive_table.into(),
+                within_secs,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_publish(name: impl Into<String>, data: Vec<Value>) -> Self {
+    pub fn new_hdrainprefix(table: impl Into<String>, prefix: impl Into<String>) -> Self {
         Self {
-            request_data: Some(RequestData::Publish(Publish {
-                topic: name.into(),
-                data,
+            request_data: Some(RequestData::Hdrainprefix(Hdrainprefix {
+                table: table.into(),
+                prefix: prefix.into(),
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_ack() -> Self {
+        Self {
+            request_data: Some(RequestData::Ack(Ack {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_recent(n: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Recent(Recent { n })),
+            ..Default::default()
+        }
+    }
+
+    /// 命令的名字
+    pub fn name(&self) -> &'static str {
+        match &self.request_data {
+            Some(RequestData::Hget(_)) => "hget",
+            Some(RequestData::Hgetall(_)) => "hgetall",
+            Some(RequestData::Hmget(_)) => "hmget",
+            Some(RequestData::Hset(_)) => "hset",
+            Some(RequestData::Hmset(_)) => "hmset",
+            Some(RequestData::Hdel(_)) => "hdel",
+            Some(RequestData::Hmdel(_)) => "hmdel",
+            Some(RequestData::Hexist(_)) => "hexist",
+            Some(RequestData::Hmexist(_)) => "hmexist",
+            Some(RequestData::Subscribe(_)) => "subscribe",
+            Some(RequestData::Unsubscribe(_)) => "unsubscribe",
+            Some(RequestData::Publish(_)) => "publish",
+            Some(RequestData::Compact(_)) => "compact",
+            Some(RequestData::Hgetrange(_)) => "hgetrange",
+            Some(RequestData::Hmerge(_)) => "hmerge",
+            Some(RequestData::Hcount(_)) => "hcount",
+            Some(RequestData::Hmsetnx(_)) => "hmsetnx",
+            Some(RequestData::Hmexistbits(_)) => "hmexistbits",
+            Some(RequestData::Hexpiretable(_)) => "hexpiretable",
+            Some(RequestData::Hvals(_)) => "hvals",
+            Some(RequestData::Hdelprefix(_)) => "hdelprefix",
+            Some(RequestData::Jobstatus(_)) => "jobstatus",
+            Some(RequestData::Hmatchhash(_)) => "hmatchhash",
+            Some(RequestData::Hkeyspage(_)) => "hkeyspage",
+            Some(RequestData::Recent(_)) => "recent",
+            Some(RequestData::Hdrain(_)) => "hdrain",
+            Some(RequestData::Hpatch(_)) => "hpatch",
+            Some(RequestData::Holdest(_)) => "holdest",
+            Some(RequestData::Hnewest(_)) => "hnewest",
+            Some(RequestData::Hello(_)) => "hello",
+            Some(RequestData::Backendinfo(_)) => "backendinfo",
+            Some(RequestData::Hrotate(_)) => "hrotate",
+            Some(RequestData::Hhistory(_)) => "hhistory",
+            Some(RequestData::Lockmany(_)) => "lockmany",
+            Some(RequestData::Slowest(_)) => "slowest",
+            Some(RequestData::Hmdelif(_)) => "hmdelif",
+            Some(RequestData::Hgettouch(_)) => "hgettouch",
+            Some(RequestData::Htablehash(_)) => "htablehash",
+            Some(RequestData::Hincrex(_)) => "hincrex",
+            Some(RequestData::Hreapexpired(_)) => "hreapexpired",
+            Some(RequestData::Hgetreset(_)) => "hgetreset",
+            Some(RequestData::Lpushcap(_)) => "lpushcap",
+            Some(RequestData::Eval(_)) => "eval",
+            Some(RequestData::Tablesizes(_)) => "tablesizes",
+            Some(RequestData::Sunionstore(_)) => "sunionstore",
+            Some(RequestData::Sinterstore(_)) => "sinterstore",
+            Some(RequestData::Pfadd(_)) => "pfadd",
+            Some(RequestData::Pfcount(_)) => "pfcount",
+            Some(RequestData::Hreplace(_)) => "hreplace",
+            Some(RequestData::Setbit(_)) => "setbit",
+            Some(RequestData::Getbit(_)) => "getbit",
+            Some(RequestData::Hexpiregt(_)) => "hexpiregt",
+            Some(RequestData::Serverinfo(_)) => "serverinfo",
+            Some(RequestData::Lpopn(_)) => "lpopn",
+            Some(RequestData::Hsetkeepttl(_)) => "hsetkeepttl",
+            Some(RequestData::Hsetct(_)) => "hsetct",
+            Some(RequestData::Hgetct(_)) => "hgetct",
+            Some(RequestData::Trename(_)) => "trename",
+            Some(RequestData::Ack(_)) => "ack",
+            Some(RequestData::Hmodify(_)) => "hmodify",
+            Some(RequestData::Hmgetttl(_)) => "hmgetttl",
+            Some(RequestData::Hsetpub(_)) => "hsetpub",
+            Some(RequestData::Hgetifchanged(_)) => "hgetifchanged",
+            Some(RequestData::Hweightedrand(_)) => "hweightedrand",
+            Some(RequestData::Hsetifdiff(_)) => "hsetifdiff",
+            Some(RequestData::Hdrainprefix(_)) => "hdrainprefix",
+            Some(RequestData::Hmincr(_)) => "hmincr",
+            Some(RequestData::Lease(_)) => "lease",
+            Some(RequestData::Tdiff(_)) => "tdiff",
+            Some(RequestData::Cdc(_)) => "cdc",
+            Some(RequestData::Hincrclamp(_)) => "hincrclamp",
+            Some(RequestData::Hquantiles(_)) => "hquantiles",
+            Some(RequestData::Hmgetsnapshot(_)) => "hmgetsnapshot",
+            Some(RequestData::Harchive(_)) => "harchive",
+            Some(RequestData::Hsetwithlen(_)) => "hsetwithlen",
+            Some(RequestData::Hgetblock(_)) => "hgetblock",
+            Some(RequestData::Hpexpire(_)) => "hpexpire",
+            Some(RequestData::Hpsetex(_)) => "hpsetex",
+            Some(RequestData::Hgethot(_)) => "hgethot",
+            Some(RequestData::Hhotkeys(_)) => "hhotkeys",
+            Some(RequestData::Lcompact(_)) => "lcompact",
+            Some(RequestData::Tcreate(_)) => "tcreate",
+            Some(RequestData::Tconfig(_)) => "tconfig",
+            Some(RequestData::Verify(_)) => "verify",
+            Some(RequestData::Rotate(_)) => "rotate",
+            Some(RequestData::Getgen(_)) => "getgen",
+            Some(RequestData::Hagg(_)) => "hagg",
+            Some(RequestData::Hdiffapply(_)) => "hdiffapply",
+            None => "none",
+        }
+    }
+
+    /// 要求写操作的数据落盘之后才返回
+    pub fn with_durable(mut self) -> Self {
+        self.durable = true;
+        self
+    }
+
+    /// 设置返回之前的 value 的写操作返回什么，对其他命令没有影响
+    pub fn with_return_mode(mut self, mode: ReturnMode) -> Self {
+        match &mut self.request_data {
+            Some(RequestData::Hset(v)) => v.set_return_mode(mode),
+            Some(RequestData::Hmset(v)) => v.set_return_mode(mode),
+            Some(RequestData::Hdel(v)) => v.set_return_mode(mode),
+            Some(RequestData::Hmdel(v)) => v.set_return_mode(mode),
+            Some(RequestData::Hsetpub(v)) => v.set_return_mode(mode),
+            Some(RequestData::Hsetkeepttl(v)) => v.set_return_mode(mode),
+            Some(RequestData::Hsetct(v)) => v.set_return_mode(mode),
+            _ => {}
         }
+        self
+    }
+
+    /// 给写操作带上 fencing token
+    pub fn with_fencing_token(mut self, token: u64) -> Self {
+        self.fencing_token = token;
+        self
+    }
+
+    /// 要求服务器至少处理到 version 这个版本之后才执行命令
+    pub fn with_min_version(mut self, version: u64) -> Self {
+        self.min_version = version;
+        self
+    }
+
+    /// 拆成多个 frame 的响应每发送 n 个 frame 就等待客户端的 Ack
+    pub fn with_ack_window(mut self, n: u32) -> Self {
+        self.ack_window = n;
+        self
     }
 
     /// 转换成 string 做错误处理
@@ -156,6 +1239,15 @@ impl From<i64> for Value {
     }
 }
 
+/// 从 f64 转换成 Value
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Self {
+            value: Some(value::Value::Float(f)),
+        }
+    }
+}
+
 /// 从 bool 转换成 Value
 impl From<bool> for Value {
     fn from(b: bool) -> Self {
@@ -188,12 +1280,127 @@ impl TryFrom<IVec> for Value {
 }
 
 impl Value {
+    /// 创建一个 set，重复的元素只保留一个
+    pub fn new_set(values: impl IntoIterator<Item = Value>) -> Self {
+        // Value 没有实现 Hash，用 encode 之后的字节去重
+        let mut seen = HashSet::new();
+        let set: Vec<Value> = values
+            .into_iter()
+            .filter(|v| seen.insert(v.encode_to_vec()))
+            .collect();
+        Self {
+            value: Some(value::Value::Set(Values { values: set })),
+        }
+    }
+
+    /// 创建一个 list
+    pub fn new_list(values: impl IntoIterator<Item = Value>) -> Self {
+        Self {
+            value: Some(value::Value::List(Values {
+                values: values.into_iter().collect(),
+            })),
+        }
+    }
+
+    /// encode 之后的 xxh3 64 位哈希（大端字节序），用于 Hmatchhash
+    pub fn xxhash(&self) -> [u8; 8] {
+        xxh3_64(&self.encode_to_vec()).to_be_bytes()
+    }
+
     /// 转换成 string 做错误处理
     pub fn format(&self) -> String {
         format!("{:?}", self)
     }
 }
 
+impl MergeOp {
+    /// 把 operand 合并到 old 上，返回合并后的 value
+    pub fn apply(&self, old: Option<Value>, operand: &Value) -> Result<Value, KvError> {
+        use value::Value::*;
+
+        let old = match old {
+            Some(v) => v,
+            // 之前没有值，合并的结果就是 operand（SET_UNION 的结果总是 set），
+            // operand 的类型和 key 存在时一样要检查，不能写入之后再也合并不了的值
+            None => {
+                return match (self, &operand.value) {
+                    (MergeOp::SetUnion, Some(Set(_))) => Ok(operand.clone()),
+                    (MergeOp::SetUnion, _) => Ok(Value::new_set([operand.clone()])),
+                    (MergeOp::AddInt, Some(Integer(_)))
+                    | (MergeOp::AppendBytes, Some(Binary(_) | String(_)))
+                    | (MergeOp::Max | MergeOp::Min, Some(Integer(_) | Float(_))) => {
+                        Ok(operand.clone())
+                    }
+                    _ => Err(KvError::InvalidCommand(format!(
+                        "Cannot merge {:?} with {:?}",
+                        self, operand
+                    ))),
+                }
+            }
+        };
+
+        let invalid =
+            || KvError::InvalidCommand(format!("Cannot merge {:?} with {:?}", old, operand));
+
+        match (self, &old.value, &operand.value) {
+            (MergeOp::AddInt, Some(Integer(a)), Some(Integer(b))) => a
+                .checked_add(*b)
+                .map(Value::from)
+                .ok_or_else(|| KvError::InvalidCommand("Integer overflow".into())),
+            (MergeOp::AppendBytes, Some(Binary(a)), Some(Binary(b))) => {
+                let mut buf = a.to_vec();
+                buf.extend_from_slice(b);
+                Ok(Bytes::from(buf).into())
+            }
+            (MergeOp::AppendBytes, Some(String(a)), Some(String(b))) => {
+                Ok(format!("{}{}", a, b).into())
+            }
+            (MergeOp::SetUnion, Some(Set(a)), Some(Set(b))) => Ok(Value::new_set(
+                a.values.iter().chain(b.values.iter()).cloned(),
+            )),
+            (MergeOp::SetUnion, Some(Set(a)), Some(_)) => Ok(Value::new_set(
+                a.values.iter().cloned().chain([operand.clone()]),
+            )),
+            (MergeOp::Max, Some(Integer(a)), Some(Integer(b))) => Ok((*a.max(b)).into()),
+            (MergeOp::Min, Some(Integer(a)), Some(Integer(b))) => Ok((*a.min(b)).into()),
+            (MergeOp::Max, Some(Float(a)), Some(Float(b))) => Ok(a.max(*b).into()),
+            (MergeOp::Min, Some(Float(a)), Some(Float(b))) => Ok(a.min(*b).into()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl ModifyOp {
+    /// 对 value 执行变换，返回变换之后的 value
+    pub fn apply(&self, v: &Value) -> Result<Value, KvError> {
+        use value::Value::*;
+
+        match (self, &v.value) {
+            (ModifyOp::Upper, Some(String(s))) => Ok(s.to_uppercase().into()),
+            (ModifyOp::Lower, Some(String(s))) => Ok(s.to_lowercase().into()),
+            (ModifyOp::Reverse, Some(String(s))) => {
+                Ok(s.chars().rev().collect::<std::string::String>().into())
+            }
+            (ModifyOp::Reverse, Some(Binary(b))) => {
+                let buf: Vec<u8> = b.iter().rev().copied().collect();
+                Ok(Bytes::from(buf).into())
+            }
+            (ModifyOp::Reverse, Some(List(l))) => {
+                Ok(Value::new_list(l.values.iter().rev().cloned()))
+            }
+            (ModifyOp::Negate, Some(Integer(i))) => i
+                .checked_neg()
+                .map(Value::from)
+                .ok_or_else(|| KvError::InvalidCommand("Integer overflow".into())),
+            (ModifyOp::Negate, Some(Float(f))) => Ok((-f).into()),
+            _ => Err(KvError::InvalidCommand(format!(
+                "Cannot apply {:?} to {:?}",
+                self, v
+            ))),
+        }
+    }
+}
+
 impl TryFrom<Value> for IVec {
     type Error = KvError;
 
@@ -204,6 +1411,160 @@ impl TryFrom<Value> for IVec {
     }
 }
 
+impl StoredValue {
+    /// 创建一个永不过期的 StoredValue
+    pub fn new(value: impl Into<Value>) -> Self {
+        Self {
+            value: Some(value.into()),
+            expire_at: 0,
+            seq: 0,
+            content_type: String::new(),
+        }
+    }
+
+    /// 在 now（unix 时间戳，毫秒）这个时刻是否已经过期
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expire_at != 0 && self.expire_at <= now
+    }
+
+    /// 设置从 now（unix 时间戳，毫秒）开始，经过 ttl 之后过期
+    pub fn expire_in(&mut self, now: u64, ttl: Duration) {
+        self.expire_at = now + ttl.as_millis() as u64;
+    }
+
+    /// 在 now（unix 时间戳，毫秒）这个时刻剩余的存活时间，没有设置过期时间时返回 None
+    pub fn ttl(&self, now: u64) -> Option<Duration> {
+        match self.expire_at {
+            0 => None,
+            at => Some(Duration::from_millis(at.saturating_sub(now))),
+        }
+    }
+
+    /// 取出存储的 value
+    pub fn into_value(self) -> Value {
+        self.value.unwrap_or_default()
+    }
+}
+
+/// 压缩过的数据以 0 开头：protobuf 的 field number 不能为 0，正常 encode 的数据不会以 0 开头
+const COMPRESSED: u8 = 0;
+
+impl StoredValue {
+    /// encode 之后用 deflate 压缩，见 TableOptions
+    pub(crate) fn to_compressed(&self) -> Result<IVec, KvError> {
+        let mut encoder = DeflateEncoder::new(vec![COMPRESSED], Compression::default());
+        encoder.write_all(&self.encode_to_vec())?;
+        Ok(encoder.finish()?.into())
+    }
+}
+
+impl TryFrom<IVec> for StoredValue {
+    type Error = KvError;
+
+    fn try_from(v: IVec) -> Result<Self, Self::Error> {
+        if v.first() == Some(&COMPRESSED) {
+            let mut buf = Vec::with_capacity(v.len() * 2);
+            DeflateDecoder::new(&v[1..]).read_to_end(&mut buf)?;
+            return Ok(StoredValue::decode(buf.as_slice())?);
+        }
+
+        // 早期的版本直接存储 Value。Value 是一个 oneof，encode 之后只有一个字段，
+        // 而 SledDB 写入的 StoredValue 总是同时有 value 和 seq，据此区分两种格式
+        match StoredValue::decode(v.as_ref()) {
+            Ok(stored) if stored.value.is_some() && stored.seq > 0 => Ok(stored),
+            stored => match Value::decode(v.as_ref()) {
+                Ok(value) => Ok(StoredValue::new(value)),
+                Err(e) => Err(stored.err().unwrap_or(e).into()),
+            },
+        }
+    }
+}
+
+impl TryFrom<StoredValue> for IVec {
+    type Error = KvError;
+
+    fn try_from(v: StoredValue) -> Result<Self, Self::Error> {
+        let mut buf = Vec::with_capacity(v.encoded_len());
+        v.encode(&mut buf)?;
+        Ok(buf.as_slice().into())
+    }
+}
+
+impl ChangeOp {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChangeOp::Set => "set",
+            ChangeOp::Del => "del",
+        }
+    }
+}
+
+impl From<Change> for Kvpair {
+    fn from(c: Change) -> Self {
+        let mut values = vec![c.op().as_str().into(), c.table.into(), c.key.into()];
+        if let Some(v) = c.value {
+            values.push(v);
+        }
+        Kvpair::new(c.offset.to_string(), Value::new_list(values))
+    }
+}
+
+impl TableOptions {
+    /// 以 kv pair 的形式返回所有的配置
+    pub fn to_pairs(&self) -> Vec<Kvpair> {
+        vec![
+            Kvpair::new("compression", self.compression.into()),
+            Kvpair::new("default_ttl_ms", (self.default_ttl_ms as i64).into()),
+            Kvpair::new("durable", self.durable.into()),
+        ]
+    }
+}
+
+/// TableOptions 以 encode 之后的 binary 保存在 "__tconfig" table 中
+impl From<TableOptions> for Value {
+    fn from(options: TableOptions) -> Self {
+        Bytes::from(options.encode_to_vec()).into()
+    }
+}
+
+impl TryFrom<Value> for TableOptions {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match &v.value {
+            Some(value::Value::Binary(buf)) => Ok(TableOptions::decode(buf.as_ref())?),
+            _ => Err(KvError::ConvertError(format!("{:?}", v), "TableOptions")),
+        }
+    }
+}
+
+impl TryFrom<Kvpair> for Change {
+    type Error = KvError;
+
+    fn try_from(pair: Kvpair) -> Result<Self, Self::Error> {
+        let err = || KvError::ConvertError(format!("{:?}", pair), "Change");
+        let offset = pair.key.parse().map_err(|_| err())?;
+        let mut values = match pair.value.as_ref().and_then(|v| v.value.as_ref()) {
+            Some(value::Value::List(list)) => list.values.clone().into_iter(),
+            _ => return Err(err()),
+        };
+        let mut next = || values.next().ok_or_else(err).and_then(String::try_from);
+        let op = match next()?.as_str() {
+            "set" => ChangeOp::Set,
+            "del" => ChangeOp::Del,
+            _ => return Err(err()),
+        };
+        let (table, key) = (next()?, next()?);
+        Ok(Change {
+            offset,
+            op: op as i32,
+            table,
+            key,
+            value: values.next(),
+        })
+    }
+}
+
 impl TryFrom<&Value> for i64 {
     type Error = KvError;
 
@@ -234,6 +1595,14 @@ impl CommandResponse {
         }
     }
 
+    /// 条件读取时数据没有变化
+    pub fn not_modified() -> Self {
+        CommandResponse {
+            status: StatusCode::NOT_MODIFIED.as_u16() as _,
+            ..Default::default()
+        }
+    }
+
     pub fn internal_error(msg: String) -> Self {
         CommandResponse {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
@@ -242,6 +1611,59 @@ impl CommandResponse {
         }
     }
 
+    /// 把 Cdc 返回的 pairs 解析成 Change
+    pub fn changes(&self) -> Result<Vec<Change>, KvError> {
+        self.pairs.iter().cloned().map(Change::try_from).collect()
+    }
+
+    /// 把 Hmexistbits 返回的 bitmap 解码成 n 个 key 是否存在
+    pub fn exist_bits(&self, n: usize) -> Result<Vec<bool>, KvError> {
+        let bits = match self.values.first().and_then(|v| v.value.as_ref()) {
+            Some(value::Value::Binary(bits)) if bits.len() * 8 >= n => bits,
+            _ => return Err(KvError::ConvertError(self.format(), "bitmap")),
+        };
+        Ok((0..n).map(|i| bits[i / 8] & (1 << (i % 8)) != 0).collect())
+    }
+
+    /// 把 pairs 拆分到多个响应中，每个响应最多 max_pairs 个 pair，values 放在第一个响应中
+    pub fn into_chunks(mut self, max_pairs: usize) -> Vec<CommandResponse> {
+        if max_pairs == 0 || self.pairs.len() <= max_pairs {
+            return vec![self];
+        }
+
+        let pairs = std::mem::take(&mut self.pairs);
+        let mut chunks: Vec<_> = pairs
+            .chunks(max_pairs)
+            .map(|pairs| CommandResponse {
+                status: self.status,
+                message: self.message.clone(),
+                pairs: pairs.to_vec(),
+                has_more: self.has_more,
+                continued: true,
+                version: self.version,
+                ..Default::default()
+            })
+            .collect();
+        chunks[0].values = self.values;
+        chunks[0].item_statuses = self.item_statuses;
+        if let Some(last) = chunks.last_mut() {
+            last.continued = false;
+        }
+        chunks
+    }
+
+    /// 把 continued 的下一个 frame 拼接到当前的响应上，发送到一半出错时用出错的 frame 的状态
+    pub fn merge_chunk(&mut self, next: CommandResponse) {
+        if next.status != StatusCode::OK.as_u16() as u32 {
+            self.status = next.status;
+            self.message = next.message;
+        }
+        self.values.extend(next.values);
+        self.pairs.extend(next.pairs);
+        self.item_statuses.extend(next.item_statuses);
+        self.continued = next.continued;
+    }
+
     /// 转换成 string 做错误处理
     pub fn format(&self) -> String {
         format!("{:?}", self)
@@ -278,11 +1700,32 @@ impl From<KvError> for CommandResponse {
             message: e.to_string(),
             values: vec![],
             pairs: vec![],
+            has_more: false,
+            continued: false,
+            version: 0,
+            request_id: 0,
+            item_statuses: vec![],
         };
 
         match e {
             KvError::NotFound(_) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
-            KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::InvalidCommand(_) | KvError::ValidationFailed(..) => {
+                result.status = StatusCode::BAD_REQUEST.as_u16() as _
+            }
+            KvError::FencedOut(..) | KvError::TableExists(_) => {
+                result.status = StatusCode::CONFLICT.as_u16() as _
+            }
+            KvError::ResponseTooLarge(..) => {
+                result.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _
+            }
+            KvError::VersionBehind(..) => {
+                result.status = StatusCode::SERVICE_UNAVAILABLE.as_u16() as _
+            }
+            KvError::Timeout(_) => result.status = StatusCode::REQUEST_TIMEOUT.as_u16() as _,
+            KvError::CommandDisabled(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::TooManyTables(_) => {
+                result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _
+            }
             _ => {}
         }
 
@@ -0,0 +1,95 @@
+//! Hagg 使用的聚合器
+//!
+//! 遍历 table 时逐个加入 value，只保存聚合的中间结果，不需要把所有的 value 读到内存里
+
+use crate::{value, AggOp, KvError, Value};
+
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+
+    /// 整数相加溢出时返回错误，有浮点数时结果是浮点数
+    fn add(self, other: Number) -> Result<Number, KvError> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a
+                .checked_add(b)
+                .map(Number::Int)
+                .ok_or_else(|| KvError::InvalidCommand("Integer overflow".into())),
+            (a, b) => Ok(Number::Float(a.as_f64() + b.as_f64())),
+        }
+    }
+}
+
+impl From<Number> for Value {
+    fn from(n: Number) -> Self {
+        match n {
+            Number::Int(i) => i.into(),
+            Number::Float(f) => f.into(),
+        }
+    }
+}
+
+/// 全是整数时 Sum、Min、Max 的结果是整数，有浮点数时是浮点数，Avg 总是浮点数
+#[derive(Debug)]
+pub struct Aggregator {
+    op: AggOp,
+    count: i64,
+    /// Sum、Avg 为目前为止的和，Min、Max 为目前为止的最小值、最大值，Count 不需要
+    acc: Option<Number>,
+}
+
+impl Aggregator {
+    pub fn new(op: AggOp) -> Self {
+        Self {
+            op,
+            count: 0,
+            acc: None,
+        }
+    }
+
+    /// 加入 key 的 value，value 必须是整数或者浮点数
+    pub fn add(&mut self, key: &str, v: &Value) -> Result<(), KvError> {
+        let n = match v.value {
+            Some(value::Value::Integer(i)) => Number::Int(i),
+            Some(value::Value::Float(f)) => Number::Float(f),
+            _ => {
+                return Err(KvError::InvalidCommand(format!(
+                    "Value of key {} is not a number",
+                    key
+                )))
+            }
+        };
+
+        self.count += 1;
+        self.acc = match (self.op, self.acc) {
+            (AggOp::Count, _) => None,
+            (_, None) => Some(n),
+            (AggOp::Sum, Some(acc)) => Some(acc.add(n)?),
+            (AggOp::Avg, Some(acc)) => Some(Number::Float(acc.as_f64() + n.as_f64())),
+            (AggOp::Min, Some(acc)) if n.as_f64() < acc.as_f64() => Some(n),
+            (AggOp::Max, Some(acc)) if n.as_f64() > acc.as_f64() => Some(n),
+            (_, acc) => acc,
+        };
+        Ok(())
+    }
+
+    /// 聚合的结果，没有加入任何 value 时 Sum 和 Count 为 0，Min、Max、Avg 没有结果
+    pub fn finish(self) -> Option<Value> {
+        match (self.op, self.acc) {
+            (AggOp::Count, _) => Some(self.count.into()),
+            (AggOp::Sum, None) => Some(0.into()),
+            (AggOp::Avg, Some(acc)) => Some((acc.as_f64() / self.count as f64).into()),
+            (_, acc) => acc.map(Value::from),
+        }
+    }
+}
@@ -0,0 +1,525 @@
+use dashmap::DashMap;
+use prost::Message;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use super::command_service::hits_table;
+use crate::{
+    command_request::RequestData, dispatch, CommandRequest, CommandResponse, KvError, Storage,
+};
+
+/// 结果缓存的配置
+pub struct CacheConfig {
+    /// 最多缓存多少条结果
+    pub capacity: usize,
+    /// 判断一个命令的结果是否可以缓存
+    pub cacheable: fn(&CommandRequest) -> bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            cacheable: default_cacheable,
+        }
+    }
+}
+
+/// 缺省只缓存代价最大的 HGETALL
+fn default_cacheable(cmd: &CommandRequest) -> bool {
+    matches!(cmd.request_data, Some(RequestData::Hgetall(_)))
+}
+
+/// 缓存的一条结果，记录了生成结果时 table 的版本
+struct CacheEntry {
+    version: u64,
+    /// 读到的 key 中最早过期的那个过期的时间，过了这个时间结果就失效了
+    expires: Option<Instant>,
+    res: CommandResponse,
+}
+
+impl CacheEntry {
+    fn is_fresh(&self, version: u64) -> bool {
+        self.version == version && self.expires.is_none_or(|t| Instant::now() < t)
+    }
+}
+
+/// 以 encode 后的 CommandRequest 为 key 的结果缓存
+///
+/// 每个 table 有一个版本号，修改 table 的命令执行后版本号加一，
+/// 版本号对不上的缓存结果视为失效；key 过期不会修改版本号，
+/// 读到了会过期的 key 的结果在最早的那个 key 过期之后也视为失效
+pub struct ResultCache {
+    config: CacheConfig,
+    versions: DashMap<String, u64>,
+    entries: DashMap<Vec<u8>, CacheEntry>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// 命令对 table 的访问方式
+enum Access<'a> {
+    /// 只读某个 table
+    Read(&'a str),
+    /// 修改某个 table
+    Write(&'a str),
+    /// 同时修改两个 table，比如把一个 table 改名
+    Rename(&'a str, &'a str),
+    /// 不访问 table 里的数据
+    None,
+}
+
+/// 命令是否会修改 table
+pub(crate) fn is_write(cmd: &CommandRequest) -> bool {
+    matches!(table_access(cmd), Access::Write(_) | Access::Rename(..))
+}
+
+/// 命令会读取或者修改的 table
+pub(crate) fn accessed_tables(cmd: &CommandRequest) -> Vec<&str> {
+    match table_access(cmd) {
+        Access::Read(table) | Access::Write(table) => vec![table],
+        Access::Rename(from, to) => vec![from, to],
+        Access::None => Vec::new(),
+    }
+}
+
+/// 命令会修改的 table
+pub(crate) fn written_tables(cmd: &CommandRequest) -> Vec<&str> {
+    match table_access(cmd) {
+        Access::Write(table) => vec![table],
+        Access::Rename(from, to) => vec![from, to],
+        _ => Vec::new(),
+    }
+}
+
+/// 判断写命令的响应是不是表示什么都没有修改
+///
+/// 只有 Hsetifdiff（value 没有变化）和 Hdiffapply（没有需要修改的 key）会执行成功却不修改数据，
+/// 这时不增加版本号、不唤醒等待的请求，也不让缓存失效；返回函数是因为执行时命令已经被 move 走了
+pub(crate) fn unchanged(cmd: &CommandRequest) -> fn(&CommandResponse) -> bool {
+    match &cmd.request_data {
+        Some(RequestData::Hsetifdiff(_)) => |res| res.values == [false.into()],
+        Some(RequestData::Hdiffapply(_)) => |res| res.status == 200 && res.pairs.is_empty(),
+        _ => |_| false,
+    }
+}
+
+fn table_access(cmd: &CommandRequest) -> Access<'_> {
+    match &cmd.request_data {
+        Some(RequestData::Hget(v)) => Access::Read(&v.table),
+        Some(RequestData::Getgen(v)) => Access::Read(&v.table),
+        Some(RequestData::Hagg(v)) => Access::Read(&v.table),
+        Some(RequestData::Hgetblock(v)) => Access::Read(&v.table),
+        Some(RequestData::Hgetall(v)) => Access::Read(&v.table),
+        Some(RequestData::Hmget(v)) => Access::Read(&v.table),
+        Some(RequestData::Hexist(v)) => Access::Read(&v.table),
+        Some(RequestData::Hmexist(v)) => Access::Read(&v.table),
+        Some(RequestData::Hgetrange(v)) => Access::Read(&v.table),
+        Some(RequestData::Hcount(v)) => Access::Read(&v.table),
+        Some(RequestData::Hmexistbits(v)) => Access::Read(&v.table),
+        Some(RequestData::Hvals(v)) => Access::Read(&v.table),
+        Some(RequestData::Htablehash(v)) => Access::Read(&v.table),
+        Some(RequestData::Hmatchhash(v)) => Access::Read(&v.table),
+        Some(RequestData::Hkeyspage(v)) => Access::Read(&v.table),
+        Some(RequestData::Holdest(v)) => Access::Read(&v.table),
+        Some(RequestData::Hnewest(v)) => Access::Read(&v.table),
+        Some(RequestData::Hhistory(v)) => Access::Read(&v.table),
+        // 访问次数保存在内部的 table 里，不算修改 table，见 ResultCache::execute
+        Some(RequestData::Hgethot(v)) => Access::Read(&v.table),
+        Some(RequestData::Hhotkeys(v)) => Access::Read(&v.table),
+        Some(RequestData::Hset(v)) => Access::Write(&v.table),
+        Some(RequestData::Hmset(v)) => Access::Write(&v.table),
+        Some(RequestData::Hsetkeepttl(v)) => Access::Write(&v.table),
+        Some(RequestData::Hsetct(v)) => Access::Write(&v.table),
+        Some(RequestData::Hgetct(v)) => Access::Read(&v.table),
+        Some(RequestData::Hdel(v)) => Access::Write(&v.table),
+        Some(RequestData::Hmdel(v)) => Access::Write(&v.table),
+        Some(RequestData::Hmerge(v)) => Access::Write(&v.table),
+        Some(RequestData::Hmsetnx(v)) => Access::Write(&v.table),
+        Some(RequestData::Hexpiretable(v)) => Access::Write(&v.table),
+        Some(RequestData::Hdelprefix(v)) => Access::Write(&v.table),
+        Some(RequestData::Hdrain(v)) => Access::Write(&v.table),
+        Some(RequestData::Hpatch(v)) => Access::Write(&v.table),
+        Some(RequestData::Hrotate(v)) => Access::Write(&v.table),
+        Some(RequestData::Lockmany(v)) => Access::Write(&v.table),
+        Some(RequestData::Hmdelif(v)) => Access::Write(&v.table),
+        Some(RequestData::Hincrex(v)) => Access::Write(&v.table),
+        Some(RequestData::Hgetreset(v)) => Access::Write(&v.table),
+        Some(RequestData::Lpushcap(v)) => Access::Write(&v.table),
+        Some(RequestData::Lpopn(v)) => Access::Write(&v.table),
+        Some(RequestData::Lcompact(v)) => Access::Write(&v.table),
+        Some(RequestData::Tcreate(v)) => Access::Write(&v.table),
+        Some(RequestData::Eval(v)) => Access::Write(&v.table),
+        Some(RequestData::Sunionstore(v)) => Access::Write(&v.table),
+        Some(RequestData::Sinterstore(v)) => Access::Write(&v.table),
+        Some(RequestData::Pfadd(v)) => Access::Write(&v.table),
+        Some(RequestData::Pfcount(v)) => Access::Read(&v.table),
+        Some(RequestData::Hreplace(v)) => Access::Write(&v.table),
+        Some(RequestData::Setbit(v)) => Access::Write(&v.table),
+        Some(RequestData::Getbit(v)) => Access::Read(&v.table),
+        Some(RequestData::Hexpiregt(v)) => Access::Write(&v.table),
+        Some(RequestData::Hpexpire(v)) => Access::Write(&v.table),
+        Some(RequestData::Hpsetex(v)) => Access::Write(&v.table),
+        Some(RequestData::Rotate(v)) => Access::Write(&v.table),
+        // 删除的 key 虽然已经过期，但 table 的内容变了，要和其他写操作一样清掉缓存、增加版本号
+        Some(RequestData::Hreapexpired(v)) => Access::Write(&v.table),
+        // 会修改过期时间，不能使用缓存的结果
+        Some(RequestData::Hgettouch(v)) => Access::Write(&v.table),
+        Some(RequestData::Trename(v)) => Access::Rename(&v.from, &v.to),
+        Some(RequestData::Harchive(v)) => Access::Rename(&v.table, &v.archive_table),
+        Some(RequestData::Hmodify(v)) => Access::Write(&v.table),
+        Some(RequestData::Hmgetttl(v)) => Access::Read(&v.table),
+        Some(RequestData::Hsetpub(v)) => Access::Write(&v.table),
+        Some(RequestData::Hgetifchanged(v)) => Access::Read(&v.table),
+        Some(RequestData::Hweightedrand(v)) => Access::Read(&v.table),
+        Some(RequestData::Hquantiles(v)) => Access::Read(&v.table),
+        Some(RequestData::Hsetifdiff(v)) => Access::Write(&v.table),
+        Some(RequestData::Hdiffapply(v)) => Access::Write(&v.table),
+        Some(RequestData::Hsetwithlen(v)) => Access::Write(&v.table),
+        Some(RequestData::Hdrainprefix(v)) => Access::Write(&v.table),
+        Some(RequestData::Hmincr(v)) => Access::Write(&v.table),
+        Some(RequestData::Lease(v)) => Access::Write(&v.table),
+        Some(RequestData::Hincrclamp(v)) => Access::Write(&v.table),
+        // 修改 table 的配置，和写入一样需要 fencing、durable 等处理
+        Some(RequestData::Tconfig(v)) => Access::Write(&v.table),
+        // 结果取决于两个 table，只用一个 table 的版本号没法判断是否失效，所以不缓存
+        Some(RequestData::Tdiff(_)) => Access::None,
+        // 读取的 key 可以分布在任意多个 table 里，每次都直接从存储读取同一时刻的数据
+        Some(RequestData::Hmgetsnapshot(_)) => Access::None,
+        // 检查的是存储的数据能不能 decode，每次都要重新读取；不指定 table 时会检查所有的 table
+        Some(RequestData::Verify(_)) => Access::None,
+        Some(RequestData::Subscribe(_))
+        | Some(RequestData::Unsubscribe(_))
+        | Some(RequestData::Publish(_))
+        | Some(RequestData::Compact(_))
+        | Some(RequestData::Jobstatus(_))
+        | Some(RequestData::Recent(_))
+        | Some(RequestData::Hello(_))
+        | Some(RequestData::Backendinfo(_))
+        | Some(RequestData::Slowest(_))
+        | Some(RequestData::Tablesizes(_))
+        | Some(RequestData::Serverinfo(_))
+        | Some(RequestData::Ack(_))
+        | Some(RequestData::Cdc(_))
+        | None => Access::None,
+    }
+}
+
+impl ResultCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            versions: DashMap::new(),
+            entries: DashMap::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// 缓存命中的次数
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// 缓存没有命中的次数
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// 执行命令，能用缓存的结果就直接返回，否则 dispatch 后更新缓存
+    pub fn execute(&self, cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
+        // Hgethot 每次都要计数，不能使用缓存；它只修改访问次数，只让 Hhotkeys 的结果失效
+        if let Some(RequestData::Hgethot(v)) = &cmd.request_data {
+            let hits = hits_table(&v.table);
+            let res = dispatch(cmd, store);
+            self.invalidate(&hits);
+            return res;
+        }
+        // Getgen 读的是 Rotate 内部使用的 table，之前的 value 过了宽限期就过期了，每次都直接读取
+        if let Some(RequestData::Getgen(_)) = &cmd.request_data {
+            return dispatch(cmd, store);
+        }
+
+        match table_access(&cmd) {
+            Access::Read(table) if (self.config.cacheable)(&cmd) => {
+                let key = cmd.encode_to_vec();
+                // Hhotkeys 读的是保存访问次数的 table
+                let table = match &cmd.request_data {
+                    Some(RequestData::Hhotkeys(v)) => hits_table(&v.table),
+                    _ => table.to_owned(),
+                };
+                // 在执行命令之前取版本号，这样执行过程中发生的修改会让这条结果失效
+                let version = self.version(&table);
+                if let Some(entry) = self.entries.get(&key) {
+                    if entry.is_fresh(version) {
+                        self.hits.fetch_add(1, Ordering::Relaxed);
+                        return entry.res.clone();
+                    }
+                }
+
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                // 先取时间再读过期时间，算出来的失效时间不会晚于 key 真正过期的时间
+                let now = Instant::now();
+                let expiry = earliest_expiry(&cmd, &table, store);
+                let res = dispatch(cmd, store);
+                match expiry {
+                    Ok(ttl) if res.status == 200 => {
+                        self.insert(key, version, ttl.map(|v| now + v), res.clone())
+                    }
+                    _ => {}
+                }
+                res
+            }
+            Access::Write(table) => {
+                let table = table.to_owned();
+                let unchanged = unchanged(&cmd);
+                let res = dispatch(cmd, store);
+                if !unchanged(&res) {
+                    self.invalidate(&table);
+                }
+                res
+            }
+            Access::Rename(from, to) => {
+                let (from, to) = (from.to_owned(), to.to_owned());
+                let res = dispatch(cmd, store);
+                self.invalidate(&from);
+                self.invalidate(&to);
+                res
+            }
+            _ => dispatch(cmd, store),
+        }
+    }
+
+    /// table 被修改了，让它之前缓存的结果都失效
+    pub(crate) fn invalidate(&self, table: &str) {
+        self.versions
+            .entry(table.to_owned())
+            .and_modify(|v| *v += 1)
+            .or_insert(1);
+    }
+
+    fn version(&self, table: &str) -> u64 {
+        self.versions.get(table).map(|v| *v).unwrap_or_default()
+    }
+
+    fn insert(&self, key: Vec<u8>, version: u64, expires: Option<Instant>, res: CommandResponse) {
+        if self.config.capacity == 0 {
+            return;
+        }
+
+        // 缓存满了就随便淘汰一条
+        if self.entries.len() >= self.config.capacity && !self.entries.contains_key(&key) {
+            let victim = self.entries.iter().next().map(|entry| entry.key().clone());
+            if let Some(victim) = victim {
+                self.entries.remove(&victim);
+            }
+        }
+
+        let entry = CacheEntry {
+            version,
+            expires,
+            res,
+        };
+        self.entries.insert(key, entry);
+    }
+}
+
+/// 读命令读到的 key 中最早过期的那个还有多久过期，没有会过期的 key 时返回 None
+///
+/// 只读几个 key 的命令检查这几个 key，其他的命令检查整个 table
+fn earliest_expiry(
+    cmd: &CommandRequest,
+    table: &str,
+    store: &impl Storage,
+) -> Result<Option<Duration>, KvError> {
+    let keys = match &cmd.request_data {
+        Some(RequestData::Hget(v)) => vec![v.key.clone()],
+        Some(RequestData::Hexist(v)) => vec![v.key.clone()],
+        Some(RequestData::Hmget(v)) => v.keys.clone(),
+        Some(RequestData::Hmexist(v)) => v.keys.clone(),
+        _ => store.keys(table, "")?,
+    };
+    store.read_transaction(table, |tx| {
+        let mut earliest: Option<Duration> = None;
+        for key in &keys {
+            if let Some(Some(ttl)) = tx.ttl(key)? {
+                earliest = Some(earliest.map_or(ttl, |v| v.min(ttl)));
+            }
+        }
+        Ok(earliest)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, Kvpair, MemTable, Value};
+
+    #[test]
+    fn repeated_hgetall_should_hit_cache() {
+        let store = MemTable::new();
+        let cache = ResultCache::new(CacheConfig::default());
+        cache.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+
+        let cmd = CommandRequest::new_hgetall("t1");
+        let res = cache.execute(cmd.clone(), &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("k1", "v1".into())]);
+        assert_eq!(cache.misses(), 1);
+
+        // 绕过缓存直接修改 store，缓存的结果不会变，证明命中了缓存
+        store.set("t1", "k2", "v2").unwrap();
+        let res = cache.execute(cmd, &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("k1", "v1".into())]);
+        assert_eq!(cache.hits(), 1);
+    }
+
+    #[test]
+    fn hset_should_invalidate_cache() {
+        let store = MemTable::new();
+        let cache = ResultCache::new(CacheConfig::default());
+        cache.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+
+        let cmd = CommandRequest::new_hgetall("t1");
+        cache.execute(cmd.clone(), &store);
+
+        cache.execute(CommandRequest::new_hset("t1", "k2", "v2".into()), &store);
+        let res = cache.execute(cmd, &store);
+        let pairs = &[
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into()),
+        ];
+        assert_res_ok(&res, &[], pairs);
+        assert_eq!(cache.hits(), 0);
+        assert_eq!(cache.misses(), 2);
+    }
+
+    #[test]
+    fn unchanged_hsetifdiff_should_not_invalidate_cache() {
+        let store = MemTable::new();
+        let cache = ResultCache::new(CacheConfig::default());
+        cache.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+
+        let cmd = CommandRequest::new_hgetall("t1");
+        cache.execute(cmd.clone(), &store);
+        let set = CommandRequest::new_hsetifdiff("t1", "k1", "v1");
+        cache.execute(set, &store);
+        cache.execute(cmd.clone(), &store);
+        assert_eq!(cache.hits(), 1);
+
+        let set = CommandRequest::new_hsetifdiff("t1", "k1", "v2");
+        cache.execute(set, &store);
+        let res = cache.execute(cmd, &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("k1", "v2".into())]);
+        assert_eq!(cache.misses(), 2);
+    }
+
+    #[test]
+    fn cached_results_should_expire_with_keys() {
+        let store = MemTable::new();
+        let cache = ResultCache::new(CacheConfig {
+            capacity: 16,
+            cacheable: |_| true,
+        });
+        cache.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        cache.execute(CommandRequest::new_hset("t1", "k2", "v2".into()), &store);
+        store
+            .expire("t1", "k1", Duration::from_millis(100))
+            .unwrap();
+
+        let get_all = CommandRequest::new_hgetall("t1");
+        let get = CommandRequest::new_hget("t1", "k1");
+        let get_other = CommandRequest::new_hget("t1", "k2");
+        for _ in 0..2 {
+            cache.execute(get_all.clone(), &store);
+            cache.execute(get.clone(), &store);
+            cache.execute(get_other.clone(), &store);
+        }
+        assert_eq!(cache.hits(), 3);
+
+        // k1 过期之后，读到过 k1 的结果失效，只读了 k2 的结果不受影响
+        std::thread::sleep(Duration::from_millis(150));
+        let res = cache.execute(get_all, &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("k2", "v2".into())]);
+        let res = cache.execute(get, &store);
+        assert_eq!(res.status, 404);
+        cache.execute(get_other, &store);
+        assert_eq!(cache.hits(), 4);
+    }
+
+    #[test]
+    fn getgen_should_not_be_cached() {
+        let store = MemTable::new();
+        let cache = ResultCache::new(CacheConfig {
+            capacity: 16,
+            cacheable: |_| true,
+        });
+        let rotate = |v: &str| CommandRequest::new_rotate("t1", "k1", v, 60_000);
+        let getgen = CommandRequest::new_getgen("t1", "k1");
+        cache.execute(rotate("v1"), &store);
+        let res = cache.execute(getgen.clone(), &store);
+        assert_res_ok(&res, &[1.into(), Value::default()], &[]);
+
+        cache.execute(rotate("v2"), &store);
+        let res = cache.execute(getgen, &store);
+        assert_res_ok(&res, &[2.into(), "v1".into()], &[]);
+        assert_eq!(cache.hits() + cache.misses(), 0);
+    }
+
+    #[test]
+    fn trename_should_invalidate_both_tables() {
+        let store = MemTable::new();
+        let cache = ResultCache::new(CacheConfig::default());
+        cache.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        cache.execute(CommandRequest::new_hset("t2", "k2", "v2".into()), &store);
+
+        let get_t1 = CommandRequest::new_hgetall("t1");
+        let get_t2 = CommandRequest::new_hgetall("t2");
+        cache.execute(get_t1.clone(), &store);
+        cache.execute(get_t2.clone(), &store);
+
+        cache.execute(CommandRequest::new_trename("t1", "t2", true), &store);
+        let res = cache.execute(get_t1, &store);
+        assert_res_ok(&res, &[], &[]);
+        let res = cache.execute(get_t2, &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("k1", "v1".into())]);
+        assert_eq!(cache.hits(), 0);
+    }
+
+    #[test]
+    fn hgethot_should_only_invalidate_hotkeys() {
+        let store = MemTable::new();
+        let cache = ResultCache::new(CacheConfig {
+            capacity: 16,
+            cacheable: |_| true,
+        });
+        cache.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+
+        let get_all = CommandRequest::new_hgetall("t1");
+        let hotkeys = CommandRequest::new_hhotkeys("t1", 10);
+        cache.execute(get_all.clone(), &store);
+        cache.execute(hotkeys.clone(), &store);
+
+        // Hgethot 不使用缓存，每次都会计数
+        for _ in 0..2 {
+            let res = cache.execute(CommandRequest::new_hgethot("t1", "k1"), &store);
+            assert_res_ok(&res, &["v1".into()], &[]);
+        }
+        cache.execute(get_all, &store);
+        assert_eq!(cache.hits(), 1);
+        let res = cache.execute(hotkeys, &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("k1", 2.into())]);
+        assert_eq!(cache.hits(), 1);
+    }
+
+    #[test]
+    fn hset_on_other_table_should_not_invalidate_cache() {
+        let store = MemTable::new();
+        let cache = ResultCache::new(CacheConfig::default());
+
+        let cmd = CommandRequest::new_hgetall("t1");
+        cache.execute(cmd.clone(), &store);
+        cache.execute(CommandRequest::new_hset("t2", "k1", "v1".into()), &store);
+        cache.execute(cmd, &store);
+        assert_eq!(cache.hits(), 1);
+    }
+}
@@ -1,3 +1,17 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::StatusCode;
+use prost::Message;
+use xxhash_rust::xxh3::Xxh3;
+
+use super::aggregate::Aggregator;
+use super::hll::HyperLogLog;
+use super::sample::WeightedReservoir;
+use super::script::{Action, Script};
+use super::tdigest::TDigest;
 use crate::*;
 
 impl CommandService for Hget {
@@ -10,59 +24,82 @@ impl CommandService for Hget {
     }
 }
 
-impl CommandService for Hgetall {
+impl CommandService for Hgetblock {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
-            Ok(v) => v.into(),
+        // 等待的部分在 Service 里完成，这里和 Hget 一样只读一次
+        match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
             Err(e) => e.into(),
         }
     }
 }
 
-impl CommandService for Hset {
+impl CommandService for Hgettouch {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match self.pair {
-            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
-                Ok(Some(v)) => v.into(),
-                Ok(None) => Value::default().into(),
-                Err(e) => e.into(),
-            },
-            None => Value::default().into(),
+        if self.ttl_secs == 0 {
+            return KvError::InvalidCommand("ttl_secs must be greater than 0".into()).into();
+        }
+
+        let ttl = Duration::from_secs(self.ttl_secs);
+        let result = store.transaction(&self.table, |tx| {
+            let value = tx.get(&self.key)?;
+            if value.is_some() {
+                tx.expire(&self.key, ttl)?;
+            }
+            Ok(value)
+        });
+
+        match result {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
+            Err(e) => e.into(),
         }
     }
 }
 
-impl CommandService for Hmget {
+impl CommandService for Hexpiregt {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.get(&self.table, key) {
-                Ok(Some(v)) => v,
-                _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+        if self.ttl_secs == 0 {
+            return KvError::InvalidCommand("ttl_secs must be greater than 0".into()).into();
+        }
+
+        let ttl = Duration::from_secs(self.ttl_secs);
+        match store.transaction(&self.table, |tx| tx.expire_gt(&self.key, ttl)) {
+            Ok(changed) => Value::from(changed).into(),
+            Err(e) => e.into(),
+        }
     }
 }
 
-impl CommandService for Hmset {
+impl CommandService for Hpexpire {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.pairs
-            .into_iter()
-            .map(|Kvpair { key, value }| {
-                match store.set(&self.table, key, value.unwrap_or_default()) {
-                    Ok(Some(v)) => v,
-                    _ => Value::default(),
-                }
-            })
-            .collect::<Vec<_>>()
-            .into()
+        if self.ttl_ms == 0 {
+            return KvError::InvalidCommand("ttl_ms must be greater than 0".into()).into();
+        }
+
+        let ttl = Duration::from_millis(self.ttl_ms);
+        match store.expire(&self.table, &self.key, ttl) {
+            Ok(changed) => Value::from(changed).into(),
+            Err(e) => e.into(),
+        }
     }
 }
 
-impl CommandService for Hdel {
+impl CommandService for Hpsetex {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.del(&self.table, &self.key) {
+        if self.ttl_ms == 0 {
+            return KvError::InvalidCommand("ttl_ms must be greater than 0".into()).into();
+        }
+
+        let value = self.value.unwrap_or_default();
+        let ttl = Duration::from_millis(self.ttl_ms);
+        let result = store.transaction(&self.table, |tx| {
+            let old = tx.set(&self.key, value.clone())?;
+            tx.expire(&self.key, ttl)?;
+            Ok(old)
+        });
+        match result {
             Ok(Some(v)) => v.into(),
             Ok(None) => Value::default().into(),
             Err(e) => e.into(),
@@ -70,130 +107,3641 @@ impl CommandService for Hdel {
     }
 }
 
-impl CommandService for Hmdel {
+/// 保存 table 中 Rotate 过的 key 的版本号和之前的 value 的 table，它是内部使用的 table，
+/// 不会占用客户端的 key
+pub(crate) fn rotate_table(table: &str) -> String {
+    format!("{}rotate:{}", RESERVED_TABLE_PREFIX, table)
+}
+
+/// rotate_table 中保存 key 的版本号的 key
+fn gen_key(key: &str) -> String {
+    format!("{}#gen", key)
+}
+
+/// rotate_table 中保存 key 之前的 value 的 key，宽限期过了之后过期
+fn prev_key(key: &str) -> String {
+    format!("{}#prev", key)
+}
+
+impl CommandService for Rotate {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.del(&self.table, key) {
-                Ok(Some(v)) => v,
-                _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+        if self.grace_ms == 0 {
+            return KvError::InvalidCommand("grace_ms must be greater than 0".into()).into();
+        }
+
+        let value = self.new_value.unwrap_or_default();
+        let grace = Duration::from_millis(self.grace_ms);
+        let rotate = rotate_table(&self.table);
+        let (gen, prev) = (gen_key(&self.key), prev_key(&self.key));
+        // value 和版本号在同一个事务里修改，不会只写入了新的 value 却没有新的版本号
+        let rotated = store.transaction_pair(&self.table, &rotate, |tx, side| {
+            let old = tx.set(&self.key, value.clone())?;
+            let generation = match side.get(&gen)? {
+                Some(v) => i64::try_from(&v)? + 1,
+                None => 1,
+            };
+            // key 之前不存在时没有之前的 value，也不能留下更早的 value
+            match &old {
+                Some(old) => {
+                    side.set(&prev, old.clone())?;
+                    side.expire(&prev, grace)?;
+                }
+                None => {
+                    side.del(&prev)?;
+                }
+            }
+            side.set(&gen, generation)?;
+            Ok(vec![generation.into(), old.unwrap_or_default()])
+        });
+        match rotated {
+            Ok(values) => values.into(),
+            Err(e) => e.into(),
+        }
     }
 }
 
-impl CommandService for Hexist {
+impl CommandService for Getgen {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.contains(&self.table, &self.key) {
-            Ok(v) => Value::from(v).into(),
+        let rotate = rotate_table(&self.table);
+        let get = || -> Result<Vec<Value>, KvError> {
+            let generation = store.get(&rotate, &gen_key(&self.key))?;
+            let prev = store.get(&rotate, &prev_key(&self.key))?;
+            Ok(vec![
+                generation.unwrap_or_else(|| 0.into()),
+                prev.unwrap_or_default(),
+            ])
+        };
+        match get() {
+            Ok(values) => values.into(),
             Err(e) => e.into(),
         }
     }
 }
 
-impl CommandService for Hmexist {
+impl CommandService for Hgetall {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get_all(&self.table) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmatchhash {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => Value::from(v.xxhash()[..] == self.client_hash[..]).into(),
+            Ok(None) => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetifchanged {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => {
+                let hash = v.xxhash();
+                match hash[..] == self.since_hash[..] {
+                    true => CommandResponse::not_modified(),
+                    false => vec![v, Value::from(&hash)].into(),
+                }
+            }
+            Ok(None) => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hweightedrand {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let iter = match store.get_iter(&self.table) {
+            Ok(iter) => iter,
+            Err(e) => return e.into(),
+        };
+
+        let mut rng = rand::thread_rng();
+        let mut reservoir = WeightedReservoir::new(self.n as usize);
+        for pair in iter {
+            let pair = match pair {
+                Ok(pair) => pair,
+                Err(e) => return e.into(),
+            };
+            let weight = match pair.value.as_ref().and_then(|v| v.value.as_ref()) {
+                Some(value::Value::Integer(i)) => *i as f64,
+                Some(value::Value::Float(f)) => *f,
+                _ => {
+                    return KvError::InvalidCommand(format!(
+                        "Value of key {} is not a number",
+                        pair.key
+                    ))
+                    .into()
+                }
+            };
+            reservoir.add(pair, weight, &mut rng);
+        }
+        reservoir.into_sorted_vec().into()
+    }
+}
+
+impl CommandService for Hquantiles {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
+        if let Some(q) = self.qs.iter().find(|q| !(0.0..=1.0).contains(*q)) {
+            return KvError::InvalidCommand(format!("Quantile {} is not in [0, 1]", q)).into();
+        }
+        let iter = match store.get_iter(&self.table) {
+            Ok(iter) => iter,
+            Err(e) => return e.into(),
+        };
+
+        let mut digest = TDigest::default();
+        for pair in iter {
+            let pair = match pair {
+                Ok(pair) => pair,
+                Err(e) => return e.into(),
+            };
+            match pair.value.as_ref().and_then(|v| v.value.as_ref()) {
+                Some(value::Value::Integer(i)) => digest.add(*i as f64),
+                Some(value::Value::Float(f)) => digest.add(*f),
+                _ => {
+                    return KvError::InvalidCommand(format!(
+                        "Value of key {} is not a number",
+                        pair.key
+                    ))
+                    .into()
+                }
+            }
+        }
+
+        let values: Option<Vec<Value>> = self
+            .qs
             .iter()
-            .map(|key| match store.contains(&self.table, key) {
-                Ok(v) => v.into(),
-                Err(_) => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+            .map(|q| digest.quantile(*q).map(Value::from))
+            .collect();
+        match values {
+            Some(values) => values.into(),
+            None => KvError::NotFound(format!("numbers in table {}", self.table)).into(),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl CommandService for Hagg {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let iter = match store.get_iter(&self.table) {
+            Ok(iter) => iter,
+            Err(e) => return e.into(),
+        };
 
-    #[test]
-    fn hset_should_work() {
-        let store = MemTable::new();
-        let cmd = CommandRequest::new_hset("t1", "hello", "world".into());
-        let res = dispatch(cmd.clone(), &store);
-        assert_res_ok(&res, &[Value::default()], &[]);
+        let mut agg = Aggregator::new(self.op());
+        for pair in iter {
+            let added = pair.and_then(|pair| agg.add(&pair.key, &pair.value.unwrap_or_default()));
+            if let Err(e) = added {
+                return e.into();
+            }
+        }
+        match agg.finish() {
+            Some(v) => v.into(),
+            None => KvError::NotFound(format!("numbers in table {}", self.table)).into(),
+        }
+    }
+}
 
-        let res = dispatch(cmd, &store);
-        assert_res_ok(&res, &["world".into()], &[]);
+impl CommandService for Htablehash {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut pairs = match store.get_all(&self.table) {
+            Ok(pairs) => pairs,
+            Err(e) => return e.into(),
+        };
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+
+        // encode 之后的 Kvpair 自带长度信息，直接拼接不会有歧义
+        let mut hasher = Xxh3::new();
+        for pair in pairs {
+            hasher.update(&pair.encode_to_vec());
+        }
+        Value::from(&hasher.digest().to_be_bytes()).into()
     }
+}
 
-    #[test]
-    fn hget_should_work() {
-        let store = MemTable::new();
-        let cmd = CommandRequest::new_hset("score", "u1", 10.into());
-        dispatch(cmd, &store);
-        let cmd = CommandRequest::new_hget("score", "u1");
-        let res = dispatch(cmd, &store);
-        assert_res_ok(&res, &[10.into()], &[]);
+impl CommandService for Hkeyspage {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut keys = match store.keys(&self.table, "") {
+            Ok(keys) => keys,
+            Err(e) => return e.into(),
+        };
+        keys.sort();
+
+        let rest = keys.len().saturating_sub(self.offset as usize);
+        let limit = match self.limit {
+            0 => rest,
+            n => rest.min(n as usize),
+        };
+        let values: Vec<Value> = keys
+            .into_iter()
+            .skip(self.offset as usize)
+            .take(limit)
+            .map(|k| k.into())
+            .collect();
+
+        let mut res: CommandResponse = values.into();
+        res.has_more = limit < rest;
+        res
     }
+}
 
-    #[test]
-    fn hmget_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("user", vec![("u1", "s1"), ("u2", "s2")], &store);
-        let cmd = CommandRequest::new_hmget("user", vec!["u1".into(), "u2".into(), "u3".into()]);
-        let res = dispatch(cmd, &store);
-        assert_res_ok(&res, &["s1".into(), "s2".into(), Value::default()], &[]);
+impl CommandService for Holdest {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.by_insertion(&self.table, self.n as usize, false) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
     }
+}
 
-    #[test]
-    fn hmset_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("user", vec![("u1", "s1"), ("u2", "s2")], &store);
-        let pairs = vec![
-            Kvpair::new("u1", "ns1".into()),
-            Kvpair::new("u2", "ns2".into()),
-            Kvpair::new("u3", "ns3".into()),
-        ];
-        let cmd = CommandRequest::new_hmset("user", pairs);
-        let res = dispatch(cmd, &store);
-        assert_res_ok(&res, &["s1".into(), "s2".into(), Value::default()], &[]);
+impl CommandService for Hnewest {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.by_insertion(&self.table, self.n as usize, true) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
     }
+}
 
-    #[test]
-    fn hdel_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("user", vec![("u1", "s1")], &store);
-        let cmd = CommandRequest::new_hdel("user", "u1");
-        let res = dispatch(cmd.clone(), &store);
-        assert_res_ok(&res, &["s1".into()], &[]);
+impl CommandService for Hdrain {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.drain(&self.table) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
 
-        let res = dispatch(cmd, &store);
-        assert_res_ok(&res, &[Value::default()], &[]);
+impl CommandService for Hdrainprefix {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let keys = match store.keys(&self.table, &self.prefix) {
+            Ok(keys) => keys,
+            Err(e) => return e.into(),
+        };
+
+        // 在事务里删除，已经被别人取走的 key 删除时拿不到 value，直接跳过
+        let result = store.transaction(&self.table, |tx| {
+            let mut pairs = Vec::new();
+            for key in &keys {
+                if let Some(v) = tx.del(key)? {
+                    pairs.push(Kvpair::new(key, v));
+                }
+            }
+            Ok(pairs)
+        });
+
+        match result {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
     }
+}
 
-    #[test]
-    fn hmdel_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("user", vec![("u1", "s1")], &store);
-        let cmd = CommandRequest::new_hmdel("user", vec!["u1".into(), "u2".into()]);
-        let res = dispatch(cmd.clone(), &store);
-        assert_res_ok(&res, &["s1".into(), Value::default()], &[]);
+impl CommandService for Harchive {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        if self.table == self.archive_table {
+            let msg = format!("Cannot archive table {} into itself", self.table);
+            return KvError::InvalidCommand(msg).into();
+        }
+
+        // 只移动设置了过期时间、并且 within 之内就要过期的 key
+        let within = Duration::from_secs(self.within_secs);
+        let moved = store.move_keys(
+            &self.table,
+            &self.archive_table,
+            |_key, ttl| matches!(ttl, Some(ttl) if ttl <= within),
+        );
+        match moved {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
     }
+}
 
-    #[test]
-    fn hexist_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("user", vec![("u1", "s1")], &store);
-        let cmd = CommandRequest::new_hexist("user", "u1");
-        let res = dispatch(cmd.clone(), &store);
-        assert_res_ok(&res, &[true.into()], &[]);
+impl CommandService for Hreplace {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.replace(&self.table, self.pairs) {
+            Ok(()) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        }
+    }
+}
 
-        let cmd = CommandRequest::new_hexist("user", "u2");
-        let res = dispatch(cmd.clone(), &store);
-        assert_res_ok(&res, &[false.into()], &[]);
+impl CommandService for Hvals {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get_all(&self.table) {
+            Ok(mut pairs) => {
+                // MemTable 返回的顺序是不确定的，按 key 排序让结果可以预期
+                pairs.sort_by(|a, b| a.key.cmp(&b.key));
+                pairs
+                    .into_iter()
+                    .map(|pair| pair.value.unwrap_or_default())
+                    .collect::<Vec<_>>()
+                    .into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetrange {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let v = match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                return KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into()
+            }
+            Err(e) => return e.into(),
+        };
+
+        // 把 [start, end) 截断到 [0, len] 之内
+        let range = |len: usize| {
+            let end = (self.end.min(len as u64)) as usize;
+            let start = (self.start.min(end as u64)) as usize;
+            start..end
+        };
+
+        match v.value {
+            Some(value::Value::String(s)) => {
+                // 字节区间可能切在一个 utf8 字符的中间，这部分字符会被替换成 U+FFFD
+                let bytes = &s.as_bytes()[range(s.len())];
+                Value::from(String::from_utf8_lossy(bytes).into_owned()).into()
+            }
+            Some(value::Value::Binary(b)) => Value::from(b.slice(range(b.len()))).into(),
+            _ => KvError::ConvertError(v.format(), "String or Binary").into(),
+        }
     }
+}
 
-    #[test]
-    fn hmexist_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("user", vec![("u1", "s1"), ("u2", "s2")], &store);
-        let cmd = CommandRequest::new_hmexist("user", vec!["u1".into(), "u2".into(), "u3".into()]);
-        let res = dispatch(cmd.clone(), &store);
-        assert_res_ok(&res, &[true.into(), true.into(), false.into()], &[]);
+impl CommandService for Hset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mode = self.return_mode();
+        let (res, count) = match self.pair {
+            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
+                Ok(Some(v)) => (v.into(), 1),
+                Ok(None) => (Value::default().into(), 1),
+                Err(e) => (e.into(), 0),
+            },
+            None => (Value::default().into(), 0),
+        };
+        apply_return_mode(mode, res, count)
+    }
+}
+
+impl CommandService for Hsetpub {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // 只负责写入，发布由 Service 在写入成功之后处理
+        let mode = self.return_mode();
+        let res = match store.set(&self.table, self.key, self.value.unwrap_or_default()) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        };
+        apply_return_mode(mode, res, 1)
+    }
+}
+
+impl CommandService for Hsetifdiff {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = self.value.unwrap_or_default();
+        let result = store.transaction(&self.table, |tx| {
+            if tx.get(&self.key)?.as_ref() == Some(&value) {
+                return Ok(false);
+            }
+            tx.set(&self.key, value.clone())?;
+            Ok(true)
+        });
+
+        match result {
+            Ok(changed) => Value::from(changed).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hdiffapply {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let result = store.transaction(&self.table, |tx| {
+            let mut changed = Vec::new();
+            for pair in &self.desired {
+                let value = pair.value.clone().unwrap_or_default();
+                let old = tx.get(&pair.key)?;
+                if old.as_ref() == Some(&value) {
+                    continue;
+                }
+                tx.set(&pair.key, value.clone())?;
+                let diff = Value::new_list([old.unwrap_or_default(), value]);
+                changed.push(Kvpair::new(&pair.key, diff));
+            }
+            Ok(changed)
+        });
+
+        match result {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hsetwithlen {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = self.value.unwrap_or_default();
+        match store.set_with_len(&self.table, &self.key, value) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hsetkeepttl {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mode = self.return_mode();
+        let value = self.value.unwrap_or_default();
+        let res = match store.transaction(&self.table, |tx| tx.update(&self.key, value.clone())) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        };
+        apply_return_mode(mode, res, 1)
+    }
+}
+
+impl CommandService for Hsetct {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mode = self.return_mode();
+        let value = self.value.unwrap_or_default();
+        let result = store.transaction(&self.table, |tx| {
+            let old = tx.set(&self.key, value.clone())?;
+            tx.set_content_type(&self.key, &self.content_type)?;
+            Ok(old)
+        });
+
+        let res = match result {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        };
+        apply_return_mode(mode, res, 1)
+    }
+}
+
+impl CommandService for Hgetct {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let result = store.read_transaction(&self.table, |tx| {
+            match (tx.get(&self.key)?, tx.content_type(&self.key)?) {
+                (Some(v), Some(content_type)) => Ok(Some(vec![v, content_type.into()])),
+                _ => Ok(None),
+            }
+        });
+
+        match result {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmget {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let items = self
+            .keys
+            .iter()
+            .map(|key| match store.get(&self.table, key) {
+                Ok(Some(v)) => (v, StatusCode::OK.as_u16() as u32),
+                Ok(None) => (Value::default(), StatusCode::NOT_FOUND.as_u16() as u32),
+                Err(e) => (Value::default(), error_status(e)),
+            })
+            .collect();
+        with_item_statuses(items)
+    }
+}
+
+/// 单个 key 出错时的状态码，和整个命令因为这个错误失败时返回的一样
+fn error_status(e: KvError) -> u32 {
+    CommandResponse::from(e).status
+}
+
+/// 多个 key 的命令的响应：每个 key 的 value 放在 values 中，它们各自的状态码放在 item_statuses 中
+fn with_item_statuses(items: Vec<(Value, u32)>) -> CommandResponse {
+    let (values, statuses): (Vec<_>, Vec<_>) = items.into_iter().unzip();
+    let mut res = CommandResponse::from(values);
+    res.item_statuses = statuses;
+    res
+}
+
+/// Hmgetsnapshot 一次最多读多少个 key；MemTable 有写操作撞上时要让所有的写操作等这一批读完
+const MAX_SNAPSHOT_READS: usize = 1000;
+
+impl CommandService for Hmgetsnapshot {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        if self.reads.len() > MAX_SNAPSHOT_READS {
+            let msg = format!("at most {} reads are allowed", MAX_SNAPSHOT_READS);
+            return KvError::InvalidCommand(msg).into();
+        }
+        let reads: Vec<_> = self.reads.into_iter().map(|r| (r.table, r.key)).collect();
+        match store.get_many(&reads) {
+            Ok(values) => values
+                .into_iter()
+                .map(Option::unwrap_or_default)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmgetttl {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let result = store.read_transaction(&self.table, |tx| {
+            let mut values = Vec::with_capacity(self.keys.len() * 2);
+            let mut statuses = Vec::with_capacity(self.keys.len());
+            for key in &self.keys {
+                // 读取一个 key 出错时不影响其他的 key，错误记录在 item_statuses 里
+                let item = tx.get(key).and_then(|v| Ok((v, tx.ttl(key)?)));
+                let (value, ttl, status) = match item {
+                    Ok((Some(v), Some(Some(ttl)))) => {
+                        let secs = ttl.as_millis().div_ceil(1000) as i64;
+                        (v, secs.into(), StatusCode::OK.as_u16() as u32)
+                    }
+                    Ok((Some(v), _)) => (v, (-1).into(), StatusCode::OK.as_u16() as u32),
+                    Ok((None, _)) => (
+                        Value::default(),
+                        (-2).into(),
+                        StatusCode::NOT_FOUND.as_u16() as u32,
+                    ),
+                    Err(e) => (Value::default(), Value::default(), error_status(e)),
+                };
+                values.extend([value, ttl]);
+                statuses.push(status);
+            }
+            Ok((values, statuses))
+        });
+
+        match result {
+            Ok((values, statuses)) => {
+                let mut res = CommandResponse::from(values);
+                res.item_statuses = statuses;
+                res
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mode = self.return_mode();
+        // 只统计写入成功的 key
+        let mut count = 0;
+        let res = self
+            .pairs
+            .into_iter()
+            .map(|Kvpair { key, value }| {
+                match store.set(&self.table, key, value.unwrap_or_default()) {
+                    Ok(v) => {
+                        count += 1;
+                        v.unwrap_or_default()
+                    }
+                    Err(_) => Value::default(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .into();
+        apply_return_mode(mode, res, count)
+    }
+}
+
+impl CommandService for Hdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let (res, count) = match store.del(&self.table, &self.key) {
+            Ok(Some(v)) => (v.into(), 1),
+            Ok(None) => (Value::default().into(), 0),
+            Err(e) => (e.into(), 0),
+        };
+        apply_return_mode(self.return_mode(), res, count)
+    }
+}
+
+/// 按 return_mode 改写写操作成功的响应，count 是受影响的 key 的个数
+fn apply_return_mode(mode: ReturnMode, res: CommandResponse, count: usize) -> CommandResponse {
+    if res.status != StatusCode::OK.as_u16() as u32 {
+        return res;
+    }
+
+    match mode {
+        ReturnMode::OldValue => res,
+        ReturnMode::Count => Value::from(count as i64).into(),
+        ReturnMode::None => CommandResponse::ok(),
+    }
+}
+
+impl CommandService for Hmdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mode = match self.count_only {
+            true => ReturnMode::Count,
+            false => self.return_mode(),
+        };
+        let items: Vec<_> = self
+            .keys
+            .iter()
+            .map(|key| match store.del(&self.table, key) {
+                Ok(Some(v)) => (v, StatusCode::OK.as_u16() as u32),
+                Ok(None) => (Value::default(), StatusCode::NOT_FOUND.as_u16() as u32),
+                Err(e) => (Value::default(), error_status(e)),
+            })
+            .collect();
+        if mode == ReturnMode::OldValue {
+            return with_item_statuses(items);
+        }
+
+        // 不返回之前的 value 时 item_statuses 仍然和 keys 一一对应
+        let statuses: Vec<_> = items.into_iter().map(|(_v, status)| status).collect();
+        let count = statuses
+            .iter()
+            .filter(|s| **s == StatusCode::OK.as_u16() as u32)
+            .count();
+        let mut res = apply_return_mode(mode, CommandResponse::ok(), count);
+        res.item_statuses = statuses;
+        res
+    }
+}
+
+impl CommandService for Hexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.contains(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let items = self
+            .keys
+            .iter()
+            .map(|key| match store.contains(&self.table, key) {
+                Ok(v) => (v.into(), StatusCode::OK.as_u16() as u32),
+                Err(e) => (Value::default(), error_status(e)),
+            })
+            .collect();
+        with_item_statuses(items)
+    }
+}
+
+impl CommandService for Hmexistbits {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // 第 i 个 key 对应第 i / 8 个字节的第 i % 8 位（从低位开始）
+        let mut bits = vec![0u8; self.keys.len().div_ceil(8)];
+        for (i, key) in self.keys.iter().enumerate() {
+            match store.contains(&self.table, key) {
+                Ok(true) => bits[i / 8] |= 1 << (i % 8),
+                Ok(false) => {}
+                Err(e) => return e.into(),
+            }
+        }
+        Value::from(bytes::Bytes::from(bits)).into()
+    }
+}
+
+impl CommandService for Hmerge {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let op = self.op();
+        let operand = self.operand.unwrap_or_default();
+        match store.merge(&self.table, &self.key, op, operand) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmodify {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let op = self.op();
+        let result = store.transaction(&self.table, |tx| match tx.get(&self.key)? {
+            Some(v) => {
+                let modified = op.apply(&v)?;
+                tx.update(&self.key, modified.clone())?;
+                Ok(Some(modified))
+            }
+            None => Ok(None),
+        });
+
+        match result {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hpatch {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let patch: serde_json::Value = match serde_json::from_str(&self.json_patch) {
+            Ok(v) => v,
+            Err(e) => return KvError::InvalidCommand(format!("Invalid JSON patch: {}", e)).into(),
+        };
+
+        let result = store.transaction(&self.table, |tx| {
+            // 不存在的 key 当作 null，patch 之后就是一个新的对象
+            let mut target = match tx.get(&self.key)? {
+                Some(v) => parse_json(&v)?,
+                None => serde_json::Value::Null,
+            };
+            json_merge_patch(&mut target, &patch);
+            let value: Value = target.to_string().into();
+            tx.set(&self.key, value.clone())?;
+            Ok(value)
+        });
+
+        match result {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 把 string 或者 binary 的 value 解析成 JSON
+fn parse_json(v: &Value) -> Result<serde_json::Value, KvError> {
+    let parsed = match &v.value {
+        Some(value::Value::String(s)) => serde_json::from_str(s).ok(),
+        Some(value::Value::Binary(b)) => serde_json::from_slice(b).ok(),
+        _ => None,
+    };
+    parsed.ok_or_else(|| KvError::InvalidCommand(format!("Value {} is not valid JSON", v.format())))
+}
+
+/// RFC 7386 JSON merge patch：patch 中的 null 代表删除字段，对象递归合并，其他的值直接替换
+fn json_merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let patch = match patch {
+        serde_json::Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !target.is_object() {
+        *target = serde_json::Value::Object(Default::default());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (k, v) in patch {
+        if v.is_null() {
+            target.remove(k);
+        } else {
+            json_merge_patch(
+                target.entry(k.as_str()).or_insert(serde_json::Value::Null),
+                v,
+            );
+        }
+    }
+}
+
+impl CommandService for Hcount {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.count(&self.table, &self.prefix) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hrotate {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = self.value.unwrap_or_default();
+        let result = store.transaction(&self.table, |tx| {
+            let mut history = into_history(tx.get(&self.key)?);
+            let old = history.first().cloned().unwrap_or_default();
+            history.insert(0, value.clone());
+            if self.keep > 0 {
+                history.truncate(self.keep as usize);
+            }
+            // 历史只是多了一个 value，保留 key 原来的过期时间
+            tx.update(&self.key, Value::new_list(history))?;
+            Ok(old)
+        });
+
+        match result {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lpushcap {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        if self.max == 0 {
+            return KvError::InvalidCommand("max must be greater than 0".into()).into();
+        }
+
+        let value = self.value.unwrap_or_default();
+        let result = store.transaction(&self.table, |tx| {
+            let mut list = match tx.get(&self.key)? {
+                Some(Value {
+                    value: Some(value::Value::List(list)),
+                }) => list.values,
+                Some(v) => return Err(KvError::ConvertError(v.format(), "List")),
+                None => vec![],
+            };
+            list.insert(0, value.clone());
+            list.truncate(self.max as usize);
+            let len = list.len() as i64;
+            // 追加元素不应该让 key 变成永不过期
+            tx.update(&self.key, Value::new_list(list))?;
+            Ok(len)
+        });
+
+        match result {
+            Ok(n) => Value::from(n).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Sunionstore {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        store_set_op(store, &self.table, &self.dest, &self.keys, |acc, set| {
+            let mut seen: HashSet<_> = acc.iter().map(Value::encode_to_vec).collect();
+            for v in set {
+                if seen.insert(v.encode_to_vec()) {
+                    acc.push(v);
+                }
+            }
+        })
+    }
+}
+
+impl CommandService for Sinterstore {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        store_set_op(store, &self.table, &self.dest, &self.keys, |acc, set| {
+            let set: HashSet<_> = set.iter().map(Value::encode_to_vec).collect();
+            acc.retain(|v| set.contains(&v.encode_to_vec()))
+        })
+    }
+}
+
+/// 在一个事务里依次读出 keys 的 set，用 combine 合并之后保存到 dest，返回结果中元素的个数
+///
+/// Value 没有实现 Hash，combine 用 encode 之后的字节查找相同的元素，合并的开销和元素的总数成正比
+fn store_set_op(
+    store: &impl Storage,
+    table: &str,
+    dest: &str,
+    keys: &[String],
+    combine: impl Fn(&mut Vec<Value>, Vec<Value>),
+) -> CommandResponse {
+    if keys.is_empty() {
+        return KvError::InvalidCommand("keys must not be empty".into()).into();
+    }
+
+    let result = store.transaction(table, |tx| {
+        let mut acc: Option<Vec<Value>> = None;
+        for key in keys {
+            let set = match tx.get(key)? {
+                Some(Value {
+                    value: Some(value::Value::Set(set)),
+                }) => set.values,
+                Some(v) => return Err(KvError::ConvertError(v.format(), "Set")),
+                None => vec![],
+            };
+            match acc.as_mut() {
+                Some(acc) => combine(acc, set),
+                None => acc = Some(set),
+            }
+        }
+
+        let acc = acc.unwrap_or_default();
+        let len = acc.len() as i64;
+        match acc.is_empty() {
+            true => tx.del(dest)?,
+            false => tx.set(dest, Value::new_set(acc))?,
+        };
+        Ok(len)
+    });
+
+    match result {
+        Ok(n) => Value::from(n).into(),
+        Err(e) => e.into(),
+    }
+}
+
+impl CommandService for Pfadd {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let result = store.transaction(&self.table, |tx| {
+            let (mut hll, mut changed) = match tx.get(&self.key)? {
+                Some(v) => (HyperLogLog::try_from(v)?, false),
+                None => (HyperLogLog::default(), true),
+            };
+            for element in &self.elements {
+                changed |= hll.add(element.as_bytes());
+            }
+            if changed {
+                tx.update(&self.key, hll)?;
+            }
+            Ok(changed)
+        });
+
+        match result {
+            Ok(changed) => Value::from(changed).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Pfcount {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let count = match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => HyperLogLog::try_from(v).map(|hll| hll.count()),
+            Ok(None) => Ok(0),
+            Err(e) => Err(e),
+        };
+
+        match count {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// bitmap 最多 8MB，避免一个很大的 offset 分配过多的内存
+const MAX_BIT_OFFSET: u32 = 8 * 1024 * 1024 * 8;
+
+impl CommandService for Setbit {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        if self.offset >= MAX_BIT_OFFSET {
+            return KvError::InvalidCommand(format!("offset must be less than {}", MAX_BIT_OFFSET))
+                .into();
+        }
+
+        let (index, mask) = bit_position(self.offset);
+        let result = store.transaction(&self.table, |tx| {
+            let mut bitmap = match tx.get(&self.key)? {
+                Some(v) => into_bitmap(v)?.to_vec(),
+                None => vec![],
+            };
+            if bitmap.len() <= index {
+                bitmap.resize(index + 1, 0);
+            }
+            let old = bitmap[index] & mask != 0;
+            match self.value {
+                true => bitmap[index] |= mask,
+                false => bitmap[index] &= !mask,
+            }
+            tx.update(&self.key, Bytes::from(bitmap))?;
+            Ok(old)
+        });
+
+        match result {
+            Ok(old) => Value::from(old).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Getbit {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let (index, mask) = bit_position(self.offset);
+        let bit = match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => into_bitmap(v).map(|v| v.get(index).is_some_and(|b| b & mask != 0)),
+            Ok(None) => Ok(false),
+            Err(e) => Err(e),
+        };
+
+        match bit {
+            Ok(bit) => Value::from(bit).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 第 offset 位所在的字节，以及它在字节中的 mask（从最高位开始数）
+fn bit_position(offset: u32) -> (usize, u8) {
+    ((offset / 8) as usize, 0x80 >> (offset % 8))
+}
+
+fn into_bitmap(v: Value) -> Result<Bytes, KvError> {
+    match v.value {
+        Some(value::Value::Binary(bitmap)) => Ok(bitmap),
+        _ => Err(KvError::ConvertError(v.format(), "Bitmap")),
+    }
+}
+
+impl CommandService for Lpopn {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        if self.count == 0 {
+            return KvError::InvalidCommand("count must be greater than 0".into()).into();
+        }
+
+        let result = store.transaction(&self.table, |tx| {
+            let mut list = match tx.get(&self.key)? {
+                Some(Value {
+                    value: Some(value::Value::List(list)),
+                }) => list.values,
+                Some(v) => return Err(KvError::ConvertError(v.format(), "List")),
+                None => return Ok(vec![]),
+            };
+            let n = list.len().min(self.count as usize);
+            let popped: Vec<_> = list.drain(..n).collect();
+            match list.is_empty() {
+                true => tx.del(&self.key)?,
+                false => tx.update(&self.key, Value::new_list(list))?,
+            };
+            Ok(popped)
+        });
+
+        match result {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hhistory {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => into_history(Some(v)).into(),
+            Ok(None) => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 保存 table 中每个 key 的访问次数的 table，它是内部使用的 table，不会和客户端的 table 重名
+pub(crate) fn hits_table(table: &str) -> String {
+    format!("{}hits:{}", RESERVED_TABLE_PREFIX, table)
+}
+
+impl CommandService for Hgethot {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                return KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into()
+            }
+            Err(e) => return e.into(),
+        };
+
+        // 计数用 merge 原子地加一，并发的读不会丢失次数
+        let hits = hits_table(&self.table);
+        match store.merge(&hits, &self.key, MergeOp::AddInt, 1.into()) {
+            Ok(_) => value.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hhotkeys {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let pairs = match store.get_all(&hits_table(&self.table)) {
+            Ok(pairs) => pairs,
+            Err(e) => return e.into(),
+        };
+
+        let mut counts: Vec<_> = pairs
+            .into_iter()
+            .filter_map(|pair| {
+                let n = i64::try_from(&pair.value.unwrap_or_default()).ok()?;
+                Some((pair.key, n))
+            })
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+            .into_iter()
+            .take(self.n as usize)
+            .map(|(key, n)| Kvpair::new(key, n.into()))
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+/// 把 key 的 value 转换成历史 list，不是 list 的 value 当作只有一个元素的历史
+fn into_history(v: Option<Value>) -> Vec<Value> {
+    match v {
+        Some(Value {
+            value: Some(value::Value::List(list)),
+        }) => list.values,
+        Some(v) => vec![v],
+        None => vec![],
+    }
+}
+
+impl CommandService for Lcompact {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.compact_list(&self.table, &self.key) {
+            Ok(Some(n)) => Value::from(n as i64).into(),
+            Ok(None) => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lockmany {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let ttl = Duration::from_secs(self.ttl_secs);
+        let result = store.transaction(&self.table, |tx| {
+            // 过期的锁在事务里读不到，视为没有被锁住
+            for key in &self.keys {
+                if tx.contains(key)? {
+                    return Ok(false);
+                }
+            }
+            for key in &self.keys {
+                tx.set(key, self.owner.as_str())?;
+                if self.ttl_secs > 0 {
+                    tx.expire(key, ttl)?;
+                }
+            }
+            Ok(true)
+        });
+
+        match result {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lease {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let result = store.transaction(&self.table, |tx| {
+            // 过期的租约在事务里读不到，视为没有被持有
+            if let Some(holder) = tx.get(&self.key)? {
+                if holder != Value::from(self.owner.as_str()) {
+                    return Ok((false, holder));
+                }
+            }
+            tx.set(&self.key, self.owner.as_str())?;
+            if self.ttl_secs > 0 {
+                tx.expire(&self.key, Duration::from_secs(self.ttl_secs))?;
+            }
+            Ok((true, self.owner.as_str().into()))
+        });
+
+        match result {
+            Ok((held, holder)) => vec![held.into(), holder].into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrclamp {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        if self.min > self.max {
+            let msg = format!("min {} is greater than max {}", self.min, self.max);
+            return KvError::InvalidCommand(msg).into();
+        }
+
+        let result = store.transaction(&self.table, |tx| {
+            let old = match tx.get(&self.key)? {
+                Some(v) => i64::try_from(&v)?,
+                None => 0,
+            };
+            // 溢出时先饱和到 i64 的边界，再限制到 [min, max]，不会因为溢出而失败
+            let clamped = old.saturating_add(self.delta).clamp(self.min, self.max);
+            tx.update(&self.key, clamped)?;
+            Ok((clamped, old.checked_add(self.delta) != Some(clamped)))
+        });
+
+        match result {
+            Ok((v, clamped)) => vec![Value::from(v), clamped.into()].into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrex {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let delta = Value::from(self.delta);
+        let result = store.transaction(&self.table, |tx| match tx.get(&self.key)? {
+            Some(old) => {
+                // update 会保留原来的过期时间，窗口不会被延长
+                let v = MergeOp::AddInt.apply(Some(old), &delta)?;
+                tx.update(&self.key, v.clone())?;
+                Ok(v)
+            }
+            None => {
+                tx.set(&self.key, delta.clone())?;
+                if self.ttl_secs > 0 {
+                    tx.expire(&self.key, Duration::from_secs(self.ttl_secs))?;
+                }
+                Ok(delta.clone())
+            }
+        });
+
+        match result {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmincr {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let result = store.transaction(&self.table, |tx| {
+            let mut values = Vec::with_capacity(self.deltas.len());
+            for KeyDelta { key, delta } in &self.deltas {
+                let v = MergeOp::AddInt.apply(tx.get(key)?, &Value::from(*delta))?;
+                tx.update(key, v.clone())?;
+                values.push(v);
+            }
+            Ok(values)
+        });
+
+        match result {
+            Ok(values) => values.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetreset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let result = store.transaction(&self.table, |tx| match tx.get(&self.key)? {
+            Some(v) => {
+                let n = i64::try_from(&v)?;
+                tx.update(&self.key, 0)?;
+                Ok(n)
+            }
+            None => Ok(0),
+        });
+
+        match result {
+            Ok(n) => Value::from(n).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Eval {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // 先解析脚本，语法错误不需要进入事务
+        let script = match Script::parse(&self.script) {
+            Ok(v) => v,
+            Err(e) => return e.into(),
+        };
+
+        let result = store.transaction(&self.table, |tx| {
+            let current = tx.get(&self.key)?.unwrap_or_default();
+            match script.eval(&current)? {
+                Action::Keep => Ok(current),
+                Action::Set(v) => {
+                    tx.update(&self.key, v.clone())?;
+                    Ok(v)
+                }
+                Action::Del => {
+                    tx.del(&self.key)?;
+                    Ok(Value::default())
+                }
+            }
+        });
+
+        match result {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmdelif {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let entries = &self.entries;
+        let result = store.transaction(&self.table, |tx| {
+            let mut deleted: Vec<Value> = Vec::with_capacity(entries.len());
+            for entry in entries {
+                let expected = entry.value.clone().unwrap_or_default();
+                let matched = tx.get(&entry.key)? == Some(expected);
+                if matched {
+                    tx.del(&entry.key)?;
+                }
+                deleted.push(matched.into());
+            }
+            Ok(deleted)
+        });
+
+        match result {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmsetnx {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let pairs = &self.pairs;
+        let result = store.transaction(&self.table, |tx| {
+            for pair in pairs {
+                if tx.contains(&pair.key)? {
+                    return Ok(false);
+                }
+            }
+            for pair in pairs {
+                tx.set(&pair.key, pair.value.clone().unwrap_or_default())?;
+            }
+            Ok(true)
+        });
+
+        match result {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hdelprefix {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // 这里总是同步删除，后台删除由 Service 负责
+        let delete = || -> Result<i64, KvError> {
+            let mut n = 0;
+            for key in store.keys(&self.table, &self.prefix)? {
+                if store.del(&self.table, &key)?.is_some() {
+                    n += 1;
+                }
+            }
+            Ok(n)
+        };
+
+        match delete() {
+            Ok(n) => Value::from(n).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Jobstatus {
+    fn execute(self, _store: &impl Storage) -> CommandResponse {
+        // 后台任务只有 Service 才有，单独 dispatch 的时候查不到
+        KvError::NotFound(format!("job {}", self.id)).into()
+    }
+}
+
+impl CommandService for Backendinfo {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.info() {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hello {
+    fn execute(self, _store: &impl Storage) -> CommandResponse {
+        // 协商的是连接的参数，由 ProstServerStream 处理
+        KvError::InvalidCommand("Hello is only available on a connection".into()).into()
+    }
+}
+
+impl CommandService for Slowest {
+    fn execute(self, _store: &impl Storage) -> CommandResponse {
+        // 慢命令的记录在 Service 上，由 Service 处理
+        KvError::InvalidCommand("Slowest is only available on a service".into()).into()
+    }
+}
+
+impl CommandService for Serverinfo {
+    fn execute(self, _store: &impl Storage) -> CommandResponse {
+        // 启动时间记录在 Service 上，由 Service 处理
+        KvError::InvalidCommand("Serverinfo is only available on a service".into()).into()
+    }
+}
+
+impl CommandService for Ack {
+    fn execute(self, _store: &impl Storage) -> CommandResponse {
+        // 流控由 ProstServerStream 在发送响应的过程中处理，响应之外的 Ack 是错误的
+        KvError::InvalidCommand("Ack is only available in a flow-controlled response".into()).into()
+    }
+}
+
+impl CommandService for Recent {
+    fn execute(self, _store: &impl Storage) -> CommandResponse {
+        // 最近的请求记录在连接上，由 ProstServerStream 处理
+        KvError::InvalidCommand("Recent is only available on a connection".into()).into()
+    }
+}
+
+impl CommandService for Hexpiretable {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let ttl = Duration::from_secs(self.ttl_secs);
+        match store.expire_table(&self.table, ttl) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Tablesizes {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let sizes = || -> Result<Vec<Kvpair>, KvError> {
+            // 边迭代边统计，不需要先拿到所有 table 的名字；只保留非空的 table
+            let mut pairs = Vec::new();
+            for table in store.table_iter()? {
+                let n = store.count(&table, "")?;
+                if n > 0 {
+                    pairs.push(Kvpair::new(table, (n as i64).into()));
+                }
+            }
+            Ok(pairs)
+        };
+
+        match sizes() {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Tcreate {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.create_table(&self.table, self.pairs) {
+            Ok(false) if self.fail_if_exists => KvError::TableExists(self.table).into(),
+            Ok(created) => Value::from(created).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Tconfig {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.set_table_options(&self.table, self.options.unwrap_or_default()) {
+            Ok(old) => old.to_pairs().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Verify {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let tables = match self.table.is_empty() {
+            // 检查所有的 table 时也检查保存 table 配置的 table
+            true => store.table_names().map(|mut tables| {
+                tables.push(TABLE_OPTIONS_TABLE.into());
+                tables
+            }),
+            false => Ok(vec![self.table]),
+        };
+        let verified = tables.and_then(|tables| {
+            tables
+                .into_iter()
+                .map(|table| Ok((store.verify(&table)?, table)))
+                .collect::<Result<Vec<_>, KvError>>()
+        });
+        match verified {
+            Ok(verified) => {
+                let total = verified.iter().map(|(bad, _t)| bad.len()).sum::<usize>();
+                let pairs: Vec<_> = verified
+                    .into_iter()
+                    .map(|(bad, table)| {
+                        Kvpair::new(table, Value::new_list(bad.into_iter().map(Value::from)))
+                    })
+                    .collect();
+                let mut res: CommandResponse = pairs.into();
+                res.values = vec![(total as i64).into()];
+                res
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Trename {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.rename_table(&self.from, &self.to, self.overwrite) {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Tdiff {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let diff = || -> Result<Vec<Kvpair>, KvError> {
+            // 同时遍历两个按 key 排序的 table，每次取出 key 较小的一边
+            let mut a = store.sorted_iter(&self.table_a)?.peekable();
+            let mut b = store.sorted_iter(&self.table_b)?.peekable();
+            let mut diff = Vec::new();
+            loop {
+                let ordering = match (a.peek(), b.peek()) {
+                    (Some(Ok(x)), Some(Ok(y))) => x.key.cmp(&y.key),
+                    // 读取出错的一边先被取出来，整个命令返回这个错误
+                    (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+                    (_, Some(_)) => Ordering::Greater,
+                    (None, None) => break,
+                };
+                let (key, values) = match ordering {
+                    Ordering::Less => {
+                        let x = a.next().unwrap()?;
+                        (x.key, vec!["only_a".into(), x.value.unwrap_or_default()])
+                    }
+                    Ordering::Greater => {
+                        let y = b.next().unwrap()?;
+                        (y.key, vec!["only_b".into(), y.value.unwrap_or_default()])
+                    }
+                    Ordering::Equal => {
+                        let (x, y) = (a.next().unwrap()?, b.next().unwrap()?);
+                        if x.value == y.value {
+                            continue;
+                        }
+                        let values = vec![
+                            "differ".into(),
+                            x.value.unwrap_or_default(),
+                            y.value.unwrap_or_default(),
+                        ];
+                        (x.key, values)
+                    }
+                };
+                diff.push(Kvpair::new(key, Value::new_list(values)));
+            }
+            Ok(diff)
+        };
+
+        match diff() {
+            Ok(diff) => diff.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Cdc {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.changes(self.from_offset, self.limit as usize) {
+            Ok(changes) => changes
+                .into_iter()
+                .map(Kvpair::from)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hreapexpired {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.reap_expired(&self.table, self.remove) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Compact {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.compact() {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 访问名为 bad 的 key 时返回错误的存储，用来模拟单个 key 的读写失败，其余的操作交给 MemTable
+    struct FaultyStore(MemTable);
+
+    impl FaultyStore {
+        fn check(key: &str) -> Result<(), KvError> {
+            match key {
+                "bad" => Err(KvError::Internal("injected fault".into())),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Storage for FaultyStore {
+        fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            Self::check(key)?;
+            self.0.get(table, key)
+        }
+
+        fn set(
+            &self,
+            table: &str,
+            key: impl Into<String>,
+            value: impl Into<Value>,
+        ) -> Result<Option<Value>, KvError> {
+            self.0.set(table, key, value)
+        }
+
+        fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+            Self::check(key)?;
+            self.0.contains(table, key)
+        }
+
+        fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            Self::check(key)?;
+            self.0.del(table, key)
+        }
+
+        fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            self.0.get_all(table)
+        }
+
+        fn verify(&self, table: &str) -> Result<Vec<String>, KvError> {
+            let keys = self.0.keys(table, "")?;
+            Ok(keys
+                .into_iter()
+                .filter(|k| Self::check(k).is_err())
+                .collect())
+        }
+
+        fn get_iter(
+            &self,
+            table: &str,
+        ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+            self.0.get_iter(table)
+        }
+
+        fn keys(&self, table: &str, prefix: &str) -> Result<Vec<String>, KvError> {
+            self.0.keys(table, prefix)
+        }
+
+        fn drain(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            self.0.drain(table)
+        }
+
+        fn replace(&self, table: &str, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+            self.0.replace(table, pairs)
+        }
+
+        fn by_insertion(
+            &self,
+            table: &str,
+            n: usize,
+            newest: bool,
+        ) -> Result<Vec<Kvpair>, KvError> {
+            self.0.by_insertion(table, n, newest)
+        }
+
+        fn count(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+            self.0.count(table, prefix)
+        }
+
+        fn table_names(&self) -> Result<Vec<String>, KvError> {
+            self.0.table_names()
+        }
+
+        fn rename_table(&self, from: &str, to: &str, overwrite: bool) -> Result<(), KvError> {
+            self.0.rename_table(from, to, overwrite)
+        }
+
+        fn flush(&self) -> Result<(), KvError> {
+            self.0.flush()
+        }
+
+        fn info(&self) -> Result<Vec<Kvpair>, KvError> {
+            self.0.info()
+        }
+
+        fn compact(&self) -> Result<(), KvError> {
+            self.0.compact()
+        }
+
+        fn transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+        where
+            F: Fn(&mut TxContext) -> Result<R, KvError>,
+        {
+            self.0.transaction(table, f)
+        }
+
+        fn read_transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+        where
+            F: Fn(&mut TxContext) -> Result<R, KvError>,
+        {
+            // 通过 get 读取，出错的 key 在事务里同样会出错；测试里的 key 都没有过期时间
+            let reader = |key: &str| Ok(self.get(table, key)?.map(crate::StoredValue::new));
+            f(&mut TxContext::new(&reader, 0))
+        }
+
+        fn expire_table(&self, table: &str, ttl: Duration) -> Result<usize, KvError> {
+            self.0.expire_table(table, ttl)
+        }
+
+        fn reap_expired(&self, table: &str, remove: bool) -> Result<usize, KvError> {
+            self.0.reap_expired(table, remove)
+        }
+    }
+
+    #[test]
+    fn multi_key_commands_should_report_item_statuses() {
+        let store = FaultyStore(MemTable::new());
+        set_key_pairs("t1", vec![("k1", "v1"), ("bad", "v2")], &store);
+        let keys = vec!["k1".into(), "bad".into(), "none".into()];
+
+        // 不存在的 key 是 404，出错的 key 是 500，两者的 value 都是空的
+        let res = dispatch(CommandRequest::new_hmget("t1", keys.clone()), &store);
+        assert_res_ok(
+            &res,
+            &["v1".into(), Value::default(), Value::default()],
+            &[],
+        );
+        assert_eq!(res.item_statuses, [200, 500, 404]);
+
+        let res = dispatch(CommandRequest::new_hmexist("t1", keys.clone()), &store);
+        assert_res_ok(&res, &[true.into(), Value::default(), false.into()], &[]);
+        assert_eq!(res.item_statuses, [200, 500, 200]);
+
+        let res = dispatch(CommandRequest::new_hmgetttl("t1", keys.clone()), &store);
+        let values = [
+            "v1".into(),
+            (-1).into(),
+            Value::default(),
+            Value::default(),
+            Value::default(),
+            (-2).into(),
+        ];
+        assert_res_ok(&res, &values, &[]);
+        assert_eq!(res.item_statuses, [200, 500, 404]);
+
+        let res = dispatch(CommandRequest::new_hmdel("t1", keys.clone()), &store);
+        assert_res_ok(
+            &res,
+            &["v1".into(), Value::default(), Value::default()],
+            &[],
+        );
+        assert_eq!(res.item_statuses, [200, 500, 404]);
+
+        // 只返回个数时也带上每个 key 的状态码
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+        let res = dispatch(CommandRequest::new_hmdel_count("t1", keys), &store);
+        assert_res_ok(&res, &[1.into()], &[]);
+        assert_eq!(res.item_statuses, [200, 500, 404]);
+
+        // 单个 key 的命令没有 item_statuses
+        let res = dispatch(CommandRequest::new_hget("t1", "bad"), &store);
+        assert_res_error(&res, 500, "injected fault");
+        assert!(res.item_statuses.is_empty());
+    }
+
+    #[test]
+    fn verify_should_report_unreadable_keys() {
+        let store = FaultyStore(MemTable::new());
+        set_key_pairs("t1", vec![("k1", "v1"), ("bad", "v2")], &store);
+        set_key_pairs("t2", vec![("k1", "v1")], &store);
+
+        // 检查所有的 table，没有问题的 table 返回空的 list
+        let res = dispatch(CommandRequest::new_verify(""), &store);
+        let pairs = vec![
+            Kvpair::new(TABLE_OPTIONS_TABLE, Value::new_list([])),
+            Kvpair::new("t1", Value::new_list(["bad".into()])),
+            Kvpair::new("t2", Value::new_list([])),
+        ];
+        assert_res_ok(&res, &[1.into()], &pairs);
+
+        let res = dispatch(CommandRequest::new_verify("t2"), &store);
+        assert_res_ok(&res, &[0.into()], &[Kvpair::new("t2", Value::new_list([]))]);
+    }
+
+    #[test]
+    fn hset_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset("t1", "hello", "world".into());
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &["world".into()], &[]);
+    }
+
+    #[test]
+    fn hmgetttl_should_work() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        set_key_pairs("cache", vec![("k1", "v1"), ("k2", "v2")], &store);
+        store
+            .expire("cache", "k1", Duration::from_secs(10))
+            .unwrap();
+
+        // 剩余 7.5 秒，向上取整为 8 秒
+        clock.advance(Duration::from_millis(2500));
+        let keys = vec!["k1".into(), "k2".into(), "k3".into()];
+        let res = dispatch(CommandRequest::new_hmgetttl("cache", keys), &store);
+        let values = &[
+            "v1".into(),
+            8.into(),
+            "v2".into(),
+            (-1).into(),
+            Value::default(),
+            (-2).into(),
+        ];
+        assert_res_ok(&res, values, &[]);
+
+        // 过期之后和不存在的 key 一样
+        clock.advance(Duration::from_secs(8));
+        let keys = vec!["k1".into()];
+        let res = dispatch(CommandRequest::new_hmgetttl("cache", keys), &store);
+        assert_res_ok(&res, &[Value::default(), (-2).into()], &[]);
+    }
+
+    #[test]
+    fn hsetwithlen_should_return_table_len_after_set() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        let res = dispatch(CommandRequest::new_hsetwithlen("t1", "k1", "v1"), &store);
+        assert_res_ok(&res, &[1.into()], &[]);
+        let res = dispatch(CommandRequest::new_hsetwithlen("t1", "k2", "v2"), &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+
+        // 覆盖已有的 key 不会增加个数
+        let res = dispatch(CommandRequest::new_hsetwithlen("t1", "k1", "v3"), &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_ok(&res, &["v3".into()], &[]);
+
+        // 已经过期的 key 不算在内
+        store.expire("t1", "k2", Duration::from_secs(10)).unwrap();
+        clock.advance(Duration::from_secs(20));
+        let res = dispatch(CommandRequest::new_hsetwithlen("t1", "k3", "v3"), &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+    }
+
+    #[test]
+    fn hdiffapply_should_only_apply_changed_keys() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1"), ("k2", "v2"), ("k3", "v3")], &store);
+        store.expire("t1", "k1", Duration::from_secs(10)).unwrap();
+
+        let desired = vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "new".into()),
+            Kvpair::new("k4", "v4".into()),
+        ];
+        let res = dispatch(CommandRequest::new_hdiffapply("t1", desired), &store);
+        assert_eq!(res.status, 200);
+        // 已经一样的 k1 没有写入，k3 不在 desired 中也不受影响
+        assert_eq!(
+            res.pairs,
+            [
+                Kvpair::new("k2", Value::new_list(["v2".into(), "new".into()])),
+                Kvpair::new("k4", Value::new_list([Value::default(), "v4".into()])),
+            ]
+        );
+        let res = dispatch(
+            CommandRequest::new_hmgetttl("t1", vec!["k1".into()]),
+            &store,
+        );
+        assert_res_ok(&res, &["v1".into(), 10.into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetall("t1"), &store);
+        let pairs = [("k1", "v1"), ("k2", "new"), ("k3", "v3"), ("k4", "v4")]
+            .into_iter()
+            .map(|(k, v)| Kvpair::new(k, v.into()))
+            .collect::<Vec<_>>();
+        assert_res_ok(&res, &[], &pairs);
+
+        // 再执行一次什么都不会写入
+        let desired = vec![Kvpair::new("k2", "new".into())];
+        let res = dispatch(CommandRequest::new_hdiffapply("t1", desired), &store);
+        assert_res_ok(&res, &[], &[]);
+    }
+
+    #[test]
+    fn hsetifdiff_should_only_write_changes() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        let res = dispatch(CommandRequest::new_hsetifdiff("t1", "k1", "v1"), &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+        set_key_pairs("t1", vec![("k2", "v2")], &store);
+        store.expire("t1", "k1", Duration::from_secs(10)).unwrap();
+
+        // 相同的 value 没有写入：过期时间还在，k1 也没有变成最新写入的 key
+        let res = dispatch(CommandRequest::new_hsetifdiff("t1", "k1", "v1"), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+        let res = dispatch(CommandRequest::new_hnewest("t1", 1), &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("k2", "v2".into())]);
+        let res = dispatch(
+            CommandRequest::new_hmgetttl("t1", vec!["k1".into()]),
+            &store,
+        );
+        assert_res_ok(&res, &["v1".into(), 10.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hsetifdiff("t1", "k1", "v2"), &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hnewest("t1", 1), &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("k1", "v2".into())]);
+    }
+
+    #[test]
+    fn harchive_should_only_move_keys_near_expiry() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        set_key_pairs(
+            "hot",
+            vec![("soon", "v1"), ("later", "v2"), ("forever", "v3")],
+            &store,
+        );
+        store
+            .expire("hot", "soon", Duration::from_secs(30))
+            .unwrap();
+        store
+            .expire("hot", "later", Duration::from_secs(3600))
+            .unwrap();
+
+        let res = dispatch(CommandRequest::new_harchive("hot", "cold", 60), &store);
+        assert_res_ok(&res, &[1.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hgetall("hot"), &store);
+        let pairs = &[
+            Kvpair::new("forever", "v3".into()),
+            Kvpair::new("later", "v2".into()),
+        ];
+        assert_res_ok(&res, &[], pairs);
+
+        // 在 archive table 中不会过期
+        clock.advance(Duration::from_secs(7200));
+        let res = dispatch(CommandRequest::new_hgetall("cold"), &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("soon", "v1".into())]);
+
+        let res = dispatch(CommandRequest::new_harchive("hot", "hot", 60), &store);
+        assert_res_error(&res, 400, "into itself");
+    }
+
+    #[test]
+    fn hdrainprefix_should_hand_each_job_to_one_consumer() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let store = MemTable::new();
+        set_key_pairs("queue", vec![("other", "v")], &store);
+        let produced = AtomicBool::new(false);
+
+        let consume = || {
+            let mut jobs = Vec::new();
+            loop {
+                // 先看生产者是否结束，再取一次，保证最后一批也能取到
+                let done = produced.load(Ordering::SeqCst);
+                let res = dispatch(CommandRequest::new_hdrainprefix("queue", "job:"), &store);
+                jobs.extend(res.pairs.into_iter().map(|p| p.key));
+                if done {
+                    return jobs;
+                }
+                std::thread::yield_now();
+            }
+        };
+
+        let (mut a, b) = std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..2000 {
+                    store.set("queue", format!("job:{}", i), i).unwrap();
+                }
+                produced.store(true, Ordering::SeqCst);
+            });
+            let a = s.spawn(consume);
+            let b = s.spawn(consume);
+            (a.join().unwrap(), b.join().unwrap())
+        });
+
+        // 两个消费者拿到的 job 没有重复，合起来正好是所有的 job
+        a.extend(b);
+        let total = a.len();
+        a.sort();
+        a.dedup();
+        assert_eq!(a.len(), total);
+        assert_eq!(total, 2000);
+
+        let res = dispatch(CommandRequest::new_hget("queue", "other"), &store);
+        assert_res_ok(&res, &["v".into()], &[]);
+    }
+
+    #[test]
+    fn hmincr_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("stats", "a", 10.into()), &store);
+        let cmd = CommandRequest::new_hmincr("stats", [("a", 5), ("b", 3), ("a", -1)]);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[15.into(), 3.into(), 14.into()], &[]);
+
+        // 有一个 key 不是整数，整个命令失败，别的 key 也没有被修改
+        dispatch(
+            CommandRequest::new_hset("stats", "name", "v".into()),
+            &store,
+        );
+        let cmd = CommandRequest::new_hmincr("stats", [("a", 1), ("name", 1), ("b", 1)]);
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 400, "Cannot merge");
+        let keys = vec!["a".into(), "b".into()];
+        let res = dispatch(CommandRequest::new_hmget("stats", keys), &store);
+        assert_res_ok(&res, &[14.into(), 3.into()], &[]);
+    }
+
+    #[test]
+    fn hsetkeepttl_should_keep_ttl() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        set_key_pairs("cache", vec![("k1", "v1")], &store);
+        store
+            .expire("cache", "k1", Duration::from_secs(10))
+            .unwrap();
+
+        clock.advance(Duration::from_secs(6));
+        let res = dispatch(CommandRequest::new_hsetkeepttl("cache", "k1", "v2"), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("cache", "k1"), &store);
+        assert_res_ok(&res, &["v2".into()], &[]);
+
+        // 原来的 ttl 继续计时，而不是从修改的时候重新开始
+        clock.advance(Duration::from_secs(5));
+        let res = dispatch(CommandRequest::new_hget("cache", "k1"), &store);
+        assert_res_error(&res, 404, "Not found");
+
+        // 不存在的 key 创建之后永不过期
+        let res = dispatch(CommandRequest::new_hsetkeepttl("cache", "k2", "v1"), &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+        clock.advance(Duration::from_secs(1000));
+        let res = dispatch(CommandRequest::new_hget("cache", "k2"), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn hsetct_and_hgetct_should_work() {
+        let store = MemTable::new();
+        let json = r#"{"name":"simplekv"}"#;
+        let cmd = CommandRequest::new_hsetct("docs", "d1", json, "application/json");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        let res = dispatch(CommandRequest::new_hgetct("docs", "d1"), &store);
+        assert_res_ok(&res, &[json.into(), "application/json".into()], &[]);
+
+        // 普通的 Hset 修改了 value，内容类型也就被清除了
+        dispatch(
+            CommandRequest::new_hset("docs", "d1", "plain".into()),
+            &store,
+        );
+        let res = dispatch(CommandRequest::new_hgetct("docs", "d1"), &store);
+        assert_res_ok(&res, &["plain".into(), "".into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hgetct("docs", "none"), &store);
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn tdiff_should_categorize_keys() {
+        let store = MemTable::new();
+        set_key_pairs("a", vec![("k1", "v1"), ("k2", "v2"), ("k3", "v3")], &store);
+        set_key_pairs(
+            "b",
+            vec![("k2", "v2"), ("k3", "other"), ("k4", "v4")],
+            &store,
+        );
+        let res = dispatch(CommandRequest::new_tdiff("a", "b"), &store);
+        let pairs = &[
+            Kvpair::new("k1", Value::new_list(["only_a".into(), "v1".into()])),
+            Kvpair::new(
+                "k3",
+                Value::new_list(["differ".into(), "v3".into(), "other".into()]),
+            ),
+            Kvpair::new("k4", Value::new_list(["only_b".into(), "v4".into()])),
+        ];
+        assert_res_ok(&res, &[], pairs);
+
+        // 内容相同的 table 没有差异
+        let res = dispatch(CommandRequest::new_tdiff("a", "a"), &store);
+        assert_res_ok(&res, &[], &[]);
+    }
+
+    #[test]
+    fn cdc_should_resume_from_last_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CdcStore::new(MemTable::new(), OpLog::open(dir.path()).unwrap());
+        set_key_pairs("t1", vec![("k1", "v1"), ("k2", "v2")], &store);
+        dispatch(CommandRequest::new_hdel("t1", "k1"), &store);
+
+        let res = dispatch(CommandRequest::new_cdc(0, 0), &store);
+        let changes = res.changes().unwrap();
+        let ops: Vec<_> = changes
+            .iter()
+            .map(|c| (c.offset, c.op(), c.key.as_str()))
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                (0, ChangeOp::Set, "k1"),
+                (1, ChangeOp::Set, "k2"),
+                (2, ChangeOp::Del, "k1")
+            ]
+        );
+        let last = changes.last().unwrap().offset;
+
+        // 从上次处理的最后一个 offset 之后继续读，没有遗漏也没有重复
+        set_key_pairs("t1", vec![("k3", "v3")], &store);
+        let res = dispatch(CommandRequest::new_cdc(last + 1, 0), &store);
+        let changes = res.changes().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].offset, 3);
+        assert_eq!(changes[0].value, Some("v3".into()));
+
+        // 没有打开 change feed 的存储不支持 Cdc
+        let res = dispatch(CommandRequest::new_cdc(0, 0), &MemTable::new());
+        assert_res_error(&res, 400, "Change feed is not enabled");
+    }
+
+    #[test]
+    fn tcreate_should_only_initialize_empty_table() {
+        let store = MemTable::new();
+        let pairs = vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into()),
+        ];
+        let res = dispatch(
+            CommandRequest::new_tcreate("cfg", pairs.clone(), true),
+            &store,
+        );
+        assert_res_ok(&res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetall("cfg"), &store);
+        assert_res_ok(&res, &[], &pairs);
+
+        // 已经初始化过的 table 不会被修改
+        let other = vec![Kvpair::new("k3", "v3".into())];
+        let res = dispatch(
+            CommandRequest::new_tcreate("cfg", other.clone(), false),
+            &store,
+        );
+        assert_res_ok(&res, &[false.into()], &[]);
+        let res = dispatch(CommandRequest::new_tcreate("cfg", other, true), &store);
+        assert_res_error(&res, 409, "Table already exists: cfg");
+        let res = dispatch(CommandRequest::new_hgetall("cfg"), &store);
+        assert_res_ok(&res, &[], &pairs);
+
+        // 清空之后的 table 可以重新初始化
+        dispatch(CommandRequest::new_hdrain("cfg"), &store);
+        let res = dispatch(CommandRequest::new_tcreate("cfg", vec![], true), &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+    }
+
+    #[test]
+    fn trename_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+        let res = dispatch(CommandRequest::new_trename("t1", "t2", false), &store);
+        assert_res_ok(&res, &[], &[]);
+        let res = dispatch(CommandRequest::new_hget("t2", "k1"), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_error(&res, 404, "Not found");
+
+        // 源 table 不存在
+        let res = dispatch(CommandRequest::new_trename("none", "t3", false), &store);
+        assert_res_error(&res, 404, "Not found");
+
+        // 目标 table 已经存在，没有 overwrite 时拒绝，数据保持不变
+        set_key_pairs("t3", vec![("k3", "v3")], &store);
+        let res = dispatch(CommandRequest::new_trename("t2", "t3", false), &store);
+        assert_res_error(&res, 409, "Table already exists");
+        let res = dispatch(CommandRequest::new_hget("t3", "k3"), &store);
+        assert_res_ok(&res, &["v3".into()], &[]);
+
+        let res = dispatch(CommandRequest::new_trename("t2", "t3", true), &store);
+        assert_res_ok(&res, &[], &[]);
+        let res = dispatch(CommandRequest::new_hgetall("t3"), &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("k1", "v1".into())]);
+    }
+
+    #[test]
+    fn hset_return_mode_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+        let hset = |mode| CommandRequest::new_hset("t1", "k1", "v2".into()).with_return_mode(mode);
+
+        let res = dispatch(hset(ReturnMode::OldValue), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        let res = dispatch(hset(ReturnMode::Count), &store);
+        assert_res_ok(&res, &[1.into()], &[]);
+
+        let res = dispatch(hset(ReturnMode::None), &store);
+        assert_res_ok(&res, &[], &[]);
+
+        // 删除不存在的 key 不影响任何 key
+        let cmd = CommandRequest::new_hdel("t1", "none").with_return_mode(ReturnMode::Count);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[0.into()], &[]);
+
+        // 其他返回之前的 value 的写操作也支持 return_mode
+        let cmd =
+            CommandRequest::new_hsetkeepttl("t1", "k2", "v1").with_return_mode(ReturnMode::Count);
+        assert_res_ok(&dispatch(cmd, &store), &[1.into()], &[]);
+        let cmd = CommandRequest::new_hsetct("t1", "k3", "v1", "text/plain")
+            .with_return_mode(ReturnMode::None);
+        assert_res_ok(&dispatch(cmd, &store), &[], &[]);
+        let keys = vec!["k1".into(), "k2".into(), "none".into()];
+        let cmd = CommandRequest::new_hmdel("t1", keys).with_return_mode(ReturnMode::Count);
+        assert_res_ok(&dispatch(cmd, &store), &[2.into()], &[]);
+        let cmd =
+            CommandRequest::new_hmdel("t1", vec!["k3".into()]).with_return_mode(ReturnMode::None);
+        assert_res_ok(&dispatch(cmd, &store), &[], &[]);
+        let res = dispatch(CommandRequest::new_hget("t1", "k3"), &store);
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn hget_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset("score", "u1", 10.into());
+        dispatch(cmd, &store);
+        let cmd = CommandRequest::new_hget("score", "u1");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[10.into()], &[]);
+    }
+
+    #[test]
+    fn hgetrange_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("s", "hello world")], &store);
+        let cmd = CommandRequest::new_hset("t1", "b", b"hello world".into());
+        dispatch(cmd, &store);
+
+        // 取中间的一段
+        let res = dispatch(CommandRequest::new_hgetrange("t1", "s", 6, 9), &store);
+        assert_res_ok(&res, &["wor".into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetrange("t1", "b", 0, 5), &store);
+        assert_res_ok(&res, &[b"hello".into()], &[]);
+
+        // end 超出范围会被截断
+        let res = dispatch(CommandRequest::new_hgetrange("t1", "s", 6, 100), &store);
+        assert_res_ok(&res, &["world".into()], &[]);
+
+        // start 超出范围返回空值
+        let res = dispatch(CommandRequest::new_hgetrange("t1", "s", 100, 200), &store);
+        assert_res_ok(&res, &["".into()], &[]);
+    }
+
+    #[test]
+    fn hgetrange_with_non_exist_key_should_return_404() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hgetrange("t1", "s", 0, 1);
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn hmget_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("user", vec![("u1", "s1"), ("u2", "s2")], &store);
+        let cmd = CommandRequest::new_hmget("user", vec!["u1".into(), "u2".into(), "u3".into()]);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &["s1".into(), "s2".into(), Value::default()], &[]);
+    }
+
+    #[test]
+    fn hmset_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("user", vec![("u1", "s1"), ("u2", "s2")], &store);
+        let pairs = vec![
+            Kvpair::new("u1", "ns1".into()),
+            Kvpair::new("u2", "ns2".into()),
+            Kvpair::new("u3", "ns3".into()),
+        ];
+        let cmd = CommandRequest::new_hmset("user", pairs);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &["s1".into(), "s2".into(), Value::default()], &[]);
+    }
+
+    #[test]
+    fn hdel_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("user", vec![("u1", "s1")], &store);
+        let cmd = CommandRequest::new_hdel("user", "u1");
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &["s1".into()], &[]);
+
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+    }
+
+    #[test]
+    fn hmdel_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("user", vec![("u1", "s1")], &store);
+        let cmd = CommandRequest::new_hmdel("user", vec!["u1".into(), "u2".into()]);
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &["s1".into(), Value::default()], &[]);
+    }
+
+    #[test]
+    fn hmdel_count_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("user", vec![("u1", "s1"), ("u2", "s2")], &store);
+        let keys = vec!["u1".into(), "u2".into(), "u3".into()];
+        let cmd = CommandRequest::new_hmdel_count("user", keys);
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+
+        // 再删一次，已经没有 key 存在了
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[0.into()], &[]);
+    }
+
+    #[test]
+    fn hexist_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("user", vec![("u1", "s1")], &store);
+        let cmd = CommandRequest::new_hexist("user", "u1");
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        let cmd = CommandRequest::new_hexist("user", "u2");
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+    }
+
+    #[test]
+    fn hmexist_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("user", vec![("u1", "s1"), ("u2", "s2")], &store);
+        let cmd = CommandRequest::new_hmexist("user", vec!["u1".into(), "u2".into(), "u3".into()]);
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[true.into(), true.into(), false.into()], &[]);
+    }
+
+    #[test]
+    fn hmerge_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hmerge("score", "u1", MergeOp::AddInt, 10.into());
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[10.into()], &[]);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[20.into()], &[]);
+
+        let cmd = CommandRequest::new_hmerge("score", "u1", MergeOp::AddInt, "a".into());
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 400, "Cannot merge");
+
+        // key 不存在时也要检查 operand 的类型
+        let cmd = CommandRequest::new_hmerge("score", "u2", MergeOp::AddInt, "a".into());
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 400, "Cannot merge");
+        let res = dispatch(CommandRequest::new_hget("score", "u2"), &store);
+        assert_res_error(&res, 404, "Not found");
+        let cmd = CommandRequest::new_hmerge("score", "u2", MergeOp::Max, 1.5.into());
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[1.5.into()], &[]);
+    }
+
+    #[test]
+    fn hmodify_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("name", "Tyr")], &store);
+        let cmd = CommandRequest::new_hmodify("t1", "name", ModifyOp::Upper);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &["TYR".into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("t1", "name"), &store);
+        assert_res_ok(&res, &["TYR".into()], &[]);
+
+        dispatch(CommandRequest::new_hset("t1", "score", 42.into()), &store);
+        let cmd = CommandRequest::new_hmodify("t1", "score", ModifyOp::Negate);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[(-42).into()], &[]);
+
+        // 类型不匹配时拒绝，value 保持不变
+        let cmd = CommandRequest::new_hmodify("t1", "score", ModifyOp::Upper);
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 400, "Cannot apply");
+        let res = dispatch(CommandRequest::new_hget("t1", "score"), &store);
+        assert_res_ok(&res, &[(-42).into()], &[]);
+
+        let cmd = CommandRequest::new_hmodify("t1", "none", ModifyOp::Lower);
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn hcount_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2"), ("o1", "v3")], &store);
+        let res = dispatch(CommandRequest::new_hcount("t1", ""), &store);
+        assert_res_ok(&res, &[3.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hcount("t1", "u"), &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+    }
+
+    #[test]
+    fn compact_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("user", vec![("u1", "s1")], &store);
+        let cmd = CommandRequest::new_compact();
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[], &[]);
+
+        let res = dispatch(CommandRequest::new_hget("user", "u1"), &store);
+        assert_res_ok(&res, &["s1".into()], &[]);
+    }
+
+    #[test]
+    fn hmsetnx_should_work() {
+        let store = MemTable::new();
+        let pairs = vec![
+            Kvpair::new("u1", "v1".into()),
+            Kvpair::new("u2", "v2".into()),
+        ];
+        let res = dispatch(CommandRequest::new_hmsetnx("t1", pairs), &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        let res = dispatch(
+            CommandRequest::new_hmget("t1", vec!["u1".into(), "u2".into()]),
+            &store,
+        );
+        assert_res_ok(&res, &["v1".into(), "v2".into()], &[]);
+    }
+
+    #[test]
+    fn hmsetnx_with_exist_key_should_not_apply() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u2", "old")], &store);
+        let pairs = vec![
+            Kvpair::new("u1", "v1".into()),
+            Kvpair::new("u2", "v2".into()),
+        ];
+        let res = dispatch(CommandRequest::new_hmsetnx("t1", pairs), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        // 所有的 key 都不会被修改
+        let res = dispatch(
+            CommandRequest::new_hmget("t1", vec!["u1".into(), "u2".into()]),
+            &store,
+        );
+        assert_res_ok(&res, &[Value::default(), "old".into()], &[]);
+    }
+
+    #[test]
+    fn hincrclamp_should_clamp_into_range() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hincrclamp("stock", "apple", 8, 0, 10);
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(&res, &[8.into(), false.into()], &[]);
+
+        // 超过上限时限制在 max
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[10.into(), true.into()], &[]);
+
+        // 低于下限时限制在 min
+        let cmd = CommandRequest::new_hincrclamp("stock", "apple", -15, 0, 10);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[0.into(), true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("stock", "apple"), &store);
+        assert_res_ok(&res, &[0.into()], &[]);
+
+        let cmd = CommandRequest::new_hincrclamp("stock", "apple", 1, 10, 0);
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 400, "min 10 is greater than max 0");
+
+        // 溢出时饱和之后再限制，不会返回错误
+        dispatch(
+            CommandRequest::new_hset("stock", "big", (i64::MAX - 1).into()),
+            &store,
+        );
+        let cmd = CommandRequest::new_hincrclamp("stock", "big", 10, 0, i64::MAX);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[i64::MAX.into(), true.into()], &[]);
+        let cmd = CommandRequest::new_hincrclamp("stock", "big", i64::MIN, -5, 5);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[(-1).into(), false.into()], &[]);
+    }
+
+    #[test]
+    fn hincrex_should_work() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+
+        // 第一次调用创建计数器，10 秒之后过期
+        let res = dispatch(CommandRequest::new_hincrex("limits", "u1", 1, 10), &store);
+        assert_res_ok(&res, &[1.into()], &[]);
+
+        // 之后的调用只递增，不会延长过期时间
+        clock.advance(Duration::from_secs(6));
+        let res = dispatch(CommandRequest::new_hincrex("limits", "u1", 2, 10), &store);
+        assert_res_ok(&res, &[3.into()], &[]);
+
+        clock.advance(Duration::from_secs(3));
+        let res = dispatch(CommandRequest::new_hget("limits", "u1"), &store);
+        assert_res_ok(&res, &[3.into()], &[]);
+
+        // 过期之后重新开始一个窗口
+        clock.advance(Duration::from_secs(2));
+        let res = dispatch(CommandRequest::new_hincrex("limits", "u1", 1, 10), &store);
+        assert_res_ok(&res, &[1.into()], &[]);
+
+        clock.advance(Duration::from_secs(9));
+        let res = dispatch(CommandRequest::new_hget("limits", "u1"), &store);
+        assert_res_ok(&res, &[1.into()], &[]);
+    }
+
+    #[test]
+    fn hincrex_on_non_integer_should_fail() {
+        let store = MemTable::new();
+        set_key_pairs("limits", vec![("u1", "v1")], &store);
+        let res = dispatch(CommandRequest::new_hincrex("limits", "u1", 1, 10), &store);
+        assert_res_error(&res, 400, "Cannot merge");
+    }
+
+    #[test]
+    fn hgetreset_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hgetreset("metrics", "reqs"), &store);
+        assert_res_ok(&res, &[0.into()], &[]);
+
+        set_key_pairs("metrics", vec![("reqs", 10)], &store);
+        let res = dispatch(CommandRequest::new_hgetreset("metrics", "reqs"), &store);
+        assert_res_ok(&res, &[10.into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("metrics", "reqs"), &store);
+        assert_res_ok(&res, &[0.into()], &[]);
+
+        set_key_pairs("metrics", vec![("name", "v1")], &store);
+        let res = dispatch(CommandRequest::new_hgetreset("metrics", "name"), &store);
+        assert_res_error(&res, 500, "Cannot convert value");
+    }
+
+    #[test]
+    fn hgetreset_should_not_lose_concurrent_increments() {
+        let store = MemTable::new();
+        let incr = || CommandRequest::new_hmerge("metrics", "reqs", MergeOp::AddInt, 1.into());
+
+        let scraped = std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        assert_eq!(dispatch(incr(), &store).status, 200);
+                    }
+                });
+            }
+
+            // 一边递增一边定期取出计数
+            let scraper = s.spawn(|| {
+                let mut total = 0;
+                for _ in 0..100 {
+                    let res = dispatch(CommandRequest::new_hgetreset("metrics", "reqs"), &store);
+                    total += i64::try_from(&res.values[0]).unwrap();
+                    std::thread::yield_now();
+                }
+                total
+            });
+            scraper.join().unwrap()
+        });
+
+        let res = dispatch(CommandRequest::new_hgetreset("metrics", "reqs"), &store);
+        let rest = i64::try_from(&res.values[0]).unwrap();
+        assert_eq!(scraped + rest, 4000);
+    }
+
+    #[test]
+    fn hgetall_should_not_see_partial_hreplace() {
+        check_hreplace_is_atomic(MemTable::new());
+        check_hreplace_is_atomic(SledDB::new(tempfile::tempdir().unwrap()));
+        check_hreplace_is_atomic(BTreeStore::new());
+    }
+
+    fn check_hreplace_is_atomic(store: impl Storage + Sync) {
+        let config = |version: &str| -> Vec<Kvpair> {
+            (0..50)
+                .map(|i| Kvpair::new(format!("k{:02}", i), version.into()))
+                .collect()
+        };
+        let (old, new) = (config("old"), config("new"));
+        dispatch(CommandRequest::new_hreplace("cfg", old.clone()), &store);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..200 {
+                    let pairs = if i % 2 == 0 { new.clone() } else { old.clone() };
+                    let res = dispatch(CommandRequest::new_hreplace("cfg", pairs), &store);
+                    assert_res_ok(&res, &[], &[]);
+                }
+            });
+
+            // 读到的一定是完整的旧配置或者完整的新配置
+            for _ in 0..200 {
+                let mut res = dispatch(CommandRequest::new_hgetall("cfg"), &store);
+                res.pairs.sort_by(|a, b| a.key.cmp(&b.key));
+                assert!(res.pairs == old || res.pairs == new);
+            }
+        });
+    }
+
+    #[test]
+    fn eval_conditional_set_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("small", 5), ("big", 15)], &store);
+
+        let script = "if value > 10 then set 0";
+        let res = dispatch(CommandRequest::new_eval("t1", "small", script), &store);
+        assert_res_ok(&res, &[5.into()], &[]);
+        let res = dispatch(CommandRequest::new_eval("t1", "big", script), &store);
+        assert_res_ok(&res, &[0.into()], &[]);
+
+        let res = dispatch(
+            CommandRequest::new_hmget("t1", vec!["small".into(), "big".into()]),
+            &store,
+        );
+        assert_res_ok(&res, &[5.into(), 0.into()], &[]);
+
+        // 可以删除 key
+        let script = "if value == 0 then del else keep";
+        let res = dispatch(CommandRequest::new_eval("t1", "big", script), &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_hexist("t1", "big"), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+    }
+
+    #[test]
+    fn eval_arithmetic_transform_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("celsius", 30)], &store);
+
+        let script = "set value * 9 / 5 + 32";
+        let res = dispatch(CommandRequest::new_eval("t1", "celsius", script), &store);
+        assert_res_ok(&res, &[86.into()], &[]);
+
+        // key 不存在时 value 为空
+        let script = "if value == 1 then keep else set 1";
+        let res = dispatch(CommandRequest::new_eval("t1", "none", script), &store);
+        assert_res_ok(&res, &[1.into()], &[]);
+
+        // 脚本出错时 key 不会被修改
+        let res = dispatch(
+            CommandRequest::new_eval("t1", "celsius", "set value +"),
+            &store,
+        );
+        assert_res_error(&res, 400, "script");
+        let res = dispatch(
+            CommandRequest::new_eval("t1", "celsius", "set value / 0"),
+            &store,
+        );
+        assert_res_error(&res, 400, "division by zero");
+        let res = dispatch(CommandRequest::new_hget("t1", "celsius"), &store);
+        assert_res_ok(&res, &[86.into()], &[]);
+    }
+
+    #[test]
+    fn hmdelif_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        let entries = vec![
+            Kvpair::new("u1", "v1".into()),
+            Kvpair::new("u2", "v2".into()),
+        ];
+        let res = dispatch(CommandRequest::new_hmdelif("t1", entries), &store);
+        assert_res_ok(&res, &[true.into(), true.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hgetall("t1"), &store);
+        assert_res_ok(&res, &[], &[]);
+    }
+
+    #[test]
+    fn hmdelif_should_skip_mismatched_keys() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "changed")], &store);
+        let entries = vec![
+            Kvpair::new("u1", "v1".into()),
+            Kvpair::new("u2", "v2".into()),
+            Kvpair::new("u3", "v3".into()),
+        ];
+        let res = dispatch(CommandRequest::new_hmdelif("t1", entries), &store);
+        assert_res_ok(&res, &[true.into(), false.into(), false.into()], &[]);
+
+        // 只有匹配的 key 被删除
+        let res = dispatch(
+            CommandRequest::new_hmget("t1", vec!["u1".into(), "u2".into()]),
+            &store,
+        );
+        assert_res_ok(&res, &[Value::default(), "changed".into()], &[]);
+    }
+
+    #[test]
+    fn hmexistbits_should_work() {
+        let store = MemTable::new();
+        // 9 个 key 需要两个字节
+        let keys: Vec<String> = (0..9).map(|i| format!("k{}", i)).collect();
+        let present = [true, false, false, true, false, false, false, false, true];
+        let pairs: Vec<_> = keys
+            .iter()
+            .zip(present)
+            .filter(|(_, p)| *p)
+            .map(|(k, _)| (k.as_str(), "v"))
+            .collect();
+        set_key_pairs("t1", pairs, &store);
+
+        let res = dispatch(CommandRequest::new_hmexistbits("t1", keys), &store);
+        assert_res_ok(&res, &[(&[0b0000_1001, 0b0000_0001]).into()], &[]);
+        assert_eq!(res.exist_bits(9).unwrap(), present);
+    }
+
+    #[test]
+    fn hexpiretable_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        let res = dispatch(CommandRequest::new_hexpiretable("t1", 10), &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+
+        // 还没有过期
+        let res = dispatch(CommandRequest::new_hget("t1", "u1"), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn hgettouch_should_keep_key_alive() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        dispatch(CommandRequest::new_hexpiretable("t1", 10), &store);
+
+        // 每 6 秒读一次 u1，加起来已经超过了最初的 10 秒
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(6));
+            let res = dispatch(CommandRequest::new_hgettouch("t1", "u1", 10), &store);
+            assert_res_ok(&res, &["v1".into()], &[]);
+        }
+
+        // 没有被读过的 u2 已经过期了
+        let res = dispatch(CommandRequest::new_hgettouch("t1", "u2", 10), &store);
+        assert_res_error(&res, 404, "Not found");
+
+        // 不再读取之后 u1 也会过期
+        clock.advance(Duration::from_secs(11));
+        let res = dispatch(CommandRequest::new_hget("t1", "u1"), &store);
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn hpexpire_should_expire_in_milliseconds() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        set_key_pairs("locks", vec![("l1", "owner1")], &store);
+
+        let res = dispatch(CommandRequest::new_hpexpire("locks", "l1", 50), &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+        clock.advance(Duration::from_millis(49));
+        let res = dispatch(CommandRequest::new_hget("locks", "l1"), &store);
+        assert_res_ok(&res, &["owner1".into()], &[]);
+        clock.advance(Duration::from_millis(1));
+        let res = dispatch(CommandRequest::new_hget("locks", "l1"), &store);
+        assert_res_error(&res, 404, "Not found");
+
+        let res = dispatch(CommandRequest::new_hpexpire("locks", "none", 50), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+        let res = dispatch(CommandRequest::new_hpexpire("locks", "l1", 0), &store);
+        assert_res_error(&res, 400, "ttl_ms must be greater than 0");
+    }
+
+    #[test]
+    fn rotate_should_keep_previous_value_during_grace_period() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        let res = dispatch(CommandRequest::new_getgen("secrets", "api"), &store);
+        assert_res_ok(&res, &[0.into(), Value::default()], &[]);
+
+        // 第一次 Rotate 时 key 不存在，没有之前的 value
+        let res = dispatch(
+            CommandRequest::new_rotate("secrets", "api", "s1", 100),
+            &store,
+        );
+        assert_res_ok(&res, &[1.into(), Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_getgen("secrets", "api"), &store);
+        assert_res_ok(&res, &[1.into(), Value::default()], &[]);
+        let res = dispatch(
+            CommandRequest::new_rotate("secrets", "api", "s2", 100),
+            &store,
+        );
+        assert_res_ok(&res, &[2.into(), "s1".into()], &[]);
+
+        // 宽限期之内新旧两个 value 都可以读到
+        clock.advance(Duration::from_millis(50));
+        let res = dispatch(CommandRequest::new_hget("secrets", "api"), &store);
+        assert_res_ok(&res, &["s2".into()], &[]);
+        let res = dispatch(CommandRequest::new_getgen("secrets", "api"), &store);
+        assert_res_ok(&res, &[2.into(), "s1".into()], &[]);
+
+        // 宽限期过了之后只剩下新的 value，版本号不受影响
+        clock.advance(Duration::from_millis(50));
+        let res = dispatch(CommandRequest::new_getgen("secrets", "api"), &store);
+        assert_res_ok(&res, &[2.into(), Value::default()], &[]);
+
+        // 版本号和之前的 value 不占用 table 里的 key
+        let res = dispatch(CommandRequest::new_hgetall("secrets"), &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("api", "s2".into())]);
+
+        let res = dispatch(
+            CommandRequest::new_rotate("secrets", "api", "s3", 0),
+            &store,
+        );
+        assert_res_error(&res, 400, "grace_ms");
+    }
+
+    #[test]
+    fn hpsetex_should_set_value_with_millisecond_ttl() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        let res = dispatch(
+            CommandRequest::new_hpsetex("locks", "l1", "owner1", 50),
+            &store,
+        );
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = dispatch(
+            CommandRequest::new_hmgetttl("locks", vec!["l1".into()]),
+            &store,
+        );
+        // Hmgetttl 按秒返回剩余时间，不足一秒的按一秒算
+        assert_res_ok(&res, &["owner1".into(), 1.into()], &[]);
+
+        clock.advance(Duration::from_millis(30));
+        let res = dispatch(
+            CommandRequest::new_hpsetex("locks", "l1", "owner2", 50),
+            &store,
+        );
+        assert_res_ok(&res, &["owner1".into()], &[]);
+
+        // 重新写入之后从头开始计时
+        clock.advance(Duration::from_millis(40));
+        let res = dispatch(CommandRequest::new_hget("locks", "l1"), &store);
+        assert_res_ok(&res, &["owner2".into()], &[]);
+        clock.advance(Duration::from_millis(10));
+        let res = dispatch(CommandRequest::new_hget("locks", "l1"), &store);
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn hexpiregt_should_only_extend_ttl() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        set_key_pairs("leases", vec![("l1", "owner1"), ("l2", "owner2")], &store);
+        store
+            .expire("leases", "l1", Duration::from_secs(10))
+            .unwrap();
+
+        // 更长的 ttl 会延长过期时间
+        let res = dispatch(CommandRequest::new_hexpiregt("leases", "l1", 30), &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        // 更短的 ttl 被忽略
+        let res = dispatch(CommandRequest::new_hexpiregt("leases", "l1", 5), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        clock.advance(Duration::from_secs(20));
+        let res = dispatch(CommandRequest::new_hget("leases", "l1"), &store);
+        assert_res_ok(&res, &["owner1".into()], &[]);
+        clock.advance(Duration::from_secs(11));
+        let res = dispatch(CommandRequest::new_hget("leases", "l1"), &store);
+        assert_res_error(&res, 404, "Not found");
+
+        // 永不过期的 key 和不存在的 key 都不会被设置
+        let res = dispatch(CommandRequest::new_hexpiregt("leases", "l2", 30), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+        let res = dispatch(CommandRequest::new_hexpiregt("leases", "none", 30), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+    }
+
+    #[test]
+    fn htablehash_should_work() {
+        let store1 = MemTable::new();
+        let store2 = MemTable::new();
+        set_key_pairs(
+            "t1",
+            vec![("u1", "v1"), ("u2", "v2"), ("u3", "v3")],
+            &store1,
+        );
+        set_key_pairs(
+            "t1",
+            vec![("u3", "v3"), ("u1", "v1"), ("u2", "v2")],
+            &store2,
+        );
+
+        // 写入的顺序不同，哈希也相同
+        let res1 = dispatch(CommandRequest::new_htablehash("t1"), &store1);
+        let res2 = dispatch(CommandRequest::new_htablehash("t1"), &store2);
+        assert_eq!(res1.status, 200);
+        assert_eq!(res1, res2);
+
+        // 修改一个 value 之后哈希就不同了
+        set_key_pairs("t1", vec![("u2", "v22")], &store2);
+        let res2 = dispatch(CommandRequest::new_htablehash("t1"), &store2);
+        assert_ne!(res1, res2);
+
+        // 把 value 移到另一个 key 上，哈希也不同
+        let store3 = MemTable::new();
+        set_key_pairs(
+            "t1",
+            vec![("u1", "v2"), ("u2", "v1"), ("u3", "v3")],
+            &store3,
+        );
+        let res3 = dispatch(CommandRequest::new_htablehash("t1"), &store3);
+        assert_ne!(res1, res3);
+    }
+
+    #[test]
+    fn tablesizes_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t2", vec![("u1", "v1"), ("u2", "v2")], &store);
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        set_key_pairs("t3", vec![("u1", "v1"), ("u2", "v2"), ("u3", "v3")], &store);
+        // 读取不存在的 table 不会让它出现在结果里
+        dispatch(CommandRequest::new_hget("t4", "u1"), &store);
+
+        let res = dispatch(CommandRequest::new_tablesizes(), &store);
+        let expected = [
+            Kvpair::new("t1", 1.into()),
+            Kvpair::new("t2", 2.into()),
+            Kvpair::new("t3", 3.into()),
+        ];
+        assert_res_ok(&res, &[], &expected);
+    }
+
+    #[test]
+    fn hreapexpired_should_work() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        dispatch(CommandRequest::new_hexpiretable("t1", 10), &store);
+        set_key_pairs("t1", vec![("u3", "v3")], &store);
+
+        clock.advance(Duration::from_secs(11));
+        let res = dispatch(CommandRequest::new_hreapexpired("t1", false), &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_hreapexpired("t1", true), &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+
+        // 已经删除了，不会再统计到
+        let res = dispatch(CommandRequest::new_hreapexpired("t1", false), &store);
+        assert_res_ok(&res, &[0.into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetall("t1"), &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("u3", "v3".into())]);
+    }
+
+    #[test]
+    fn hvals_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u3", 3), ("u1", 1), ("u2", 2)], &store);
+        let res = dispatch(CommandRequest::new_hvals("t1"), &store);
+        assert_res_ok(&res, &[1.into(), 2.into(), 3.into()], &[]);
+    }
+
+    #[test]
+    fn hdelprefix_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2"), ("o1", "v3")], &store);
+        let res = dispatch(CommandRequest::new_hdelprefix("t1", "u", false), &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hgetall("t1"), &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("o1", "v3".into())]);
+    }
+
+    #[test]
+    fn hgetifchanged_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let hash = Value::from("v1").xxhash();
+
+        // 客户端还没有缓存时拿到 value 和它的哈希
+        let cmd = CommandRequest::new_hgetifchanged("t1", "u1", Bytes::new());
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &["v1".into(), Value::from(&hash)], &[]);
+
+        let cmd = CommandRequest::new_hgetifchanged("t1", "u1", hash.to_vec());
+        let res = dispatch(cmd.clone(), &store);
+        assert_eq!(res.status, 304);
+        assert!(res.values.is_empty());
+
+        set_key_pairs("t1", vec![("u1", "v2")], &store);
+        let res = dispatch(cmd, &store);
+        let new_hash = Value::from("v2").xxhash();
+        assert_res_ok(&res, &["v2".into(), Value::from(&new_hash)], &[]);
+
+        let cmd = CommandRequest::new_hgetifchanged("t1", "u2", hash.to_vec());
+        let res = dispatch(cmd, &store);
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn hweightedrand_should_prefer_heavier_keys() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("w", "light", 1.into()), &store);
+        dispatch(CommandRequest::new_hset("w", "heavy", 9.into()), &store);
+        dispatch(CommandRequest::new_hset("w", "zero", 0.into()), &store);
+
+        // 概率分别是 10% 和 90%，2000 次之后 heavy 的次数几乎不可能少于 light 的 4 倍
+        let (mut light, mut heavy) = (0, 0);
+        for _ in 0..2000 {
+            let res = dispatch(CommandRequest::new_hweightedrand("w", 1), &store);
+            assert_eq!(res.pairs.len(), 1);
+            match res.pairs[0].key.as_str() {
+                "light" => light += 1,
+                "heavy" => heavy += 1,
+                key => panic!("unexpected key {}", key),
+            }
+        }
+        assert!(heavy > light * 4, "light {}, heavy {}", light, heavy);
+
+        // n 比可以选的 key 多时返回所有权重为正数的 key
+        let res = dispatch(CommandRequest::new_hweightedrand("w", 5), &store);
+        assert_eq!(res.pairs.len(), 2);
+
+        dispatch(CommandRequest::new_hset("w", "name", "v".into()), &store);
+        let res = dispatch(CommandRequest::new_hweightedrand("w", 1), &store);
+        assert_res_error(&res, 400, "not a number");
+    }
+
+    #[test]
+    fn hmgetsnapshot_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+        set_key_pairs("t2", vec![("k2", "v2")], &store);
+        let cmd = CommandRequest::new_hmgetsnapshot([("t1", "k1"), ("t2", "k2"), ("t2", "none")]);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &["v1".into(), "v2".into(), Value::default()], &[]);
+
+        let reads = (0..=MAX_SNAPSHOT_READS).map(|i| ("t1", format!("k{}", i)));
+        let res = dispatch(CommandRequest::new_hmgetsnapshot(reads), &store);
+        assert_res_error(&res, 400, "at most");
+    }
+
+    #[test]
+    fn hagg_should_work() {
+        let store = MemTable::new();
+        set_key_pairs(
+            "scores",
+            vec![("a", 90), ("b", 60), ("c", 75), ("d", 85)],
+            &store,
+        );
+        let agg = |table: &str, op| dispatch(CommandRequest::new_hagg(table, op), &store);
+
+        assert_res_ok(&agg("scores", AggOp::Sum), &[310.into()], &[]);
+        assert_res_ok(&agg("scores", AggOp::Min), &[60.into()], &[]);
+        assert_res_ok(&agg("scores", AggOp::Max), &[90.into()], &[]);
+        assert_res_ok(&agg("scores", AggOp::Avg), &[77.5.into()], &[]);
+        assert_res_ok(&agg("scores", AggOp::Count), &[4.into()], &[]);
+
+        // 有浮点数时结果是浮点数
+        store.set("scores", "e", 9.5).unwrap();
+        assert_res_ok(&agg("scores", AggOp::Sum), &[319.5.into()], &[]);
+        assert_res_ok(&agg("scores", AggOp::Min), &[9.5.into()], &[]);
+
+        // 空的 table
+        assert_res_ok(&agg("empty", AggOp::Sum), &[0.into()], &[]);
+        assert_res_ok(&agg("empty", AggOp::Count), &[0.into()], &[]);
+        assert_res_error(&agg("empty", AggOp::Avg), 404, "Not found");
+
+        store.set("scores", "f", "n/a").unwrap();
+        assert_res_error(&agg("scores", AggOp::Max), 400, "key f is not a number");
+    }
+
+    #[test]
+    fn hquantiles_should_be_within_tolerance() {
+        let store = MemTable::new();
+        let pairs = (1..=1000)
+            .map(|i| Kvpair::new(format!("k{}", i), (i as i64).into()))
+            .collect();
+        dispatch(CommandRequest::new_hmset("latency", pairs), &store);
+
+        let res = dispatch(
+            CommandRequest::new_hquantiles("latency", vec![0.5, 0.9, 0.99]),
+            &store,
+        );
+        assert_eq!(res.status, 200);
+        let expected = [500.0, 900.0, 990.0];
+        for (v, expected) in res.values.iter().zip(expected) {
+            let v = match v.value {
+                Some(value::Value::Float(f)) => f,
+                _ => panic!("expected float, got {:?}", v),
+            };
+            assert!((v - expected).abs() < 10.0, "{} vs {}", v, expected);
+        }
+
+        let res = dispatch(CommandRequest::new_hquantiles("latency", vec![1.5]), &store);
+        assert_res_error(&res, 400, "not in [0, 1]");
+        let res = dispatch(CommandRequest::new_hquantiles("empty", vec![0.5]), &store);
+        assert_res_error(&res, 404, "Not found");
+        dispatch(
+            CommandRequest::new_hset("latency", "name", "v".into()),
+            &store,
+        );
+        let res = dispatch(CommandRequest::new_hquantiles("latency", vec![0.5]), &store);
+        assert_res_error(&res, 400, "not a number");
+    }
+
+    #[test]
+    fn hmatchhash_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let hash = Value::from("v1").xxhash();
+        let res = dispatch(
+            CommandRequest::new_hmatchhash("t1", "u1", hash.to_vec()),
+            &store,
+        );
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        // value 变了之后客户端的哈希就对不上了
+        set_key_pairs("t1", vec![("u1", "v2")], &store);
+        let res = dispatch(
+            CommandRequest::new_hmatchhash("t1", "u1", hash.to_vec()),
+            &store,
+        );
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        let res = dispatch(
+            CommandRequest::new_hmatchhash("t1", "u2", hash.to_vec()),
+            &store,
+        );
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn hkeyspage_should_work() {
+        let store = MemTable::new();
+        let keys: Vec<_> = (0..5).map(|i| format!("k{}", i)).collect();
+        // 乱序写入
+        for key in keys.iter().rev() {
+            store.set("t1", key.as_str(), "v").unwrap();
+        }
+
+        let mut all = Vec::new();
+        let mut offset = 0;
+        loop {
+            let res = dispatch(CommandRequest::new_hkeyspage("t1", offset, 2), &store);
+            assert_eq!(res.status, 200);
+            offset += res.values.len() as u64;
+            all.extend(res.values);
+            if !res.has_more {
+                break;
+            }
+        }
+        let expected: Vec<Value> = keys.into_iter().map(|k| k.into()).collect();
+        assert_eq!(all, expected);
+
+        let res = dispatch(CommandRequest::new_hkeyspage("t1", 10, 2), &store);
+        assert_res_ok(&res, &[], &[]);
+        assert!(!res.has_more);
+    }
+
+    #[test]
+    fn hdrain_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        let res = dispatch(CommandRequest::new_hdrain("t1"), &store);
+        let pairs = &[
+            Kvpair::new("u1", "v1".into()),
+            Kvpair::new("u2", "v2".into()),
+        ];
+        assert_res_ok(&res, &[], pairs);
+
+        let res = dispatch(CommandRequest::new_hgetall("t1"), &store);
+        assert_res_ok(&res, &[], &[]);
+    }
+
+    #[test]
+    fn hpatch_should_work() {
+        let store = MemTable::new();
+        set_key_pairs(
+            "t1",
+            vec![("u1", r#"{"name":"tyr","age":10,"tmp":true}"#)],
+            &store,
+        );
+        // 修改 age，删除 tmp，增加 city
+        let patch = r#"{"age":11,"tmp":null,"city":"beijing"}"#;
+        let res = dispatch(CommandRequest::new_hpatch("t1", "u1", patch), &store);
+        assert_eq!(res.status, 200);
+
+        let v = store.get("t1", "u1").unwrap().unwrap();
+        let expected = serde_json::json!({"name": "tyr", "age": 11, "city": "beijing"});
+        assert_eq!(parse_json(&v).unwrap(), expected);
+    }
+
+    #[test]
+    fn hpatch_with_non_json_value_should_fail() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "not json")], &store);
+        let res = dispatch(CommandRequest::new_hpatch("t1", "u1", r#"{"a":1}"#), &store);
+        assert_res_error(&res, 400, "is not valid JSON");
+
+        let res = dispatch(CommandRequest::new_hpatch("t1", "u1", "{"), &store);
+        assert_res_error(&res, 400, "Invalid JSON patch");
+    }
+
+    #[test]
+    fn holdest_and_hnewest_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u3", "v1"), ("u1", "v2"), ("u2", "v3")], &store);
+
+        // assert_res_ok 会把 pairs 排序，这里直接比较
+        let res = dispatch(CommandRequest::new_holdest("t1", 2), &store);
+        let expected = vec![
+            Kvpair::new("u3", "v1".into()),
+            Kvpair::new("u1", "v2".into()),
+        ];
+        assert_eq!(res.pairs, expected);
+
+        let res = dispatch(CommandRequest::new_hnewest("t1", 2), &store);
+        let expected = vec![
+            Kvpair::new("u2", "v3".into()),
+            Kvpair::new("u1", "v2".into()),
+        ];
+        assert_eq!(res.pairs, expected);
+    }
+
+    #[test]
+    fn backendinfo_should_work() {
+        let keys = |res: CommandResponse| {
+            assert_eq!(res.status, 200);
+            res.pairs.into_iter().map(|p| p.key).collect::<Vec<_>>()
+        };
+
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        let res = dispatch(CommandRequest::new_backendinfo(), &store);
+        assert!(res.pairs.contains(&Kvpair::new("entries", 2.into())));
+        assert_eq!(
+            keys(res),
+            ["backend", "tables", "entries", "estimated_bytes"]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledDB::new(&dir);
+        let res = dispatch(CommandRequest::new_backendinfo(), &store);
+        assert_eq!(keys(res), ["backend", "size_on_disk", "recovered", "trees"]);
+    }
+
+    #[test]
+    fn hrotate_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("cfg", "v1")], &store);
+        let res = dispatch(
+            CommandRequest::new_hrotate("t1", "cfg", "v2".into(), 3),
+            &store,
+        );
+        assert_res_ok(&res, &["v1".into()], &[]);
+        dispatch(
+            CommandRequest::new_hrotate("t1", "cfg", "v3".into(), 3),
+            &store,
+        );
+        dispatch(
+            CommandRequest::new_hrotate("t1", "cfg", "v4".into(), 3),
+            &store,
+        );
+
+        // 最多保留 3 个，最早的 v1 被丢弃
+        let res = dispatch(CommandRequest::new_hhistory("t1", "cfg"), &store);
+        assert_res_ok(&res, &["v4".into(), "v3".into(), "v2".into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hhistory("t1", "none"), &store);
+        assert_res_error(&res, 404, "Not found");
+
+        // 原来的过期时间被保留
+        store.expire("t1", "cfg", Duration::from_secs(10)).unwrap();
+        dispatch(
+            CommandRequest::new_hrotate("t1", "cfg", "v5".into(), 3),
+            &store,
+        );
+        let res = dispatch(
+            CommandRequest::new_hmgetttl("t1", vec!["cfg".into()]),
+            &store,
+        );
+        assert_eq!(res.values[1], 10.into());
+    }
+
+    #[test]
+    fn hgethot_should_count_reads_and_rank_hot_keys() {
+        let store = MemTable::new();
+        set_key_pairs("pages", vec![("a", "pa"), ("b", "pb"), ("c", "pc")], &store);
+        for (key, reads) in [("a", 2), ("b", 5), ("c", 2)] {
+            for _ in 0..reads {
+                let res = dispatch(CommandRequest::new_hgethot("pages", key), &store);
+                assert_res_ok(&res, &[format!("p{}", key).into()], &[]);
+            }
+        }
+        // 不存在的 key 不计数，普通的 Hget 也不计数
+        let res = dispatch(CommandRequest::new_hgethot("pages", "none"), &store);
+        assert_res_error(&res, 404, "Not found");
+        dispatch(CommandRequest::new_hget("pages", "c"), &store);
+
+        // assert_res_ok 会把 pairs 排序，这里要检查顺序，直接比较
+        let res = dispatch(CommandRequest::new_hhotkeys("pages", 2), &store);
+        let pairs = [Kvpair::new("b", 5.into()), Kvpair::new("a", 2.into())];
+        assert_eq!(res.pairs, pairs);
+
+        dispatch(CommandRequest::new_hgethot("pages", "c"), &store);
+        let res = dispatch(CommandRequest::new_hhotkeys("pages", 10), &store);
+        let pairs = [
+            Kvpair::new("b", 5.into()),
+            Kvpair::new("c", 3.into()),
+            Kvpair::new("a", 2.into()),
+        ];
+        assert_eq!(res.pairs, pairs);
+
+        // 访问次数保存在内部的 table 中，客户端看不到也不能直接修改
+        assert_eq!(store.table_names().unwrap(), ["pages"]);
+        let res = dispatch(CommandRequest::new_hgetall("__hits:pages"), &store);
+        assert_res_error(&res, 400, "Table __hits:pages is reserved");
+        let res = dispatch(
+            CommandRequest::new_hset("__hits:pages", "a", 0.into()),
+            &store,
+        );
+        assert_res_error(&res, 400, "Table __hits:pages is reserved");
+    }
+
+    #[test]
+    fn lpushcap_should_work() {
+        let store = MemTable::new();
+        for (i, expected) in [1, 2, 3, 3, 3].into_iter().enumerate() {
+            let cmd = CommandRequest::new_lpushcap("logs", "l1", format!("e{}", i), 3);
+            let res = dispatch(cmd, &store);
+            assert_res_ok(&res, &[expected.into()], &[]);
+        }
+
+        // 最多保留 3 个，最早的 e0、e1 被丢弃
+        let res = dispatch(CommandRequest::new_hget("logs", "l1"), &store);
+        let expected = Value::new_list(["e4".into(), "e3".into(), "e2".into()]);
+        assert_res_ok(&res, &[expected], &[]);
+
+        // 原来的过期时间被保留
+        store.expire("logs", "l1", Duration::from_secs(10)).unwrap();
+        dispatch(CommandRequest::new_lpushcap("logs", "l1", "e5", 3), &store);
+        let res = dispatch(
+            CommandRequest::new_hmgetttl("logs", vec!["l1".into()]),
+            &store,
+        );
+        assert_eq!(res.values[1], 10.into());
+    }
+
+    #[test]
+    fn lpushcap_on_non_list_should_fail() {
+        let store = MemTable::new();
+        set_key_pairs("logs", vec![("l1", "v1")], &store);
+        let res = dispatch(CommandRequest::new_lpushcap("logs", "l1", "e1", 3), &store);
+        assert_res_error(&res, 500, "Cannot convert value");
+
+        let res = dispatch(CommandRequest::new_lpushcap("logs", "l2", "e1", 0), &store);
+        assert_res_error(&res, 400, "max must be greater than 0");
+    }
+
+    #[test]
+    fn sunionstore_and_sinterstore_should_work() {
+        let store = MemTable::new();
+        let set = |vs: &[&str]| Value::new_set(vs.iter().map(|&v| v.into()));
+        store.set("s", "a", set(&["x", "y", "z"])).unwrap();
+        store.set("s", "b", set(&["y", "z", "w"])).unwrap();
+        let keys = vec!["a".to_string(), "b".to_string()];
+
+        let res = dispatch(
+            CommandRequest::new_sunionstore("s", "u", keys.clone()),
+            &store,
+        );
+        assert_res_ok(&res, &[4.into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("s", "u"), &store);
+        assert_res_ok(&res, &[set(&["x", "y", "z", "w"])], &[]);
+
+        let res = dispatch(CommandRequest::new_sinterstore("s", "i", keys), &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("s", "i"), &store);
+        assert_res_ok(&res, &[set(&["y", "z"])], &[]);
+
+        // 不存在的 key 当作空集合，交集为空时删除 dest
+        let keys = vec!["a".to_string(), "none".to_string()];
+        let res = dispatch(CommandRequest::new_sinterstore("s", "i", keys), &store);
+        assert_res_ok(&res, &[0.into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("s", "i"), &store);
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn set_store_on_non_set_should_fail() {
+        let store = MemTable::new();
+        set_key_pairs("s", vec![("a", "v1")], &store);
+        let res = dispatch(
+            CommandRequest::new_sunionstore("s", "u", vec!["a".into()]),
+            &store,
+        );
+        assert_res_error(&res, 500, "Cannot convert value");
+
+        let res = dispatch(CommandRequest::new_sinterstore("s", "u", vec![]), &store);
+        assert_res_error(&res, 400, "keys must not be empty");
+    }
+
+    #[test]
+    fn pfadd_and_pfcount_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_pfcount("hll", "visitors"), &store);
+        assert_res_ok(&res, &[0.into()], &[]);
+
+        for chunk in 0..10 {
+            let elements = (0..1000)
+                .map(|i| format!("user{}", chunk * 1000 + i))
+                .collect();
+            let res = dispatch(
+                CommandRequest::new_pfadd("hll", "visitors", elements),
+                &store,
+            );
+            assert_res_ok(&res, &[true.into()], &[]);
+        }
+
+        // 重复的元素不会改变 HyperLogLog
+        let res = dispatch(
+            CommandRequest::new_pfadd("hll", "visitors", vec!["user0".into()]),
+            &store,
+        );
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        // 标准误差约 1.6%，允许 3 倍的误差
+        let res = dispatch(CommandRequest::new_pfcount("hll", "visitors"), &store);
+        let count = i64::try_from(&res.values[0]).unwrap();
+        assert!((9500..=10500).contains(&count), "count: {}", count);
+    }
+
+    #[test]
+    fn pfadd_on_non_hyperloglog_should_fail() {
+        let store = MemTable::new();
+        set_key_pairs("hll", vec![("k1", "v1")], &store);
+        let res = dispatch(
+            CommandRequest::new_pfadd("hll", "k1", vec!["a".into()]),
+            &store,
+        );
+        assert_res_error(&res, 500, "Cannot convert value");
+        let res = dispatch(CommandRequest::new_pfcount("hll", "k1"), &store);
+        assert_res_error(&res, 500, "Cannot convert value");
+    }
+
+    #[test]
+    fn setbit_and_getbit_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_setbit("flags", "f1", 100, true), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        // bitmap 增长到 13 个字节，第 100 位是第 12 个字节的第 4 位
+        let mut expected = vec![0u8; 13];
+        expected[12] = 0x08;
+        let res = dispatch(CommandRequest::new_hget("flags", "f1"), &store);
+        assert_res_ok(&res, &[Bytes::from(expected).into()], &[]);
+
+        let res = dispatch(CommandRequest::new_getbit("flags", "f1", 100), &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_getbit("flags", "f1", 99), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+        let res = dispatch(CommandRequest::new_getbit("flags", "f1", 1000), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        // 清除之后返回之前的值
+        let res = dispatch(
+            CommandRequest::new_setbit("flags", "f1", 100, false),
+            &store,
+        );
+        assert_res_ok(&res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_getbit("flags", "f1", 100), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+    }
+
+    #[test]
+    fn setbit_on_invalid_input_should_fail() {
+        let store = MemTable::new();
+        set_key_pairs("flags", vec![("f1", "v1")], &store);
+        let res = dispatch(CommandRequest::new_setbit("flags", "f1", 1, true), &store);
+        assert_res_error(&res, 500, "Cannot convert value");
+
+        let res = dispatch(
+            CommandRequest::new_setbit("flags", "f2", u32::MAX, true),
+            &store,
+        );
+        assert_res_error(&res, 400, "offset must be less than");
+    }
+
+    #[test]
+    fn lpopn_should_work() {
+        let store = MemTable::new();
+        let list = Value::new_list(["a".into(), "b".into(), "c".into()]);
+        store.set("q", "jobs", list).unwrap();
+
+        let res = dispatch(CommandRequest::new_lpopn("q", "jobs", 2), &store);
+        assert_res_ok(&res, &["a".into(), "b".into()], &[]);
+
+        // 不够的时候有多少取多少，取空之后 key 被删除
+        let res = dispatch(CommandRequest::new_lpopn("q", "jobs", 2), &store);
+        assert_res_ok(&res, &["c".into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("q", "jobs"), &store);
+        assert_res_error(&res, 404, "Not found");
+
+        let res = dispatch(CommandRequest::new_lpopn("q", "jobs", 2), &store);
+        assert_res_ok(&res, &[], &[]);
+        let res = dispatch(CommandRequest::new_lpopn("q", "jobs", 0), &store);
+        assert_res_error(&res, 400, "count must be greater than 0");
+    }
+
+    #[test]
+    fn lcompact_should_keep_list_elements() {
+        let store = MemTable::new();
+        for i in 0..200 {
+            dispatch(CommandRequest::new_lpushcap("q", "jobs", i, 100), &store);
+        }
+        dispatch(CommandRequest::new_lpopn("q", "jobs", 90), &store);
+        let before = dispatch(CommandRequest::new_hget("q", "jobs"), &store);
+
+        let res = dispatch(CommandRequest::new_lcompact("q", "jobs"), &store);
+        assert_res_ok(&res, &[10.into()], &[]);
+        let after = dispatch(CommandRequest::new_hget("q", "jobs"), &store);
+        assert_eq!(after.values, before.values);
+
+        let res = dispatch(CommandRequest::new_lcompact("q", "none"), &store);
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[test]
+    fn lpopn_consumers_should_not_share_items() {
+        let store = MemTable::new();
+        let items: Vec<Value> = (0..100).map(|i| i.into()).collect();
+        store.set("q", "jobs", Value::new_list(items)).unwrap();
+
+        let consume = || {
+            let mut consumed = vec![];
+            loop {
+                let res = dispatch(CommandRequest::new_lpopn("q", "jobs", 3), &store);
+                if res.values.is_empty() {
+                    return consumed;
+                }
+                consumed.extend(res.values.iter().map(|v| i64::try_from(v).unwrap()));
+            }
+        };
+        let mut consumed = std::thread::scope(|s| {
+            let c1 = s.spawn(consume);
+            let c2 = s.spawn(consume);
+            let mut all = c1.join().unwrap();
+            all.extend(c2.join().unwrap());
+            all
+        });
+
+        consumed.sort();
+        assert_eq!(consumed, (0..100).collect::<Vec<i64>>());
+    }
+
+    #[test]
+    fn lockmany_should_work() {
+        let store = MemTable::new();
+        let keys = vec!["l1".to_string(), "l2".to_string()];
+        let res = dispatch(
+            CommandRequest::new_lockmany("locks", keys.clone(), "a", 10),
+            &store,
+        );
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hmget("locks", keys), &store);
+        assert_res_ok(&res, &["a".into(), "a".into()], &[]);
+    }
+
+    #[test]
+    fn lockmany_with_conflict_should_not_lock_any_key() {
+        let store = MemTable::new();
+        set_key_pairs("locks", vec![("l2", "a")], &store);
+        let keys = vec!["l1".to_string(), "l2".to_string(), "l3".to_string()];
+        let res = dispatch(CommandRequest::new_lockmany("locks", keys, "b", 10), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        // l1、l3 没有被锁住，l2 还是 a 的
+        let res = dispatch(CommandRequest::new_hgetall("locks"), &store);
+        assert_res_ok(&res, &[], &[Kvpair::new("l2", "a".into())]);
+    }
+
+    #[test]
+    fn lease_should_be_acquired_and_renewed_by_owner() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        let res = dispatch(
+            CommandRequest::new_lease("leases", "leader", "a", 10),
+            &store,
+        );
+        assert_res_ok(&res, &[true.into(), "a".into()], &[]);
+
+        // 持有者续约之后过期时间重新计算
+        clock.advance(Duration::from_secs(8));
+        let res = dispatch(
+            CommandRequest::new_lease("leases", "leader", "a", 10),
+            &store,
+        );
+        assert_res_ok(&res, &[true.into(), "a".into()], &[]);
+
+        clock.advance(Duration::from_secs(8));
+        let res = dispatch(CommandRequest::new_hget("leases", "leader"), &store);
+        assert_res_ok(&res, &["a".into()], &[]);
+    }
+
+    #[test]
+    fn lease_held_by_other_should_be_rejected_until_expired() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        dispatch(
+            CommandRequest::new_lease("leases", "leader", "a", 10),
+            &store,
+        );
+
+        let res = dispatch(
+            CommandRequest::new_lease("leases", "leader", "b", 10),
+            &store,
+        );
+        assert_res_ok(&res, &[false.into(), "a".into()], &[]);
+
+        // a 没有续约，过期之后 b 可以获得租约
+        clock.advance(Duration::from_secs(11));
+        let res = dispatch(
+            CommandRequest::new_lease("leases", "leader", "b", 10),
+            &store,
+        );
+        assert_res_ok(&res, &[true.into(), "b".into()], &[]);
     }
 
     fn set_key_pairs(table: &str, pairs: Vec<(&str, impl Into<Value>)>, store: &impl Storage) {
@@ -0,0 +1,104 @@
+use dashmap::DashMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, OnceLock,
+};
+use std::time::{Duration, Instant};
+
+/// 结束的任务保留多久，过了这段时间之后就查不到它的进度了
+const FINISHED_JOB_RETENTION: Duration = Duration::from_secs(600);
+
+/// 一个后台任务的进度
+#[derive(Debug, Default)]
+pub struct JobProgress {
+    processed: AtomicU64,
+    finished_at: OnceLock<Instant>,
+}
+
+impl JobProgress {
+    /// 已经处理了多少个 key
+    pub fn processed(&self) -> u64 {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    /// 任务是否已经结束
+    pub fn is_done(&self) -> bool {
+        self.finished_at.get().is_some()
+    }
+
+    pub(crate) fn add(&self, n: u64) {
+        self.processed.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn finish(&self) {
+        let _ = self.finished_at.set(Instant::now());
+    }
+
+    /// 任务结束的时间超过了 retention
+    fn expired(&self, retention: Duration) -> bool {
+        self.finished_at
+            .get()
+            .is_some_and(|t| t.elapsed() >= retention)
+    }
+}
+
+/// 记录所有后台任务的进度，任务 id 从 1 开始递增
+///
+/// 结束的任务保留 FINISHED_JOB_RETENTION，之后在创建新任务时被删除
+#[derive(Debug)]
+pub struct Jobs {
+    last_id: AtomicU64,
+    jobs: DashMap<u64, Arc<JobProgress>>,
+    retention: Duration,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self::with_retention(FINISHED_JOB_RETENTION)
+    }
+}
+
+impl Jobs {
+    /// 结束的任务保留 retention 之后删除
+    pub fn with_retention(retention: Duration) -> Self {
+        Self {
+            last_id: Default::default(),
+            jobs: Default::default(),
+            retention,
+        }
+    }
+
+    /// 创建一个新任务，返回它的 id 和进度；同时删除结束太久的任务
+    pub fn create(&self) -> (u64, Arc<JobProgress>) {
+        self.jobs.retain(|_id, job| !job.expired(self.retention));
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let progress = Arc::new(JobProgress::default());
+        self.jobs.insert(id, progress.clone());
+        (id, progress)
+    }
+
+    /// 获取任务的进度
+    pub fn get(&self, id: u64) -> Option<Arc<JobProgress>> {
+        self.jobs.get(&id).map(|v| v.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finished_jobs_should_be_pruned() {
+        let jobs = Jobs::with_retention(Duration::ZERO);
+        let (id1, job1) = jobs.create();
+        let (id2, _job2) = jobs.create();
+        job1.finish();
+        assert!(jobs.get(id1).unwrap().is_done());
+
+        // 创建新任务时删除结束了的任务，还在运行的任务不受影响
+        let (id3, _job3) = jobs.create();
+        assert!(jobs.get(id1).is_none());
+        assert!(!jobs.get(id2).unwrap().is_done());
+        assert_eq!(id3, 3);
+    }
+}
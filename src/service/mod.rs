@@ -1,16 +1,41 @@
 use crate::{
-    command_request::RequestData, CommandRequest, CommandResponse, KvError, MemTable, Storage,
+    command_request::RequestData, is_reserved_table, CommandRequest, CommandResponse, Hdelprefix,
+    Hsetpub, KvError, Kvpair, MemTable, Storage, Value,
 };
-use futures::stream;
-use std::sync::Arc;
-use tracing::debug;
+use futures::{stream, StreamExt};
+use http::StatusCode;
+use prost::Message;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
 
+mod aggregate;
+mod cache;
 mod command_service;
+mod hll;
+mod job;
+mod sample;
+mod script;
+mod slowest;
+mod statsd;
+mod tdigest;
+mod tenant;
 mod topic;
 mod topic_service;
+mod validator;
+mod waiter;
 
+pub use cache::{CacheConfig, ResultCache};
+pub use job::{JobProgress, Jobs};
+pub use slowest::SlowestLog;
+pub use statsd::StatsdExporter;
+pub use tenant::TenantServices;
 pub use topic::{Broadcaster, Topic};
 pub use topic_service::{StreamingResponse, TopicService};
+pub use validator::{Validator, Validators};
+pub use waiter::KeyWaiters;
 
 /// 对 Command 的处理的抽象
 pub trait CommandService {
@@ -46,10 +71,20 @@ impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
     }
 }
 
+/// 后台删除时每一批删除多少个 key，删完一批之后让出执行权
+const DELETE_CHUNK_SIZE: usize = 1000;
+
+/// 保存见过的最大的 fencing token 的 table，重启之后旧的 leader 也不能写入
+const FENCING_TABLE: &str = "__fencing";
+const FENCING_KEY: &str = "token";
+
 /// Service 数据结构
 pub struct Service<Store = MemTable> {
     inner: Arc<ServiceInner<Store>>,
     broadcaster: Arc<Broadcaster>,
+    jobs: Arc<Jobs>,
+    validators: Arc<Validators>,
+    waiters: Arc<KeyWaiters>,
 }
 
 impl<Store> Clone for Service<Store> {
@@ -57,17 +92,40 @@ impl<Store> Clone for Service<Store> {
         Self {
             inner: Arc::clone(&self.inner),
             broadcaster: Arc::clone(&self.broadcaster),
+            jobs: Arc::clone(&self.jobs),
+            validators: Arc::clone(&self.validators),
+            waiters: Arc::clone(&self.waiters),
         }
     }
 }
 
+/// 执行命令之前的检查，返回 Err 时命令会被拒绝
+pub type PreExecuteHook = fn(&CommandRequest) -> Result<(), KvError>;
+
 /// Service 内部数据结构
 pub struct ServiceInner<Store> {
     store: Store,
     on_received: Vec<fn(&CommandRequest)>,
+    on_pre_execute: Vec<PreExecuteHook>,
     on_executed: Vec<fn(&CommandResponse)>,
     on_before_send: Vec<fn(&mut CommandResponse)>,
     on_after_send: Vec<fn()>,
+    cache: Option<ResultCache>,
+    slowest: Option<SlowestLog>,
+    statsd: Option<StatsdExporter>,
+    max_pairs_per_frame: Option<usize>,
+    max_response_size: Option<usize>,
+    /// 被禁用的命令的名字，见 CommandRequest::name()
+    disabled_commands: HashSet<String>,
+    /// 见过的最大的 fencing token，只是 FENCING_TABLE 中的 token 的缓存，
+    /// 比它小的 token 不用读存储就可以拒绝
+    fencing_token: AtomicU64,
+    /// 带 fencing token 的写操作在这个锁里检查 token 并写入，检查通过之后
+    /// 不会被更大的 token 抢先写入。token 是所有 table 共用的，所以只有一把锁
+    fence: Mutex<()>,
+    /// 版本号，每个成功的写操作加一
+    version: AtomicU64,
+    started: Instant,
 }
 
 impl<Store: Storage> ServiceInner<Store> {
@@ -75,9 +133,20 @@ impl<Store: Storage> ServiceInner<Store> {
         Self {
             store,
             on_received: Vec::new(),
+            on_pre_execute: Vec::new(),
             on_executed: Vec::new(),
             on_before_send: Vec::new(),
             on_after_send: Vec::new(),
+            cache: None,
+            slowest: None,
+            statsd: None,
+            max_pairs_per_frame: None,
+            max_response_size: None,
+            disabled_commands: HashSet::new(),
+            fencing_token: AtomicU64::new(0),
+            fence: Mutex::new(()),
+            version: AtomicU64::new(0),
+            started: Instant::now(),
         }
     }
 
@@ -86,6 +155,12 @@ impl<Store: Storage> ServiceInner<Store> {
         self
     }
 
+    /// 注册一个在执行命令之前调用的检查，返回 Err 时命令不会被执行，错误直接返回给客户端
+    pub fn fn_pre_execute(mut self, f: PreExecuteHook) -> Self {
+        self.on_pre_execute.push(f);
+        self
+    }
+
     pub fn fn_executed(mut self, f: fn(&CommandResponse)) -> Self {
         self.on_executed.push(f);
         self
@@ -100,6 +175,51 @@ impl<Store: Storage> ServiceInner<Store> {
         self.on_after_send.push(f);
         self
     }
+
+    /// 记录启动以来最慢的 capacity 个命令，可以用 Slowest 命令查询
+    pub fn with_slowest(mut self, capacity: usize) -> Self {
+        self.slowest = Some(SlowestLog::new(capacity));
+        self
+    }
+
+    /// 把每个命令的次数、错误数和耗时发送给 statsd
+    pub fn with_statsd(mut self, exporter: StatsdExporter) -> Self {
+        self.statsd = Some(exporter);
+        self
+    }
+
+    /// 一个响应的 pair 超过 n 个时拆成多个 frame 发送，避免一次分配过大的内存
+    pub fn with_max_pairs_per_frame(mut self, n: usize) -> Self {
+        self.max_pairs_per_frame = Some(n);
+        self
+    }
+
+    /// 读命令的响应 encode 之后超过 n 字节时返回 ResponseTooLarge，提示客户端分页读取
+    ///
+    /// 写命令不受限制：它们已经修改了数据，不能用一个错误代替执行的结果
+    pub fn with_max_response_size(mut self, n: usize) -> Self {
+        self.max_response_size = Some(n);
+        self
+    }
+
+    /// 禁用这些命令，比如 hdrain、hreplace，收到它们时返回 CommandDisabled，缺省所有的命令都可用
+    ///
+    /// 命令的名字和 CommandRequest::name() 一样，不区分大小写
+    pub fn with_disabled_commands<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let names = names.into_iter().map(|v| v.as_ref().to_lowercase());
+        self.disabled_commands.extend(names);
+        self
+    }
+
+    /// 打开结果缓存
+    pub fn with_cache(mut self, config: CacheConfig) -> Self {
+        self.cache = Some(ResultCache::new(config));
+        self
+    }
 }
 
 impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
@@ -107,34 +227,400 @@ impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
         Self {
             inner: Arc::new(inner),
             broadcaster: Default::default(),
+            jobs: Default::default(),
+            validators: Default::default(),
+            waiters: Default::default(),
         }
     }
 }
 
-impl<Store: Storage> Service<Store> {
+impl<Store: Storage + Send + Sync + 'static> Service<Store> {
     pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
         debug!("Got request: {:?}", cmd);
+        let start = Instant::now();
         self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd.clone(), &self.inner.store);
+        let checked = self
+            .inner
+            .check_enabled(&cmd)
+            .and_then(|_| check_reserved(&cmd))
+            .and_then(|_| self.inner.on_pre_execute.iter().try_for_each(|f| f(&cmd)))
+            .and_then(|_| self.validators.check(&cmd))
+            .and_then(|_| self.inner.check_min_version(&cmd));
+
+        if let (Ok(_), Some(RequestData::Hsetpub(param))) = (&checked, &cmd.request_data) {
+            let service = self.clone();
+            let param = param.clone();
+            let res = async move {
+                let res = service.set_and_publish(&cmd, param).await;
+                service.respond(&cmd, res, start)
+            };
+            return Box::pin(stream::once(res).flatten());
+        }
+
+        if let (Ok(_), Some(RequestData::Hgetall(param))) = (&checked, &cmd.request_data) {
+            if let Some(res) = self.stream_all(&cmd, &param.table, start) {
+                return res;
+            }
+        }
+
+        if let (Ok(_), Some(RequestData::Hgetblock(param))) = (&checked, &cmd.request_data) {
+            if param.timeout_ms > 0 {
+                let service = self.clone();
+                let (table, timeout_ms) = (param.table.clone(), param.timeout_ms);
+                let res = async move {
+                    let res = service.get_blocking(&cmd, &table, timeout_ms).await;
+                    let res = service.inner.limit_size(&cmd, res);
+                    service.respond(&cmd, res, start)
+                };
+                return Box::pin(stream::once(res).flatten());
+            }
+        }
+
+        let res = match checked {
+            Ok(_) => self.inner.limit_size(&cmd, self.execute_unary(cmd.clone())),
+            Err(e) => e.into(),
+        };
 
         if res == CommandResponse::default() {
             dispatch_stream(cmd, Arc::clone(&self.broadcaster))
         } else {
-            debug!("Executed response: {:?}", res);
-            self.inner.on_executed.notify(&res);
-            self.inner.on_before_send.notify(&mut res);
-            if !self.inner.on_before_send.is_empty() {
-                debug!("Modified response: {:?}", res);
+            self.respond(&cmd, res, start)
+        }
+    }
+
+    /// 记录命令的执行情况，调用注册的回调，然后把响应拆成 frame
+    fn respond(
+        &self,
+        cmd: &CommandRequest,
+        mut res: CommandResponse,
+        start: Instant,
+    ) -> StreamingResponse {
+        let elapsed = start.elapsed();
+        res.version = self.written(cmd, &res);
+        self.inner.executed(cmd.name(), &res, elapsed);
+        self.inner.on_before_send.notify(&mut res);
+        if !self.inner.on_before_send.is_empty() {
+            debug!("Modified response: {:?}", res);
+        }
+
+        match self.inner.max_pairs_per_frame {
+            Some(n) => Box::pin(stream::iter(res.into_chunks(n).into_iter().map(Arc::new))),
+            None => Box::pin(stream::once(async { Arc::new(res) })),
+        }
+    }
+
+    /// 写操作执行完之后增加版本号、唤醒等待这些 table 的请求，返回执行完之后的版本号
+    fn written(&self, cmd: &CommandRequest, res: &CommandResponse) -> u64 {
+        let version = self.inner.bump_version(cmd, res);
+        if res.status == StatusCode::OK.as_u16() as u32 && !cache::unchanged(cmd)(res) {
+            for table in cache::written_tables(cmd) {
+                self.waiters.wake(table);
+            }
+        }
+        version
+    }
+
+    /// 执行 Hgetall：拆分 frame 并且不限制响应的大小时，每次从 get_iter 中取出一个 frame 的数据，
+    /// 发送之后再取下一个，不用先生成整个响应再拆分；get_iter 返回的是同一时刻的数据，
+    /// frame 之间的写入不会让客户端拼出一个不完整的 table
+    ///
+    /// 需要完整的响应才能处理的情况（不拆分 frame，或者要检查响应的大小）返回 None，
+    /// 这样的 Hgetall 走普通的路径，结果可以被缓存
+    fn stream_all(
+        &self,
+        cmd: &CommandRequest,
+        table: &str,
+        start: Instant,
+    ) -> Option<StreamingResponse> {
+        let n = match (self.inner.max_pairs_per_frame, self.inner.max_response_size) {
+            (Some(n), None) if n > 0 && !is_reserved_table(table) => n,
+            _ => return None,
+        };
+        let mut iter = match self.inner.store.get_iter(table) {
+            Ok(iter) => iter.peekable(),
+            Err(e) => return Some(self.respond(cmd, e.into(), start)),
+        };
+
+        let inner = Arc::clone(&self.inner);
+        let name = cmd.name();
+        let version = inner.bump_version(cmd, &CommandResponse::ok());
+        let mut done = false;
+        let frames = std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let mut pairs = Vec::with_capacity(n);
+            let mut res = loop {
+                match iter.next() {
+                    Some(Ok(pair)) => pairs.push(pair),
+                    // 读到一半出错时最后一个 frame 带上错误，客户端拼接之后得到的是错误
+                    Some(Err(e)) => break CommandResponse::from(e),
+                    None => break CommandResponse::from(std::mem::take(&mut pairs)),
+                }
+                if pairs.len() == n {
+                    let mut res = CommandResponse::from(std::mem::take(&mut pairs));
+                    res.continued = iter.peek().is_some();
+                    break res;
+                }
+            };
+            res.version = version;
+            done = !res.continued;
+            if done {
+                inner.executed(name, &res, start.elapsed());
+            }
+            inner.on_before_send.notify(&mut res);
+            Some(Arc::new(res))
+        });
+        Some(Box::pin(stream::iter(frames)))
+    }
+
+    /// 执行 Hgetblock：key 不存在时等到它被写入，超过 timeout_ms 还没有等到就返回 Timeout
+    async fn get_blocking(
+        &self,
+        cmd: &CommandRequest,
+        table: &str,
+        timeout_ms: u64,
+    ) -> CommandResponse {
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(timeout_ms);
+        let notify = self.waiters.watch(table);
+        let res = loop {
+            // 先登记再读，读完之后到开始等待之间的写入也能唤醒它
+            let notified = notify.notified();
+            let res = self.execute_unary(cmd.clone());
+            if res.status != StatusCode::NOT_FOUND.as_u16() as u32 {
+                break res;
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                break KvError::Timeout(timeout_ms).into();
+            }
+        };
+        self.waiters.unwatch(table, notify);
+        res
+    }
+
+    /// 执行 Hsetpub：在执行命令的 task 里写入，成功之后按写入的顺序发布
+    async fn set_and_publish(&self, cmd: &CommandRequest, param: Hsetpub) -> CommandResponse {
+        let topic = param.topic.clone();
+        self.broadcaster
+            .write_and_publish(topic, || {
+                let res = self.dispatch(cmd.clone());
+                if res.status != StatusCode::OK.as_u16() as u32 {
+                    return (res, None);
+                }
+                let data = vec![
+                    param.table.into(),
+                    param.key.into(),
+                    param.value.unwrap_or_default(),
+                ];
+                (res, Some(Arc::new(data.into())))
+            })
+            .await
+    }
+
+    /// 设置 table 的 value 检查，之后写入这个 table 的 Hset/Hmset 都要先通过检查
+    pub fn set_table_validator(&self, table: impl Into<String>, validator: Validator) {
+        self.validators.set(table, validator);
+    }
+
+    /// 执行后台任务相关的命令，其他命令交给缓存或者 dispatch
+    fn execute_unary(&self, cmd: CommandRequest) -> CommandResponse {
+        match (&cmd.request_data, &self.inner.cache) {
+            (Some(RequestData::Hdelprefix(param)), _) if param.background => {
+                // 只在启动任务之前检查 token，之后分批的删除不再受 fencing token 的保护
+                let _fence = self.inner.fence.lock().unwrap();
+                match self.inner.check_fencing_token(&cmd) {
+                    Ok(_) => self.spawn_delete_prefix(&cmd, param.clone()),
+                    Err(e) => e.into(),
+                }
             }
+            (Some(RequestData::Slowest(param)), _) => match &self.inner.slowest {
+                Some(slowest) => slowest
+                    .slowest(param.n as usize)
+                    .into_iter()
+                    .map(|(latency, name)| format!("{} {}", latency.as_micros(), name).into())
+                    .collect::<Vec<Value>>()
+                    .into(),
+                None => KvError::InvalidCommand("Slowest log is not enabled".into()).into(),
+            },
+            (Some(RequestData::Serverinfo(_)), _) => self.inner.server_info().into(),
+            (Some(RequestData::Jobstatus(param)), _) => match self.jobs.get(param.id) {
+                Some(job) => vec![Value::from(job.processed() as i64), job.is_done().into()].into(),
+                None => KvError::NotFound(format!("job {}", param.id)).into(),
+            },
+            _ => self.dispatch(cmd),
+        }
+    }
 
-            Box::pin(stream::once(async { Arc::new(res) }))
+    /// 经过缓存（如果打开了的话）执行命令，带 fencing token 的写操作在 fence 锁里
+    /// 检查 token 并执行，检查和写入之间不会插进来 token 更大的写操作
+    fn dispatch(&self, cmd: CommandRequest) -> CommandResponse {
+        let _fence = match cmd.fencing_token != 0 && cache::is_write(&cmd) {
+            true => Some(self.inner.fence.lock().unwrap()),
+            false => None,
+        };
+        if let Err(e) = self.inner.check_fencing_token(&cmd) {
+            return e.into();
+        }
+        match &self.inner.cache {
+            Some(cache) => cache.execute(cmd, &self.inner.store),
+            None => dispatch(cmd, &self.inner.store),
+        }
+    }
+
+    /// 在后台分批删除 key，立刻返回任务的 id
+    fn spawn_delete_prefix(&self, cmd: &CommandRequest, param: Hdelprefix) -> CommandResponse {
+        let (id, progress) = self.jobs.create();
+        let (service, cmd) = (self.clone(), cmd.clone());
+        tokio::spawn(async move {
+            if let Err(e) = service.delete_prefix(&cmd, &param, &progress).await {
+                warn!("Failed to delete prefix in background: {:?}", e);
+            }
+            progress.finish();
+        });
+        Value::from(id as i64).into()
+    }
+
+    /// 分批删除 key，每一批都和 dispatch 执行的写操作一样让缓存失效、增加版本号、
+    /// 唤醒等待的请求，durable 的 table 还要先落盘
+    async fn delete_prefix(
+        &self,
+        cmd: &CommandRequest,
+        param: &Hdelprefix,
+        progress: &JobProgress,
+    ) -> Result<(), KvError> {
+        let store = &self.inner.store;
+        let durable = is_durable(cmd, store);
+        let keys = store.keys(&param.table, &param.prefix)?;
+        for chunk in keys.chunks(DELETE_CHUNK_SIZE) {
+            let mut n = 0;
+            for key in chunk {
+                if store.del(&param.table, key)?.is_some() {
+                    n += 1;
+                }
+            }
+            progress.add(chunk.len() as u64);
+            if n > 0 {
+                if durable {
+                    store.flush()?;
+                }
+                if let Some(cache) = &self.inner.cache {
+                    cache.invalidate(&param.table);
+                }
+                self.written(cmd, &Value::from(n).into());
+            }
+            // 每删完一批就让出执行权，这样其他的请求不会被饿死
+            tokio::task::yield_now().await;
+        }
+        Ok(())
+    }
+}
+
+impl<Store: Storage> ServiceInner<Store> {
+    /// 被禁用的命令不会被执行
+    fn check_enabled(&self, cmd: &CommandRequest) -> Result<(), KvError> {
+        match self.disabled_commands.contains(cmd.name()) {
+            true => Err(KvError::CommandDisabled(cmd.name().into())),
+            false => Ok(()),
+        }
+    }
+
+    /// 带有 fencing token 的写操作，token 不能比见过的最大的 token 小。
+    /// 调用的时候要持有 fence 锁，并且在释放锁之前完成写入
+    fn check_fencing_token(&self, cmd: &CommandRequest) -> Result<(), KvError> {
+        let token = cmd.fencing_token;
+        if token == 0 || !cache::is_write(cmd) {
+            return Ok(());
+        }
+
+        let highest = self.fencing_token.load(Ordering::SeqCst);
+        if token < highest {
+            return Err(KvError::FencedOut(token, highest));
+        }
+
+        // 以存储中的 token 为准，检查和更新在一个事务里，同时到达的两个 token 不会都以为自己是最大的
+        let value = i64::try_from(token)
+            .map_err(|_| KvError::InvalidCommand("fencing token is too large".into()))?;
+        self.store.transaction(FENCING_TABLE, |tx| {
+            let highest = match tx.get(FENCING_KEY)? {
+                Some(v) => i64::try_from(&v)?,
+                None => 0,
+            };
+            match value.cmp(&highest) {
+                std::cmp::Ordering::Less => Err(KvError::FencedOut(token, highest as u64)),
+                std::cmp::Ordering::Equal => Ok(()),
+                std::cmp::Ordering::Greater => tx.set(FENCING_KEY, value).map(|_| ()),
+            }
+        })?;
+        self.fencing_token.fetch_max(token, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// 超过 max_response_size 的读命令的响应换成 ResponseTooLarge
+    fn limit_size(&self, cmd: &CommandRequest, res: CommandResponse) -> CommandResponse {
+        let size = res.encoded_len();
+        match self.max_response_size {
+            Some(limit) if size > limit && !cache::is_write(cmd) => {
+                KvError::ResponseTooLarge(size, limit).into()
+            }
+            _ => res,
+        }
+    }
+
+    /// 命令执行完之后记录耗时，调用注册的回调
+    fn executed(&self, name: &'static str, res: &CommandResponse, elapsed: Duration) {
+        if let Some(slowest) = &self.slowest {
+            slowest.record(elapsed, name);
+        }
+        if let Some(statsd) = &self.statsd {
+            statsd.record(name, res.status, elapsed);
+        }
+        debug!("Executed response: {:?}", res);
+        self.on_executed.notify(res);
+    }
+
+    /// Serverinfo 返回的服务器信息
+    fn server_info(&self) -> Vec<Kvpair> {
+        let mut features = vec![];
+        if cfg!(feature = "testing") {
+            features.push("testing".into());
+        }
+
+        vec![
+            Kvpair::new(
+                "uptime_ms",
+                (self.started.elapsed().as_millis() as i64).into(),
+            ),
+            Kvpair::new("version", env!("CARGO_PKG_VERSION").into()),
+            Kvpair::new("features", Value::new_list(features)),
+        ]
+    }
+
+    /// 服务器的版本号比命令要求的小，说明还有写操作没有被处理
+    fn check_min_version(&self, cmd: &CommandRequest) -> Result<(), KvError> {
+        let current = self.version.load(Ordering::SeqCst);
+        match cmd.min_version > current {
+            true => Err(KvError::VersionBehind(cmd.min_version, current)),
+            false => Ok(()),
+        }
+    }
+
+    /// 成功的写操作让版本号加一（没有修改任何数据的除外），返回执行完命令之后的版本号
+    fn bump_version(&self, cmd: &CommandRequest, res: &CommandResponse) -> u64 {
+        let ok = res.status == StatusCode::OK.as_u16() as u32;
+        match cache::is_write(cmd) && ok && !cache::unchanged(cmd)(res) {
+            true => self.version.fetch_add(1, Ordering::SeqCst) + 1,
+            false => self.version.load(Ordering::SeqCst),
         }
     }
 }
 
 /// 从 Request 中得到 Response，目前处理所有 HGET/HSET/HDEL/HEXIST
 pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
-    match cmd.request_data {
+    if let Err(e) = check_reserved(&cmd) {
+        return e.into();
+    }
+    let durable = is_durable(&cmd, store);
+    let res = match cmd.request_data {
         Some(RequestData::Hget(param)) => param.execute(store),
         Some(RequestData::Hgetall(param)) => param.execute(store),
         Some(RequestData::Hmget(param)) => param.execute(store),
@@ -144,10 +630,121 @@ pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
         Some(RequestData::Hmdel(param)) => param.execute(store),
         Some(RequestData::Hexist(param)) => param.execute(store),
         Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Compact(param)) => param.execute(store),
+        Some(RequestData::Hgetrange(param)) => param.execute(store),
+        Some(RequestData::Hmerge(param)) => param.execute(store),
+        Some(RequestData::Hcount(param)) => param.execute(store),
+        Some(RequestData::Hmsetnx(param)) => param.execute(store),
+        Some(RequestData::Hmexistbits(param)) => param.execute(store),
+        Some(RequestData::Hexpiretable(param)) => param.execute(store),
+        Some(RequestData::Hvals(param)) => param.execute(store),
+        Some(RequestData::Hdelprefix(param)) => param.execute(store),
+        Some(RequestData::Jobstatus(param)) => param.execute(store),
+        Some(RequestData::Hmatchhash(param)) => param.execute(store),
+        Some(RequestData::Hkeyspage(param)) => param.execute(store),
+        Some(RequestData::Recent(param)) => param.execute(store),
+        Some(RequestData::Hdrain(param)) => param.execute(store),
+        Some(RequestData::Hpatch(param)) => param.execute(store),
+        Some(RequestData::Holdest(param)) => param.execute(store),
+        Some(RequestData::Hnewest(param)) => param.execute(store),
+        Some(RequestData::Hello(param)) => param.execute(store),
+        Some(RequestData::Backendinfo(param)) => param.execute(store),
+        Some(RequestData::Hrotate(param)) => param.execute(store),
+        Some(RequestData::Hhistory(param)) => param.execute(store),
+        Some(RequestData::Lockmany(param)) => param.execute(store),
+        Some(RequestData::Slowest(param)) => param.execute(store),
+        Some(RequestData::Hmdelif(param)) => param.execute(store),
+        Some(RequestData::Hgettouch(param)) => param.execute(store),
+        Some(RequestData::Htablehash(param)) => param.execute(store),
+        Some(RequestData::Hincrex(param)) => param.execute(store),
+        Some(RequestData::Hreapexpired(param)) => param.execute(store),
+        Some(RequestData::Hgetreset(param)) => param.execute(store),
+        Some(RequestData::Lpushcap(param)) => param.execute(store),
+        Some(RequestData::Eval(param)) => param.execute(store),
+        Some(RequestData::Tablesizes(param)) => param.execute(store),
+        Some(RequestData::Sunionstore(param)) => param.execute(store),
+        Some(RequestData::Sinterstore(param)) => param.execute(store),
+        Some(RequestData::Pfadd(param)) => param.execute(store),
+        Some(RequestData::Pfcount(param)) => param.execute(store),
+        Some(RequestData::Hreplace(param)) => param.execute(store),
+        Some(RequestData::Setbit(param)) => param.execute(store),
+        Some(RequestData::Getbit(param)) => param.execute(store),
+        Some(RequestData::Hexpiregt(param)) => param.execute(store),
+        Some(RequestData::Serverinfo(param)) => param.execute(store),
+        Some(RequestData::Lpopn(param)) => param.execute(store),
+        Some(RequestData::Hsetkeepttl(param)) => param.execute(store),
+        Some(RequestData::Hsetct(param)) => param.execute(store),
+        Some(RequestData::Hgetct(param)) => param.execute(store),
+        Some(RequestData::Trename(param)) => param.execute(store),
+        Some(RequestData::Ack(param)) => param.execute(store),
+        Some(RequestData::Hmodify(param)) => param.execute(store),
+        Some(RequestData::Hmgetttl(param)) => param.execute(store),
+        Some(RequestData::Hsetpub(param)) => param.execute(store),
+        Some(RequestData::Hgetifchanged(param)) => param.execute(store),
+        Some(RequestData::Hweightedrand(param)) => param.execute(store),
+        Some(RequestData::Hsetifdiff(param)) => param.execute(store),
+        Some(RequestData::Hdrainprefix(param)) => param.execute(store),
+        Some(RequestData::Hmincr(param)) => param.execute(store),
+        Some(RequestData::Lease(param)) => param.execute(store),
+        Some(RequestData::Tdiff(param)) => param.execute(store),
+        Some(RequestData::Cdc(param)) => param.execute(store),
+        Some(RequestData::Hincrclamp(param)) => param.execute(store),
+        Some(RequestData::Hquantiles(param)) => param.execute(store),
+        Some(RequestData::Hmgetsnapshot(param)) => param.execute(store),
+        Some(RequestData::Harchive(param)) => param.execute(store),
+        Some(RequestData::Hgetblock(param)) => param.execute(store),
+        Some(RequestData::Hpexpire(param)) => param.execute(store),
+        Some(RequestData::Hpsetex(param)) => param.execute(store),
+        Some(RequestData::Hgethot(param)) => param.execute(store),
+        Some(RequestData::Hhotkeys(param)) => param.execute(store),
+        Some(RequestData::Lcompact(param)) => param.execute(store),
+        Some(RequestData::Tcreate(param)) => param.execute(store),
+        Some(RequestData::Tconfig(param)) => param.execute(store),
+        Some(RequestData::Verify(param)) => param.execute(store),
+        Some(RequestData::Rotate(param)) => param.execute(store),
+        Some(RequestData::Getgen(param)) => param.execute(store),
+        Some(RequestData::Hagg(param)) => param.execute(store),
+        Some(RequestData::Hdiffapply(param)) => param.execute(store),
+        Some(RequestData::Hsetwithlen(param)) => param.execute(store),
         None => KvError::InvalidCommand("Request has no data".into()).into(),
         // 处理不了的返回一个啥都不包括的 Response，这样后续可以用 dispatch_stream 处理
         _ => CommandResponse::default(),
+    };
+
+    // 要求落盘的命令，执行成功之后先 flush 再返回
+    if durable && res.status == StatusCode::OK.as_u16() as u32 {
+        if let Err(e) = store.flush() {
+            return e.into();
+        }
+    }
+    res
+}
+
+/// 内部使用的 table 只能由服务器自己读写
+fn check_reserved(cmd: &CommandRequest) -> Result<(), KvError> {
+    match cache::accessed_tables(cmd)
+        .into_iter()
+        .find(|t| is_reserved_table(t))
+    {
+        Some(table) => Err(KvError::InvalidCommand(format!(
+            "Table {} is reserved",
+            table
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// 修改了配置为 durable 的 table 的命令，和设置了 durable 的命令一样处理
+fn is_durable(cmd: &CommandRequest, store: &impl Storage) -> bool {
+    let mut durable = cmd.durable;
+    for table in cache::written_tables(cmd) {
+        match store.table_options(table) {
+            Ok(options) => durable |= options.durable,
+            // 读不到配置时按缺省的配置处理，不影响命令的执行
+            Err(e) => warn!("Failed to read options of table {}: {:?}", table, e),
+        }
     }
+    durable
 }
 
 /// 从 Request 中得到 Response，目前处理所有 PUBLISH/SUBSCRIBE/UNSUBSCRIBE
@@ -163,12 +760,14 @@ pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingRespo
 
 #[cfg(test)]
 mod tests {
-    use http::StatusCode;
+    use std::{fs, path::Path, time::Duration};
+
+    use tempfile::tempdir;
     use tokio_stream::StreamExt;
     use tracing::info;
 
     use super::*;
-    use crate::{MemTable, Value};
+    use crate::{MemTable, SledDB, TableOptions, TABLE_OPTIONS_TABLE};
 
     #[tokio::test]
     async fn service_should_works() {
@@ -222,10 +821,545 @@ mod tests {
         assert_eq!(data.message, "");
         assert_eq!(data.values, vec![Value::default()]);
     }
-}
 
-#[cfg(test)]
-use crate::{Kvpair, Value};
+    #[tokio::test]
+    async fn rejecting_pre_execute_hook_should_abort_command() {
+        fn readonly(cmd: &CommandRequest) -> Result<(), KvError> {
+            match cmd.request_data {
+                Some(RequestData::Hset(_)) => Err(KvError::InvalidCommand("read only".into())),
+                _ => Ok(()),
+            }
+        }
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_pre_execute(|_: &CommandRequest| Ok(()))
+            .fn_pre_execute(readonly)
+            .into();
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let data = res.next().await.unwrap();
+        assert_res_error(&data, 400, "read only");
+
+        // 被拒绝的命令不会修改 store
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        let data = res.next().await.unwrap();
+        assert_res_error(&data, 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn hgetblock_should_wait_until_key_is_set() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let cloned = service.clone();
+        let mut waiter = tokio::spawn(async move {
+            let cmd = CommandRequest::new_hgetblock("jobs", "j1", 5000);
+            cloned.execute(cmd).next().await.unwrap()
+        });
+
+        // 写入别的 key 不会让等待的命令返回
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let cmd = CommandRequest::new_hset("jobs", "j0", "v0".into());
+        service.execute(cmd).next().await.unwrap();
+        let pending = tokio::time::timeout(Duration::from_millis(50), &mut waiter).await;
+        assert!(pending.is_err());
+
+        let cmd = CommandRequest::new_hset("jobs", "j1", "v1".into());
+        service.execute(cmd).next().await.unwrap();
+        let data = waiter.await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+
+        // key 已经存在时立刻返回
+        let cmd = CommandRequest::new_hgetblock("jobs", "j1", 5000);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+
+        let cmd = CommandRequest::new_hgetblock("jobs", "none", 50);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&data, 408, "Timed out after 50 ms");
+        assert!(service.waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disabled_command_should_be_rejected() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_disabled_commands(["Hdrain", "hreplace"])
+            .into();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+
+        let data = service
+            .execute(CommandRequest::new_hdrain("t1"))
+            .next()
+            .await
+            .unwrap();
+        assert_res_error(&data, 403, "Command is disabled: hdrain");
+        let data = service
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .next()
+            .await
+            .unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+
+        // 缺省所有的命令都可用
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let data = service
+            .execute(CommandRequest::new_hdrain("t1"))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(data.status, 200);
+    }
+
+    #[tokio::test]
+    async fn table_validator_should_reject_invalid_values() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        service.set_table_validator(
+            "counters",
+            Box::new(|v: &Value| i64::try_from(v).map(|_| ())),
+        );
+
+        let cmd = CommandRequest::new_hset("counters", "k1", "v1".into());
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&data, 400, "Invalid value for table: counters, key: k1");
+
+        // Hmset 里有一个不合法，整个命令都会被拒绝
+        let pairs = vec![Kvpair::new("k2", 1.into()), Kvpair::new("k3", "v3".into())];
+        let cmd = CommandRequest::new_hmset("counters", pairs);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&data, 400, "Invalid value for table: counters, key: k3");
+        let data = service
+            .execute(CommandRequest::new_hget("counters", "k2"))
+            .next()
+            .await;
+        assert_res_error(&data.unwrap(), 404, "Not found");
+
+        let cmd = CommandRequest::new_hset("counters", "k1", 10.into());
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+
+        // 别的 table 不受影响
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+    }
+
+    #[test]
+    fn chunked_hgetall_should_not_see_partial_hreplace() {
+        let dir = tempdir().unwrap();
+        let service: Service<SledDB> = ServiceInner::new(SledDB::new(&dir))
+            .with_max_pairs_per_frame(7)
+            .into();
+        let config = |version: &str| -> Vec<Kvpair> {
+            (0..50)
+                .map(|i| Kvpair::new(format!("k{:02}", i), version.into()))
+                .collect()
+        };
+        let (old, new) = (config("old"), config("new"));
+        service.inner.store.replace("cfg", old.clone()).unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..200 {
+                    let pairs = if i % 2 == 0 { new.clone() } else { old.clone() };
+                    service.inner.store.replace("cfg", pairs).unwrap();
+                }
+            });
+
+            // 拼接所有的 frame 之后，读到的一定是完整的旧配置或者完整的新配置
+            for _ in 0..200 {
+                let frames = service.execute(CommandRequest::new_hgetall("cfg"));
+                let frames: Vec<_> = futures::executor::block_on(frames.collect());
+                assert!(frames.len() > 1);
+                let pairs: Vec<_> = frames.iter().flat_map(|v| v.pairs.clone()).collect();
+                assert!(pairs == old || pairs == new);
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn large_hgetall_should_be_split_into_frames() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_max_pairs_per_frame(1000)
+            .into();
+        for i in 0..2500 {
+            service.inner.store.set("t1", format!("k{}", i), i).unwrap();
+        }
+
+        let frames: Vec<_> = service
+            .execute(CommandRequest::new_hgetall("t1"))
+            .collect()
+            .await;
+        let sizes: Vec<_> = frames
+            .iter()
+            .map(|v| (v.pairs.len(), v.continued))
+            .collect();
+        assert_eq!(sizes, [(1000, true), (1000, true), (500, false)]);
+        assert!(frames.iter().all(|v| v.status == 200));
+
+        // 刚好是整数个 frame 时，最后一个满的 frame 就是结尾，不会多出一个空的 frame
+        for i in 0..2000 {
+            service.inner.store.set("t2", format!("k{}", i), i).unwrap();
+        }
+        let mut frames = service.execute(CommandRequest::new_hgetall("t2"));
+        let first = frames.next().await.unwrap();
+        assert_eq!((first.pairs.len(), first.continued), (1000, true));
+        let last = frames.next().await.unwrap();
+        assert_eq!((last.pairs.len(), last.continued), (1000, false));
+        assert!(frames.next().await.is_none());
+
+        // 小的响应不会被拆分
+        let frames: Vec<_> = service
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .collect()
+            .await;
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_fencing_token_should_be_rejected() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let hset = |v: &str, token| {
+            CommandRequest::new_hset("t1", "k1", v.into()).with_fencing_token(token)
+        };
+
+        let data = service.execute(hset("v1", 2)).next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+
+        // 旧的 leader 带着更小的 token 写入会被拒绝
+        let data = service.execute(hset("v0", 1)).next().await.unwrap();
+        assert_res_error(&data, 409, "Fenced out: token 1 is lower than 2");
+
+        // 相同或者更大的 token 可以写入
+        let data = service.execute(hset("v2", 2)).next().await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+        let data = service.execute(hset("v3", 3)).next().await.unwrap();
+        assert_res_ok(&data, &["v2".into()], &[]);
+        let data = service.execute(hset("v4", 2)).next().await.unwrap();
+        assert_res_error(&data, 409, "Fenced out");
+
+        // 读操作不受 fencing token 的限制
+        let cmd = CommandRequest::new_hget("t1", "k1").with_fencing_token(1);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &["v3".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn fencing_token_should_survive_restart() {
+        let dir = tempdir().unwrap();
+        let service: Service<SledDB> = ServiceInner::new(SledDB::new(dir.path())).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into()).with_fencing_token(2);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+        drop(service);
+
+        // 重启之后旧的 leader 还是不能写入
+        let service: Service<SledDB> = ServiceInner::new(SledDB::new(dir.path())).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v0".into()).with_fencing_token(1);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&data, 409, "Fenced out: token 1 is lower than 2");
+        let cmd = CommandRequest::new_hset("t1", "k1", "v2".into()).with_fencing_token(2);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn fenced_writes_should_not_be_overtaken() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let tokens = std::sync::atomic::AtomicU64::new(1);
+
+        // 写入成功的时候，之前写入的 token 一定不比自己的大
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..200 {
+                        let token = tokens.fetch_add(1, Ordering::SeqCst);
+                        let cmd = CommandRequest::new_hset("t1", "k1", (token as i64).into())
+                            .with_fencing_token(token);
+                        let frames =
+                            futures::executor::block_on(service.execute(cmd).collect::<Vec<_>>());
+                        let res = &frames[0];
+                        if res.status == 200 {
+                            let prev = res.values[0].clone();
+                            let prev = i64::try_from(&prev).unwrap_or_default();
+                            assert!(prev < token as i64);
+                        } else {
+                            assert_eq!(res.status, 409);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn write_should_bump_version() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_eq!(data.version, 1);
+
+        // 读操作不改变版本号
+        let cmd = CommandRequest::new_hget("t1", "k1").with_min_version(1);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+        assert_eq!(data.version, 1);
+
+        // 服务器还没有到达要求的版本号
+        let cmd = CommandRequest::new_hget("t1", "k1").with_min_version(2);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&data, 503, "Server is behind: required version 2");
+
+        // 没有修改任何数据的写操作也不改变版本号
+        let cmd = CommandRequest::new_hsetifdiff("t1", "k1", "v1");
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &[false.into()], &[]);
+        assert_eq!(data.version, 1);
+        let cmd = CommandRequest::new_hdiffapply("t1", vec![Kvpair::new("k1", "v1".into())]);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &[], &[]);
+        assert_eq!(data.version, 1);
+        let cmd = CommandRequest::new_hsetifdiff("t1", "k1", "v2");
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_eq!(data.version, 2);
+    }
+
+    #[tokio::test]
+    async fn large_response_should_be_rejected() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_max_response_size(1024)
+            .into();
+        let pairs = (0..100)
+            .map(|i| Kvpair::new(format!("k{}", i), "0123456789".into()))
+            .collect();
+        let cmd = CommandRequest::new_hmset("big", pairs);
+        service.execute(cmd).next().await.unwrap();
+        let cmd = CommandRequest::new_hset("small", "k1", "v1".into());
+        service.execute(cmd).next().await.unwrap();
+
+        let cmd = CommandRequest::new_hgetall("big");
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&data, 413, "Response is too large");
+        assert!(data.message.contains("paginate"));
+
+        let cmd = CommandRequest::new_hgetall("small");
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &[], &[Kvpair::new("k1", "v1".into())]);
+    }
+
+    #[tokio::test]
+    async fn hsetpub_should_publish_every_change() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let mut events = service.execute(CommandRequest::new_subscribe("changes"));
+        // 第一个响应是 subscription id
+        events.next().await.unwrap();
+
+        for i in 0..100 {
+            let cmd = CommandRequest::new_hsetpub("t1", format!("k{}", i), i, "changes");
+            let res = service.execute(cmd).next().await.unwrap();
+            assert_res_ok(&res, &[Value::default()], &[]);
+        }
+
+        // 发布在写入的 task 里完成，到达的顺序和写入的顺序一致
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            let event = events.next().await.unwrap();
+            assert_eq!(event.values[0], "t1".into());
+            received.push(i64::try_from(&event.values[2]).unwrap());
+        }
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+
+        let res = service
+            .execute(CommandRequest::new_hget("t1", "k42"))
+            .next()
+            .await
+            .unwrap();
+        assert_res_ok(&res, &[42.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn serverinfo_should_work() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let uptime = |res: &CommandResponse| {
+            let pair = res.pairs.iter().find(|p| p.key == "uptime_ms").unwrap();
+            i64::try_from(pair.value.as_ref().unwrap()).unwrap()
+        };
+
+        let first = service
+            .execute(CommandRequest::new_serverinfo())
+            .next()
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let second = service
+            .execute(CommandRequest::new_serverinfo())
+            .next()
+            .await
+            .unwrap();
+        assert!(uptime(&second) > uptime(&first));
+
+        let version = second.pairs.iter().find(|p| p.key == "version").unwrap();
+        assert_ne!(version.value, Some("".into()));
+    }
+
+    #[tokio::test]
+    async fn background_hdelprefix_should_work() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let store = &service.inner.store;
+        for i in 0..10_000 {
+            store.set("t1", format!("tmp{}", i), i as i64).unwrap();
+        }
+        store.set("t1", "keep", "v1").unwrap();
+
+        let cmd = CommandRequest::new_hdelprefix("t1", "tmp", true);
+        let data = service.execute(cmd).next().await.unwrap();
+        let id = i64::try_from(data.as_ref()).unwrap() as u64;
+
+        // 删除在后台进行，不影响读取别的 key
+        let data = service
+            .execute(CommandRequest::new_hget("t1", "keep"))
+            .next()
+            .await;
+        assert_res_ok(&data.unwrap(), &["v1".into()], &[]);
+
+        loop {
+            let data = service
+                .execute(CommandRequest::new_jobstatus(id))
+                .next()
+                .await;
+            let data = data.unwrap();
+            if data.values[1] == true.into() {
+                assert_res_ok(&data, &[10_000.into(), true.into()], &[]);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(store.count("t1", "").unwrap(), 1);
+        // 返回任务 id 时加一次，之后每删完一批再加一次
+        assert_eq!(service.inner.version.load(Ordering::SeqCst), 11);
+        let data = service
+            .execute(CommandRequest::new_jobstatus(id + 1))
+            .next()
+            .await;
+        assert_res_error(&data.unwrap(), 404, "Not found");
+
+        // 后台删除也不能删除内部使用的 table
+        store.set(FENCING_TABLE, FENCING_KEY, 1).unwrap();
+        let cmd = CommandRequest::new_hdelprefix(FENCING_TABLE, "", true);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&data, 400, "Table __fencing is reserved");
+        assert!(store.contains(FENCING_TABLE, FENCING_KEY).unwrap());
+    }
+
+    #[tokio::test]
+    async fn slowest_should_report_slowest_commands() {
+        // 用 pre_execute 拖慢命令：hget 慢 30ms，hset 慢 10ms
+        fn slow_down(cmd: &CommandRequest) -> Result<(), KvError> {
+            let ms = match cmd.request_data {
+                Some(RequestData::Hget(_)) => 30,
+                Some(RequestData::Hset(_)) => 10,
+                _ => 0,
+            };
+            std::thread::sleep(Duration::from_millis(ms));
+            Ok(())
+        }
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_pre_execute(slow_down)
+            .with_slowest(2)
+            .into();
+        for cmd in [
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hgetall("t1"),
+        ] {
+            service.execute(cmd).next().await.unwrap();
+        }
+
+        let data = service
+            .execute(CommandRequest::new_slowest(10))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(data.status, 200);
+        let names: Vec<_> = data
+            .values
+            .iter()
+            .map(|v| {
+                let s = String::try_from(v.clone()).unwrap();
+                s.split(' ').nth(1).unwrap().to_owned()
+            })
+            .collect();
+        assert_eq!(names, ["hget", "hset"]);
+    }
+
+    #[test]
+    fn durable_hset_should_survive_crash() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir.path());
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into()).with_durable();
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        // 在 store 还在运行时拷贝磁盘上的文件，模拟进程 crash 之后留下的数据
+        let crashed = tempdir().unwrap();
+        copy_dir(dir.path(), crashed.path());
+        let store = SledDB::new(crashed.path());
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn writes_to_durable_table_should_survive_crash() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir.path());
+        let options = TableOptions {
+            durable: true,
+            ..Default::default()
+        };
+        let res = dispatch(CommandRequest::new_tconfig("t1", options.clone()), &store);
+        // 返回之前的配置
+        assert_eq!(res.pairs, TableOptions::default().to_pairs());
+        // 没有设置 durable 的命令也会落盘
+        let res = dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        let crashed = tempdir().unwrap();
+        copy_dir(dir.path(), crashed.path());
+        let store = SledDB::new(crashed.path());
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+        assert_eq!(store.table_options("t1").unwrap(), options);
+    }
+
+    #[tokio::test]
+    async fn tconfig_should_be_a_write() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let cmd = CommandRequest::new_tconfig("t1", TableOptions::default());
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_eq!(data.status, 200);
+        assert_eq!(data.version, 1);
+
+        // 保存配置的 table 是内部使用的，客户端不能直接修改
+        let cmd = CommandRequest::new_hset(TABLE_OPTIONS_TABLE, "t1", "bad".into());
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&data, 400, "Table __tconfig is reserved");
+        assert_eq!(data.version, 1);
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        for entry in fs::read_dir(from).unwrap() {
+            let path = entry.unwrap().path();
+            let target = to.join(path.file_name().unwrap());
+            if path.is_dir() {
+                fs::create_dir_all(&target).unwrap();
+                copy_dir(&path, &target);
+            } else {
+                fs::copy(&path, &target).unwrap();
+            }
+        }
+    }
+}
 
 // 测试成功返回的结果
 #[cfg(test)]
@@ -0,0 +1,103 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use rand::Rng;
+
+/// 加权的蓄水池抽样（Efraimidis-Spirakis A-Res）
+///
+/// 每个元素取一个随机数 u，用 ln(u) / weight 作为它的分数，保留分数最大的 n 个，
+/// 只需要遍历一次，内存和 n 成正比。权重越大的元素分数越接近 0，越容易被留下
+pub struct WeightedReservoir<T> {
+    n: usize,
+    heap: BinaryHeap<Reverse<Scored<T>>>,
+}
+
+struct Scored<T> {
+    score: f64,
+    item: T,
+}
+
+impl<T> WeightedReservoir<T> {
+    pub fn new(n: usize) -> Self {
+        Self {
+            n,
+            // n 来自客户端，可能比元素的个数大得多，heap 随着加入的元素增长，不预先分配
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    /// 加入一个元素，权重不是正数的元素永远不会被选中
+    pub fn add(&mut self, item: T, weight: f64, rng: &mut impl Rng) {
+        if self.n == 0 || weight <= 0.0 || !weight.is_finite() {
+            return;
+        }
+
+        // gen 的范围是 [0, 1)，换成 (0, 1] 避免 ln(0)
+        let u: f64 = 1.0 - rng.gen::<f64>();
+        let score = u.ln() / weight;
+        if self.heap.len() < self.n {
+            self.heap.push(Reverse(Scored { score, item }));
+        } else if let Some(mut min) = self.heap.peek_mut() {
+            if score > min.0.score {
+                *min = Reverse(Scored { score, item });
+            }
+        }
+    }
+
+    /// 选中的元素，被选中时分数越高的越靠前
+    pub fn into_sorted_vec(self) -> Vec<T> {
+        // Reverse 之后升序排列的就是分数从高到低
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(v)| v.item)
+            .collect()
+    }
+}
+
+impl<T> PartialEq for Scored<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.score.total_cmp(&other.score) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Scored<T> {}
+
+impl<T> PartialOrd for Scored<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Scored<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservoir_should_keep_at_most_n_items() {
+        let mut rng = rand::thread_rng();
+        let mut reservoir = WeightedReservoir::new(3);
+        for i in 0..100 {
+            reservoir.add(i, 1.0, &mut rng);
+        }
+        // 权重为 0 的元素不会被选中
+        reservoir.add(1000, 0.0, &mut rng);
+        let items = reservoir.into_sorted_vec();
+        assert_eq!(items.len(), 3);
+        assert!(!items.contains(&1000));
+    }
+
+    #[test]
+    fn huge_n_should_not_allocate_up_front() {
+        let mut rng = rand::thread_rng();
+        let mut reservoir = WeightedReservoir::new(u32::MAX as usize);
+        reservoir.add(1, 1.0, &mut rng);
+        assert_eq!(reservoir.into_sorted_vec(), [1]);
+    }
+}
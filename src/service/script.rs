@@ -0,0 +1,524 @@
+//! Eval 命令使用的脚本语言
+//!
+//! 脚本只能操作一个 key 的 value，没有循环，长度和嵌套深度都有上限，所以执行时间是有界的：
+//!
+//! ```text
+//! script  := "if" expr "then" action ["else" script] | action
+//! action  := "set" expr | "del" | "keep"
+//! expr    := and ("or" and)*
+//! and     := cmp ("and" cmp)*
+//! cmp     := sum [("==" | "!=" | ">" | ">=" | "<" | "<=") sum]
+//! sum     := term (("+" | "-") term)*
+//! term    := unary (("*" | "/" | "%") unary)*
+//! unary   := ("-" | "not") unary | primary
+//! primary := 整数 | 浮点数 | "字符串" | "true" | "false" | "value" | "(" expr ")"
+//! ```
+//!
+//! `value` 是 key 当前的 value，key 不存在时为空的 Value
+
+use std::cmp::Ordering;
+
+use crate::{value, KvError, Value};
+
+/// 脚本的最大长度（字节）
+const MAX_SCRIPT_LEN: usize = 1024;
+/// 表达式最大的嵌套深度
+const MAX_DEPTH: usize = 32;
+
+/// 脚本执行的结果，对 key 做什么操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// 不修改
+    Keep,
+    /// 设置成新的 value
+    Set(Value),
+    /// 删除
+    Del,
+}
+
+/// 解析好的脚本
+#[derive(Debug, Clone, PartialEq)]
+pub enum Script {
+    If(Expr, Box<Script>, Option<Box<Script>>),
+    Set(Expr),
+    Del,
+    Keep,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Current,
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Ident(String),
+    Sym(&'static str),
+}
+
+impl Script {
+    /// 解析脚本
+    pub fn parse(script: &str) -> Result<Self, KvError> {
+        if script.len() > MAX_SCRIPT_LEN {
+            return Err(script_error(format!(
+                "script is longer than {} bytes",
+                MAX_SCRIPT_LEN
+            )));
+        }
+
+        let mut parser = Parser {
+            tokens: tokenize(script)?,
+            pos: 0,
+        };
+        let script = parser.script(0)?;
+        match parser.peek() {
+            None => Ok(script),
+            Some(t) => Err(script_error(format!("unexpected {:?}", t))),
+        }
+    }
+
+    /// 对 key 当前的 value 执行脚本，得到要做的操作
+    pub fn eval(&self, current: &Value) -> Result<Action, KvError> {
+        match self {
+            Script::If(cond, then, otherwise) => {
+                if truthy(&cond.eval(current)?)? {
+                    then.eval(current)
+                } else {
+                    match otherwise {
+                        Some(v) => v.eval(current),
+                        None => Ok(Action::Keep),
+                    }
+                }
+            }
+            Script::Set(expr) => Ok(Action::Set(expr.eval(current)?)),
+            Script::Del => Ok(Action::Del),
+            Script::Keep => Ok(Action::Keep),
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, current: &Value) -> Result<Value, KvError> {
+        match self {
+            Expr::Literal(v) => Ok(v.clone()),
+            Expr::Current => Ok(current.clone()),
+            Expr::Not(v) => Ok((!truthy(&v.eval(current)?)?).into()),
+            Expr::Neg(v) => match v.eval(current)?.value {
+                Some(value::Value::Integer(i)) => i
+                    .checked_neg()
+                    .map(Value::from)
+                    .ok_or_else(|| script_error("integer overflow")),
+                Some(value::Value::Float(f)) => Ok((-f).into()),
+                v => Err(script_error(format!("cannot negate {:?}", v))),
+            },
+            // and/or 短路求值
+            Expr::Binary(Op::And, a, b) => {
+                Ok((truthy(&a.eval(current)?)? && truthy(&b.eval(current)?)?).into())
+            }
+            Expr::Binary(Op::Or, a, b) => {
+                Ok((truthy(&a.eval(current)?)? || truthy(&b.eval(current)?)?).into())
+            }
+            Expr::Binary(op, a, b) => binary(*op, a.eval(current)?, b.eval(current)?),
+        }
+    }
+}
+
+fn script_error(msg: impl Into<String>) -> KvError {
+    KvError::InvalidCommand(format!("script: {}", msg.into()))
+}
+
+fn truthy(v: &Value) -> Result<bool, KvError> {
+    match v.value {
+        Some(value::Value::Bool(b)) => Ok(b),
+        _ => Err(script_error(format!("{:?} is not a bool", v.value))),
+    }
+}
+
+fn binary(op: Op, a: Value, b: Value) -> Result<Value, KvError> {
+    use value::Value::*;
+
+    // 类型不同的 value 只能判断是否相等，不能比较大小
+    let ordering = || {
+        compare(&a, &b)
+            .ok_or_else(|| script_error(format!("cannot compare {:?} with {:?}", a.value, b.value)))
+    };
+    match op {
+        Op::Eq => return Ok((compare(&a, &b) == Some(Ordering::Equal)).into()),
+        Op::Ne => return Ok((compare(&a, &b) != Some(Ordering::Equal)).into()),
+        Op::Gt => return Ok((ordering()? == Ordering::Greater).into()),
+        Op::Ge => return Ok((ordering()? != Ordering::Less).into()),
+        Op::Lt => return Ok((ordering()? == Ordering::Less).into()),
+        Op::Le => return Ok((ordering()? != Ordering::Greater).into()),
+        _ => {}
+    }
+
+    let overflow = || script_error("integer overflow");
+    match (op, a.value, b.value) {
+        (Op::Add, Some(String(a)), Some(String(b))) => Ok(format!("{}{}", a, b).into()),
+        (Op::Add, Some(Integer(a)), Some(Integer(b))) => {
+            a.checked_add(b).map(Value::from).ok_or_else(overflow)
+        }
+        (Op::Sub, Some(Integer(a)), Some(Integer(b))) => {
+            a.checked_sub(b).map(Value::from).ok_or_else(overflow)
+        }
+        (Op::Mul, Some(Integer(a)), Some(Integer(b))) => {
+            a.checked_mul(b).map(Value::from).ok_or_else(overflow)
+        }
+        (Op::Div | Op::Rem, Some(Integer(_)), Some(Integer(0))) => {
+            Err(script_error("division by zero"))
+        }
+        (Op::Div, Some(Integer(a)), Some(Integer(b))) => {
+            a.checked_div(b).map(Value::from).ok_or_else(overflow)
+        }
+        (Op::Rem, Some(Integer(a)), Some(Integer(b))) => {
+            a.checked_rem(b).map(Value::from).ok_or_else(overflow)
+        }
+        (op, a, b) => match (as_float(&a), as_float(&b)) {
+            (Some(a), Some(b)) => Ok(match op {
+                Op::Add => a + b,
+                Op::Sub => a - b,
+                Op::Mul => a * b,
+                Op::Div => a / b,
+                _ => a % b,
+            }
+            .into()),
+            _ => Err(script_error(format!(
+                "cannot apply {:?} to {:?} and {:?}",
+                op, a, b
+            ))),
+        },
+    }
+}
+
+/// 比较两个 value，整数和浮点数之间可以互相比较，无法比较时返回 None
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    use value::Value::*;
+
+    match (&a.value, &b.value) {
+        (Some(String(a)), Some(String(b))) => Some(a.cmp(b)),
+        (Some(Bool(a)), Some(Bool(b))) => Some(a.cmp(b)),
+        (Some(Integer(a)), Some(Integer(b))) => Some(a.cmp(b)),
+        (None, None) => Some(Ordering::Equal),
+        (a, b) => as_float(a)?.partial_cmp(&as_float(b)?),
+    }
+}
+
+fn as_float(v: &Option<value::Value>) -> Option<f64> {
+    match v {
+        Some(value::Value::Integer(i)) => Some(*i as f64),
+        Some(value::Value::Float(f)) => Some(*f),
+        _ => None,
+    }
+}
+
+fn tokenize(script: &str) -> Result<Vec<Token>, KvError> {
+    const SYMBOLS: [&str; 14] = [
+        "==", "!=", ">=", "<=", ">", "<", "+", "-", "*", "/", "%", "(", ")", "=",
+    ];
+
+    let mut tokens = Vec::new();
+    let mut rest = script.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let s = &rest[..len];
+            let token = match s.contains('.') {
+                true => s.parse().map(Token::Float).ok(),
+                false => s.parse().map(Token::Int).ok(),
+            };
+            tokens.push(token.ok_or_else(|| script_error(format!("invalid number {}", s)))?);
+            len
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_owned()));
+            len
+        } else if c == '"' {
+            // 字符串里用 \" 和 \\ 转义
+            let mut s = String::new();
+            let mut chars = rest.char_indices().skip(1);
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 1,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => s.push(c),
+                        None => return Err(script_error("unterminated string")),
+                    },
+                    Some((_, c)) => s.push(c),
+                    None => return Err(script_error("unterminated string")),
+                }
+            };
+            tokens.push(Token::Str(s));
+            end
+        } else {
+            let s = SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(*s))
+                .ok_or_else(|| script_error(format!("unexpected character {:?}", c)))?;
+            // "=" 只是 "==" 的简写
+            tokens.push(Token::Sym(if *s == "=" { "==" } else { s }));
+            s.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// 下一个 token 是关键字 word 时跳过它
+    fn keyword(&mut self, word: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(v)) if v == word => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), KvError> {
+        match self.keyword(word) {
+            true => Ok(()),
+            false => Err(script_error(format!("expect {}", word))),
+        }
+    }
+
+    fn symbol(&mut self, symbols: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Sym(s)) if symbols.contains(s) => {
+                let s = *s;
+                self.pos += 1;
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    fn check_depth(depth: usize) -> Result<usize, KvError> {
+        match depth < MAX_DEPTH {
+            true => Ok(depth + 1),
+            false => Err(script_error("script is nested too deeply")),
+        }
+    }
+
+    fn script(&mut self, depth: usize) -> Result<Script, KvError> {
+        let depth = Self::check_depth(depth)?;
+        if self.keyword("if") {
+            let cond = self.expr(depth)?;
+            self.expect("then")?;
+            let then = self.action(depth)?;
+            let otherwise = match self.keyword("else") {
+                true => Some(Box::new(self.script(depth)?)),
+                false => None,
+            };
+            return Ok(Script::If(cond, Box::new(then), otherwise));
+        }
+        self.action(depth)
+    }
+
+    fn action(&mut self, depth: usize) -> Result<Script, KvError> {
+        if self.keyword("set") {
+            Ok(Script::Set(self.expr(depth)?))
+        } else if self.keyword("del") {
+            Ok(Script::Del)
+        } else if self.keyword("keep") {
+            Ok(Script::Keep)
+        } else {
+            Err(script_error("expect set, del or keep"))
+        }
+    }
+
+    fn expr(&mut self, depth: usize) -> Result<Expr, KvError> {
+        let depth = Self::check_depth(depth)?;
+        let mut left = self.and(depth)?;
+        while self.keyword("or") {
+            left = Expr::Binary(Op::Or, Box::new(left), Box::new(self.and(depth)?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Expr, KvError> {
+        let mut left = self.cmp(depth)?;
+        while self.keyword("and") {
+            left = Expr::Binary(Op::And, Box::new(left), Box::new(self.cmp(depth)?));
+        }
+        Ok(left)
+    }
+
+    fn cmp(&mut self, depth: usize) -> Result<Expr, KvError> {
+        let left = self.sum(depth)?;
+        let op = match self.symbol(&["==", "!=", ">", ">=", "<", "<="]) {
+            Some("==") => Op::Eq,
+            Some("!=") => Op::Ne,
+            Some(">") => Op::Gt,
+            Some(">=") => Op::Ge,
+            Some("<") => Op::Lt,
+            Some("<=") => Op::Le,
+            _ => return Ok(left),
+        };
+        Ok(Expr::Binary(op, Box::new(left), Box::new(self.sum(depth)?)))
+    }
+
+    fn sum(&mut self, depth: usize) -> Result<Expr, KvError> {
+        let mut left = self.term(depth)?;
+        while let Some(s) = self.symbol(&["+", "-"]) {
+            let op = if s == "+" { Op::Add } else { Op::Sub };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.term(depth)?));
+        }
+        Ok(left)
+    }
+
+    fn term(&mut self, depth: usize) -> Result<Expr, KvError> {
+        let mut left = self.unary(depth)?;
+        while let Some(s) = self.symbol(&["*", "/", "%"]) {
+            let op = match s {
+                "*" => Op::Mul,
+                "/" => Op::Div,
+                _ => Op::Rem,
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary(depth)?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Expr, KvError> {
+        let depth = Self::check_depth(depth)?;
+        if self.symbol(&["-"]).is_some() {
+            return Ok(Expr::Neg(Box::new(self.unary(depth)?)));
+        }
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.unary(depth)?)));
+        }
+        self.primary(depth)
+    }
+
+    fn primary(&mut self, depth: usize) -> Result<Expr, KvError> {
+        match self.next() {
+            Some(Token::Int(i)) => Ok(Expr::Literal(i.into())),
+            Some(Token::Float(f)) => Ok(Expr::Literal(f.into())),
+            Some(Token::Str(s)) => Ok(Expr::Literal(s.into())),
+            Some(Token::Ident(v)) if v == "true" => Ok(Expr::Literal(true.into())),
+            Some(Token::Ident(v)) if v == "false" => Ok(Expr::Literal(false.into())),
+            Some(Token::Ident(v)) if v == "value" => Ok(Expr::Current),
+            Some(Token::Sym("(")) => {
+                let expr = self.expr(depth)?;
+                match self.symbol(&[")"]) {
+                    Some(_) => Ok(expr),
+                    None => Err(script_error("expect )")),
+                }
+            }
+            Some(t) => Err(script_error(format!("unexpected {:?}", t))),
+            None => Err(script_error("unexpected end of script")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(script: &str, current: Value) -> Result<Action, KvError> {
+        Script::parse(script)?.eval(&current)
+    }
+
+    #[test]
+    fn script_should_work() {
+        assert_eq!(
+            eval("set value * 2 + 1", 5.into()).unwrap(),
+            Action::Set(11.into())
+        );
+        assert_eq!(
+            eval("set (value - 1) % 3", 5.into()).unwrap(),
+            Action::Set(1.into())
+        );
+        assert_eq!(
+            eval("set value / 2", 5.5.into()).unwrap(),
+            Action::Set(2.75.into())
+        );
+        assert_eq!(
+            eval("set value + \"!\"", "hi".into()).unwrap(),
+            Action::Set("hi!".into())
+        );
+
+        let script = "if value > 10 and not (value == 20) then set 0 else if value < 0 then del";
+        assert_eq!(eval(script, 15.into()).unwrap(), Action::Set(0.into()));
+        assert_eq!(eval(script, 20.into()).unwrap(), Action::Keep);
+        assert_eq!(eval(script, (-1).into()).unwrap(), Action::Del);
+
+        // key 不存在时 value 为空，和任何 value 都不相等
+        let script = "if value == 1 then keep else set 1";
+        assert_eq!(
+            eval(script, Value::default()).unwrap(),
+            Action::Set(1.into())
+        );
+    }
+
+    #[test]
+    fn invalid_script_should_fail() {
+        for script in [
+            "value",
+            "set",
+            "if value then",
+            "set 1 2",
+            "set \"abc",
+            "set value $ 1",
+            "if true set 1",
+        ] {
+            assert!(Script::parse(script).is_err(), "{}", script);
+        }
+
+        // 嵌套太深和太长的脚本会被拒绝
+        let script = format!("set {}1{}", "(".repeat(100), ")".repeat(100));
+        assert!(Script::parse(&script).is_err());
+        let script = format!("set {}", "1 + ".repeat(300) + "1");
+        assert!(Script::parse(&script).is_err());
+
+        // 运行时的错误
+        assert!(eval("set value / 0", 1.into()).is_err());
+        assert!(eval("set value + 1", i64::MAX.into()).is_err());
+        assert!(eval("if value then del", 1.into()).is_err());
+        assert!(eval("set value - 1", "abc".into()).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// 记录启动以来最慢的若干个命令
+pub struct SlowestLog {
+    capacity: usize,
+    /// 按耗时排序的小顶堆，满了之后新的命令只需要和堆顶（最快的那个）比较
+    heap: Mutex<BinaryHeap<Reverse<(Duration, &'static str)>>>,
+}
+
+impl SlowestLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            heap: Mutex::new(BinaryHeap::with_capacity(capacity + 1)),
+        }
+    }
+
+    /// 记录一个命令的耗时
+    pub fn record(&self, latency: Duration, command: &'static str) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut heap = self.heap.lock().unwrap();
+        heap.push(Reverse((latency, command)));
+        if heap.len() > self.capacity {
+            heap.pop();
+        }
+    }
+
+    /// 最慢的 n 个命令，最慢的排在最前面
+    pub fn slowest(&self, n: usize) -> Vec<(Duration, &'static str)> {
+        let heap = self.heap.lock().unwrap().clone();
+        // 小顶堆排序之后是从大到小
+        heap.into_sorted_vec()
+            .into_iter()
+            .take(n)
+            .map(|Reverse(v)| v)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slowest_log_should_keep_slowest_commands() {
+        let log = SlowestLog::new(2);
+        log.record(Duration::from_millis(5), "hget");
+        log.record(Duration::from_millis(20), "hset");
+        log.record(Duration::from_millis(1), "hdel");
+        log.record(Duration::from_millis(10), "hgetall");
+
+        let slowest = log.slowest(10);
+        assert_eq!(
+            slowest,
+            vec![
+                (Duration::from_millis(20), "hset"),
+                (Duration::from_millis(10), "hgetall")
+            ]
+        );
+        assert_eq!(log.slowest(1), vec![(Duration::from_millis(20), "hset")]);
+    }
+}
@@ -0,0 +1,99 @@
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::KvError;
+
+/// 把每个命令的次数、错误数和耗时用 UDP 发送给 statsd
+///
+/// 每个命令发送一个包，包里每行一个指标：
+///
+/// ```text
+/// <prefix>.<command>.count:1|c
+/// <prefix>.<command>.errors:1|c      （只有命令失败时才有）
+/// <prefix>.<command>.time:<毫秒>|ms
+/// ```
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+impl StatsdExporter {
+    /// 发送到 addr 的 statsd，所有指标的名字都以 prefix 开头
+    pub fn new(addr: impl ToSocketAddrs, prefix: impl Into<String>) -> Result<Self, KvError> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        // 指标丢了也没关系，不能因为 statsd 发不出去而阻塞命令的处理
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            prefix: prefix.into(),
+        })
+    }
+
+    /// 记录一个命令的执行结果和耗时
+    pub fn record(&self, command: &str, status: u32, latency: Duration) {
+        let name = format!("{}.{}", self.prefix, command);
+        let mut lines = format!("{}.count:1|c\n", name);
+        if !(200..300).contains(&status) {
+            lines.push_str(&format!("{}.errors:1|c\n", name));
+        }
+        lines.push_str(&format!(
+            "{}.time:{:.3}|ms",
+            name,
+            latency.as_secs_f64() * 1000.0
+        ));
+
+        if let Err(e) = self.socket.send(lines.as_bytes()) {
+            debug!("Failed to send metrics to statsd: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, MemTable, Service, ServiceInner};
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn commands_should_be_sent_to_statsd() -> anyhow::Result<()> {
+        let statsd = UdpSocket::bind("127.0.0.1:0")?;
+        statsd.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let exporter = StatsdExporter::new(statsd.local_addr()?, "kv")?;
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_statsd(exporter)
+            .into();
+
+        service
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .next()
+            .await;
+        service
+            .execute(CommandRequest::new_hget("t1", "k2"))
+            .next()
+            .await;
+
+        let mut buf = [0; 1024];
+        let mut recv = || -> anyhow::Result<Vec<String>> {
+            let n = statsd.recv(&mut buf)?;
+            let packet = std::str::from_utf8(&buf[..n])?;
+            Ok(packet.lines().map(|v| v.to_owned()).collect())
+        };
+
+        let lines = recv()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "kv.hset.count:1|c");
+        assert!(lines[1].starts_with("kv.hset.time:") && lines[1].ends_with("|ms"));
+
+        // 找不到 key 是一个错误
+        let lines = recv()?;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "kv.hget.count:1|c");
+        assert_eq!(lines[1], "kv.hget.errors:1|c");
+        assert!(lines[2].starts_with("kv.hget.time:"));
+
+        Ok(())
+    }
+}
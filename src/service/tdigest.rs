@@ -0,0 +1,158 @@
+//! Hquantiles 使用的 t-digest
+//!
+//! 把数据压缩成若干个质心（均值 + 权重），两端的质心小、中间的质心大，
+//! 所以越靠近 0 和 1 的分位数越精确。新的数据先放进缓冲区，缓冲区满了之后和已有的质心一起排序合并，
+//! 内存只和 compression 有关，不需要把所有的数据排序
+
+use std::f64::consts::PI;
+
+/// 缺省的压缩参数，质心的个数大约在 compression 左右
+const DEFAULT_COMPRESSION: f64 = 100.0;
+
+#[derive(Debug, Clone, Copy)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TDigest {
+    compression: f64,
+    centroids: Vec<Centroid>,
+    buffer: Vec<f64>,
+    count: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for TDigest {
+    fn default() -> Self {
+        Self::new(DEFAULT_COMPRESSION)
+    }
+}
+
+impl TDigest {
+    pub fn new(compression: f64) -> Self {
+        Self {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            count: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// 加入一个数据，NaN 会被忽略
+    pub fn add(&mut self, x: f64) {
+        if x.is_nan() {
+            return;
+        }
+        self.buffer.push(x);
+        self.count += 1.0;
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+        if self.buffer.len() as f64 >= self.compression * 5.0 {
+            self.compress();
+        }
+    }
+
+    /// 估算 q 分位数（0 <= q <= 1），没有数据时返回 None
+    pub fn quantile(&mut self, q: f64) -> Option<f64> {
+        self.compress();
+        let centroids = &self.centroids;
+        let (first, last) = (centroids.first()?, centroids.last()?);
+        let target = q.clamp(0.0, 1.0) * self.count;
+
+        // 每个质心的数据看作均匀分布在它的中心两侧，相邻的中心之间线性插值，两端用 min 和 max
+        if target <= first.weight / 2.0 {
+            return Some(lerp(self.min, first.mean, target / (first.weight / 2.0)));
+        }
+        let mut seen = 0.0;
+        for pair in centroids.windows(2) {
+            let (left, right) = (pair[0], pair[1]);
+            let left_center = seen + left.weight / 2.0;
+            let right_center = seen + left.weight + right.weight / 2.0;
+            if target <= right_center {
+                let t = (target - left_center) / (right_center - left_center);
+                return Some(lerp(left.mean, right.mean, t));
+            }
+            seen += left.weight;
+        }
+        let t = (target - (self.count - last.weight / 2.0)) / (last.weight / 2.0);
+        Some(lerp(last.mean, self.max, t))
+    }
+
+    /// 把缓冲区中的数据和已有的质心一起排序，按大小限制合并成新的质心
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+
+        let mut all: Vec<_> = self
+            .buffer
+            .drain(..)
+            .map(|mean| Centroid { mean, weight: 1.0 })
+            .chain(self.centroids.drain(..))
+            .collect();
+        all.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+
+        let mut merged: Vec<Centroid> = Vec::with_capacity(all.len());
+        // 当前质心左边界对应的 k 值，质心跨越的 k 不能超过 1
+        let mut seen = 0.0;
+        let mut k_left = self.k(0.0);
+        for c in all {
+            let last = match merged.last_mut() {
+                Some(last) => last,
+                None => {
+                    merged.push(c);
+                    continue;
+                }
+            };
+            let q_right = (seen + last.weight + c.weight) / self.count;
+            if self.k(q_right) - k_left <= 1.0 {
+                let weight = last.weight + c.weight;
+                last.mean += (c.mean - last.mean) * c.weight / weight;
+                last.weight = weight;
+            } else {
+                seen += last.weight;
+                k_left = self.k(seen / self.count);
+                merged.push(c);
+            }
+        }
+        self.centroids = merged;
+    }
+
+    /// scale function k1：q 接近 0 和 1 时变化得快，那里的质心就小
+    fn k(&self, q: f64) -> f64 {
+        self.compression / (2.0 * PI) * (2.0 * q.clamp(0.0, 1.0) - 1.0).asin()
+    }
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t.clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tdigest_should_estimate_uniform_quantiles() {
+        let mut digest = TDigest::default();
+        // 打乱顺序加入 0..100000，分位数 q 的真实值约为 q * 100000
+        for i in 0..100_000u64 {
+            digest.add(((i * 7919) % 100_000) as f64);
+        }
+        assert!(digest.centroids.len() < 1000);
+        for q in [0.01, 0.5, 0.9, 0.99] {
+            let v = digest.quantile(q).unwrap();
+            let expected = q * 100_000.0;
+            assert!((v - expected).abs() < 500.0, "q {}: {}", q, v);
+        }
+        assert_eq!(digest.quantile(0.0), Some(0.0));
+        assert_eq!(digest.quantile(1.0), Some(99_999.0));
+
+        assert_eq!(TDigest::default().quantile(0.5), None);
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use super::Service;
+use crate::{KvError, Storage, TenantStore};
+
+type BuildFn<S> = dyn Fn(TenantStore<S>) -> Service<TenantStore<S>> + Send + Sync;
+
+/// 每个租户一个 Service，所有的租户共享同一个底层的存储
+///
+/// 同一个租户的所有连接使用同一个 Service，缓存、订阅和等待都在这些连接之间共享；
+/// 租户通常是客户端证书的 CN（见 TlsServerAcceptor::peer_common_name）
+pub struct TenantServices<S> {
+    store: Arc<S>,
+    build: Arc<BuildFn<S>>,
+    services: Arc<DashMap<String, Service<TenantStore<S>>>>,
+}
+
+impl<S> Clone for TenantServices<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store: Arc::clone(&self.store),
+            build: Arc::clone(&self.build),
+            services: Arc::clone(&self.services),
+        }
+    }
+}
+
+impl<S: Storage> TenantServices<S> {
+    /// build 为第一次出现的租户创建 Service，比如打开缓存、注册回调
+    pub fn new(
+        store: S,
+        build: impl Fn(TenantStore<S>) -> Service<TenantStore<S>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            store: Arc::new(store),
+            build: Arc::new(build),
+            services: Default::default(),
+        }
+    }
+
+    /// 租户对应的 Service，第一次用到时创建，租户的名字不合法时返回错误
+    pub fn get(&self, tenant: &str) -> Result<Service<TenantStore<S>>, KvError> {
+        if let Some(service) = self.services.get(tenant) {
+            return Ok(service.clone());
+        }
+        let store = TenantStore::new(self.store.clone(), tenant)?;
+        let service = self
+            .services
+            .entry(tenant.into())
+            .or_insert_with(|| (self.build)(store));
+        Ok(service.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, MemTable, ServiceInner};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn tenants_should_have_their_own_tables() {
+        let services =
+            TenantServices::new(MemTable::new(), |store| ServiceInner::new(store).into());
+        let s1 = services.get("tenant1").unwrap();
+        let s2 = services.get("tenant2").unwrap();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_eq!(s1.execute(cmd).next().await.unwrap().status, 200);
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let data = s2.execute(cmd).next().await.unwrap();
+        assert_eq!(data.status, 404);
+
+        // 同一个租户的连接共享同一个 Service
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let data = services.get("tenant1").unwrap().execute(cmd).next().await;
+        assert_eq!(data.unwrap().values, vec!["v1".into()]);
+
+        assert!(services.get("a/b").is_err());
+    }
+}
@@ -3,7 +3,7 @@ use std::sync::{
     atomic::{AtomicU32, Ordering},
     Arc,
 };
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Mutex};
 use tracing::{debug, info, warn};
 
 use crate::{CommandResponse, KvError, Value};
@@ -35,6 +35,8 @@ pub struct Broadcaster {
     topics: DashMap<String, DashSet<u32>>,
     /// 所有的订阅列表
     subscriptions: DashMap<u32, mpsc::Sender<Arc<CommandResponse>>>,
+    /// 正在写入并发布的 topic，见 write_and_publish
+    publishing: DashMap<String, Arc<Mutex<()>>>,
 }
 
 impl Topic for Arc<Broadcaster> {
@@ -76,38 +78,64 @@ impl Topic for Arc<Broadcaster> {
     }
 
     fn publish(self, name: String, value: Arc<CommandResponse>) {
-        tokio::spawn(async move {
-            let mut ids = vec![];
-            if let Some(topic) = self.topics.get(&name) {
-                // 复制整个 topic 下所有的 subscription id
-                // 这里我们每个 id 是 u32，如果一个 topic 下有 10k 订阅，复制的成本
-                // 也就是 40k 堆内存（外加一些控制结构），所以效率不算差
-                // 这也是为什么我们用 NEXT_ID 来控制 subscription id 的生成
-
-                let subscriptions = topic.value().clone();
-                // 尽快释放锁
-                drop(topic);
-
-                // 循环发送
-                for id in subscriptions.into_iter() {
-                    if let Some(tx) = self.subscriptions.get(&id) {
-                        if let Err(e) = tx.send(value.clone()).await {
-                            warn!("Publish to {} failed! error: {:?}", id, e);
-                            // client 中断连接
-                            ids.push(id);
-                        }
+        tokio::spawn(async move { self.send_to_subscribers(name, value).await });
+    }
+}
+
+impl Broadcaster {
+    /// 在 topic 的锁里执行 write，再把它返回的数据发给所有的订阅者，每个订阅者都收下之后才返回
+    ///
+    /// 同一个 topic 的写入和发布不会交错，订阅者收到数据的顺序和写入的顺序一致
+    pub(crate) async fn write_and_publish<R>(
+        &self,
+        name: String,
+        write: impl FnOnce() -> (R, Option<Arc<CommandResponse>>),
+    ) -> R {
+        let lock = self.publishing.entry(name.clone()).or_default().clone();
+        let guard = lock.lock().await;
+        let (result, value) = write();
+        if let Some(value) = value {
+            self.send_to_subscribers(name.clone(), value).await;
+        }
+        drop(guard);
+        drop(lock);
+        // 没有别人在等这个 topic 的锁时把它删掉
+        self.publishing
+            .remove_if(&name, |_k, lock| Arc::strong_count(lock) == 1);
+        result
+    }
+
+    async fn send_to_subscribers(&self, name: String, value: Arc<CommandResponse>) {
+        let mut ids = vec![];
+        if let Some(topic) = self.topics.get(&name) {
+            // 复制整个 topic 下所有的 subscription id
+            // 这里我们每个 id 是 u32，如果一个 topic 下有 10k 订阅，复制的成本
+            // 也就是 40k 堆内存（外加一些控制结构），所以效率不算差
+            // 这也是为什么我们用 NEXT_ID 来控制 subscription id 的生成
+
+            let subscriptions = topic.value().clone();
+            // 尽快释放锁
+            drop(topic);
+
+            // 循环发送
+            for id in subscriptions.into_iter() {
+                // 先 clone 出 Sender，等待发送的时候不能拿着 DashMap 的锁
+                let tx = self.subscriptions.get(&id).map(|tx| tx.clone());
+                if let Some(tx) = tx {
+                    if let Err(e) = tx.send(value.clone()).await {
+                        warn!("Publish to {} failed! error: {:?}", id, e);
+                        // client 中断连接
+                        ids.push(id);
                     }
                 }
             }
+        }
 
-            for id in ids {
-                self.remove_subscription(name.clone(), id);
-            }
-        });
+        for id in ids {
+            self.remove_subscription(name.clone(), id);
+        }
     }
-}
 
-impl Broadcaster {
     pub fn remove_subscription(&self, name: String, id: u32) -> Option<u32> {
         if let Some(v) = self.topics.get_mut(&name) {
             // 在 topics 表里找到 topic 的 subscription id，删除
@@ -164,7 +192,7 @@ mod tests {
 
         // 如果 subscriber 取消订阅，则收不到新数据
         let result = b.clone().unsubscribe(lobby.clone(), id1 as _).unwrap();
-        assert_eq!(result, id1 as _);
+        assert_eq!(result, id1 as u32);
 
         // publish
         let v: Value = "world".into();
@@ -0,0 +1,51 @@
+use crate::{command_request::RequestData, CommandRequest, KvError, Kvpair, Value};
+use dashmap::DashMap;
+
+/// 检查写入 table 的 value 是否合法，返回 Err 时写入会被拒绝
+pub type Validator = Box<dyn Fn(&Value) -> Result<(), KvError> + Send + Sync>;
+
+/// 每个 table 的 value 检查
+#[derive(Default)]
+pub struct Validators {
+    tables: DashMap<String, Validator>,
+}
+
+impl Validators {
+    /// 设置 table 的检查，会替换掉之前的检查
+    pub fn set(&self, table: impl Into<String>, validator: Validator) {
+        self.tables.insert(table.into(), validator);
+    }
+
+    /// 检查 Hset/Hmset 要写入的 value，有任何一个不合法整个命令都会被拒绝
+    pub fn check(&self, cmd: &CommandRequest) -> Result<(), KvError> {
+        if self.tables.is_empty() {
+            return Ok(());
+        }
+
+        match &cmd.request_data {
+            Some(RequestData::Hset(param)) => {
+                self.check_pairs(&param.table, param.pair.as_ref().into_iter())
+            }
+            Some(RequestData::Hmset(param)) => self.check_pairs(&param.table, param.pairs.iter()),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_pairs<'a>(
+        &self,
+        table: &str,
+        mut pairs: impl Iterator<Item = &'a Kvpair>,
+    ) -> Result<(), KvError> {
+        let validator = match self.tables.get(table) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        pairs.try_for_each(|pair| {
+            let value = pair.value.clone().unwrap_or_default();
+            validator(&value).map_err(|e| {
+                KvError::ValidationFailed(table.into(), pair.key.clone(), e.to_string())
+            })
+        })
+    }
+}
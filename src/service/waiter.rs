@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tokio::sync::Notify;
+
+/// Hgetblock 在这里等待 key 被写入
+///
+/// 写命令只知道自己修改了哪个 table，不一定知道具体的 key（比如 Hmset、Hreplace），
+/// 所以按 table 唤醒，被唤醒的命令重新读一次自己等待的 key，还不存在就接着等
+#[derive(Debug, Default)]
+pub struct KeyWaiters {
+    tables: DashMap<String, Arc<Notify>>,
+}
+
+impl KeyWaiters {
+    /// 开始等待 table 被修改，返回的 Notify 需要在读 key 之前调用 notified()，才不会漏掉中间的写入
+    pub(crate) fn watch(&self, table: &str) -> Arc<Notify> {
+        match self.tables.get(table) {
+            Some(notify) => notify.clone(),
+            None => self.tables.entry(table.into()).or_default().clone(),
+        }
+    }
+
+    /// 等待结束，没有别人在等待这个 table 时把它删除
+    pub(crate) fn unwatch(&self, table: &str, notify: Arc<Notify>) {
+        drop(notify);
+        self.tables
+            .remove_if(table, |_k, notify| Arc::strong_count(notify) == 1);
+    }
+
+    /// 没有命令在等待
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    /// table 被修改了，唤醒所有在等待它的命令
+    pub(crate) fn wake(&self, table: &str) {
+        if let Some(notify) = self.tables.get(table) {
+            notify.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,648 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, Deref, DerefMut, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use prost::Message;
+
+use super::{
+    is_reserved_table, shard_table_names, take_by_insertion, StorateIter, TABLE_OPTIONS_TABLE,
+};
+use crate::{Clock, KvError, Kvpair, Storage, StoredValue, SystemClock, TxContext, Value};
+
+/// 一个 table 的数据，读写都通过 Deref 直接访问里面的 BTreeMap
+#[derive(Debug, Default)]
+struct Table {
+    entries: BTreeMap<String, StoredValue>,
+    /// 设置了过期时间的 key 最早什么时候过期的下限，还没到这个时间时没有过期的 key，
+    /// u64::MAX 代表没有会过期的 key，0 代表不知道，需要遍历一遍
+    next_expiry: u64,
+}
+
+impl Table {
+    /// 有 key 会在 expire_at 过期，修改了过期时间之后都要调用
+    fn track_expiry(&mut self, expire_at: u64) {
+        if expire_at != 0 {
+            self.next_expiry = self.next_expiry.min(expire_at);
+        }
+    }
+
+    /// 没有过期的 key 的个数，还没有 key 过期时不需要遍历 table；
+    /// 否则先删除过期的 key，再重新计算 next_expiry
+    fn live_len(&mut self, now: u64) -> usize {
+        if self.next_expiry <= now {
+            let mut next = u64::MAX;
+            self.entries.retain(|_k, v| {
+                if v.expire_at != 0 && v.expire_at > now {
+                    next = next.min(v.expire_at);
+                }
+                !v.is_expired(now)
+            });
+            self.next_expiry = next;
+        }
+        self.entries.len()
+    }
+}
+
+impl Deref for Table {
+    type Target = BTreeMap<String, StoredValue>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl DerefMut for Table {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.entries
+    }
+}
+
+/// 使用 BTreeMap 构建的内存存储，key 是有序的，适合范围查询为主的场景
+///
+/// 每个 table 是一个单独的分片，由自己的 RwLock 保护，不同 table 之间的读写互不影响。
+/// 和 MemTable 不同，clone 出来的 BTreeStore 和原来的共享同一份数据
+#[derive(Clone, Debug)]
+pub struct BTreeStore {
+    tables: Arc<DashMap<String, Arc<RwLock<Table>>>>,
+    clock: Arc<dyn Clock>,
+    /// 最后一次写入分配的顺序号
+    seq: Arc<AtomicU64>,
+    /// 最多有多少个 table，None 代表不限制
+    max_tables: Option<usize>,
+    /// 限制了 table 的个数时，创建新的 table 需要先拿到它，避免同时创建的 table 超过上限
+    create_lock: Arc<Mutex<()>>,
+}
+
+impl Default for BTreeStore {
+    fn default() -> Self {
+        Self {
+            tables: Default::default(),
+            clock: Arc::new(SystemClock),
+            seq: Default::default(),
+            max_tables: None,
+            create_lock: Default::default(),
+        }
+    }
+}
+
+impl BTreeStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 使用指定的时钟判断过期
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// 最多允许 n 个 table，写入一个新的 table 会超过上限时返回 KvError::TooManyTables
+    ///
+    /// 已经存在的 table 不受影响；读操作和删除不会创建 table，也就不会占用名额
+    pub fn with_max_tables(mut self, n: usize) -> Self {
+        self.max_tables = Some(n);
+        self
+    }
+
+    /// 按 key 的顺序返回 range 中的 kv pair，只会访问 range 内的 key
+    pub fn scan_range<'a>(
+        &self,
+        table: &str,
+        range: impl RangeBounds<&'a str>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let table = self.table(table);
+        let table = table.read().unwrap();
+        let now = self.clock.now();
+        Ok(table
+            .range::<str, _>(bounds)
+            .filter(|(_k, v)| !v.is_expired(now))
+            .map(|(k, v)| Kvpair::new(k, v.clone().into_value()))
+            .collect())
+    }
+
+    /// 按 key 的顺序返回以 prefix 开头的 kv pair，只会访问以 prefix 开头的 key
+    pub fn scan_prefix(&self, table: &str, prefix: &str) -> Result<Vec<Kvpair>, KvError> {
+        let table = self.table(table);
+        let table = table.read().unwrap();
+        let now = self.clock.now();
+        Ok(prefix_range(&table, prefix)
+            .filter(|(_k, v)| !v.is_expired(now))
+            .map(|(k, v)| Kvpair::new(k, v.clone().into_value()))
+            .collect())
+    }
+
+    /// 分配下一个写入的顺序号
+    fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// 用 pairs 构建一个新的 table，每个 value 都分配顺序号
+    fn new_table(&self, pairs: Vec<Kvpair>) -> Table {
+        let entries = pairs
+            .into_iter()
+            .map(|pair| {
+                let mut v = StoredValue::new(pair.value.unwrap_or_default());
+                v.seq = self.next_seq();
+                (pair.key, v)
+            })
+            .collect();
+        // 新写入的 key 都没有过期时间
+        Table {
+            entries,
+            next_expiry: u64::MAX,
+        }
+    }
+
+    /// 获取名为 name 的 table，不存在时返回一个空的 table，不会创建它
+    fn table(&self, name: &str) -> Arc<RwLock<Table>> {
+        match self.tables.get(name) {
+            Some(table) => table.clone(),
+            None => Default::default(),
+        }
+    }
+
+    /// 获取名为 name 的 table 用于写入，不存在时在 table 个数的上限之内创建它，
+    /// 内部使用的 table 不受上限的限制
+    fn table_mut(&self, name: &str) -> Result<Arc<RwLock<Table>>, KvError> {
+        if let Some(table) = self.tables.get(name) {
+            return Ok(table.clone());
+        }
+        let _lock = match self.max_tables {
+            Some(max) if !is_reserved_table(name) => {
+                let lock = self.create_lock.lock().unwrap();
+                let count = self
+                    .tables
+                    .iter()
+                    .filter(|t| !is_reserved_table(t.key()))
+                    .count();
+                if !self.tables.contains_key(name) && count >= max {
+                    return Err(KvError::TooManyTables(max));
+                }
+                Some(lock)
+            }
+            _ => None,
+        };
+        Ok(self.tables.entry(name.into()).or_default().clone())
+    }
+
+    /// 把事务中的写操作写入 table，调用者需要拿住 table 的写锁
+    fn commit(&self, table: &mut Table, writes: Vec<(String, Option<StoredValue>)>) {
+        for (key, value) in writes {
+            match value {
+                Some(mut v) => {
+                    // 新写入的 value 需要分配顺序号，只修改过期时间的保留原来的
+                    if v.seq == 0 {
+                        v.seq = self.next_seq();
+                    }
+                    table.track_expiry(v.expire_at);
+                    table.insert(key, v)
+                }
+                None => table.remove(&key),
+            };
+        }
+    }
+}
+
+/// 以 prefix 开头的 key 在 BTreeMap 中是连续的，从 prefix 开始遍历，遇到第一个不匹配的 key 就结束
+fn prefix_range<'a>(
+    table: &'a Table,
+    prefix: &'a str,
+) -> impl Iterator<Item = (&'a String, &'a StoredValue)> {
+    table
+        .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+        .take_while(move |(k, _v)| k.starts_with(prefix))
+}
+
+impl Storage for BTreeStore {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let table = self.table(table);
+        let table = table.read().unwrap();
+        let now = self.clock.now();
+        Ok(table
+            .get(key)
+            .filter(|v| !v.is_expired(now))
+            .map(|v| v.clone().into_value()))
+    }
+
+    fn set(
+        &self,
+        table: &str,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let table = self.table_mut(table)?;
+        let mut table = table.write().unwrap();
+        let now = self.clock.now();
+        let mut stored = StoredValue::new(value);
+        stored.seq = self.next_seq();
+        let old = table.insert(key.into(), stored);
+        Ok(old.filter(|v| !v.is_expired(now)).map(|v| v.into_value()))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.get(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let table = self.table(table);
+        let mut table = table.write().unwrap();
+        let now = self.clock.now();
+        Ok(table
+            .remove(key)
+            .filter(|v| !v.is_expired(now))
+            .map(|v| v.into_value()))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.scan_range(table, ..)
+    }
+
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        // 读锁不能跨越 Iterator 的生命周期，先把数据取出来
+        let pairs = self.get_all(table)?;
+        Ok(Box::new(StorateIter::new(pairs.into_iter())))
+    }
+
+    fn sorted_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        // get_all 按 BTreeMap 的顺序返回，已经排好序了
+        self.get_iter(table)
+    }
+
+    fn keys(&self, table: &str, prefix: &str) -> Result<Vec<String>, KvError> {
+        let table = self.table(table);
+        let table = table.read().unwrap();
+        let now = self.clock.now();
+        Ok(prefix_range(&table, prefix)
+            .filter(|(_k, v)| !v.is_expired(now))
+            .map(|(k, _v)| k.clone())
+            .collect())
+    }
+
+    fn drain(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let table = self.table(table);
+        // 拿住写锁把整个 table 换成空的，drain 期间的写入会等到替换完成之后
+        let old = std::mem::take(&mut *table.write().unwrap());
+        let now = self.clock.now();
+        Ok(old
+            .entries
+            .into_iter()
+            .filter(|(_k, v)| !v.is_expired(now))
+            .map(|(k, v)| Kvpair::new(k, v.into_value()))
+            .collect())
+    }
+
+    fn replace(&self, table: &str, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        let new = self.new_table(pairs);
+        // 拿住写锁整个换掉，读操作不会看到替换到一半的 table
+        *self.table_mut(table)?.write().unwrap() = new;
+        Ok(())
+    }
+
+    fn by_insertion(&self, table: &str, n: usize, newest: bool) -> Result<Vec<Kvpair>, KvError> {
+        let table = self.table(table);
+        let table = table.read().unwrap();
+        let now = self.clock.now();
+        let entries = table
+            .iter()
+            .filter(|(_k, v)| !v.is_expired(now))
+            .map(|(k, v)| (v.seq, k.clone(), v.clone().into_value()))
+            .collect();
+        Ok(take_by_insertion(entries, n, newest))
+    }
+
+    fn count(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        let table = self.table(table);
+        let table = table.read().unwrap();
+        let now = self.clock.now();
+        Ok(prefix_range(&table, prefix)
+            .filter(|(_k, v)| !v.is_expired(now))
+            .count())
+    }
+
+    fn table_names(&self) -> Result<Vec<String>, KvError> {
+        let mut names: Vec<_> = self.table_iter()?.collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn table_iter(&self) -> Result<Box<dyn Iterator<Item = String> + '_>, KvError> {
+        Ok(Box::new(shard_table_names(&self.tables)))
+    }
+
+    fn rename_table(&self, from: &str, to: &str, overwrite: bool) -> Result<(), KvError> {
+        if from == to {
+            return match self.tables.contains_key(from) {
+                true => Ok(()),
+                false => Err(KvError::NotFound(format!("table {}", from))),
+            };
+        }
+
+        let (_k, table) = self
+            .tables
+            .remove(from)
+            .ok_or_else(|| KvError::NotFound(format!("table {}", from)))?;
+        let rejected = match self.tables.entry(to.into()) {
+            Entry::Occupied(_) if !overwrite => Some(table),
+            Entry::Occupied(mut entry) => {
+                entry.insert(table);
+                None
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(table);
+                None
+            }
+        };
+        // entry 的锁已经释放了，可以把 table 放回原来的位置
+        match rejected {
+            Some(table) => {
+                self.tables.insert(from.into(), table);
+                Err(KvError::TableExists(to.into()))
+            }
+            None => {
+                // table 的配置跟着一起改名，from 没有配置时 to 也使用缺省的配置
+                if let Some(config) = self.tables.get(TABLE_OPTIONS_TABLE) {
+                    let mut config = config.write().unwrap();
+                    match config.remove(from) {
+                        Some(options) => config.insert(to.into(), options),
+                        None => config.remove(to),
+                    };
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn move_keys<F>(&self, from: &str, to: &str, pick: F) -> Result<usize, KvError>
+    where
+        F: Fn(&str, Option<Duration>) -> bool,
+    {
+        if from == to {
+            let msg = format!("Cannot move keys of table {} into itself", from);
+            return Err(KvError::InvalidCommand(msg));
+        }
+
+        // 按名字的顺序拿住两个 table 的写锁，读操作不会看到移动到一半的数据
+        let (source, target) = (self.table(from), self.table_mut(to)?);
+        let (mut source, mut target) = match from < to {
+            true => {
+                let source = source.write().unwrap();
+                (source, target.write().unwrap())
+            }
+            false => {
+                let target = target.write().unwrap();
+                (source.write().unwrap(), target)
+            }
+        };
+
+        let now = self.clock.now();
+        let picked: Vec<_> = source
+            .iter()
+            .filter(|(k, v)| !v.is_expired(now) && pick(k, v.ttl(now)))
+            .map(|(k, _v)| k.clone())
+            .collect();
+        for key in &picked {
+            if let Some(mut v) = source.remove(key) {
+                v.expire_at = 0;
+                v.seq = self.next_seq();
+                target.insert(key.clone(), v);
+            }
+        }
+        Ok(picked.len())
+    }
+
+    fn expire_table(&self, table: &str, ttl: Duration) -> Result<usize, KvError> {
+        let table = self.table(table);
+        let mut table = table.write().unwrap();
+        let now = self.clock.now();
+        let mut count = 0;
+        let mut next_expiry = 0;
+        for v in table.values_mut() {
+            if !v.is_expired(now) {
+                v.expire_in(now, ttl);
+                next_expiry = v.expire_at;
+                count += 1;
+            }
+        }
+        table.track_expiry(next_expiry);
+        Ok(count)
+    }
+
+    fn reap_expired(&self, table: &str, remove: bool) -> Result<usize, KvError> {
+        let table = self.table(table);
+        let now = self.clock.now();
+        if !remove {
+            let table = table.read().unwrap();
+            return Ok(table.values().filter(|v| v.is_expired(now)).count());
+        }
+
+        let mut table = table.write().unwrap();
+        let before = table.len();
+        table.retain(|_k, v| !v.is_expired(now));
+        Ok(before - table.len())
+    }
+
+    fn info(&self) -> Result<Vec<Kvpair>, KvError> {
+        let mut entries = 0;
+        let mut bytes = 0;
+        for table in self.tables.iter() {
+            let table = table.read().unwrap();
+            entries += table.len();
+            // 只计算 key 和 encode 之后的 value，不包括 BTreeMap 自身的开销
+            bytes += table
+                .iter()
+                .map(|(k, v)| k.len() + v.encoded_len())
+                .sum::<usize>();
+        }
+
+        Ok(vec![
+            Kvpair::new("backend", "btree".into()),
+            Kvpair::new("tables", (self.tables.len() as i64).into()),
+            Kvpair::new("entries", (entries as i64).into()),
+            Kvpair::new("estimated_bytes", (bytes as i64).into()),
+        ])
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        // 数据只在内存中，没有可以落盘的
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<(), KvError> {
+        // 内存中的数据没有什么可以压缩的
+        Ok(())
+    }
+
+    fn create_table(&self, table: &str, pairs: Vec<Kvpair>) -> Result<bool, KvError> {
+        let new = self.new_table(pairs);
+        let table = self.table_mut(table)?;
+        let mut table = table.write().unwrap();
+        let now = self.clock.now();
+        if table.values().any(|v| !v.is_expired(now)) {
+            return Ok(false);
+        }
+        *table = new;
+        Ok(true)
+    }
+
+    fn set_with_len(&self, table: &str, key: &str, value: Value) -> Result<usize, KvError> {
+        let table = self.table_mut(table)?;
+        let mut table = table.write().unwrap();
+        let mut stored = StoredValue::new(value);
+        stored.seq = self.next_seq();
+        table.insert(key.into(), stored);
+        Ok(table.live_len(self.clock.now()))
+    }
+
+    fn get_many(&self, reads: &[(String, String)]) -> Result<Vec<Option<Value>>, KvError> {
+        // 按名字的顺序拿住所有涉及的 table 的读锁，读完之前这些 table 都不会被修改
+        let mut names: Vec<_> = reads.iter().map(|(table, _k)| table.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        let tables: Vec<_> = names.iter().map(|name| self.table(name)).collect();
+        let guards: Vec<_> = tables.iter().map(|t| t.read().unwrap()).collect();
+
+        let now = self.clock.now();
+        Ok(reads
+            .iter()
+            .map(|(table, key)| {
+                let i = names.binary_search(&table.as_str()).unwrap();
+                guards[i]
+                    .get(key)
+                    .filter(|v| !v.is_expired(now))
+                    .map(|v| v.clone().into_value())
+            })
+            .collect())
+    }
+
+    fn read_transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        // 拿住 table 的读锁，执行期间 table 不会被修改，其他的读操作也不受影响
+        let table = self.table(table);
+        let table = table.read().unwrap();
+        let reader = |key: &str| Ok(table.get(key).cloned());
+        let mut tx = TxContext::new(&reader, self.clock.now());
+        let result = f(&mut tx)?;
+        match tx.writes().is_empty() {
+            true => Ok(result),
+            false => Err(KvError::ReadOnly),
+        }
+    }
+
+    fn transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        // table 不存在时先在空的 table 上试着执行一次，只读的事务不会创建 table，也不会占用名额
+        if !self.tables.contains_key(table) {
+            let reader = |_key: &str| Ok(None);
+            let mut tx = TxContext::new(&reader, self.clock.now());
+            let result = f(&mut tx)?;
+            if tx.writes().is_empty() {
+                return Ok(result);
+            }
+        }
+
+        // 拿住 table 的写锁，事务执行期间其他人无法访问这个 table
+        let table = self.table_mut(table)?;
+        let mut table = table.write().unwrap();
+        let (result, writes) = {
+            let reader = |key: &str| Ok(table.get(key).cloned());
+            let mut tx = TxContext::new(&reader, self.clock.now());
+            let result = f(&mut tx)?;
+            (result, tx.into_writes())
+        };
+        self.commit(&mut table, writes);
+        Ok(result)
+    }
+
+    fn transaction_pair<F, R>(&self, first: &str, second: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext, &mut TxContext) -> Result<R, KvError>,
+    {
+        if first == second {
+            let msg = format!("Cannot run a transaction on table {} twice", first);
+            return Err(KvError::InvalidCommand(msg));
+        }
+
+        // 按名字的顺序拿住两个 table 的写锁，事务执行期间其他人无法访问这两个 table
+        let (lock_first, lock_second) = (self.table_mut(first)?, self.table_mut(second)?);
+        let (mut table_first, mut table_second) = match first < second {
+            true => {
+                let table_first = lock_first.write().unwrap();
+                (table_first, lock_second.write().unwrap())
+            }
+            false => {
+                let table_second = lock_second.write().unwrap();
+                (lock_first.write().unwrap(), table_second)
+            }
+        };
+        let now = self.clock.now();
+        let (result, writes_first, writes_second) = {
+            let read_first = |key: &str| Ok(table_first.get(key).cloned());
+            let read_second = |key: &str| Ok(table_second.get(key).cloned());
+            let mut tx_first = TxContext::new(&read_first, now);
+            let mut tx_second = TxContext::new(&read_second, now);
+            let result = f(&mut tx_first, &mut tx_second)?;
+            (result, tx_first.into_writes(), tx_second.into_writes())
+        };
+        self.commit(&mut table_first, writes_first);
+        self.commit(&mut table_second, writes_second);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_range_should_work() {
+        let store = BTreeStore::new();
+        for i in 0..1000 {
+            store.set("t1", format!("k{:04}", i), i as i64).unwrap();
+        }
+
+        // [start, end)，结果按 key 排序
+        let pairs = store.scan_range("t1", "k0010".."k0013").unwrap();
+        let expected: Vec<_> = (10..13)
+            .map(|i| Kvpair::new(format!("k{:04}", i), (i as i64).into()))
+            .collect();
+        assert_eq!(pairs, expected);
+
+        let pairs = store.scan_range("t1", "k0998"..).unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert!(store.scan_range("t1", "x"..).unwrap().is_empty());
+        assert!(store.scan_range("t2", ..).unwrap().is_empty());
+    }
+
+    #[test]
+    fn scan_prefix_should_work() {
+        let store = BTreeStore::new();
+        store.set("t1", "u1", "v1").unwrap();
+        store.set("t1", "u2", "v2").unwrap();
+        store.set("t1", "o1", "v3").unwrap();
+        store.set("t1", "v1", "v4").unwrap();
+
+        let pairs = store.scan_prefix("t1", "u").unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                Kvpair::new("u1", "v1".into()),
+                Kvpair::new("u2", "v2".into())
+            ]
+        );
+        assert_eq!(store.scan_prefix("t1", "").unwrap().len(), 4);
+    }
+}
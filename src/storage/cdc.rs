@@ -0,0 +1,720 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use prost::Message;
+use sled::{Db, Tree};
+
+use crate::{
+    Change, ChangeOp, KvError, Kvpair, Storage, StoredValue, TableOptions, TxContext, Value,
+};
+
+/// meta tree 中记录已经被截断的位置：小于它的 offset 都已经被删除了
+const TRUNCATED: &[u8] = b"truncated";
+
+/// 持久化的 op log，按 offset 的顺序记录所有的修改，offset 从 0 开始递增
+///
+/// 数据保存在 sled 中，重新打开之后 offset 接着之前的继续分配。
+/// 修改先写入 op log（write-ahead），真正生效之后才对读取可见；没有生效的修改会被删除
+pub struct OpLog {
+    db: Db,
+    meta: Tree,
+    state: Mutex<LogState>,
+    /// 最多保留多少个修改，None 代表全部保留
+    retention: Option<u64>,
+}
+
+struct LogState {
+    /// 下一个修改的 offset
+    next: u64,
+    /// 已经写入 op log、但还没有生效的修改，offset 不小于其中最小值的修改都不可见
+    pending: BTreeSet<u64>,
+    /// 小于它的 offset 已经被截断
+    truncated: u64,
+}
+
+impl OpLog {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let db = sled::open(path)?;
+        let meta = db.open_tree("meta")?;
+        let truncated = match meta.get(TRUNCATED)? {
+            Some(v) => decode_offset(&v)?,
+            None => 0,
+        };
+        let next = match db.last()? {
+            Some((k, _v)) => decode_offset(&k)? + 1,
+            None => 0,
+        };
+        Ok(Self {
+            db,
+            meta,
+            state: Mutex::new(LogState {
+                next: next.max(truncated),
+                pending: BTreeSet::new(),
+                truncated,
+            }),
+            retention: None,
+        })
+    }
+
+    /// 最多保留最近的 n 个修改，更早的修改在写入新的修改时被删除，n 必须大于 0
+    pub fn with_retention(mut self, n: u64) -> Self {
+        self.retention = Some(n.max(1));
+        self
+    }
+
+    /// 下一个修改会被分配的 offset
+    pub fn next_offset(&self) -> u64 {
+        self.state.lock().unwrap().next
+    }
+
+    /// 追加一个修改并落盘，返回分配给它的 offset；调用 commit 之前它对读取不可见
+    fn append(
+        &self,
+        op: ChangeOp,
+        table: &str,
+        key: &str,
+        value: Option<Value>,
+    ) -> Result<u64, KvError> {
+        let mut state = self.state.lock().unwrap();
+        let change = Change {
+            offset: state.next,
+            op: op as i32,
+            table: table.into(),
+            key: key.into(),
+            value,
+        };
+        // offset 用大端序作为 key，sled 中 key 的顺序就是 offset 的顺序
+        self.db
+            .insert(change.offset.to_be_bytes(), change.encode_to_vec())?;
+        self.db.flush()?;
+        state.next += 1;
+        state.pending.insert(change.offset);
+        Ok(change.offset)
+    }
+
+    /// 修改已经生效，可以被读到了
+    fn commit(&self, offsets: &[u64]) -> Result<(), KvError> {
+        let mut state = self.state.lock().unwrap();
+        for offset in offsets {
+            state.pending.remove(offset);
+        }
+        self.truncate(&mut state)
+    }
+
+    /// 修改没有生效，从 op log 中删除；最后分配的 offset 会被重新使用
+    fn abort(&self, offsets: &[u64]) -> Result<(), KvError> {
+        let mut state = self.state.lock().unwrap();
+        for offset in offsets.iter().rev() {
+            self.db.remove(offset.to_be_bytes())?;
+            state.pending.remove(offset);
+            if *offset + 1 == state.next {
+                state.next -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// 超过保留的个数时删除最早的修改
+    fn truncate(&self, state: &mut LogState) -> Result<(), KvError> {
+        let retention = match self.retention {
+            Some(n) if state.next > state.truncated + n => n,
+            _ => return Ok(()),
+        };
+        let truncated = state.next - retention;
+        self.meta.insert(TRUNCATED, &truncated.to_be_bytes())?;
+        for k in self.db.range(..truncated.to_be_bytes()).keys() {
+            self.db.remove(k?)?;
+        }
+        state.truncated = truncated;
+        Ok(())
+    }
+
+    /// 按顺序读取 offset 不小于 from 的修改，最多 limit 个，0 代表不限制
+    ///
+    /// from 对应的修改已经被截断时返回错误，调用者需要重新同步全部数据
+    pub fn read_from(&self, from: u64, limit: usize) -> Result<Vec<Change>, KvError> {
+        let limit = match limit {
+            0 => usize::MAX,
+            n => n,
+        };
+        let visible = {
+            let state = self.state.lock().unwrap();
+            if from < state.truncated {
+                return Err(KvError::InvalidCommand(format!(
+                    "Offset {} has been truncated, the oldest offset is {}",
+                    from, state.truncated
+                )));
+            }
+            state.pending.iter().next().copied().unwrap_or(state.next)
+        };
+        self.db
+            .range(from.to_be_bytes()..visible.max(from).to_be_bytes())
+            .take(limit)
+            .map(|v| Ok(Change::decode(v?.1.as_ref())?))
+            .collect()
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+fn decode_offset(key: &[u8]) -> Result<u64, KvError> {
+    let bytes = key
+        .try_into()
+        .map_err(|_| KvError::ConvertError(format!("{:?}", key), "offset"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// 把所有的修改记录到 OpLog 的存储，用于 Cdc 命令提供可以断点续传的 change feed
+///
+/// 每个修改先写入 op log 并落盘，再写入存储，写入失败时从 op log 中删除，所以不会丢失修改；
+/// 如果在两者之间 crash，op log 中可能多出一个没有生效的修改。
+/// 同一个 table 的写操作是串行执行的，op log 中的顺序和它们真正生效的顺序一致，不同的 table 互不影响。
+/// 只记录 key 的写入和删除：过期时间不会被记录，expire_table 和 reap_expired 也不会产生修改
+pub struct CdcStore<S> {
+    inner: S,
+    log: OpLog,
+    locks: DashMap<String, Arc<Mutex<()>>>,
+}
+
+impl<S: Storage> CdcStore<S> {
+    pub fn new(inner: S, log: OpLog) -> Self {
+        Self {
+            inner,
+            log,
+            locks: DashMap::new(),
+        }
+    }
+
+    /// 按名字的顺序拿住 tables 的锁，然后执行 f
+    fn with_tables<R>(
+        &self,
+        tables: &[&str],
+        f: impl FnOnce() -> Result<R, KvError>,
+    ) -> Result<R, KvError> {
+        let mut names = tables.to_vec();
+        names.sort_unstable();
+        names.dedup();
+        let locks: Vec<_> = names
+            .iter()
+            .map(|name| self.locks.entry(name.to_string()).or_default().clone())
+            .collect();
+        let guards: Vec<_> = locks.iter().map(|lock| lock.lock().unwrap()).collect();
+        let result = f();
+        drop(guards);
+
+        // 没有别人在用的锁删掉，table 很多时不会一直占着内存
+        for (name, lock) in names.iter().zip(locks) {
+            drop(lock);
+            self.locks
+                .remove_if(*name, |_k, lock| Arc::strong_count(lock) == 1);
+        }
+        result
+    }
+
+    /// 先把 changes 写入 op log，再执行 f，f 成功之后 changes 才可见，失败时删除它们
+    ///
+    /// f 返回的 bool 为 false 时代表没有修改任何数据，changes 同样会被删除
+    fn logged<R>(
+        &self,
+        changes: Vec<PendingChange>,
+        f: impl FnOnce() -> Result<(R, bool), KvError>,
+    ) -> Result<R, KvError> {
+        let mut offsets = Vec::with_capacity(changes.len());
+        for (op, table, key, value) in changes {
+            match self.log.append(op, table, key, value) {
+                Ok(offset) => offsets.push(offset),
+                Err(e) => {
+                    self.log.abort(&offsets)?;
+                    return Err(e);
+                }
+            }
+        }
+        match f() {
+            Ok((result, true)) => {
+                self.log.commit(&offsets)?;
+                Ok(result)
+            }
+            Ok((result, false)) => {
+                self.log.abort(&offsets)?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.log.abort(&offsets)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// 把事务中修改了 value 的写操作写入 op log，offset 记在 offsets 里
+    fn log_writes(
+        &self,
+        table: &str,
+        tx: &TxContext,
+        offsets: &RefCell<Vec<u64>>,
+    ) -> Result<(), KvError> {
+        for (key, value) in tx.writes().iter().filter(|(_, v)| is_value_write(v)) {
+            let (op, value) = match value {
+                Some(v) => (ChangeOp::Set, Some(v.clone().into_value())),
+                None => (ChangeOp::Del, None),
+            };
+            let offset = self.log.append(op, table, key, value)?;
+            offsets.borrow_mut().push(offset);
+        }
+        Ok(())
+    }
+
+    /// 事务提交了就让 op log 中它的修改可见，否则删除它们
+    fn settle<R>(&self, result: Result<R, KvError>, offsets: &[u64]) -> Result<R, KvError> {
+        match result {
+            Ok(result) => {
+                self.log.commit(offsets)?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.log.abort(offsets)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+type PendingChange<'a> = (ChangeOp, &'a str, &'a str, Option<Value>);
+
+fn set_change<'a>(table: &'a str) -> impl Fn(&'a Kvpair) -> PendingChange<'a> + 'a {
+    move |p| {
+        let value = p.value.clone().unwrap_or_default();
+        (ChangeOp::Set, table, p.key.as_str(), Some(value))
+    }
+}
+
+/// 事务中修改了 value 的写操作：只修改过期时间、内容类型的写操作会保留原来的顺序号，不算修改
+fn is_value_write(v: &Option<StoredValue>) -> bool {
+    !matches!(v, Some(v) if v.seq != 0)
+}
+
+impl<S: Storage> Storage for CdcStore<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(
+        &self,
+        table: &str,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let (key, value) = (key.into(), value.into());
+        self.with_tables(&[table], || {
+            let changes = vec![(ChangeOp::Set, table, key.as_str(), Some(value.clone()))];
+            self.logged(changes, || {
+                Ok((self.inner.set(table, key.as_str(), value)?, true))
+            })
+        })
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.with_tables(&[table], || {
+            // 不存在的 key 删除不会产生修改，先检查一下，避免 offset 被占用之后又被删除
+            if !self.inner.contains(table, key)? {
+                return self.inner.del(table, key);
+            }
+            let changes = vec![(ChangeOp::Del, table, key, None)];
+            self.logged(changes, || {
+                let old = self.inner.del(table, key)?;
+                let deleted = old.is_some();
+                Ok((old, deleted))
+            })
+        })
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn sorted_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        self.inner.sorted_iter(table)
+    }
+
+    fn keys(&self, table: &str, prefix: &str) -> Result<Vec<String>, KvError> {
+        self.inner.keys(table, prefix)
+    }
+
+    fn drain(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.with_tables(&[table], || {
+            let keys = self.inner.keys(table, "")?;
+            let changes = keys
+                .iter()
+                .map(|k| (ChangeOp::Del, table, k.as_str(), None))
+                .collect();
+            self.logged(changes, || Ok((self.inner.drain(table)?, true)))
+        })
+    }
+
+    fn replace(&self, table: &str, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        self.with_tables(&[table], || {
+            let old = self.inner.keys(table, "")?;
+            let new: HashSet<_> = pairs.iter().map(|p| p.key.as_str()).collect();
+            // 替换之后不存在的 key 记录为删除，其余的记录为写入
+            let changes = old
+                .iter()
+                .filter(|k| !new.contains(k.as_str()))
+                .map(|k| (ChangeOp::Del, table, k.as_str(), None))
+                .chain(pairs.iter().map(set_change(table)))
+                .collect();
+            self.logged(changes, || {
+                Ok((self.inner.replace(table, pairs.clone())?, true))
+            })
+        })
+    }
+
+    fn by_insertion(&self, table: &str, n: usize, newest: bool) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.by_insertion(table, n, newest)
+    }
+
+    fn count(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        self.inner.count(table, prefix)
+    }
+
+    fn table_names(&self) -> Result<Vec<String>, KvError> {
+        self.inner.table_names()
+    }
+
+    fn table_iter(&self) -> Result<Box<dyn Iterator<Item = String> + '_>, KvError> {
+        self.inner.table_iter()
+    }
+
+    fn rename_table(&self, from: &str, to: &str, overwrite: bool) -> Result<(), KvError> {
+        if from == to {
+            return self.inner.rename_table(from, to, overwrite);
+        }
+        self.with_tables(&[from, to], || {
+            let pairs = self.inner.get_all(from)?;
+            let replaced = match overwrite {
+                true => self.inner.keys(to, "")?,
+                false => Vec::new(),
+            };
+
+            // 改名相当于删除 from 中所有的 key，再把它们写入 to
+            let changes = replaced
+                .iter()
+                .map(|k| (ChangeOp::Del, to, k.as_str(), None))
+                .chain(pairs.iter().flat_map(|p| {
+                    [
+                        (ChangeOp::Del, from, p.key.as_str(), None),
+                        set_change(to)(p),
+                    ]
+                }))
+                .collect();
+            self.logged(changes, || {
+                Ok((self.inner.rename_table(from, to, overwrite)?, true))
+            })
+        })
+    }
+
+    fn move_keys<F>(&self, from: &str, to: &str, pick: F) -> Result<usize, KvError>
+    where
+        F: Fn(&str, Option<Duration>) -> bool,
+    {
+        if from == to {
+            return self.inner.move_keys(from, to, pick);
+        }
+        self.with_tables(&[from, to], || {
+            // 先找出要移动的 key 写入 op log，再只移动这些 key；
+            // 期间过期的 key 不会被移动，op log 中可能多出它们的修改
+            let keys = self.inner.keys(from, "")?;
+            let picked = self.inner.transaction(from, |tx| {
+                let mut picked = Vec::new();
+                for key in &keys {
+                    match (tx.ttl(key)?, tx.get(key)?) {
+                        (Some(ttl), Some(v)) if pick(key, ttl) => picked.push(Kvpair::new(key, v)),
+                        _ => {}
+                    }
+                }
+                Ok(picked)
+            })?;
+            let names: HashSet<_> = picked.iter().map(|p| p.key.as_str()).collect();
+
+            let changes = picked
+                .iter()
+                .flat_map(|p| {
+                    [
+                        (ChangeOp::Del, from, p.key.as_str(), None),
+                        set_change(to)(p),
+                    ]
+                })
+                .collect();
+            self.logged(changes, || {
+                let moved = self
+                    .inner
+                    .move_keys(from, to, |k, ttl| names.contains(k) && pick(k, ttl))?;
+                Ok((moved, moved > 0))
+            })
+        })
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.inner.flush()?;
+        self.log.flush()
+    }
+
+    fn info(&self) -> Result<Vec<Kvpair>, KvError> {
+        let mut info = self.inner.info()?;
+        let next = self.log.next_offset() as i64;
+        info.push(Kvpair::new("cdc_next_offset", next.into()));
+        Ok(info)
+    }
+
+    fn compact(&self) -> Result<(), KvError> {
+        self.inner.compact()
+    }
+
+    fn transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        self.with_tables(&[table], || {
+            // f 可能被执行多次，只保留最后一次（也就是真正提交的那一次）写入 op log 的修改
+            let offsets = RefCell::new(Vec::new());
+            let result = self.inner.transaction(table, |tx| {
+                self.log.abort(&offsets.borrow_mut().split_off(0))?;
+                let result = f(tx)?;
+                self.log_writes(table, tx, &offsets)?;
+                Ok(result)
+            });
+            self.settle(result, &offsets.into_inner())
+        })
+    }
+
+    fn transaction_pair<F, R>(&self, first: &str, second: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext, &mut TxContext) -> Result<R, KvError>,
+    {
+        if first == second {
+            return self.inner.transaction_pair(first, second, f);
+        }
+        self.with_tables(&[first, second], || {
+            let offsets = RefCell::new(Vec::new());
+            let result = self
+                .inner
+                .transaction_pair(first, second, |tx_first, tx_second| {
+                    self.log.abort(&offsets.borrow_mut().split_off(0))?;
+                    let result = f(tx_first, tx_second)?;
+                    self.log_writes(first, tx_first, &offsets)?;
+                    self.log_writes(second, tx_second, &offsets)?;
+                    Ok(result)
+                });
+            self.settle(result, &offsets.into_inner())
+        })
+    }
+
+    fn expire_table(&self, table: &str, ttl: Duration) -> Result<usize, KvError> {
+        self.inner.expire_table(table, ttl)
+    }
+
+    fn reap_expired(&self, table: &str, remove: bool) -> Result<usize, KvError> {
+        self.inner.reap_expired(table, remove)
+    }
+
+    fn create_table(&self, table: &str, pairs: Vec<Kvpair>) -> Result<bool, KvError> {
+        self.with_tables(&[table], || {
+            let changes = pairs.iter().map(set_change(table)).collect();
+            self.logged(changes, || {
+                let created = self.inner.create_table(table, pairs.clone())?;
+                Ok((created, created))
+            })
+        })
+    }
+
+    fn set_with_len(&self, table: &str, key: &str, value: Value) -> Result<usize, KvError> {
+        self.with_tables(&[table], || {
+            let changes = vec![(ChangeOp::Set, table, key, Some(value.clone()))];
+            self.logged(changes, || {
+                Ok((self.inner.set_with_len(table, key, value)?, true))
+            })
+        })
+    }
+
+    fn verify(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.verify(table)
+    }
+
+    fn table_options(&self, table: &str) -> Result<TableOptions, KvError> {
+        self.inner.table_options(table)
+    }
+
+    fn set_table_options(
+        &self,
+        table: &str,
+        options: TableOptions,
+    ) -> Result<TableOptions, KvError> {
+        self.inner.set_table_options(table, options)
+    }
+
+    fn get_many(&self, reads: &[(String, String)]) -> Result<Vec<Option<Value>>, KvError> {
+        self.inner.get_many(reads)
+    }
+
+    fn read_transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        // 只读的事务不会产生修改，不需要写 op log
+        self.inner.read_transaction(table, f)
+    }
+
+    fn changes(&self, from_offset: u64, limit: usize) -> Result<Vec<Change>, KvError> {
+        self.log.read_from(from_offset, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+    use tempfile::tempdir;
+
+    #[test]
+    fn changes_should_resume_without_gaps_or_duplicates() {
+        let dir = tempdir().unwrap();
+        let store = CdcStore::new(MemTable::new(), OpLog::open(dir.path()).unwrap());
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        store.del("t1", "k1").unwrap();
+        // 不存在的 key 删除不会产生修改
+        store.del("t1", "none").unwrap();
+
+        let changes = store.changes(0, 0).unwrap();
+        let offsets: Vec<_> = changes.iter().map(|c| c.offset).collect();
+        assert_eq!(offsets, [0, 1, 2]);
+        assert_eq!(changes[2].op(), ChangeOp::Del);
+        assert_eq!(changes[2].key, "k1");
+        let last = changes.last().unwrap().offset;
+
+        // 消费者崩溃期间又有新的修改，重新打开 op log 之后 offset 接着分配
+        drop(store);
+        let store = CdcStore::new(MemTable::new(), OpLog::open(dir.path()).unwrap());
+        store
+            .merge("t1", "n", crate::MergeOp::AddInt, 1.into())
+            .unwrap();
+        store.set("t2", "k3", "v3").unwrap();
+
+        let changes = store.changes(last + 1, 0).unwrap();
+        let offsets: Vec<_> = changes.iter().map(|c| c.offset).collect();
+        assert_eq!(offsets, [3, 4]);
+        assert_eq!(changes[0].value, Some(1.into()));
+        assert_eq!(changes[1].table, "t2");
+
+        assert_eq!(store.changes(1, 2).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn failed_writes_should_not_be_logged() {
+        let dir = tempdir().unwrap();
+        let store = CdcStore::new(MemTable::new(), OpLog::open(dir.path()).unwrap());
+        store.set("t1", "k1", "v1").unwrap();
+
+        let result: Result<(), _> = store.transaction("t1", |tx| {
+            tx.set("k2", "v2")?;
+            Err(KvError::InvalidCommand("abort".into()))
+        });
+        assert!(result.is_err());
+        // table 已经存在，create_table 什么都没有写入
+        let created = store
+            .create_table("t1", vec![Kvpair::new("k3", "v3".into())])
+            .unwrap();
+        assert!(!created);
+        // 只修改过期时间不会产生修改
+        assert!(store.expire("t1", "k1", Duration::from_secs(60)).unwrap());
+
+        let changes = store.changes(0, 0).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].key, "k1");
+        // 没有生效的修改不会占用 offset
+        store.set("t1", "k4", "v4").unwrap();
+        assert_eq!(store.changes(1, 0).unwrap()[0].offset, 1);
+    }
+
+    #[test]
+    fn moved_keys_should_be_logged() {
+        let dir = tempdir().unwrap();
+        let store = CdcStore::new(MemTable::new(), OpLog::open(dir.path()).unwrap());
+        store.set("hot", "k1", "v1").unwrap();
+        store.set("hot", "k2", "v2").unwrap();
+        assert!(store.expire("hot", "k1", Duration::from_secs(60)).unwrap());
+
+        let moved = store.move_keys("hot", "cold", |_k, ttl| ttl.is_some());
+        assert_eq!(moved.unwrap(), 1);
+        let changes = store.changes(2, 0).unwrap();
+        let ops: Vec<_> = changes.iter().map(|c| (c.op(), c.table.as_str())).collect();
+        assert_eq!(ops, [(ChangeOp::Del, "hot"), (ChangeOp::Set, "cold")]);
+        assert_eq!(changes[1].value, Some("v1".into()));
+    }
+
+    #[test]
+    fn old_changes_should_be_truncated() {
+        let dir = tempdir().unwrap();
+        let log = OpLog::open(dir.path()).unwrap().with_retention(2);
+        let store = CdcStore::new(MemTable::new(), log);
+        for i in 0..5 {
+            store.set("t1", format!("k{}", i), i).unwrap();
+        }
+
+        let offsets: Vec<_> = store
+            .changes(3, 0)
+            .unwrap()
+            .iter()
+            .map(|c| c.offset)
+            .collect();
+        assert_eq!(offsets, [3, 4]);
+        // 被截断的 offset 返回错误，而不是悄悄跳过
+        assert!(store.changes(0, 0).is_err());
+
+        // 重新打开之后截断的位置不变
+        drop(store);
+        let store = CdcStore::new(MemTable::new(), OpLog::open(dir.path()).unwrap());
+        assert!(store.changes(2, 0).is_err());
+        assert_eq!(store.changes(3, 0).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn concurrent_writes_to_different_tables_should_all_be_logged() {
+        let dir = tempdir().unwrap();
+        let store = CdcStore::new(MemTable::new(), OpLog::open(dir.path()).unwrap());
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let store = &store;
+                s.spawn(move || {
+                    for i in 0..20 {
+                        store.set(&format!("t{}", t), format!("k{}", i), i).unwrap();
+                    }
+                });
+            }
+        });
+
+        let changes = store.changes(0, 0).unwrap();
+        let offsets: Vec<_> = changes.iter().map(|c| c.offset).collect();
+        assert_eq!(offsets, (0..80).collect::<Vec<_>>());
+        assert!(store.locks.is_empty());
+    }
+}
@@ -0,0 +1,57 @@
+use std::fmt::Debug;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 对时钟的抽象，和过期时间相关的计算都通过它获取当前时间
+pub trait Clock: Debug + Send + Sync + 'static {
+    /// 当前的 unix 时间戳（毫秒）
+    fn now(&self) -> u64;
+}
+
+/// 系统时钟
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+}
+
+/// 可以手动拨动的时钟，测试的时候用它代替 sleep
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    /// 创建一个 MockClock，初始时间为当前的系统时间
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(AtomicU64::new(SystemClock.now())),
+        }
+    }
+
+    /// 把时钟往前拨 d
+    pub fn advance(&self, d: Duration) {
+        self.now.fetch_add(d.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
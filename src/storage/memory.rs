@@ -1,12 +1,107 @@
-use crate::{KvError, Kvpair, Storage, Value};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use crate::{Clock, KvError, Kvpair, Storage, StoredValue, SystemClock, TxContext, Value};
 use dashmap::{mapref::one::Ref, DashMap};
+use prost::Message;
+
+use super::write_queue::{WriteQueues, WriteTurn};
+use super::{
+    is_reserved_table, shard_table_names, take_by_insertion, StorateIter, TABLE_OPTIONS_TABLE,
+};
+
+/// key 被移除的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictReason {
+    /// 已经过期，被 reap_expired、set_with_len 清理掉，或者在读到的时候删除
+    Expired,
+}
+
+/// key 被移除时调用的回调，参数是 table、key、被移除的 value 和原因
+type EvictFn = dyn Fn(&str, &str, &Value, EvictReason) + Send + Sync;
+
+#[derive(Clone)]
+struct EvictHook(Arc<EvictFn>);
+
+impl fmt::Debug for EvictHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EvictHook")
+    }
+}
+
+/// 写操作开始和结束的次数，get_many 用它们判断读的过程中有没有写操作
+#[derive(Debug, Default)]
+struct WriteCounts {
+    started: AtomicU64,
+    finished: AtomicU64,
+}
 
-use super::StorateIter;
+impl WriteCounts {
+    /// 开始修改数据，返回的 Writing 被 drop 时记录修改结束
+    fn begin<G>(&self, guard: G) -> Writing<'_, G> {
+        self.started.fetch_add(1, Ordering::SeqCst);
+        Writing {
+            _guard: guard,
+            counts: self,
+        }
+    }
+}
+
+/// 正在修改数据，同时拿着 generation 的锁
+struct Writing<'a, G> {
+    _guard: G,
+    counts: &'a WriteCounts,
+}
+
+impl<G> Drop for Writing<'_, G> {
+    fn drop(&mut self) {
+        self.counts.finished.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// get_many 先不加锁读几次，一直和写操作撞上时才独占 generation
+const OPTIMISTIC_READS: usize = 3;
 
 /// 使用 DashMap 构建的 MemTable，实现了 Storage trait
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: DashMap<String, DashMap<String, StoredValue>>,
+    clock: Arc<dyn Clock>,
+    /// 最后一次写入分配的顺序号
+    seq: Arc<AtomicU64>,
+    /// 打开之后每个 table 的写操作都要在 table 的队列里排队，一个一个执行
+    writers: Option<Arc<WriteQueues>>,
+    on_evict: Option<EvictHook>,
+    /// 写操作在修改数据时共享这把锁，get_many 一直和写操作撞上时独占它，
+    /// 所以一批读看到的是同一时刻的数据
+    generation: Arc<RwLock<()>>,
+    writes: Arc<WriteCounts>,
+    /// 最多有多少个 table，None 代表不限制
+    max_tables: Option<usize>,
+    /// 限制了 table 的个数时，创建新的 table 需要先拿到它，避免同时创建的 table 超过上限
+    create_lock: Arc<Mutex<()>>,
+    /// 每个 table 中设置了过期时间的 key 最早什么时候过期的下限，还没到这个时间时 table 中
+    /// 没有过期的 key；u64::MAX 代表没有会过期的 key，没有记录时需要遍历一遍 table
+    next_expiry: DashMap<String, u64>,
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self {
+            tables: DashMap::new(),
+            clock: Arc::new(SystemClock),
+            seq: Default::default(),
+            writers: None,
+            on_evict: None,
+            generation: Default::default(),
+            writes: Default::default(),
+            max_tables: None,
+            create_lock: Default::default(),
+            next_expiry: DashMap::new(),
+        }
+    }
 }
 
 impl MemTable {
@@ -15,8 +110,172 @@ impl MemTable {
         Self::default()
     }
 
+    /// 使用指定的时钟判断过期
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// 同一个 table 的写操作按到达的顺序排队，一个一个执行，适合少数几个 table 写得特别多的场景
+    ///
+    /// 读操作不需要排队，直接读取 DashMap；事务执行期间也不再拿住 table 的写锁，
+    /// 只在提交的时候短暂地拿一下，所以同一个 shard 里的其他 table 不会被长事务阻塞
+    pub fn with_write_queues(mut self) -> Self {
+        self.writers = Some(Arc::new(WriteQueues::default()));
+        self
+    }
+
+    /// 注册 key 被移除时的回调，比如把过期的数据转存到别的地方
+    ///
+    /// 过期的 key 在被 get、contains 读到或者被 reap_expired 清理时删除并触发回调，
+    /// 一直没有被访问的 key 需要定期调用 reap_expired 才会触发。
+    /// 回调在释放了 table 的锁之后才调用，所以可以在回调里访问这个 MemTable
+    pub fn on_evict(
+        mut self,
+        f: impl Fn(&str, &str, &Value, EvictReason) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_evict = Some(EvictHook(Arc::new(f)));
+        self
+    }
+
+    /// 最多允许 n 个 table，写入一个新的 table 会超过上限时返回 KvError::TooManyTables
+    ///
+    /// 已经存在的 table 不受影响；读操作和删除不会创建 table，也就不会占用名额
+    pub fn with_max_tables(mut self, n: usize) -> Self {
+        self.max_tables = Some(n);
+        self
+    }
+
+    /// 打开了写队列时，在 table 的队列里等待轮到自己
+    fn write_turn(&self, table: &str) -> Option<WriteTurn> {
+        self.writers.as_ref().map(|w| w.acquire(table))
+    }
+
+    /// 修改数据之前先拿到它，必须在拿 table 的锁之前拿，否则会和 get_many 互相等待
+    fn write_guard(&self) -> Writing<'_, RwLockReadGuard<'_, ()>> {
+        self.writes.begin(self.generation.read().unwrap())
+    }
+
+    /// 独占 generation，修改期间没有别的写操作，get_many 也不会读到修改到一半的数据
+    fn exclusive_guard(&self) -> Writing<'_, RwLockWriteGuard<'_, ()>> {
+        self.writes.begin(self.generation.write().unwrap())
+    }
+
+    /// 读一批 key，返回过期时间还没到的 value
+    fn read_many(&self, reads: &[(String, String)], now: u64) -> Vec<Option<Value>> {
+        reads
+            .iter()
+            .map(|(table, key)| {
+                self.read(table, key)
+                    .filter(|v| !v.is_expired(now))
+                    .map(|v| v.into_value())
+            })
+            .collect()
+    }
+
+    /// 把事务中的写操作写入名为 name 的 table
+    fn commit(
+        &self,
+        name: &str,
+        table: &DashMap<String, StoredValue>,
+        writes: Vec<(String, Option<StoredValue>)>,
+    ) {
+        for (key, value) in writes {
+            match value {
+                Some(mut v) => {
+                    // 新写入的 value 需要分配顺序号，只修改过期时间的保留原来的
+                    if v.seq == 0 {
+                        v.seq = self.next_seq();
+                    }
+                    self.track_expiry(name, v.expire_at);
+                    table.insert(key, v)
+                }
+                None => table.remove(&key).map(|(_k, v)| v),
+            };
+        }
+    }
+
+    /// table 中有 key 会在 expire_at 过期，修改了过期时间之后都要在拿住 table 的时候调用
+    fn track_expiry(&self, table: &str, expire_at: u64) {
+        if expire_at == 0 {
+            return;
+        }
+        if let Some(mut next) = self.next_expiry.get_mut(table) {
+            *next = (*next).min(expire_at);
+        }
+    }
+
+    /// 读取 key 存储的数据，包括已经过期的
+    fn read(&self, table: &str, key: &str) -> Option<StoredValue> {
+        let table = self.tables.get(table)?;
+        let v = table.get(key)?;
+        Some(v.value().clone())
+    }
+
+    /// 读到了过期的 key 时删除它并触发回调，删除之前 key 被重新写入了的话什么都不做
+    fn evict_expired(&self, table: &str, key: &str, now: u64) {
+        let removed = match self.tables.get(table) {
+            Some(t) => t.remove_if(key, |_k, v| v.is_expired(now)),
+            None => return,
+        };
+        if let Some((key, v)) = removed {
+            self.notify_evicted(table, &[(key, v.into_value())], EvictReason::Expired);
+        }
+    }
+
+    /// 调用 key 被移除时的回调，调用之前必须释放 table 的锁
+    fn notify_evicted(&self, table: &str, evicted: &[(String, Value)], reason: EvictReason) {
+        if let Some(EvictHook(f)) = &self.on_evict {
+            for (key, value) in evicted {
+                f(table, key, value, reason);
+            }
+        }
+    }
+
+    /// 分配下一个写入的顺序号
+    fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// 用 pairs 构建一个新的 table，每个 value 都分配顺序号
+    fn new_table(&self, pairs: Vec<Kvpair>) -> DashMap<String, StoredValue> {
+        pairs
+            .into_iter()
+            .map(|pair| {
+                let mut v = StoredValue::new(pair.value.unwrap_or_default());
+                v.seq = self.next_seq();
+                (pair.key, v)
+            })
+            .collect()
+    }
+
+    /// 写入之前确认 table 存在，不存在时在 table 个数的上限之内创建它
+    ///
+    /// 必须在拿到 write_guard 之后调用，这样 rename_table 不会在检查和写入之间把 table 改名，
+    /// 让之后的写入绕过上限重新创建它；内部使用的 table 不受上限的限制
+    fn ensure_table(&self, name: &str) -> Result<(), KvError> {
+        let max = match self.max_tables {
+            Some(max) if !self.tables.contains_key(name) && !is_reserved_table(name) => max,
+            _ => return Ok(()),
+        };
+        let _lock = self.create_lock.lock().unwrap();
+        if self.tables.contains_key(name) {
+            return Ok(());
+        }
+        let count = self
+            .tables
+            .iter()
+            .filter(|t| !is_reserved_table(t.key()))
+            .count();
+        if count >= max {
+            return Err(KvError::TooManyTables(max));
+        }
+        self.tables.insert(name.into(), DashMap::new());
+        Ok(())
+    }
+
     /// 如果名为 name 的 hash table 不存在，则创建，否则返回
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, StoredValue>> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -29,8 +288,14 @@ impl MemTable {
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        let now = self.clock.now();
+        match self.read(table, key) {
+            Some(v) if v.is_expired(now) => {
+                self.evict_expired(table, key, now);
+                Ok(None)
+            }
+            v => Ok(v.map(|v| v.into_value())),
+        }
     }
 
     fn set(
@@ -39,32 +304,509 @@ impl Storage for MemTable {
         key: impl Into<String>,
         value: impl Into<Value>,
     ) -> Result<Option<Value>, KvError> {
+        let _turn = self.write_turn(table);
+        let _guard = self.write_guard();
+        self.ensure_table(table)?;
         let table = self.get_or_create_table(table);
-        Ok(table.insert(key.into(), value.into()))
+        let now = self.clock.now();
+        let mut stored = StoredValue::new(value);
+        stored.seq = self.next_seq();
+        let old = table.insert(key.into(), stored);
+        Ok(old.filter(|v| !v.is_expired(now)).map(|v| v.into_value()))
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.contains_key(key))
+        Ok(self.get(table, key)?.is_some())
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_k, v)| v))
+        let _turn = self.write_turn(table);
+        let _guard = self.write_guard();
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let now = self.clock.now();
+        Ok(table
+            .remove(key)
+            .filter(|(_k, v)| !v.is_expired(now))
+            .map(|(_k, v)| v.into_value()))
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let table = self.get_or_create_table(table);
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+        let now = self.clock.now();
         Ok(table
             .iter()
-            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .filter(|v| !v.value().is_expired(now))
+            .map(|v| Kvpair::new(v.key(), v.value().clone().into_value()))
             .collect())
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
         // 使用 clone() 来获取 table 的 snapshot
-        let table = self.get_or_create_table(table).clone();
-        Ok(Box::new(StorateIter::new(table.into_iter())))
+        let table = self
+            .tables
+            .get(table)
+            .map(|t| t.clone())
+            .unwrap_or_default();
+        let now = self.clock.now();
+        let iter = table
+            .into_iter()
+            .filter(move |(_k, v)| !v.is_expired(now))
+            .map(|(k, v)| (k, v.into_value()));
+        Ok(Box::new(StorateIter::new(iter)))
+    }
+
+    fn keys(&self, table: &str, prefix: &str) -> Result<Vec<String>, KvError> {
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+        let now = self.clock.now();
+        Ok(table
+            .iter()
+            .filter(|v| v.key().starts_with(prefix) && !v.value().is_expired(now))
+            .map(|v| v.key().clone())
+            .collect())
+    }
+
+    fn drain(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let _turn = self.write_turn(table);
+        let _guard = self.write_guard();
+        // 拿住 table 的写锁，直接把整个 table 换成空的，drain 期间的写入会等到替换完成之后
+        let old = match self.tables.get_mut(table) {
+            Some(mut table) => std::mem::take(&mut *table),
+            None => return Ok(Vec::new()),
+        };
+        let now = self.clock.now();
+        Ok(old
+            .into_iter()
+            .filter(|(_k, v)| !v.is_expired(now))
+            .map(|(k, v)| Kvpair::new(k, v.into_value()))
+            .collect())
+    }
+
+    fn replace(&self, table: &str, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        let new = self.new_table(pairs);
+        // 拿住 table 的写锁整个换掉，读操作不会看到替换到一半的 table
+        let _turn = self.write_turn(table);
+        let _guard = self.write_guard();
+        self.ensure_table(table)?;
+        let mut entry = self.tables.entry(table.into()).or_default();
+        *entry = new;
+        // 新写入的 key 都没有过期时间
+        self.next_expiry.insert(table.into(), u64::MAX);
+        Ok(())
+    }
+
+    fn by_insertion(&self, table: &str, n: usize, newest: bool) -> Result<Vec<Kvpair>, KvError> {
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+        let now = self.clock.now();
+        let entries = table
+            .iter()
+            .filter(|v| !v.value().is_expired(now))
+            .map(|v| (v.seq, v.key().clone(), v.value().clone().into_value()))
+            .collect();
+        Ok(take_by_insertion(entries, n, newest))
+    }
+
+    fn count(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(0),
+        };
+        let now = self.clock.now();
+        Ok(table
+            .iter()
+            .filter(|v| v.key().starts_with(prefix) && !v.value().is_expired(now))
+            .count())
+    }
+
+    fn table_names(&self) -> Result<Vec<String>, KvError> {
+        let mut names: Vec<_> = self.table_iter()?.collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn table_iter(&self) -> Result<Box<dyn Iterator<Item = String> + '_>, KvError> {
+        Ok(Box::new(shard_table_names(&self.tables)))
+    }
+
+    fn rename_table(&self, from: &str, to: &str, overwrite: bool) -> Result<(), KvError> {
+        if from == to {
+            return match self.tables.contains_key(from) {
+                true => Ok(()),
+                false => Err(KvError::NotFound(format!("table {}", from))),
+            };
+        }
+
+        // 按名字的顺序排队，避免两个方向相反的改名互相等待
+        let mut names = [from, to];
+        names.sort();
+        let _turns: Vec<_> = names.iter().filter_map(|t| self.write_turn(t)).collect();
+        // 独占 generation，改名期间所有的写操作都要等待，检查和搬运之间不会有别的修改
+        let _generation = self.exclusive_guard();
+
+        let table = match self.tables.get(from) {
+            Some(table) => table.clone(),
+            None => return Err(KvError::NotFound(format!("table {}", from))),
+        };
+        if !overwrite && self.tables.contains_key(to) {
+            return Err(KvError::TableExists(to.into()));
+        }
+        // 先写入 to 再删除 from，读操作在任何时候都能看到完整的数据
+        self.tables.insert(to.into(), table);
+        self.tables.remove(from);
+
+        match self.next_expiry.remove(from) {
+            Some((_k, next)) => self.next_expiry.insert(to.into(), next),
+            None => self.next_expiry.remove(to).map(|(_k, v)| v),
+        };
+
+        // table 的配置跟着一起改名，from 没有配置时 to 也使用缺省的配置
+        if let Some(config) = self.tables.get(TABLE_OPTIONS_TABLE) {
+            match config.remove(from) {
+                Some((_k, options)) => config.insert(to.into(), options),
+                None => config.remove(to).map(|(_k, v)| v),
+            };
+        }
+        Ok(())
+    }
+
+    fn move_keys<F>(&self, from: &str, to: &str, pick: F) -> Result<usize, KvError>
+    where
+        F: Fn(&str, Option<Duration>) -> bool,
+    {
+        if from == to {
+            let msg = format!("Cannot move keys of table {} into itself", from);
+            return Err(KvError::InvalidCommand(msg));
+        }
+
+        // 和 rename_table 一样按名字的顺序排队，再独占 generation，移动期间两个 table 都不会有别的写入
+        let mut names = [from, to];
+        names.sort();
+        let _turns: Vec<_> = names.iter().filter_map(|t| self.write_turn(t)).collect();
+        let _generation = self.exclusive_guard();
+
+        let now = self.clock.now();
+        let picked: Vec<_> = match self.tables.get(from) {
+            Some(table) => table
+                .iter()
+                .filter(|v| !v.is_expired(now) && pick(v.key(), v.ttl(now)))
+                .map(|v| (v.key().clone(), v.value().clone()))
+                .collect(),
+            None => return Ok(0),
+        };
+        if picked.is_empty() {
+            return Ok(0);
+        }
+
+        // 先写入 to 再从 from 中删除，读操作在任何时候都能看到移动的 key
+        self.ensure_table(to)?;
+        let target = self.tables.entry(to.into()).or_default();
+        for (key, v) in &picked {
+            let mut v = v.clone();
+            v.expire_at = 0;
+            v.seq = self.next_seq();
+            target.insert(key.clone(), v);
+        }
+        drop(target);
+        if let Some(source) = self.tables.get(from) {
+            for (key, _v) in &picked {
+                source.remove(key);
+            }
+        }
+        Ok(picked.len())
+    }
+
+    fn transaction_pair<F, R>(&self, first: &str, second: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext, &mut TxContext) -> Result<R, KvError>,
+    {
+        if first == second {
+            let msg = format!("Cannot run a transaction on table {} twice", first);
+            return Err(KvError::InvalidCommand(msg));
+        }
+
+        // 和 move_keys 一样按名字的顺序排队，再独占 generation，事务期间两个 table 都不会有别的写入
+        let mut names = [first, second];
+        names.sort();
+        let _turns: Vec<_> = names.iter().filter_map(|t| self.write_turn(t)).collect();
+        let _generation = self.exclusive_guard();
+
+        let now = self.clock.now();
+        let (read_first, read_second) = (
+            |key: &str| Ok(self.read(first, key)),
+            |key: &str| Ok(self.read(second, key)),
+        );
+        let mut tx_first = TxContext::new(&read_first, now);
+        let mut tx_second = TxContext::new(&read_second, now);
+        let result = f(&mut tx_first, &mut tx_second)?;
+
+        // 两个 table 都确认可以写入之后再提交，不会只写入了其中一个
+        let writes = [
+            (first, tx_first.into_writes()),
+            (second, tx_second.into_writes()),
+        ];
+        for (name, writes) in &writes {
+            if !writes.is_empty() {
+                self.ensure_table(name)?;
+            }
+        }
+        for (name, writes) in writes {
+            if !writes.is_empty() {
+                self.commit(name, &self.tables.entry(name.into()).or_default(), writes);
+            }
+        }
+        Ok(result)
+    }
+
+    fn expire_table(&self, table: &str, ttl: Duration) -> Result<usize, KvError> {
+        let _turn = self.write_turn(table);
+        let _guard = self.write_guard();
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(0),
+        };
+        let now = self.clock.now();
+        let mut count = 0;
+        for mut v in table.iter_mut() {
+            if !v.is_expired(now) {
+                v.expire_in(now, ttl);
+                self.track_expiry(table.key(), v.expire_at);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn reap_expired(&self, table: &str, remove: bool) -> Result<usize, KvError> {
+        let now = self.clock.now();
+        if !remove {
+            return Ok(self.tables.get(table).map_or(0, |t| {
+                t.iter().filter(|v| v.value().is_expired(now)).count()
+            }));
+        }
+
+        let turn = self.write_turn(table);
+        let guard = self.write_guard();
+        let t = match self.tables.get(table) {
+            Some(t) => t,
+            None => return Ok(0),
+        };
+
+        let mut evicted = Vec::new();
+        t.retain(|k, v| {
+            let expired = v.is_expired(now);
+            if expired {
+                evicted.push((k.clone(), v.clone().into_value()));
+            }
+            !expired
+        });
+        drop(t);
+        drop(guard);
+        drop(turn);
+
+        // 已经释放了 table 的锁，回调里再访问 MemTable 不会死锁
+        self.notify_evicted(table, &evicted, EvictReason::Expired);
+        Ok(evicted.len())
+    }
+
+    fn info(&self) -> Result<Vec<Kvpair>, KvError> {
+        let mut entries = 0;
+        let mut bytes = 0;
+        for table in self.tables.iter() {
+            entries += table.len();
+            // 只计算 key 和 encode 之后的 value，不包括 DashMap 自身的开销
+            bytes += table
+                .iter()
+                .map(|v| v.key().len() + v.value().encoded_len())
+                .sum::<usize>();
+        }
+
+        Ok(vec![
+            Kvpair::new("backend", "memtable".into()),
+            Kvpair::new("tables", (self.tables.len() as i64).into()),
+            Kvpair::new("entries", (entries as i64).into()),
+            Kvpair::new("estimated_bytes", (bytes as i64).into()),
+        ])
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        // 数据只在内存中，没有可以落盘的
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<(), KvError> {
+        // 内存中的数据没有什么可以压缩的
+        Ok(())
+    }
+
+    fn create_table(&self, table: &str, pairs: Vec<Kvpair>) -> Result<bool, KvError> {
+        let new = self.new_table(pairs);
+        let _turn = self.write_turn(table);
+        let _guard = self.write_guard();
+        self.ensure_table(table)?;
+        // 拿住 table 的写锁检查和替换，中间不会有别的写入
+        let mut entry = self.tables.entry(table.into()).or_default();
+        let now = self.clock.now();
+        if entry.iter().any(|v| !v.value().is_expired(now)) {
+            return Ok(false);
+        }
+        *entry = new;
+        self.next_expiry.insert(table.into(), u64::MAX);
+        Ok(true)
+    }
+
+    fn set_with_len(&self, table: &str, key: &str, value: Value) -> Result<usize, KvError> {
+        let turn = self.write_turn(table);
+        let guard = self.write_guard();
+        self.ensure_table(table)?;
+        // 拿住 table 的写锁，写入和统计之间其他人无法修改这个 table
+        let t = self.tables.entry(table.into()).or_default();
+        let mut stored = StoredValue::new(value);
+        stored.seq = self.next_seq();
+        t.insert(key.into(), stored);
+
+        // 还没有 key 过期时 table 的大小就是 key 的个数，不需要遍历；
+        // 否则先删除过期的 key，再重新计算最早的过期时间
+        let now = self.clock.now();
+        let mut evicted = Vec::new();
+        if self.next_expiry.get(table).is_none_or(|next| *next <= now) {
+            let mut next = u64::MAX;
+            t.retain(|k, v| {
+                if v.is_expired(now) {
+                    evicted.push((k.clone(), v.clone().into_value()));
+                    return false;
+                }
+                if v.expire_at != 0 {
+                    next = next.min(v.expire_at);
+                }
+                true
+            });
+            self.next_expiry.insert(table.into(), next);
+        }
+        let len = t.len();
+        drop(t);
+        drop(guard);
+        drop(turn);
+
+        self.notify_evicted(table, &evicted, EvictReason::Expired);
+        Ok(len)
+    }
+
+    fn get_many(&self, reads: &[(String, String)]) -> Result<Vec<Option<Value>>, KvError> {
+        // 过期的 key 留给之后的读或者 reap_expired 删除，这里只读不写
+        let now = self.clock.now();
+        // 开始读之前没有正在进行的写操作，读完之后也没有新的写操作开始，读到的就是同一时刻的数据
+        for _ in 0..OPTIMISTIC_READS {
+            let started = self.writes.started.load(Ordering::SeqCst);
+            if self.writes.finished.load(Ordering::SeqCst) != started {
+                std::thread::yield_now();
+                continue;
+            }
+            let values = self.read_many(reads, now);
+            if self.writes.started.load(Ordering::SeqCst) == started {
+                return Ok(values);
+            }
+        }
+
+        // 拿住之后所有的写操作都要等这一批读完，正在进行的写操作也已经全部完成了
+        let _generation = self.generation.write().unwrap();
+        Ok(self.read_many(reads, now))
+    }
+
+    fn read_transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        // 和 get_many 一样先不加锁读，一直和写操作撞上时才独占 generation
+        let reader = |key: &str| Ok(self.read(table, key));
+        let run = || {
+            let mut tx = TxContext::new(&reader, self.clock.now());
+            let result = f(&mut tx)?;
+            match tx.writes().is_empty() {
+                true => Ok(result),
+                false => Err(KvError::ReadOnly),
+            }
+        };
+        for _ in 0..OPTIMISTIC_READS {
+            let started = self.writes.started.load(Ordering::SeqCst);
+            if self.writes.finished.load(Ordering::SeqCst) != started {
+                std::thread::yield_now();
+                continue;
+            }
+            let result = run();
+            if self.writes.started.load(Ordering::SeqCst) == started {
+                return result;
+            }
+        }
+
+        let _generation = self.generation.write().unwrap();
+        run()
+    }
+
+    fn transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        if let Some(turn) = self.write_turn(table) {
+            // 同一个 table 的写操作已经排好队了，执行 f 时不需要拿住 table 的写锁；
+            // 只读的事务不会创建 table，有写入时才在提交的时候创建
+            let reader = |key: &str| {
+                let v = self
+                    .tables
+                    .get(table)
+                    .and_then(|t| t.get(key).map(|v| v.clone()));
+                Ok(v)
+            };
+            let mut tx = TxContext::new(&reader, self.clock.now());
+            let result = f(&mut tx)?;
+            if tx.writes().is_empty() {
+                return Ok(result);
+            }
+            // 提交时拿住写锁，读操作不会看到提交到一半的事务
+            let _guard = self.write_guard();
+            self.ensure_table(table)?;
+            self.commit(
+                table,
+                &self.tables.entry(table.into()).or_default(),
+                tx.into_writes(),
+            );
+            drop(turn);
+            return Ok(result);
+        }
+
+        // table 不存在时先在空的 table 上试着执行一次，只读的事务不会创建 table，也不会占用名额
+        if !self.tables.contains_key(table) {
+            let reader = |_key: &str| Ok(None);
+            let mut tx = TxContext::new(&reader, self.clock.now());
+            let result = f(&mut tx)?;
+            if tx.writes().is_empty() {
+                return Ok(result);
+            }
+        }
+
+        // 拿住 table 的写锁，事务执行期间其他人无法访问这个 table
+        let _guard = self.write_guard();
+        self.ensure_table(table)?;
+        let name = table;
+        let table = self.tables.entry(name.into()).or_default();
+        let reader = |key: &str| Ok(table.get(key).map(|v| v.value().clone()));
+        let mut tx = TxContext::new(&reader, self.clock.now());
+        let result = f(&mut tx)?;
+        self.commit(name, &table, tx.into_writes());
+        Ok(result)
     }
 }
 
@@ -77,6 +819,9 @@ impl From<(String, Value)> for Kvpair {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::MockClock;
+    use std::sync::{mpsc, OnceLock};
+    use std::thread;
 
     #[test]
     fn get_or_create_table_should_work() {
@@ -85,4 +830,153 @@ mod tests {
         store.get_or_create_table("t1");
         assert!(store.tables.contains_key("t1"));
     }
+
+    #[test]
+    fn compact_list_should_release_unused_capacity() {
+        use crate::{value, Values};
+
+        let capacity = |store: &MemTable| {
+            let table = store.tables.get("q").unwrap();
+            let stored = table.get("jobs").unwrap();
+            match &stored.value {
+                Some(Value {
+                    value: Some(value::Value::List(list)),
+                }) => list.values.capacity(),
+                _ => panic!("not a list"),
+            }
+        };
+
+        // 从很长的 list 中取走大部分元素之后，Vec 仍然保留着原来的容量
+        let mut values: Vec<Value> = (0..1000).map(Value::from).collect();
+        values.drain(..990);
+        let expected = Value::new_list(values.clone());
+        let list = Value {
+            value: Some(value::Value::List(Values { values })),
+        };
+        let store = MemTable::new();
+        store.set("q", "jobs", list).unwrap();
+        assert!(capacity(&store) >= 1000);
+
+        assert_eq!(store.compact_list("q", "jobs").unwrap(), Some(10));
+        assert_eq!(capacity(&store), 10);
+        assert_eq!(store.get("q", "jobs").unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn reaping_expired_keys_should_fire_evict_callback() {
+        let clock = MockClock::new();
+        let (tx, rx) = mpsc::channel();
+        let store = Arc::new(OnceLock::<MemTable>::new());
+        let inner = store.clone();
+        let table =
+            MemTable::new()
+                .with_clock(clock.clone())
+                .on_evict(move |table, key, value, reason| {
+                    // 回调里访问同一个 table 不会死锁
+                    let store = inner.get().unwrap();
+                    assert_eq!(store.get(table, key).unwrap(), None);
+                    tx.send((table.to_owned(), key.to_owned(), value.clone(), reason))
+                        .unwrap();
+                });
+        let store = store.get_or_init(|| table);
+
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        store.expire("t1", "k1", Duration::from_secs(1)).unwrap();
+
+        // 还没有过期的时候不会触发回调
+        assert_eq!(store.reap_expired("t1", true).unwrap(), 0);
+        assert!(rx.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(store.reap_expired("t1", true).unwrap(), 1);
+        let evicted = rx.try_recv().unwrap();
+        assert_eq!(
+            evicted,
+            ("t1".into(), "k1".into(), "v1".into(), EvictReason::Expired)
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn reading_expired_keys_should_fire_evict_callback() {
+        let clock = MockClock::new();
+        let (tx, rx) = mpsc::channel();
+        let store =
+            MemTable::new()
+                .with_clock(clock.clone())
+                .on_evict(move |table, key, value, reason| {
+                    tx.send((table.to_owned(), key.to_owned(), value.clone(), reason))
+                        .unwrap();
+                });
+
+        store.set("t1", "k1", "v1").unwrap();
+        store.expire("t1", "k1", Duration::from_secs(1)).unwrap();
+        clock.advance(Duration::from_secs(2));
+
+        // 不调用 reap_expired，读到过期的 key 时也会触发回调，而且只触发一次
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert!(!store.contains("t1", "k1").unwrap());
+        let evicted = rx.try_recv().unwrap();
+        assert_eq!(
+            evicted,
+            ("t1".into(), "k1".into(), "v1".into(), EvictReason::Expired)
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(store.reap_expired("t1", true).unwrap(), 0);
+    }
+
+    #[test]
+    fn write_queues_should_serialize_writes_per_table() {
+        let store = &MemTable::new().with_write_queues();
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        thread::scope(|s| {
+            // 一个很长的事务占住 t1 的队列
+            let long_tx = s.spawn(move || {
+                store
+                    .transaction("t1", |tx| {
+                        started_tx.send(()).unwrap();
+                        release_rx.recv().unwrap();
+                        tx.set("k1", "first")
+                    })
+                    .unwrap();
+            });
+            started_rx.recv().unwrap();
+
+            // 其他 table 的读写不受影响，t1 的读也不会被阻塞
+            store.set("t2", "k1", "v1").unwrap();
+            assert_eq!(store.get("t2", "k1").unwrap(), Some("v1".into()));
+            assert_eq!(store.get("t1", "k1").unwrap(), None);
+
+            // t1 的写排在事务之后
+            let queued = s.spawn(move || store.set("t1", "k1", "second").unwrap());
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(store.get("t1", "k1").unwrap(), None);
+
+            release_tx.send(()).unwrap();
+            long_tx.join().unwrap();
+            assert_eq!(queued.join().unwrap(), Some("first".into()));
+        });
+
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("second".into()));
+    }
+
+    #[test]
+    fn write_queues_should_keep_transaction_semantics() {
+        let store = MemTable::new().with_write_queues();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..250 {
+                        store
+                            .merge("t1", "counter", crate::MergeOp::AddInt, 1.into())
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(store.get("t1", "counter").unwrap(), Some(1000.into()));
+    }
 }
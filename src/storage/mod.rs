@@ -1,10 +1,57 @@
+mod btree;
+mod cdc;
+mod clock;
 mod memory;
+mod normalize;
 mod sleddb;
+mod snapshot;
+mod tenant;
+mod transaction;
+mod write_queue;
 
-pub use memory::MemTable;
+pub use btree::BTreeStore;
+pub use cdc::{CdcStore, OpLog};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use memory::{EvictReason, MemTable};
+pub use normalize::{
+    IdentityNormalizer, KeyNormalizer, LowercaseNormalizer, NormalizingStore, TrimNormalizer,
+};
 pub use sleddb::SledDB;
+pub use snapshot::SledSnapshot;
+pub use tenant::TenantStore;
+pub use transaction::{Savepoint, TxContext};
 
-use crate::{KvError, Kvpair, Value};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tracing::warn;
+
+use crate::{value, Change, KvError, Kvpair, MergeOp, TableOptions, Value};
+
+/// 以它开头的 table 是服务器内部使用的，客户端不能直接访问，也不会出现在 table_names 中
+pub const RESERVED_TABLE_PREFIX: &str = "__";
+
+/// 保存 table 配置的 table，key 是 table 的名字，见 Storage::table_options
+pub const TABLE_OPTIONS_TABLE: &str = "__tconfig";
+
+/// table 是否是服务器内部使用的 table
+pub fn is_reserved_table(table: &str) -> bool {
+    table.starts_with(RESERVED_TABLE_PREFIX)
+}
+
+/// 一个分片一个分片地返回 tables 里 table 的名字，内部使用的 table 不返回
+///
+/// 每次只拿一个分片的读锁，复制完这个分片里的名字就放开，迭代的过程中可以继续读写 table
+fn shard_table_names<V>(tables: &DashMap<String, V>) -> impl Iterator<Item = String> + '_ {
+    tables.shards().iter().flat_map(|shard| {
+        shard
+            .read()
+            .keys()
+            .filter(|name| !is_reserved_table(name))
+            .cloned()
+            .collect::<Vec<_>>()
+    })
+}
 
 /// 对存储的抽象，不关心数据存在哪儿，但需要定义外界如何和存储打交道
 pub trait Storage {
@@ -23,8 +70,212 @@ pub trait Storage {
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
     /// 遍历 HashTable，返回所有 kv pair（这个接口不好）
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
-    /// 遍历 HashTable，返回 kv pair 的 Iterator
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    /// 遍历 HashTable，返回 kv pair 的 Iterator，无法读取的数据以错误的形式返回，不会被跳过
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError>;
+    /// 和 get_iter 一样，但是按 key 从小到大的顺序返回
+    ///
+    /// 缺省的实现取出所有的 kv pair 之后再排序，SledDB 和 BTreeStore 本来就按 key 的顺序保存，覆盖了它
+    fn sorted_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        let mut pairs = self.get_all(table)?;
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(Box::new(StorateIter::new(pairs.into_iter())))
+    }
+    /// 返回 HashTable 中所有以 prefix 开头的 key，prefix 为空时返回所有 key
+    fn keys(&self, table: &str, prefix: &str) -> Result<Vec<String>, KvError>;
+    /// 取出 HashTable 中所有的 kv pair，同时清空 HashTable
+    ///
+    /// 和 drain 同时进行的写入不会丢失：写入的 key 要么出现在返回的结果里，要么留在 HashTable 中
+    fn drain(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
+    /// 把 HashTable 的内容整个替换成 pairs，读操作要么看到替换之前的数据，要么看到替换之后的数据
+    fn replace(&self, table: &str, pairs: Vec<Kvpair>) -> Result<(), KvError>;
+    /// 按写入的先后顺序返回 n 个 kv pair：newest 为 false 时返回最早写入的，从早到晚排列；
+    /// 为 true 时返回最晚写入的，从晚到早排列
+    fn by_insertion(&self, table: &str, n: usize, newest: bool) -> Result<Vec<Kvpair>, KvError>;
+    /// 统计 HashTable 中以 prefix 开头的 key 的个数，prefix 为空时统计所有 key
+    fn count(&self, table: &str, prefix: &str) -> Result<usize, KvError>;
+    /// 返回所有 HashTable 的名字，按名字排序
+    fn table_names(&self) -> Result<Vec<String>, KvError>;
+    /// 逐个返回所有 HashTable 的名字，不保证顺序；table 很多时比 table_names 省内存，
+    /// 迭代的过程中可以读写 table
+    fn table_iter(&self) -> Result<Box<dyn Iterator<Item = String> + '_>, KvError> {
+        Ok(Box::new(self.table_names()?.into_iter()))
+    }
+    /// 把名为 from 的 HashTable 改名为 to，from 不存在时返回 NotFound；
+    /// to 已经存在时，overwrite 为 true 则替换掉它，否则返回 TableExists；table 的配置跟着一起改名
+    fn rename_table(&self, from: &str, to: &str, overwrite: bool) -> Result<(), KvError>;
+    /// 把之前的写操作持久化到磁盘
+    fn flush(&self) -> Result<(), KvError>;
+    /// 存储后端的状态，比如占用的空间、数据的条数等，不同的后端返回的字段不同
+    fn info(&self) -> Result<Vec<Kvpair>, KvError>;
+    /// 压缩存储，回收不再使用的空间
+    fn compact(&self) -> Result<(), KvError>;
+    /// 在一个 HashTable 上执行事务，f 返回 Err 时事务中所有的修改都会被丢弃
+    ///
+    /// f 可能被执行多次（比如 SledDB 遇到冲突时会重试），所以 f 里面不要有别的副作用
+    fn transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>;
+    /// 在一个 HashTable 上执行只读的事务，f 中有写操作时返回 KvError::ReadOnly
+    ///
+    /// 和 transaction 不同，不会拿 table 的写锁或者排队，不会挡住同一个 table 的写操作；
+    /// f 同样可能被执行多次。缺省的实现直接使用 transaction
+    fn read_transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        self.transaction(table, |tx| {
+            let result = f(tx)?;
+            match tx.writes().is_empty() {
+                true => Ok(result),
+                false => Err(KvError::ReadOnly),
+            }
+        })
+    }
+    /// 在两个不同的 HashTable 上执行同一个事务，f 的两个参数分别是 first 和 second 的上下文
+    ///
+    /// 两个 table 的修改要么都生效，要么都不生效；和 transaction 一样，f 可能被执行多次。
+    /// 缺省不支持
+    fn transaction_pair<F, R>(&self, _first: &str, _second: &str, _f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext, &mut TxContext) -> Result<R, KvError>,
+    {
+        Err(KvError::InvalidCommand(
+            "Transactions across tables are not supported".into(),
+        ))
+    }
+    /// 设置 key 在 ttl 之后过期，key 不存在时返回 false
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.transaction(table, |tx| tx.expire(key, ttl))
+    }
+    /// 给 HashTable 中现有的所有 key 设置 ttl 之后过期，返回设置了多少个 key
+    fn expire_table(&self, table: &str, ttl: Duration) -> Result<usize, KvError>;
+    /// 统计 HashTable 中已经过期但还没有被清理的 key，remove 为 true 时同时删除它们，返回 key 的个数
+    fn reap_expired(&self, table: &str, remove: bool) -> Result<usize, KvError>;
+    /// 把 operand 原子地合并到 key 的 value 上，返回合并后的 value
+    fn merge(&self, table: &str, key: &str, op: MergeOp, operand: Value) -> Result<Value, KvError> {
+        self.transaction(table, |tx| {
+            let merged = op.apply(tx.get(key)?, &operand)?;
+            tx.set(key, merged.clone())?;
+            Ok(merged)
+        })
+    }
+    /// 把 from 中 pick 返回 true 的 key 原子地移到 to 中，返回移动了多少个 key
+    ///
+    /// pick 的参数是 key 和它剩余的存活时间，没有设置过期时间时为 None。移到 to 的 key 不再有过期时间，
+    /// 会覆盖 to 中同名的 key；和移动同时进行的写操作要么在移动之前生效，要么在移动之后生效
+    fn move_keys<F>(&self, _from: &str, _to: &str, _pick: F) -> Result<usize, KvError>
+    where
+        F: Fn(&str, Option<Duration>) -> bool,
+    {
+        Err(KvError::InvalidCommand(
+            "Moving keys is not supported".into(),
+        ))
+    }
+    /// HashTable 中没有任何 key 时写入 pairs 并返回 true，否则什么都不做，返回 false
+    ///
+    /// 缺省的实现先检查再写入，两步之间可能插入别的写操作，MemTable、BTreeStore 和 SledDB 都覆盖了它
+    fn create_table(&self, table: &str, pairs: Vec<Kvpair>) -> Result<bool, KvError> {
+        if self.count(table, "")? > 0 {
+            return Ok(false);
+        }
+        self.replace(table, pairs)?;
+        Ok(true)
+    }
+    /// 把 key 的 list 重新写一遍，释放反复修改之后多占用的空间，返回元素的个数，key 不存在时返回 None
+    ///
+    /// 过期时间保持不变，value 不是 list 时返回错误。SledDB 会重新写入这个 key 的数据
+    fn compact_list(&self, table: &str, key: &str) -> Result<Option<usize>, KvError> {
+        self.transaction(table, |tx| {
+            let mut list = match tx.get(key)? {
+                Some(Value {
+                    value: Some(value::Value::List(list)),
+                }) => list.values,
+                Some(v) => return Err(KvError::ConvertError(v.format(), "List")),
+                None => return Ok(None),
+            };
+            list.shrink_to_fit();
+            let len = list.len();
+            tx.update(key, Value::new_list(list))?;
+            Ok(Some(len))
+        })
+    }
+    /// 写入 key，返回写入之后 HashTable 中 key 的个数，中间不会插入别的写操作
+    ///
+    /// 缺省的实现写入之后再统计，不能保证原子性，MemTable、BTreeStore 和 SledDB 都覆盖了它
+    fn set_with_len(&self, table: &str, key: &str, value: Value) -> Result<usize, KvError> {
+        self.set(table, key, value)?;
+        self.count(table, "")
+    }
+    /// 读取一批 (table, key)，按顺序返回它们的 value，所有的读都对应同一时刻的数据，
+    /// 同时进行的写操作要么全部可见，要么全部不可见
+    ///
+    /// 缺省的实现逐个读取，只有没有并发写入的存储（比如只读的快照）才能直接使用
+    fn get_many(&self, reads: &[(String, String)]) -> Result<Vec<Option<Value>>, KvError> {
+        reads
+            .iter()
+            .map(|(table, key)| self.get(table, key))
+            .collect()
+    }
+    /// 检查 HashTable 中的每个 key 能否正常读取，返回无法读取的 key，不会因为有 key 无法读取而失败
+    ///
+    /// 缺省的实现认为所有的 key 都可以读取，只有 SledDB 需要 decode 存储的数据
+    fn verify(&self, _table: &str) -> Result<Vec<String>, KvError> {
+        Ok(Vec::new())
+    }
+    /// 获取 table 的配置，没有配置过时返回缺省的配置
+    fn table_options(&self, table: &str) -> Result<TableOptions, KvError> {
+        let v = self.get(TABLE_OPTIONS_TABLE, table)?;
+        Ok(decode_table_options(table, Ok(v)))
+    }
+    /// 修改 table 的配置，返回之前的配置，只有之后的操作才会使用新的配置
+    fn set_table_options(
+        &self,
+        table: &str,
+        options: TableOptions,
+    ) -> Result<TableOptions, KvError> {
+        let old = self.set(TABLE_OPTIONS_TABLE, table, options)?;
+        Ok(decode_table_options(table, Ok(old)))
+    }
+    /// 按顺序返回 offset 不小于 from_offset 的修改，最多 limit 个（0 代表不限制），见 CdcStore
+    fn changes(&self, _from_offset: u64, _limit: usize) -> Result<Vec<Change>, KvError> {
+        Err(KvError::InvalidCommand("Change feed is not enabled".into()))
+    }
+}
+
+/// 把保存的配置 decode 成 TableOptions
+///
+/// 配置无法 decode 时使用缺省的配置，一个 table 的配置坏了不应该让它无法写入
+fn decode_table_options(table: &str, v: Result<Option<Value>, KvError>) -> TableOptions {
+    match v.and_then(|v| v.map(TableOptions::try_from).transpose()) {
+        Ok(options) => options.unwrap_or_default(),
+        Err(e) => {
+            warn!("Invalid options of table {}: {:?}", table, e);
+            TableOptions::default()
+        }
+    }
+}
+
+/// 把 (seq, key, value) 按写入的先后顺序排序，取出 n 个
+fn take_by_insertion(
+    mut entries: Vec<(u64, String, Value)>,
+    n: usize,
+    newest: bool,
+) -> Vec<Kvpair> {
+    entries.sort_by_key(|(seq, _k, _v)| *seq);
+    if newest {
+        entries.reverse();
+    }
+    entries
+        .into_iter()
+        .take(n)
+        .map(|(_seq, k, v)| Kvpair::new(k, v))
+        .collect()
 }
 
 struct StorateIter<T> {
@@ -42,16 +293,18 @@ where
     T: Iterator,
     T::Item: Into<Kvpair>,
 {
-    type Item = Kvpair;
+    type Item = Result<Kvpair, KvError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.data.next().map(|v| v.into())
+        self.data.next().map(|v| Ok(v.into()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Arc;
+    use std::thread;
     use tempfile::tempdir;
 
     #[test]
@@ -78,18 +331,648 @@ mod tests {
         test_basi_interface(store);
     }
 
+    #[test]
+    fn btree_basic_interface_should_work() {
+        let store = BTreeStore::new();
+        test_basi_interface(store);
+    }
+
     #[test]
     fn sleddb_get_all_should_work() {
         let store = SledDB::new(tempdir().unwrap());
         test_get_all(store);
     }
 
+    #[test]
+    fn btree_get_all_should_work() {
+        let store = BTreeStore::new();
+        test_get_all(store);
+    }
+
     #[test]
     fn sleddb_iter_should_work() {
         let store = SledDB::new(tempdir().unwrap());
         test_get_iter(store);
     }
 
+    #[test]
+    fn btree_iter_should_work() {
+        let store = BTreeStore::new();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn memtable_count_should_work() {
+        let store = MemTable::new();
+        test_count(store);
+    }
+
+    #[test]
+    fn sleddb_count_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_count(store);
+    }
+
+    #[test]
+    fn btree_count_should_work() {
+        let store = BTreeStore::new();
+        test_count(store);
+    }
+
+    #[test]
+    fn memtable_compact_should_work() {
+        let store = MemTable::new();
+        test_compact(store);
+    }
+
+    #[test]
+    fn sleddb_compact_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_compact(store);
+    }
+
+    #[test]
+    fn btree_compact_should_work() {
+        let store = BTreeStore::new();
+        test_compact(store);
+    }
+
+    #[test]
+    fn memtable_transaction_should_work() {
+        let store = MemTable::new();
+        test_transaction(store);
+    }
+
+    #[test]
+    fn sleddb_transaction_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_transaction(store);
+    }
+
+    #[test]
+    fn btree_transaction_should_work() {
+        let store = BTreeStore::new();
+        test_transaction(store);
+    }
+
+    #[test]
+    fn memtable_read_transaction_should_work() {
+        test_read_transaction(MemTable::new().with_write_queues());
+    }
+
+    #[test]
+    fn sleddb_read_transaction_should_work() {
+        test_read_transaction(SledDB::new(tempdir().unwrap()));
+    }
+
+    #[test]
+    fn btree_read_transaction_should_work() {
+        test_read_transaction(BTreeStore::new());
+    }
+
+    #[test]
+    fn transaction_pair_should_work() {
+        test_transaction_pair(MemTable::new());
+        test_transaction_pair(MemTable::new().with_write_queues());
+        test_transaction_pair(SledDB::new(tempdir().unwrap()));
+        test_transaction_pair(BTreeStore::new());
+    }
+
+    #[test]
+    fn memtable_transaction_savepoint_should_work() {
+        let store = MemTable::new();
+        test_transaction_savepoint(store);
+    }
+
+    #[test]
+    fn sleddb_transaction_savepoint_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_transaction_savepoint(store);
+    }
+
+    #[test]
+    fn btree_transaction_savepoint_should_work() {
+        let store = BTreeStore::new();
+        test_transaction_savepoint(store);
+    }
+
+    #[test]
+    fn memtable_keys_should_work() {
+        let store = MemTable::new();
+        test_keys(store);
+    }
+
+    #[test]
+    fn sleddb_keys_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_keys(store);
+    }
+
+    #[test]
+    fn btree_keys_should_work() {
+        let store = BTreeStore::new();
+        test_keys(store);
+    }
+
+    #[test]
+    fn memtable_drain_should_work() {
+        let store = MemTable::new();
+        test_drain(store);
+    }
+
+    #[test]
+    fn sleddb_drain_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_drain(store);
+    }
+
+    #[test]
+    fn btree_drain_should_work() {
+        let store = BTreeStore::new();
+        test_drain(store);
+    }
+
+    #[test]
+    fn memtable_replace_should_work() {
+        let store = MemTable::new();
+        test_replace(store);
+    }
+
+    #[test]
+    fn sleddb_replace_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_replace(store);
+    }
+
+    #[test]
+    fn btree_replace_should_work() {
+        let store = BTreeStore::new();
+        test_replace(store);
+    }
+
+    #[test]
+    fn memtable_by_insertion_should_work() {
+        let store = MemTable::new();
+        test_by_insertion(store);
+    }
+
+    #[test]
+    fn sleddb_by_insertion_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_by_insertion(store);
+    }
+
+    #[test]
+    fn btree_by_insertion_should_work() {
+        let store = BTreeStore::new();
+        test_by_insertion(store);
+    }
+
+    #[test]
+    fn memtable_expire_should_work() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        test_expire(store, clock);
+    }
+
+    #[test]
+    fn sleddb_expire_should_work() {
+        let clock = MockClock::new();
+        let store = SledDB::new(tempdir().unwrap()).with_clock(clock.clone());
+        test_expire(store, clock);
+    }
+
+    #[test]
+    fn btree_expire_should_work() {
+        let clock = MockClock::new();
+        let store = BTreeStore::new().with_clock(clock.clone());
+        test_expire(store, clock);
+    }
+
+    #[test]
+    fn memtable_expire_table_should_work() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        test_expire_table(store, clock);
+    }
+
+    #[test]
+    fn sleddb_expire_table_should_work() {
+        let clock = MockClock::new();
+        let store = SledDB::new(tempdir().unwrap()).with_clock(clock.clone());
+        test_expire_table(store, clock);
+    }
+
+    #[test]
+    fn btree_expire_table_should_work() {
+        let clock = MockClock::new();
+        let store = BTreeStore::new().with_clock(clock.clone());
+        test_expire_table(store, clock);
+    }
+
+    #[test]
+    fn memtable_set_with_len_should_work() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        test_set_with_len(store, clock);
+    }
+
+    #[test]
+    fn sleddb_set_with_len_should_work() {
+        let clock = MockClock::new();
+        let store = SledDB::new(tempdir().unwrap()).with_clock(clock.clone());
+        test_set_with_len(store, clock);
+    }
+
+    #[test]
+    fn btree_set_with_len_should_work() {
+        let clock = MockClock::new();
+        let store = BTreeStore::new().with_clock(clock.clone());
+        test_set_with_len(store, clock);
+    }
+
+    #[test]
+    fn memtable_table_names_should_work() {
+        let store = MemTable::new();
+        test_table_names(store);
+    }
+
+    #[test]
+    fn sleddb_table_names_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_table_names(store);
+    }
+
+    #[test]
+    fn btree_table_names_should_work() {
+        let store = BTreeStore::new();
+        test_table_names(store);
+    }
+
+    #[test]
+    fn memtable_table_iter_should_work() {
+        let store = MemTable::new();
+        test_table_iter(store);
+    }
+
+    #[test]
+    fn sleddb_table_iter_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_table_iter(store);
+    }
+
+    #[test]
+    fn btree_table_iter_should_work() {
+        let store = BTreeStore::new();
+        test_table_iter(store);
+    }
+
+    #[test]
+    fn memtable_sorted_iter_should_work() {
+        let store = MemTable::new();
+        test_sorted_iter(store);
+    }
+
+    #[test]
+    fn sleddb_sorted_iter_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_sorted_iter(store);
+    }
+
+    #[test]
+    fn btree_sorted_iter_should_work() {
+        let store = BTreeStore::new();
+        test_sorted_iter(store);
+    }
+
+    #[test]
+    fn memtable_rename_table_should_work() {
+        let store = MemTable::new();
+        test_rename_table(store);
+    }
+
+    #[test]
+    fn sleddb_rename_table_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_rename_table(store);
+    }
+
+    #[test]
+    fn btree_rename_table_should_work() {
+        let store = BTreeStore::new();
+        test_rename_table(store);
+    }
+
+    #[test]
+    fn memtable_rename_table_should_not_lose_writes() {
+        test_rename_table_with_writes(MemTable::new());
+        test_rename_table_with_writes(MemTable::new().with_write_queues());
+    }
+
+    #[test]
+    fn sleddb_rename_table_should_not_lose_writes() {
+        test_rename_table_with_writes(SledDB::new(tempdir().unwrap()));
+    }
+
+    #[test]
+    fn rename_table_should_move_options() {
+        test_rename_table_options(MemTable::new());
+        test_rename_table_options(SledDB::new(tempdir().unwrap()));
+        test_rename_table_options(BTreeStore::new());
+    }
+
+    #[test]
+    fn invalid_table_options_should_not_break_writes() {
+        test_invalid_table_options(MemTable::new());
+        test_invalid_table_options(SledDB::new(tempdir().unwrap()));
+    }
+
+    #[test]
+    fn move_keys_should_work() {
+        let clock = MockClock::new();
+        test_move_keys(MemTable::new().with_clock(clock.clone()), clock);
+        let clock = MockClock::new();
+        test_move_keys(
+            SledDB::new(tempdir().unwrap()).with_clock(clock.clone()),
+            clock,
+        );
+        let clock = MockClock::new();
+        test_move_keys(BTreeStore::new().with_clock(clock.clone()), clock);
+    }
+
+    #[test]
+    fn move_keys_should_not_lose_writes() {
+        test_move_keys_with_writes(MemTable::new());
+        test_move_keys_with_writes(MemTable::new().with_write_queues());
+        test_move_keys_with_writes(SledDB::new(tempdir().unwrap()));
+        test_move_keys_with_writes(BTreeStore::new());
+    }
+
+    #[test]
+    fn max_tables_should_reject_new_tables() {
+        test_max_tables(MemTable::new().with_max_tables(2));
+        test_max_tables(MemTable::new().with_write_queues().with_max_tables(2));
+        test_max_tables(SledDB::new(tempdir().unwrap()).with_max_tables(2));
+        test_max_tables(BTreeStore::new().with_max_tables(2));
+    }
+
+    #[test]
+    fn memtable_reap_expired_should_work() {
+        let clock = MockClock::new();
+        let store = MemTable::new().with_clock(clock.clone());
+        test_reap_expired(store, clock);
+    }
+
+    #[test]
+    fn sleddb_reap_expired_should_work() {
+        let clock = MockClock::new();
+        let store = SledDB::new(tempdir().unwrap()).with_clock(clock.clone());
+        test_reap_expired(store, clock);
+    }
+
+    #[test]
+    fn btree_reap_expired_should_work() {
+        let clock = MockClock::new();
+        let store = BTreeStore::new().with_clock(clock.clone());
+        test_reap_expired(store, clock);
+    }
+
+    #[test]
+    fn tenants_should_not_collide() {
+        let store = Arc::new(MemTable::new());
+        let t1 = TenantStore::new(store.clone(), "tenant1").unwrap();
+        let t2 = TenantStore::new(store.clone(), "tenant2").unwrap();
+
+        t1.set("t1", "k1", "v1").unwrap();
+        t2.set("t1", "k1", "v2").unwrap();
+        assert_eq!(t1.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(t2.get("t1", "k1").unwrap(), Some("v2".into()));
+
+        // 一个租户清空 table 不影响另一个租户
+        assert_eq!(
+            t1.drain("t1").unwrap(),
+            vec![Kvpair::new("k1", "v1".into())]
+        );
+        assert_eq!(
+            t2.get_all("t1").unwrap(),
+            vec![Kvpair::new("k1", "v2".into())]
+        );
+
+        assert_eq!(t2.table_names().unwrap(), ["t1"]);
+
+        // 底层的存储里 table 带上了租户的前缀
+        assert_eq!(store.get("tenant2/t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+
+        // info 只统计自己的数据
+        store.set("t1", "k1", "v1").unwrap();
+        t2.set("t2", "k1", "v1").unwrap();
+        let info = t2.info().unwrap();
+        assert!(info.contains(&Kvpair::new("tables", 2i64.into())));
+        assert!(info.contains(&Kvpair::new("entries", 2i64.into())));
+        assert!(info.contains(&Kvpair::new("tenant", "tenant2".into())));
+    }
+
+    #[test]
+    fn tenant_reserved_tables_should_stay_reserved() {
+        let clock = MockClock::new();
+        let store = Arc::new(MemTable::new().with_clock(clock.clone()).with_max_tables(2));
+        let t1 = TenantStore::new(store.clone(), "tenant1").unwrap();
+        let t2 = TenantStore::new(store.clone(), "tenant2").unwrap();
+
+        // 租户内部使用的 table 在底层的存储里也是内部使用的，不占用名额
+        t1.set("t1", "k1", "v1").unwrap();
+        t1.set("__hits:t1", "k1", 1i64).unwrap();
+        t2.set("t1", "k1", "v1").unwrap();
+        assert!(store.contains("__hits:t1/tenant1", "k1").unwrap());
+        assert_eq!(t1.table_names().unwrap(), ["t1"]);
+
+        // compact 只清理自己的 table
+        for t in [&t1, &t2] {
+            t.expire("t1", "k1", Duration::from_secs(1)).unwrap();
+        }
+        clock.advance(Duration::from_secs(2));
+        t1.compact().unwrap();
+        assert_eq!(store.reap_expired("tenant1/t1", false).unwrap(), 0);
+        assert_eq!(store.reap_expired("tenant2/t1", false).unwrap(), 1);
+    }
+
+    #[test]
+    fn tenant_name_should_not_be_ambiguous() {
+        let store = Arc::new(MemTable::new());
+        // "a/b" + "c" 和 "a" + "b/c" 会映射到同一个 table
+        for name in ["", "a/b", "__a"] {
+            assert!(matches!(
+                TenantStore::new(store.clone(), name),
+                Err(KvError::InvalidCommand(_))
+            ));
+        }
+        let t = TenantStore::new(store.clone(), "a").unwrap();
+        t.set("b/c", "k1", "v1").unwrap();
+        t.set("__hits:b", "k1", 1i64).unwrap();
+        assert_eq!(t.table_names().unwrap(), ["b/c"]);
+    }
+
+    #[test]
+    fn sleddb_snapshot_should_not_see_later_writes() {
+        let store = SledDB::new(tempdir().unwrap());
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+
+        let snapshot = store.snapshot().unwrap();
+        store.set("t1", "k1", "v11").unwrap();
+        store.del("t1", "k2").unwrap();
+        store.set("t1", "k3", "v3").unwrap();
+
+        let expected = vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into()),
+        ];
+        assert_eq!(snapshot.get_all("t1").unwrap(), expected);
+        let pairs: Vec<_> = snapshot
+            .get_iter("t1")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(pairs, expected);
+        assert_eq!(snapshot.get("t1", "k3").unwrap(), None);
+
+        // 快照是只读的
+        assert!(matches!(
+            snapshot.set("t1", "k4", "v4"),
+            Err(KvError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn sleddb_snapshot_should_be_point_in_time_across_tables() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir.path());
+        store.set("t1", "k", "v").unwrap();
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        thread::scope(|s| {
+            // key 在两个 table 之间来回移动，任何时刻都只在其中一个 table 里
+            s.spawn(|| {
+                for i in 0..200 {
+                    let (from, to) = if i % 2 == 0 {
+                        ("t1", "t2")
+                    } else {
+                        ("t2", "t1")
+                    };
+                    store.move_keys(from, to, |_k, _ttl| true).unwrap();
+                }
+                done.store(true, std::sync::atomic::Ordering::SeqCst);
+            });
+
+            while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                let snapshot = store.snapshot().unwrap();
+                let found = ["t1", "t2"]
+                    .iter()
+                    .filter(|t| snapshot.contains(t, "k").unwrap())
+                    .count();
+                assert_eq!(found, 1);
+            }
+        });
+
+        // 快照被 drop 之后临时的数据也被删除了
+        let snapshots = dir.path().join("snapshots");
+        assert_eq!(std::fs::read_dir(snapshots).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn memtable_get_many_should_be_consistent() {
+        test_get_many(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_get_many_should_be_consistent() {
+        test_get_many(SledDB::new(tempdir().unwrap()));
+    }
+
+    #[test]
+    fn btree_get_many_should_be_consistent() {
+        test_get_many(BTreeStore::new());
+    }
+
+    #[test]
+    fn memtable_compact_list_should_work() {
+        test_compact_list(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_compact_list_should_work() {
+        test_compact_list(SledDB::new(tempdir().unwrap()));
+    }
+
+    fn test_compact_list(store: impl Storage) {
+        let list = Value::new_list((0..10).map(Value::from));
+        store.set("t1", "list", list.clone()).unwrap();
+        store
+            .expire("t1", "list", Duration::from_secs(3600))
+            .unwrap();
+
+        assert_eq!(store.compact_list("t1", "list").unwrap(), Some(10));
+        assert_eq!(store.get("t1", "list").unwrap(), Some(list));
+        // 过期时间保持不变
+        let ttl = store.transaction("t1", |tx| tx.ttl("list")).unwrap();
+        assert!(matches!(ttl, Some(Some(_))));
+
+        assert_eq!(store.compact_list("t1", "none").unwrap(), None);
+        store.set("t1", "str", "v").unwrap();
+        assert!(store.compact_list("t1", "str").is_err());
+    }
+
+    fn test_get_many(store: impl Storage + Sync) {
+        store.set("t2", "x", "v").unwrap();
+        let reads = [("t1", "a"), ("t2", "x"), ("t1", "b"), ("t3", "none")]
+            .map(|(t, k)| (t.to_owned(), k.to_owned()));
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            // 每个事务同时修改 a 和 b，一批读要么都看到，要么都看不到
+            s.spawn(|| {
+                for i in 0..500 {
+                    store
+                        .transaction("t1", |tx| {
+                            tx.set("a", i)?;
+                            tx.set("b", i)
+                        })
+                        .unwrap();
+                }
+                done.store(true, std::sync::atomic::Ordering::SeqCst);
+            });
+
+            while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                let values = store.get_many(&reads).unwrap();
+                assert_eq!(values[0], values[2]);
+                assert_eq!(values[1], Some("v".into()));
+                assert_eq!(values[3], None);
+            }
+        });
+        assert!(store.get_many(&[]).unwrap().is_empty());
+        // 读不存在的 table 不会创建它
+        assert_eq!(store.table_names().unwrap(), ["t1", "t2"]);
+    }
+
+    #[test]
+    fn memtable_merge_should_work() {
+        let store = MemTable::new();
+        test_merge(store);
+    }
+
+    #[test]
+    fn sleddb_merge_should_work() {
+        let store = SledDB::new(tempdir().unwrap());
+        test_merge(store);
+    }
+
+    #[test]
+    fn btree_merge_should_work() {
+        let store = BTreeStore::new();
+        test_merge(store);
+    }
+
     fn test_basi_interface(store: impl Storage) {
         // 第一次 set 会创建 table，插入 key 并返回 None（之前没值）
         let v = store.set("t1", "hello", "world");
@@ -137,7 +1020,11 @@ mod tests {
     fn test_get_iter(store: impl Storage) {
         store.set("t2", "k1", "v1").unwrap();
         store.set("t2", "k2", "v2").unwrap();
-        let mut data: Vec<_> = store.get_iter("t2").unwrap().collect();
+        let mut data: Vec<_> = store
+            .get_iter("t2")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
         data.sort_by(|a, b| a.partial_cmp(b).unwrap());
         assert_eq!(
             data,
@@ -147,4 +1034,537 @@ mod tests {
             ]
         )
     }
+
+    fn test_count(store: impl Storage) {
+        store.set("t1", "user:1", "v1").unwrap();
+        store.set("t1", "user:2", "v2").unwrap();
+        store.set("t1", "order:1", "v3").unwrap();
+        assert_eq!(store.count("t1", "").unwrap(), 3);
+        assert_eq!(store.count("t1", "user:").unwrap(), 2);
+        assert_eq!(store.count("t1", "item:").unwrap(), 0);
+        assert_eq!(store.count("t2", "").unwrap(), 0);
+    }
+
+    fn test_compact(store: impl Storage) {
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        store.del("t1", "k2").unwrap();
+        assert!(store.compact().is_ok());
+
+        // 压缩之后数据依旧可读
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+    }
+
+    fn test_transaction(store: impl Storage) {
+        store.set("t1", "k1", "v1").unwrap();
+
+        // 事务成功，所有修改都生效
+        let old = store
+            .transaction("t1", |tx| {
+                tx.set("k2", "v2")?;
+                tx.del("k1")
+            })
+            .unwrap();
+        assert_eq!(old, Some("v1".into()));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+
+        // 事务失败，所有修改都被丢弃
+        let result: Result<(), _> = store.transaction("t1", |tx| {
+            tx.set("k3", "v3")?;
+            tx.del("k2")?;
+            Err(KvError::Internal("abort".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t1", "k3").unwrap(), None);
+    }
+
+    fn test_transaction_pair(store: impl Storage) {
+        store.set("t1", "k1", "v1").unwrap();
+
+        // 两个 table 的修改都生效
+        let old = store
+            .transaction_pair("t1", "t2", |t1, t2| {
+                let old = t1.set("k1", "v2")?;
+                t2.set("k1", old.clone().unwrap_or_default())?;
+                Ok(old)
+            })
+            .unwrap();
+        assert_eq!(old, Some("v1".into()));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t2", "k1").unwrap(), Some("v1".into()));
+
+        // 事务失败，两个 table 的修改都被丢弃
+        let result: Result<(), _> = store.transaction_pair("t1", "t2", |t1, t2| {
+            t1.del("k1")?;
+            t2.set("k2", "v2")?;
+            Err(KvError::Internal("abort".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t2", "k2").unwrap(), None);
+
+        let result = store.transaction_pair("t1", "t1", |_t1, _t2| Ok(()));
+        assert!(matches!(result, Err(KvError::InvalidCommand(_))));
+    }
+
+    fn test_read_transaction(store: impl Storage) {
+        store.set("t1", "k1", "v1").unwrap();
+        store.expire("t1", "k1", Duration::from_secs(10)).unwrap();
+
+        let (v, ttl) = store
+            .read_transaction("t1", |tx| Ok((tx.get("k1")?, tx.ttl("k1")?)))
+            .unwrap();
+        assert_eq!(v, Some("v1".into()));
+        assert!(ttl.unwrap().unwrap() <= Duration::from_secs(10));
+
+        // 有写操作的只读事务被拒绝，什么都不会写入
+        let result = store.read_transaction("t1", |tx| tx.set("k2", "v2"));
+        assert!(matches!(result, Err(KvError::ReadOnly)));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+    }
+
+    fn test_transaction_savepoint(store: impl Storage) {
+        store
+            .transaction("t1", |tx| {
+                tx.set("k1", "v1")?;
+                let sp1 = tx.savepoint();
+                tx.set("k2", "v2")?;
+                let sp2 = tx.savepoint();
+                tx.set("k1", "v11")?;
+                tx.set("k3", "v3")?;
+
+                // 回滚到 sp2，只丢弃 sp2 之后的修改
+                tx.rollback_to(sp2);
+                assert_eq!(tx.get("k1")?, Some("v1".into()));
+                assert_eq!(tx.get("k2")?, Some("v2".into()));
+                assert_eq!(tx.get("k3")?, None);
+
+                // 回滚到 sp1，k2 也被丢弃
+                tx.rollback_to(sp1);
+                assert_eq!(tx.get("k2")?, None);
+                tx.set("k4", "v4")?;
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        assert_eq!(store.get("t1", "k3").unwrap(), None);
+        assert_eq!(store.get("t1", "k4").unwrap(), Some("v4".into()));
+    }
+
+    fn test_keys(store: impl Storage) {
+        store.set("t1", "u1", "v1").unwrap();
+        store.set("t1", "u2", "v2").unwrap();
+        store.set("t1", "o1", "v3").unwrap();
+        let mut keys = store.keys("t1", "u").unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["u1", "u2"]);
+        assert_eq!(store.keys("t1", "").unwrap().len(), 3);
+        assert!(store.keys("t2", "").unwrap().is_empty());
+    }
+
+    fn test_drain(store: impl Storage) {
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        let mut pairs = store.drain("t1").unwrap();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            pairs,
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k2", "v2".into())
+            ]
+        );
+        assert!(store.get_all("t1").unwrap().is_empty());
+        assert!(store.drain("t1").unwrap().is_empty());
+
+        // drain 之后 table 还可以继续使用
+        store.set("t1", "k3", "v3").unwrap();
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
+    }
+
+    fn test_replace(store: impl Storage) {
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        let pairs = vec![
+            Kvpair::new("k2", "new2".into()),
+            Kvpair::new("k3", "new3".into()),
+        ];
+        store.replace("t1", pairs.clone()).unwrap();
+
+        let mut all = store.get_all("t1").unwrap();
+        all.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(all, pairs);
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+
+        // 替换成空的等于清空 table
+        store.replace("t1", vec![]).unwrap();
+        assert!(store.get_all("t1").unwrap().is_empty());
+    }
+
+    fn test_by_insertion(store: impl Storage) {
+        // key 的字母顺序和写入顺序不同
+        store.set("t1", "c", "v1").unwrap();
+        store.set("t1", "a", "v2").unwrap();
+        store.transaction("t1", |tx| tx.set("b", "v3")).unwrap();
+        store.set("t1", "d", "v4").unwrap();
+        // 修改过期时间不算写入
+        store.expire("t1", "c", Duration::from_secs(100)).unwrap();
+
+        let keys = |pairs: Vec<Kvpair>| pairs.into_iter().map(|p| p.key).collect::<Vec<_>>();
+        assert_eq!(
+            keys(store.by_insertion("t1", 2, false).unwrap()),
+            ["c", "a"]
+        );
+        assert_eq!(
+            keys(store.by_insertion("t1", 3, true).unwrap()),
+            ["d", "b", "a"]
+        );
+
+        // 覆盖写入之后就是最新的了
+        store.set("t1", "c", "v5").unwrap();
+        assert_eq!(keys(store.by_insertion("t1", 1, true).unwrap()), ["c"]);
+        assert_eq!(store.by_insertion("t1", 10, false).unwrap().len(), 4);
+    }
+
+    fn test_expire(store: impl Storage, clock: MockClock) {
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        assert!(store.expire("t1", "k1", Duration::from_secs(10)).unwrap());
+        // 不存在的 key 无法设置过期时间
+        assert!(!store.expire("t1", "k3", Duration::from_secs(10)).unwrap());
+
+        // 还没到过期时间
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+
+        // 过期之后 key 就不存在了
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert_eq!(store.count("t1", "").unwrap(), 1);
+        let pairs = store.get_all("t1").unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("k2", "v2".into())]);
+        let pairs: Vec<_> = store
+            .get_iter("t1")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("k2", "v2".into())]);
+
+        // 重新 set 之后，之前的过期时间被清除
+        assert_eq!(store.set("t1", "k1", "v11").unwrap(), None);
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v11".into()));
+    }
+
+    fn test_expire_table(store: impl Storage, clock: MockClock) {
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        store.set("t2", "k1", "v1").unwrap();
+        let n = store.expire_table("t1", Duration::from_secs(10)).unwrap();
+        assert_eq!(n, 2);
+
+        // 之后加入的 key 不受影响
+        clock.advance(Duration::from_secs(5));
+        store.set("t1", "k3", "v3").unwrap();
+
+        clock.advance(Duration::from_secs(5));
+        let pairs = store.get_all("t1").unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("k3", "v3".into())]);
+        // 别的 table 不受影响
+        assert_eq!(store.get("t2", "k1").unwrap(), Some("v1".into()));
+    }
+
+    fn test_set_with_len(store: impl Storage, clock: MockClock) {
+        assert_eq!(store.set_with_len("t1", "k1", "v1".into()).unwrap(), 1);
+        assert_eq!(store.set_with_len("t1", "k2", "v2".into()).unwrap(), 2);
+        // 覆盖已有的 key 不会增加个数
+        assert_eq!(store.set_with_len("t1", "k1", "v3".into()).unwrap(), 2);
+
+        // 过期的 key 不计算在内，不管是单独设置的过期时间还是整个 table 的
+        store.expire("t1", "k1", Duration::from_secs(10)).unwrap();
+        assert_eq!(store.set_with_len("t1", "k3", "v3".into()).unwrap(), 3);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(store.set_with_len("t1", "k4", "v4".into()).unwrap(), 3);
+        store.expire_table("t1", Duration::from_secs(5)).unwrap();
+        store.set("t1", "k5", "v5").unwrap();
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(store.set_with_len("t1", "k6", "v6".into()).unwrap(), 2);
+
+        // 整个换掉和改名之后也是对的
+        store
+            .replace("t1", vec![Kvpair::new("k1", "v1".into())])
+            .unwrap();
+        assert_eq!(store.set_with_len("t1", "k2", "v2".into()).unwrap(), 2);
+        store.expire("t1", "k1", Duration::from_secs(1)).unwrap();
+        store.rename_table("t1", "t2", false).unwrap();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(store.set_with_len("t2", "k3", "v3".into()).unwrap(), 2);
+    }
+
+    fn test_table_names(store: impl Storage) {
+        store.set("t2", "k1", "v1").unwrap();
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t3", "k1", "v1").unwrap();
+        assert_eq!(store.table_names().unwrap(), ["t1", "t2", "t3"]);
+    }
+
+    fn test_table_iter(store: impl Storage) {
+        for i in 0..1000 {
+            store.set(&format!("t{}", i), "k1", "v1").unwrap();
+        }
+        let mut names: Vec<_> = store.table_iter().unwrap().collect();
+        names.sort();
+        assert_eq!(names, store.table_names().unwrap());
+        assert_eq!(names.len(), 1000);
+
+        // 拿到第一个名字的时候，剩下的名字还没有全部复制出来
+        let mut iter = store.table_iter().unwrap();
+        let first = iter.next().unwrap();
+        assert!(iter.size_hint().0 < 999);
+        // 迭代的过程中可以写入 table
+        store.set(&first, "k2", "v2").unwrap();
+        store.set("t1000", "k1", "v1").unwrap();
+        assert!(iter.count() >= 999);
+    }
+
+    fn test_sorted_iter(store: impl Storage) {
+        for key in ["k3", "k10", "k1", "k2"] {
+            store.set("t1", key, "v").unwrap();
+        }
+        let keys: Vec<_> = store
+            .sorted_iter("t1")
+            .unwrap()
+            .map(|pair| pair.unwrap().key)
+            .collect();
+        assert_eq!(keys, ["k1", "k10", "k2", "k3"]);
+    }
+
+    fn test_rename_table_with_writes(store: impl Storage + Sync) {
+        store.set("t1", "k", "v").unwrap();
+        // 改名成自己什么都不做，打开了写队列时也不会死锁
+        store.rename_table("t1", "t1", false).unwrap();
+
+        // 改名期间写入 t1 的 key 要么被搬到 t2，要么留在之后重新创建的 t1 里
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..200 {
+                    store.set("t1", format!("k{}", i), i).unwrap();
+                }
+            });
+            store.rename_table("t1", "t2", false).unwrap();
+        });
+        for i in 0..200 {
+            let key = format!("k{}", i);
+            let found = store
+                .get("t1", &key)
+                .unwrap()
+                .or(store.get("t2", &key).unwrap());
+            assert_eq!(found, Some(i.into()), "{} is lost", key);
+        }
+    }
+
+    fn test_rename_table(store: impl Storage) {
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        store.rename_table("t1", "t2", false).unwrap();
+        assert_eq!(store.table_names().unwrap(), ["t2"]);
+        assert_eq!(store.get("t2", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t2", "k2").unwrap(), Some("v2".into()));
+
+        assert!(matches!(
+            store.rename_table("none", "t3", false),
+            Err(KvError::NotFound(_))
+        ));
+
+        // 目标已经存在时需要 overwrite，替换之后目标原来的数据都不见了
+        store.set("t3", "k3", "v3").unwrap();
+        assert!(matches!(
+            store.rename_table("t2", "t3", false),
+            Err(KvError::TableExists(_))
+        ));
+        assert_eq!(store.get("t2", "k1").unwrap(), Some("v1".into()));
+        store.rename_table("t2", "t3", true).unwrap();
+        assert_eq!(store.table_names().unwrap(), ["t3"]);
+        assert_eq!(store.get("t3", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t3", "k3").unwrap(), None);
+    }
+
+    fn test_rename_table_options(store: impl Storage) {
+        let options = TableOptions {
+            durable: true,
+            ..Default::default()
+        };
+        store.set_table_options("t1", options.clone()).unwrap();
+        store.set("t1", "k1", "v1").unwrap();
+        // 先读一次，确认缓存的配置也会跟着改名
+        assert_eq!(store.table_options("t1").unwrap(), options);
+        store.rename_table("t1", "t2", false).unwrap();
+        assert_eq!(store.table_options("t2").unwrap(), options);
+        assert_eq!(store.table_options("t1").unwrap(), TableOptions::default());
+
+        // 覆盖的 table 原来的配置不会留下来
+        store.set("t3", "k3", "v3").unwrap();
+        store.rename_table("t3", "t2", true).unwrap();
+        assert_eq!(store.table_options("t2").unwrap(), TableOptions::default());
+    }
+
+    fn test_invalid_table_options(store: impl Storage) {
+        store.set(TABLE_OPTIONS_TABLE, "t1", "bad").unwrap();
+        assert_eq!(store.table_options("t1").unwrap(), TableOptions::default());
+        store.set("t1", "k1", "v1").unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+    }
+
+    fn test_move_keys(store: impl Storage, clock: MockClock) {
+        store.set("hot", "soon", "v1").unwrap();
+        store.set("hot", "later", "v2").unwrap();
+        store.set("hot", "forever", "v3").unwrap();
+        store.set("cold", "soon", "old").unwrap();
+        store
+            .expire("hot", "soon", Duration::from_secs(30))
+            .unwrap();
+        store
+            .expire("hot", "later", Duration::from_secs(3600))
+            .unwrap();
+
+        let within = Duration::from_secs(60);
+        let pick = |_key: &str, ttl: Option<Duration>| matches!(ttl, Some(ttl) if ttl <= within);
+        assert_eq!(store.move_keys("hot", "cold", pick).unwrap(), 1);
+        let mut keys = store.keys("hot", "").unwrap();
+        keys.sort();
+        assert_eq!(keys, ["forever", "later"]);
+        // 覆盖 to 中同名的 key，移过去之后不会过期
+        clock.advance(Duration::from_secs(7200));
+        assert_eq!(store.get("cold", "soon").unwrap(), Some("v1".into()));
+
+        assert_eq!(store.move_keys("none", "cold", pick).unwrap(), 0);
+        assert!(store.move_keys("hot", "hot", pick).is_err());
+    }
+
+    fn test_move_keys_with_writes(store: impl Storage + Sync) {
+        // 移动期间写入 hot 的 key 要么被移到 cold，要么留在 hot 里，不会丢失也不会被覆盖
+        let pick = |_key: &str, ttl: Option<Duration>| ttl.is_some();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..200 {
+                    let key = format!("k{}", i);
+                    store
+                        .transaction("hot", |tx| {
+                            tx.set(key.as_str(), i)?;
+                            tx.expire(&key, Duration::from_secs(60))
+                        })
+                        .unwrap();
+                }
+            });
+            for _ in 0..20 {
+                store.move_keys("hot", "cold", pick).unwrap();
+            }
+        });
+        store.move_keys("hot", "cold", pick).unwrap();
+        assert_eq!(store.count("hot", "").unwrap(), 0);
+        assert_eq!(store.count("cold", "").unwrap(), 200);
+    }
+
+    fn test_max_tables(store: impl Storage) {
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t2", "k1", "v1").unwrap();
+
+        // 读、删除和只读的事务不会占用名额
+        assert_eq!(store.get("none", "k1").unwrap(), None);
+        assert_eq!(store.del("none", "k1").unwrap(), None);
+        let v = store.transaction("none", |tx| tx.get("k1")).unwrap();
+        assert_eq!(v, None);
+
+        let err = store.set("t3", "k1", "v1").unwrap_err();
+        assert!(matches!(err, KvError::TooManyTables(2)));
+        let err = store
+            .transaction("t3", |tx| tx.set("k1", "v1"))
+            .unwrap_err();
+        assert!(matches!(err, KvError::TooManyTables(2)));
+        assert_eq!(store.get("t3", "k1").unwrap(), None);
+
+        // 已经存在的 table 照常写入，内部使用的 table 不受限制
+        store.set("t1", "k2", "v2").unwrap();
+        store.transaction("t2", |tx| tx.set("k2", "v2")).unwrap();
+        assert_eq!(store.get("t2", "k2").unwrap(), Some("v2".into()));
+        store
+            .set_table_options("t1", TableOptions::default())
+            .unwrap();
+
+        // 改名之后名额不变，还是不能创建新的 table
+        store.rename_table("t1", "t4", false).unwrap();
+        let err = store.set("t1", "k1", "v1").unwrap_err();
+        assert!(matches!(err, KvError::TooManyTables(2)));
+    }
+
+    fn test_reap_expired(store: impl Storage, clock: MockClock) {
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        store.set("t1", "k3", "v3").unwrap();
+        store.expire("t1", "k1", Duration::from_secs(10)).unwrap();
+        store.expire("t1", "k2", Duration::from_secs(10)).unwrap();
+        store.expire("t1", "k3", Duration::from_secs(30)).unwrap();
+        assert_eq!(store.reap_expired("t1", true).unwrap(), 0);
+
+        clock.advance(Duration::from_secs(20));
+        // 只统计不删除，再统计一次结果不变
+        assert_eq!(store.reap_expired("t1", false).unwrap(), 2);
+        assert_eq!(store.reap_expired("t1", true).unwrap(), 2);
+        assert_eq!(store.reap_expired("t1", true).unwrap(), 0);
+        assert_eq!(
+            store.get_all("t1").unwrap(),
+            vec![Kvpair::new("k3", "v3".into())]
+        );
+    }
+
+    fn test_merge(store: impl Storage) {
+        let merge = |key, op, operand: Value| store.merge("t1", key, op, operand).unwrap();
+
+        // ADD_INT
+        assert_eq!(merge("count", MergeOp::AddInt, 10.into()), 10.into());
+        assert_eq!(merge("count", MergeOp::AddInt, (-3).into()), 7.into());
+
+        // APPEND_BYTES
+        assert_eq!(
+            merge("log", MergeOp::AppendBytes, b"hello".into()),
+            b"hello".into()
+        );
+        assert_eq!(
+            merge("log", MergeOp::AppendBytes, b" world".into()),
+            b"hello world".into()
+        );
+
+        // SET_UNION
+        let set = merge("tags", MergeOp::SetUnion, "a".into());
+        assert_eq!(set, Value::new_set(["a".into()]));
+        let set = merge(
+            "tags",
+            MergeOp::SetUnion,
+            Value::new_set(["a".into(), "b".into()]),
+        );
+        assert_eq!(set, Value::new_set(["a".into(), "b".into()]));
+
+        // MAX / MIN
+        assert_eq!(merge("max", MergeOp::Max, 5.into()), 5.into());
+        assert_eq!(merge("max", MergeOp::Max, 3.into()), 5.into());
+        assert_eq!(merge("max", MergeOp::Max, 8.into()), 8.into());
+        assert_eq!(merge("min", MergeOp::Min, 1.5.into()), 1.5.into());
+        assert_eq!(merge("min", MergeOp::Min, 0.5.into()), 0.5.into());
+        assert_eq!(merge("min", MergeOp::Min, 2.5.into()), 0.5.into());
+
+        // 合并的结果已经写入存储
+        assert_eq!(store.get("t1", "count").unwrap(), Some(7.into()));
+
+        // 类型不匹配时返回错误，原来的值不变
+        assert!(store
+            .merge("t1", "count", MergeOp::AddInt, "a".into())
+            .is_err());
+        assert_eq!(store.get("t1", "count").unwrap(), Some(7.into()));
+    }
 }
@@ -0,0 +1,301 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Change, KvError, Kvpair, Storage, TableOptions, TxContext, Value};
+
+/// 对 key 做规范化处理，比如统一大小写、去掉首尾的空白
+///
+/// 规范化必须是幂等的：对处理过的 key 再处理一次，结果不变
+pub trait KeyNormalizer: Send + Sync + 'static {
+    fn normalize(&self, key: &str) -> String;
+}
+
+/// 不做任何处理
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IdentityNormalizer;
+
+/// 把 key 转换成小写
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LowercaseNormalizer;
+
+/// 去掉 key 首尾的空白
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TrimNormalizer;
+
+impl KeyNormalizer for IdentityNormalizer {
+    fn normalize(&self, key: &str) -> String {
+        key.to_owned()
+    }
+}
+
+impl KeyNormalizer for LowercaseNormalizer {
+    fn normalize(&self, key: &str) -> String {
+        key.to_lowercase()
+    }
+}
+
+impl KeyNormalizer for TrimNormalizer {
+    fn normalize(&self, key: &str) -> String {
+        key.trim().to_owned()
+    }
+}
+
+impl<F> KeyNormalizer for F
+where
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    fn normalize(&self, key: &str) -> String {
+        self(key)
+    }
+}
+
+/// 在访问存储之前对所有的 key 做规范化处理的存储，缺省不做任何处理
+///
+/// keys/count 的 prefix 也会被同样处理，返回的 key 是规范化之后的 key
+pub struct NormalizingStore<S> {
+    inner: S,
+    normalizer: Arc<dyn KeyNormalizer>,
+}
+
+impl<S: Storage> NormalizingStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            normalizer: Arc::new(IdentityNormalizer),
+        }
+    }
+
+    /// 设置 key 的 normalizer
+    pub fn with_normalizer(mut self, normalizer: impl KeyNormalizer) -> Self {
+        self.normalizer = Arc::new(normalizer);
+        self
+    }
+
+    fn key(&self, key: &str) -> String {
+        self.normalizer.normalize(key)
+    }
+}
+
+impl<S: Storage> Storage for NormalizingStore<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, &self.key(key))
+    }
+
+    fn set(
+        &self,
+        table: &str,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        self.inner.set(table, self.key(&key.into()), value)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, &self.key(key))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.del(table, &self.key(key))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn sorted_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        self.inner.sorted_iter(table)
+    }
+
+    fn keys(&self, table: &str, prefix: &str) -> Result<Vec<String>, KvError> {
+        self.inner.keys(table, &self.key(prefix))
+    }
+
+    fn drain(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.drain(table)
+    }
+
+    fn replace(&self, table: &str, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        let pairs = pairs
+            .into_iter()
+            .map(|pair| Kvpair {
+                key: self.key(&pair.key),
+                ..pair
+            })
+            .collect();
+        self.inner.replace(table, pairs)
+    }
+
+    fn by_insertion(&self, table: &str, n: usize, newest: bool) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.by_insertion(table, n, newest)
+    }
+
+    fn count(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        self.inner.count(table, &self.key(prefix))
+    }
+
+    fn rename_table(&self, from: &str, to: &str, overwrite: bool) -> Result<(), KvError> {
+        self.inner.rename_table(from, to, overwrite)
+    }
+
+    fn move_keys<F>(&self, from: &str, to: &str, pick: F) -> Result<usize, KvError>
+    where
+        F: Fn(&str, Option<Duration>) -> bool,
+    {
+        self.inner.move_keys(from, to, pick)
+    }
+
+    fn table_names(&self) -> Result<Vec<String>, KvError> {
+        self.inner.table_names()
+    }
+
+    fn table_iter(&self) -> Result<Box<dyn Iterator<Item = String> + '_>, KvError> {
+        self.inner.table_iter()
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.inner.flush()
+    }
+
+    fn info(&self) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.info()
+    }
+
+    fn compact(&self) -> Result<(), KvError> {
+        self.inner.compact()
+    }
+
+    fn transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        self.inner.transaction(table, |tx| {
+            tx.set_normalizer(self.normalizer.clone());
+            f(tx)
+        })
+    }
+
+    fn transaction_pair<F, R>(&self, first: &str, second: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext, &mut TxContext) -> Result<R, KvError>,
+    {
+        self.inner
+            .transaction_pair(first, second, |tx_first, tx_second| {
+                tx_first.set_normalizer(self.normalizer.clone());
+                tx_second.set_normalizer(self.normalizer.clone());
+                f(tx_first, tx_second)
+            })
+    }
+
+    fn read_transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        self.inner.read_transaction(table, |tx| {
+            tx.set_normalizer(self.normalizer.clone());
+            f(tx)
+        })
+    }
+
+    fn expire_table(&self, table: &str, ttl: Duration) -> Result<usize, KvError> {
+        self.inner.expire_table(table, ttl)
+    }
+
+    fn reap_expired(&self, table: &str, remove: bool) -> Result<usize, KvError> {
+        self.inner.reap_expired(table, remove)
+    }
+
+    fn create_table(&self, table: &str, pairs: Vec<Kvpair>) -> Result<bool, KvError> {
+        let pairs = pairs
+            .into_iter()
+            .map(|pair| Kvpair {
+                key: self.key(&pair.key),
+                ..pair
+            })
+            .collect();
+        self.inner.create_table(table, pairs)
+    }
+
+    fn set_with_len(&self, table: &str, key: &str, value: Value) -> Result<usize, KvError> {
+        self.inner.set_with_len(table, &self.key(key), value)
+    }
+
+    fn verify(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.verify(table)
+    }
+
+    fn table_options(&self, table: &str) -> Result<TableOptions, KvError> {
+        self.inner.table_options(table)
+    }
+
+    fn set_table_options(
+        &self,
+        table: &str,
+        options: TableOptions,
+    ) -> Result<TableOptions, KvError> {
+        self.inner.set_table_options(table, options)
+    }
+
+    fn get_many(&self, reads: &[(String, String)]) -> Result<Vec<Option<Value>>, KvError> {
+        let reads: Vec<_> = reads
+            .iter()
+            .map(|(table, key)| (table.clone(), self.key(key)))
+            .collect();
+        self.inner.get_many(&reads)
+    }
+
+    fn changes(&self, from_offset: u64, limit: usize) -> Result<Vec<Change>, KvError> {
+        self.inner.changes(from_offset, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, dispatch, CommandRequest, MemTable, MergeOp};
+
+    #[test]
+    fn lowercase_normalizer_should_work() {
+        let store = NormalizingStore::new(MemTable::new()).with_normalizer(LowercaseNormalizer);
+        let res = dispatch(CommandRequest::new_hset("t1", "Key", "v1".into()), &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        let res = dispatch(CommandRequest::new_hget("t1", "key"), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("t1", "KEY"), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        // 事务中的 key 也会被规范化
+        let cmd = CommandRequest::new_hmerge("t1", "Count", MergeOp::AddInt, 1.into());
+        dispatch(cmd.clone(), &store);
+        let cmd = CommandRequest::new_hmerge("t1", "COUNT", MergeOp::AddInt, 1.into());
+        let res = dispatch(cmd, &store);
+        assert_res_ok(&res, &[2.into()], &[]);
+
+        let mut keys = store.keys("t1", "").unwrap();
+        keys.sort();
+        assert_eq!(keys, ["count", "key"]);
+    }
+
+    #[test]
+    fn default_normalizer_should_keep_keys() {
+        let store = NormalizingStore::new(MemTable::new());
+        store.set("t1", " Key ", "v1").unwrap();
+        assert_eq!(store.get("t1", "key").unwrap(), None);
+        assert_eq!(store.get("t1", " Key ").unwrap(), Some("v1".into()));
+
+        let store = NormalizingStore::new(MemTable::new())
+            .with_normalizer(|key: &str| key.trim().to_lowercase());
+        store.set("t1", " Key ", "v1").unwrap();
+        assert_eq!(store.get("t1", "key").unwrap(), Some("v1".into()));
+    }
+}
@@ -1,16 +1,229 @@
-use std::path::Path;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::Duration;
 
-use super::{Storage, StorateIter};
-use crate::{KvError, Kvpair, Value};
+use super::{
+    decode_table_options, is_reserved_table, take_by_insertion, SledSnapshot, Storage,
+    RESERVED_TABLE_PREFIX, TABLE_OPTIONS_TABLE,
+};
+use crate::{Clock, KvError, Kvpair, StoredValue, SystemClock, TableOptions, TxContext, Value};
 
-use sled::{Db, IVec};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree, UnabortableTransactionError,
+};
+use sled::{Db, IVec, Transactional, Tree};
+use tokio::{task::JoinHandle, time};
+use tracing::warn;
 
-pub struct SledDB(Db);
+/// SledDB::snapshot() 在 sled 的目录下生成快照的目录
+const SNAPSHOT_DIR: &str = "snapshots";
+
+pub struct SledDB {
+    db: Db,
+    /// sled 的目录，快照保存在它下面
+    path: PathBuf,
+    clock: Arc<dyn Clock>,
+    /// 写操作共享这把锁，rename_table 独占它，改名期间不会有写入丢失
+    tables_lock: Arc<RwLock<()>>,
+    /// 每个 table 一把锁，写操作和 get_all 共享它，需要整个替换或者先检查再写入的操作
+    /// （replace、create_table、set_with_len）独占它
+    table_locks: Arc<DashMap<String, Arc<RwLock<()>>>>,
+    /// 每次写入都要用到 table 的配置，读过的配置缓存在内存里
+    options: Arc<DashMap<String, TableOptions>>,
+    /// 最多有多少个 table，None 代表不限制
+    max_tables: Option<usize>,
+    /// 限制了 table 的个数时，写入空的 table 需要先拿到它，避免同时创建的 table 超过上限
+    create_lock: Arc<Mutex<()>>,
+}
 
 impl SledDB {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        let path = path.as_ref().to_owned();
+        let db = sled::open(&path).unwrap();
+        // 进程退出时没来得及删除的快照已经没有人会用了
+        let _ = fs::remove_dir_all(path.join(SNAPSHOT_DIR));
+        Self::with_db(db, path)
+    }
+
+    fn with_db(db: Db, path: PathBuf) -> Self {
+        Self {
+            db,
+            path,
+            clock: Arc::new(SystemClock),
+            tables_lock: Default::default(),
+            table_locks: Default::default(),
+            options: Default::default(),
+            max_tables: None,
+            create_lock: Default::default(),
+        }
+    }
+
+    /// 使用指定的时钟判断过期
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// 最多允许 n 个 table，写入一个新的 table 会超过上限时返回 KvError::TooManyTables
+    ///
+    /// sled 读取不存在的 table 时也会创建一个空的 tree，所以只有不为空的 table 才占用名额
+    pub fn with_max_tables(mut self, n: usize) -> Self {
+        self.max_tables = Some(n);
+        self
+    }
+
+    /// 生成当前数据的只读快照，之后对 SledDB 的修改不会影响快照
+    ///
+    /// 拷贝期间独占 tables_lock，所有的写操作都要等待，快照中所有的 table 都是同一时刻的数据；
+    /// 数据拷贝到 sled 目录下一个临时的 sled 里，不会全部读进内存
+    pub fn snapshot(&self) -> Result<SledSnapshot, KvError> {
+        let _lock = self.tables_lock.write().unwrap();
+        let path = self
+            .path
+            .join(SNAPSHOT_DIR)
+            .join(self.db.generate_id()?.to_string());
+        let db = sled::open(&path)?;
+        for name in self.db.tree_names() {
+            let (from, to) = (self.db.open_tree(&name)?, db.open_tree(&name)?);
+            for v in from.iter() {
+                let (k, v) = v?;
+                to.insert(k, v)?;
+            }
+        }
+
+        let mut snapshot = Self::with_db(db, path.clone());
+        snapshot.clock = self.clock.clone();
+        Ok(SledSnapshot::new(snapshot, path))
+    }
+
+    /// 当前的时间，见 Clock::now
+    pub(super) fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// 读取 key 的原始数据（可能已经过期），SledSnapshot 用它执行只读的事务
+    pub(super) fn get_stored(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> Result<Option<StoredValue>, KvError> {
+        let tree = self.db.open_tree(table)?;
+        flip(tree.get(key)?.map(|v| v.try_into()))
+    }
+
+    /// 修改数据之前先拿到它
+    fn write_guard(&self) -> RwLockReadGuard<'_, ()> {
+        self.tables_lock.read().unwrap()
+    }
+
+    /// table 的锁，写操作必须在拿到 write_guard 之后再拿
+    fn table_lock(&self, table: &str) -> Arc<RwLock<()>> {
+        match self.table_locks.get(table) {
+            Some(lock) => lock.clone(),
+            None => self.table_locks.entry(table.into()).or_default().clone(),
+        }
+    }
+
+    /// 在一个事务里把 tree 的内容整个替换成 pairs，调用者需要独占 table 的锁
+    fn replace_tree(&self, table: &str, tree: &Tree, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        let _quota = self.table_quota(table, tree)?;
+        let options = self.table_options(table)?;
+        let now = self.clock.now();
+        let keys = tree.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        // 调用者独占了 table 的锁，遍历到的就是所有的 key，在一个事务里删除它们并写入新的 kv pair
+        let result = tree.transaction(|tx_tree| {
+            for key in &keys {
+                tx_tree.remove(key)?;
+            }
+            for pair in &pairs {
+                let mut v = StoredValue::new(pair.value.clone().unwrap_or_default());
+                v.seq = tx_tree.generate_id()? + 1;
+                let iv = encode(&options, v, now).map_err(ConflictableTransactionError::Abort)?;
+                tx_tree.insert(pair.key.as_bytes(), iv)?;
+            }
+            Ok(())
+        });
+        result.map_err(|e: TransactionError<KvError>| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+    }
+
+    /// 写入一个 key，调用者需要拿住 table 的锁
+    fn insert(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let tree = self.db.open_tree(table)?;
+        let _quota = self.table_quota(table, &tree)?;
+        let options = self.table_options(table)?;
+        let now = self.clock.now();
+        let mut stored = StoredValue::new(value);
+        stored.seq = self.db.generate_id()? + 1;
+        let iv = encode(&options, stored, now)?;
+        decode(tree.insert(key, iv)?, now)
+    }
+
+    /// 写入空的 table 之前确认 table 的个数没有超过上限，返回的锁要一直拿到写入完成；
+    /// 内部使用的 table 不受上限的限制
+    fn table_quota(&self, table: &str, tree: &Tree) -> Result<Option<MutexGuard<'_, ()>>, KvError> {
+        self.tables_quota(&[(table, tree)])
+    }
+
+    /// 和 table_quota 一样，但是同时写入几个 table，所有新的 table 加起来也不能超过上限
+    fn tables_quota(
+        &self,
+        tables: &[(&str, &Tree)],
+    ) -> Result<Option<MutexGuard<'_, ()>>, KvError> {
+        let new: Vec<_> = tables
+            .iter()
+            .filter(|(table, tree)| tree.is_empty() && !is_reserved_table(table))
+            .map(|(table, _tree)| *table)
+            .collect();
+        let max = match self.max_tables {
+            Some(max) if !new.is_empty() => max,
+            _ => return Ok(None),
+        };
+        let lock = self.create_lock.lock().unwrap();
+        let mut count = 0;
+        for name in self.table_iter()? {
+            if !new.contains(&name.as_str()) && !self.db.open_tree(&name)?.is_empty() {
+                count += 1;
+            }
+        }
+        match count + new.len() > max {
+            true => Err(KvError::TooManyTables(max)),
+            false => Ok(Some(lock)),
+        }
+    }
+
+    /// 启动一个后台任务，每隔 period 压缩一次 sled
+    pub fn start_compaction(&self, period: Duration) -> JoinHandle<()> {
+        let db = Self {
+            db: self.db.clone(),
+            path: self.path.clone(),
+            clock: self.clock.clone(),
+            tables_lock: self.tables_lock.clone(),
+            table_locks: self.table_locks.clone(),
+            options: self.options.clone(),
+            max_tables: self.max_tables,
+            create_lock: self.create_lock.clone(),
+        };
+        tokio::spawn(async move {
+            let mut interval = time::interval(period);
+            // 第一个 tick 会立刻返回，跳过它
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = db.compact() {
+                    warn!("Failed to compact sled db: {:?}", e);
+                }
+            }
+        })
     }
 }
 
@@ -19,11 +232,45 @@ fn flip<T, E>(v: Option<Result<T, E>>) -> Result<Option<T>, E> {
     v.map_or(Ok(None), |x| x.map(Some))
 }
 
+/// 把 sled 中存储的数据 decode 成 Value，已经过期的数据视为不存在
+fn decode(v: Option<IVec>, now: u64) -> Result<Option<Value>, KvError> {
+    let v: Option<StoredValue> = flip(v.map(|v| v.try_into()))?;
+    Ok(v.filter(|v| !v.is_expired(now)).map(|v| v.into_value()))
+}
+
+/// 按 table 的配置把 StoredValue encode 成 sled 中存储的数据
+///
+/// 没有过期时间的 value 使用 table 缺省的过期时间，需要压缩的 table 存储压缩之后的数据；
+/// 压缩之后的数据可以自动识别，decode 的时候不需要知道 table 的配置
+fn encode(options: &TableOptions, mut v: StoredValue, now: u64) -> Result<IVec, KvError> {
+    if v.expire_at == 0 && options.default_ttl_ms > 0 {
+        v.expire_in(now, Duration::from_millis(options.default_ttl_ms));
+    }
+    match options.compression {
+        true => v.to_compressed(),
+        false => v.try_into(),
+    }
+}
+
+/// 把遍历 sled 得到的数据转换成 kv pair，过期的数据会被过滤掉，无法读取的数据返回错误
+fn to_pair(v: sled::Result<(IVec, IVec)>, now: u64) -> Option<Result<Kvpair, KvError>> {
+    let (k, v) = match v {
+        Ok(v) => v,
+        Err(e) => return Some(Err(e.into())),
+    };
+    let key = match str::from_utf8(k.as_ref()) {
+        Ok(key) => key,
+        Err(_) => return Some(Err(KvError::ConvertError(format!("{:?}", k), "String"))),
+    };
+    decode(Some(v), now)
+        .transpose()
+        .map(|v| v.map(|v| Kvpair::new(key, v)))
+}
+
 impl Storage for SledDB {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let tree = self.0.open_tree(table)?;
-        let value = tree.get(key)?.map(|v| v.try_into());
-        flip(value)
+        let tree = self.db.open_tree(table)?;
+        decode(tree.get(key)?, self.clock.now())
     }
 
     fn set(
@@ -32,43 +279,806 @@ impl Storage for SledDB {
         key: impl Into<String>,
         value: impl Into<Value>,
     ) -> Result<Option<Value>, KvError> {
-        let tree = self.0.open_tree(table)?;
-        let iv: IVec = value.into().try_into()?;
-        let old = tree.insert(key.into(), iv)?.map(|v| v.try_into());
-        flip(old)
+        let _guard = self.write_guard();
+        let lock = self.table_lock(table);
+        let _table = lock.read().unwrap();
+        self.insert(table, key.into(), value.into())
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let tree = self.0.open_tree(table)?;
-        tree.contains_key(key).map_err(|e| e.into())
+        Ok(self.get(table, key)?.is_some())
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let tree = self.0.open_tree(table)?;
-        let value = tree.remove(key)?.map(|v| v.try_into());
-        flip(value)
+        let _guard = self.write_guard();
+        let lock = self.table_lock(table);
+        let _table = lock.read().unwrap();
+        let tree = self.db.open_tree(table)?;
+        decode(tree.remove(key)?, self.clock.now())
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let tree = self.0.open_tree(table)?;
-        let pairs = tree.into_iter().map(|v| v.into()).collect();
-        Ok(pairs)
+        // sled 的事务对遍历不是原子的，拿住 table 的锁，不会读到 replace 替换到一半的数据
+        let lock = self.table_lock(table);
+        let _table = lock.read().unwrap();
+        let tree = self.db.open_tree(table)?;
+        let now = self.clock.now();
+        tree.into_iter().filter_map(|v| to_pair(v, now)).collect()
+    }
+
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        // 遍历的过程中不能一直拿着 table 的锁，和 get_all 一样在锁里先把数据取出来，
+        // 不会读到 replace 替换到一半的数据；decode 失败的 kv pair 还是在对应的位置上返回错误
+        let lock = self.table_lock(table);
+        let _table = lock.read().unwrap();
+        let tree = self.db.open_tree(table)?;
+        let now = self.clock.now();
+        let results: Vec<_> = tree.into_iter().filter_map(|v| to_pair(v, now)).collect();
+        Ok(Box::new(results.into_iter()))
+    }
+
+    fn sorted_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        // sled 按 key 的字节序遍历，和 String 的顺序一致
+        self.get_iter(table)
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let tree = self.0.open_tree(table)?;
-        Ok(Box::new(StorateIter::new(tree.into_iter())))
+    fn keys(&self, table: &str, prefix: &str) -> Result<Vec<String>, KvError> {
+        let tree = self.db.open_tree(table)?;
+        let now = self.clock.now();
+        let mut keys = Vec::new();
+        for v in tree.scan_prefix(prefix) {
+            let (k, v) = v?;
+            let v: StoredValue = v.try_into()?;
+            if !v.is_expired(now) {
+                keys.push(String::from_utf8_lossy(k.as_ref()).into_owned());
+            }
+        }
+        Ok(keys)
+    }
+
+    fn drain(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let _guard = self.write_guard();
+        let lock = self.table_lock(table);
+        let _table = lock.read().unwrap();
+        let tree = self.db.open_tree(table)?;
+        let keys = tree.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let now = self.clock.now();
+        // 在事务里删除遍历到的 key，返回的 value 一定是删除时的 value；
+        // 遍历之后才写入的 key 不会被删除，留在 table 中
+        let result = tree.transaction(|tx_tree| {
+            let mut pairs = Vec::new();
+            for key in &keys {
+                if let Some(v) = tx_tree.remove(key)? {
+                    pairs.push((key, v));
+                }
+            }
+            Ok(pairs)
+        });
+        let pairs = result.map_err(|e: TransactionError<KvError>| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })?;
+
+        let mut drained = Vec::new();
+        for (k, v) in pairs {
+            if let Some(v) = decode(Some(v), now)? {
+                drained.push(Kvpair::new(String::from_utf8_lossy(k.as_ref()), v));
+            }
+        }
+        Ok(drained)
+    }
+
+    fn replace(&self, table: &str, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        let _guard = self.write_guard();
+        // 独占 table 的锁，遍历 key 和事务之间不会有别的写入，替换之后只剩下 pairs
+        let lock = self.table_lock(table);
+        let _table = lock.write().unwrap();
+        let tree = self.db.open_tree(table)?;
+        self.replace_tree(table, &tree, pairs)
+    }
+
+    fn create_table(&self, table: &str, pairs: Vec<Kvpair>) -> Result<bool, KvError> {
+        let _guard = self.write_guard();
+        // 独占 table 的锁，检查和写入之间不会插入别的写操作
+        let lock = self.table_lock(table);
+        let _table = lock.write().unwrap();
+        let tree = self.db.open_tree(table)?;
+        let now = self.clock.now();
+        for v in tree.iter().values() {
+            let v: StoredValue = v?.try_into()?;
+            if !v.is_expired(now) {
+                return Ok(false);
+            }
+        }
+        self.replace_tree(table, &tree, pairs)?;
+        Ok(true)
+    }
+
+    fn set_with_len(&self, table: &str, key: &str, value: Value) -> Result<usize, KvError> {
+        let _guard = self.write_guard();
+        // 独占 table 的锁，写入和统计之间不会插入别的写操作
+        let lock = self.table_lock(table);
+        let _table = lock.write().unwrap();
+        self.insert(table, key.into(), value)?;
+        self.count(table, "")
+    }
+
+    fn by_insertion(&self, table: &str, n: usize, newest: bool) -> Result<Vec<Kvpair>, KvError> {
+        let tree = self.db.open_tree(table)?;
+        let now = self.clock.now();
+        let mut entries = Vec::new();
+        for v in tree.iter() {
+            let (k, v) = v?;
+            let v: StoredValue = v.try_into()?;
+            if !v.is_expired(now) {
+                let key = String::from_utf8_lossy(k.as_ref()).into_owned();
+                entries.push((v.seq, key, v.into_value()));
+            }
+        }
+        Ok(take_by_insertion(entries, n, newest))
+    }
+
+    fn count(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        let tree = self.db.open_tree(table)?;
+        let now = self.clock.now();
+        // 需要 decode 出过期时间，但不会构造 Kvpair 返回
+        let mut count = 0;
+        for v in tree.scan_prefix(prefix) {
+            let v: StoredValue = v?.1.try_into()?;
+            if !v.is_expired(now) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn table_names(&self) -> Result<Vec<String>, KvError> {
+        let mut names: Vec<_> = self.table_iter()?.collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn table_iter(&self) -> Result<Box<dyn Iterator<Item = String> + '_>, KvError> {
+        // sled 自带一个缺省的 tree，它不是我们创建的 table；
+        // 每次写入都会读取 table 的配置，保存配置的 tree 总是会被创建出来，它和其他内部使用的 tree 都不算 table
+        let default = self.db.name();
+        // tree_names 返回的是 IVec，只有在用到的时候才转换成 String
+        let iter = self
+            .db
+            .tree_names()
+            .into_iter()
+            .filter(move |name| {
+                *name != default && !name.starts_with(RESERVED_TABLE_PREFIX.as_bytes())
+            })
+            .map(|name| String::from_utf8_lossy(name.as_ref()).into_owned());
+        Ok(Box::new(iter))
+    }
+
+    fn rename_table(&self, from: &str, to: &str, overwrite: bool) -> Result<(), KvError> {
+        // 独占 tables_lock，检查、搬运和删除 from 之间不会有别的写入
+        let _lock = self.tables_lock.write().unwrap();
+        let names = self.table_names()?;
+        if !names.iter().any(|name| name == from) {
+            return Err(KvError::NotFound(format!("table {}", from)));
+        }
+        if from == to {
+            return Ok(());
+        }
+        if !overwrite && names.iter().any(|name| name == to) {
+            return Err(KvError::TableExists(to.into()));
+        }
+
+        // sled 不能直接给 tree 改名，只能在一个跨 tree 的事务里把数据搬过去，
+        // 这样读操作不会看到搬到一半的 table
+        let src = self.db.open_tree(from)?;
+        let dst = self.db.open_tree(to)?;
+        let config = self.db.open_tree(TABLE_OPTIONS_TABLE)?;
+        let old_keys = dst.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let pairs = src.iter().collect::<Result<Vec<_>, _>>()?;
+        let result = (&src, &dst, &config).transaction(|(tx_src, tx_dst, tx_config)| {
+            for key in &old_keys {
+                tx_dst.remove(key)?;
+            }
+            for (key, value) in &pairs {
+                tx_dst.insert(key, value)?;
+                tx_src.remove(key)?;
+            }
+            // table 的配置跟着一起改名，from 没有配置时 to 也使用缺省的配置
+            match tx_config.remove(from.as_bytes())? {
+                Some(options) => tx_config.insert(to.as_bytes(), options)?,
+                None => tx_config.remove(to.as_bytes())?,
+            };
+            Ok(())
+        });
+        result.map_err(|e: TransactionError<KvError>| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })?;
+
+        self.db.drop_tree(from)?;
+        self.options.remove(from);
+        self.options.remove(to);
+        Ok(())
+    }
+
+    fn move_keys<F>(&self, from: &str, to: &str, pick: F) -> Result<usize, KvError>
+    where
+        F: Fn(&str, Option<Duration>) -> bool,
+    {
+        if from == to {
+            let msg = format!("Cannot move keys of table {} into itself", from);
+            return Err(KvError::InvalidCommand(msg));
+        }
+
+        let _guard = self.write_guard();
+        // 两个 table 的锁按名字顺序拿，避免和反方向的 move_keys 死锁
+        let (first, second) = if from < to { (from, to) } else { (to, from) };
+        let (first, second) = (self.table_lock(first), self.table_lock(second));
+        let _first = first.read().unwrap();
+        let _second = second.read().unwrap();
+        let src = self.db.open_tree(from)?;
+        let dst = self.db.open_tree(to)?;
+        let _quota = self.table_quota(to, &dst)?;
+        let options = self.table_options(to)?;
+        let now = self.clock.now();
+        // 事务里不能遍历 tree，先取出所有的 key，再在一个跨两个 tree 的事务里逐个检查和移动，
+        // 遍历之后才写入的 key 不会被移动
+        let keys = src.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let result = (&src, &dst).transaction(|(tx_src, tx_dst)| {
+            let mut moved = 0;
+            for key in &keys {
+                let mut v: StoredValue = match tx_src.get(key)? {
+                    Some(v) => v.try_into().map_err(ConflictableTransactionError::Abort)?,
+                    None => continue,
+                };
+                let name = String::from_utf8_lossy(key.as_ref());
+                if v.is_expired(now) || !pick(&name, v.ttl(now)) {
+                    continue;
+                }
+                v.expire_at = 0;
+                v.seq = tx_dst.generate_id()? + 1;
+                let iv = encode(&options, v, now).map_err(ConflictableTransactionError::Abort)?;
+                tx_dst.insert(key, iv)?;
+                tx_src.remove(key)?;
+                moved += 1;
+            }
+            Ok(moved)
+        });
+        result.map_err(|e: TransactionError<KvError>| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+    }
+
+    fn expire_table(&self, table: &str, ttl: Duration) -> Result<usize, KvError> {
+        let _guard = self.write_guard();
+        let lock = self.table_lock(table);
+        let _table = lock.read().unwrap();
+        let tree = self.db.open_tree(table)?;
+        let options = self.table_options(table)?;
+        let now = self.clock.now();
+        let mut count = 0;
+        for v in tree.iter() {
+            let (k, old) = v?;
+            let mut value: StoredValue = old.clone().try_into()?;
+            if value.is_expired(now) {
+                continue;
+            }
+            value.expire_in(now, ttl);
+            // 早期版本写入的数据没有顺序号，重新写入时要补上，否则会被当成早期的格式
+            if value.seq == 0 {
+                value.seq = self.db.generate_id()? + 1;
+            }
+            let new = encode(&options, value, now)?;
+            // 用 compare_and_swap 而不是 batch 一起写入：遍历之后如果 key 被并发修改了，
+            // batch 会用旧的 value 覆盖掉新的修改，这里宁可放弃这个 key
+            if tree.compare_and_swap(k, Some(old), Some(new))?.is_ok() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn reap_expired(&self, table: &str, remove: bool) -> Result<usize, KvError> {
+        let _guard = self.write_guard();
+        let lock = self.table_lock(table);
+        let _table = lock.read().unwrap();
+        let tree = self.db.open_tree(table)?;
+        let now = self.clock.now();
+        let mut count = 0;
+        for v in tree.iter() {
+            let (k, old) = v?;
+            let value: StoredValue = old.clone().try_into()?;
+            if !value.is_expired(now) {
+                continue;
+            }
+            // 和 expire_table 一样用 compare_and_swap：遍历之后被重新写入的 key 不能删除
+            if !remove
+                || tree
+                    .compare_and_swap(k, Some(old), None as Option<IVec>)?
+                    .is_ok()
+            {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn info(&self) -> Result<Vec<Kvpair>, KvError> {
+        // sled 0.34 没有公开 page cache 的统计数据，只能返回这些
+        Ok(vec![
+            Kvpair::new("backend", "sled".into()),
+            Kvpair::new("size_on_disk", (self.db.size_on_disk()? as i64).into()),
+            Kvpair::new("recovered", self.db.was_recovered().into()),
+            Kvpair::new("trees", (self.db.tree_names().len() as i64).into()),
+        ])
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<(), KvError> {
+        // 过期的 key 没有被读到之前一直占着空间，先把所有 table（包括内部使用的）中过期的 key 删掉；
+        // sled 没有暴露 gc 的接口，之后 flush 把脏页写回磁盘，sled 才能回收只剩旧数据的 segment
+        let default = self.db.name();
+        let mut result = Ok(());
+        for name in self.db.tree_names() {
+            if name == default {
+                continue;
+            }
+            let table = String::from_utf8_lossy(&name);
+            // 一个 table 出错不影响其他 table 的清理，最后还是要 flush
+            if let Err(e) = self.reap_expired(&table, true) {
+                warn!("Failed to remove expired keys of table {}: {:?}", table, e);
+                result = Err(e);
+            }
+        }
+        self.db.flush()?;
+        result
+    }
+
+    fn verify(&self, table: &str) -> Result<Vec<String>, KvError> {
+        // open_tree 会创建不存在的 table，检查之前先确认 table 存在
+        if !self
+            .db
+            .tree_names()
+            .iter()
+            .any(|name| name == table.as_bytes())
+        {
+            return Ok(Vec::new());
+        }
+        let tree = self.db.open_tree(table)?;
+        // decode 失败的数据不影响别的 key；已经过期的数据也要检查
+        let mut bad = Vec::new();
+        for v in tree.iter() {
+            let (k, v) = v?;
+            if StoredValue::try_from(v).is_err() {
+                bad.push(String::from_utf8_lossy(k.as_ref()).into_owned());
+            }
+        }
+        Ok(bad)
+    }
+
+    fn table_options(&self, table: &str) -> Result<TableOptions, KvError> {
+        if let Some(options) = self.options.get(table) {
+            return Ok(options.clone());
+        }
+        // 在 entry 的锁里读取配置，rename_table 清除缓存时不会被一个读到一半的旧配置覆盖
+        match self.options.entry(table.into()) {
+            Entry::Occupied(entry) => Ok(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let config = self.db.open_tree(TABLE_OPTIONS_TABLE)?;
+                let now = self.clock.now();
+                let options = decode_table_options(table, decode(config.get(table)?, now));
+                Ok(entry.insert(options).clone())
+            }
+        }
+    }
+
+    fn set_table_options(
+        &self,
+        table: &str,
+        options: TableOptions,
+    ) -> Result<TableOptions, KvError> {
+        let _guard = self.write_guard();
+        let config = self.db.open_tree(TABLE_OPTIONS_TABLE)?;
+        // 拿住 entry 的锁再写入，缓存和 sled 中的配置总是一致的
+        let mut entry = self.options.entry(table.into()).or_default();
+        let now = self.clock.now();
+        let mut stored = StoredValue::new(options.clone());
+        stored.seq = self.db.generate_id()? + 1;
+        let iv = encode(&TableOptions::default(), stored, now)?;
+        let old = decode_table_options(table, decode(config.insert(table, iv)?, now));
+        *entry = options;
+        Ok(old)
+    }
+
+    fn get_many(&self, reads: &[(String, String)]) -> Result<Vec<Option<Value>>, KvError> {
+        // sled 0.34 没有轻量的快照，在一个跨所有涉及的 tree 的事务里读，事务保证读到的是同一时刻的数据
+        // 不存在的 table 里读不到任何 key，不用放进事务里，open_tree 也会创建它们
+        let existing = self.db.tree_names();
+        let mut names: Vec<_> = reads
+            .iter()
+            .map(|(table, _k)| table.as_str())
+            .filter(|name| existing.iter().any(|t| t == name.as_bytes()))
+            .collect();
+        if names.is_empty() {
+            return Ok(vec![None; reads.len()]);
+        }
+        names.sort_unstable();
+        names.dedup();
+        let trees = names
+            .iter()
+            .map(|name| self.db.open_tree(name))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let now = self.clock.now();
+        let result = trees[..].transaction(|tx_trees| {
+            let mut values = Vec::with_capacity(reads.len());
+            for (table, key) in reads {
+                let value = match names.binary_search(&table.as_str()) {
+                    Ok(i) => tx_trees[i].get(key.as_bytes())?,
+                    Err(_) => None,
+                };
+                values.push(value);
+            }
+            Ok::<_, ConflictableTransactionError<KvError>>(values)
+        });
+        let values = result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })?;
+        values.into_iter().map(|v| decode(v, now)).collect()
+    }
+
+    fn read_transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        // sled 的事务会和写操作冲突，只读时直接从 tree 里读；每个 key 只读一次，
+        // 同一个 key 的多次读取结果一致，不同的 key 不保证是同一时刻的数据
+        let tree = self.db.open_tree(table)?;
+        let read: RefCell<HashMap<String, Option<StoredValue>>> = Default::default();
+        let reader = |key: &str| {
+            if let Some(v) = read.borrow().get(key) {
+                return Ok(v.clone());
+            }
+            let v = flip(tree.get(key)?.map(|v| v.try_into()))?;
+            read.borrow_mut().insert(key.to_owned(), v.clone());
+            Ok(v)
+        };
+        let mut tx = TxContext::new(&reader, self.clock.now());
+        let result = f(&mut tx)?;
+        match tx.writes().is_empty() {
+            true => Ok(result),
+            false => Err(KvError::ReadOnly),
+        }
+    }
+
+    fn transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        let _guard = self.write_guard();
+        let lock = self.table_lock(table);
+        let _table = lock.read().unwrap();
+        let tree = self.db.open_tree(table)?;
+        // 空的 table 先试着执行一次，只读的事务不需要占用名额
+        if self.max_tables.is_some() && tree.is_empty() {
+            let reader = |_key: &str| Ok(None);
+            let mut tx = TxContext::new(&reader, self.clock.now());
+            let result = f(&mut tx)?;
+            if tx.writes().is_empty() {
+                return Ok(result);
+            }
+        }
+        let _quota = self.table_quota(table, &tree)?;
+        let options = self.table_options(table)?;
+        let now = self.clock.now();
+        let result = tree.transaction(|tx_tree| {
+            let conflict = Cell::new(None);
+            let reader = tx_reader(tx_tree, &conflict);
+            let mut tx = TxContext::new(&reader, now);
+            let result = f(&mut tx).map_err(|e| tx_error(e, &conflict))?;
+            apply_writes(tx_tree, &options, tx.into_writes(), now)?;
+            Ok(result)
+        });
+
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+    }
+
+    fn transaction_pair<F, R>(&self, first: &str, second: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext, &mut TxContext) -> Result<R, KvError>,
+    {
+        if first == second {
+            let msg = format!("Cannot run a transaction on table {} twice", first);
+            return Err(KvError::InvalidCommand(msg));
+        }
+
+        let _guard = self.write_guard();
+        // 按名字的顺序拿两个 table 的锁
+        let mut names = [first, second];
+        names.sort();
+        let locks: Vec<_> = names.iter().map(|t| self.table_lock(t)).collect();
+        let _tables: Vec<_> = locks.iter().map(|lock| lock.read().unwrap()).collect();
+        let trees = (self.db.open_tree(first)?, self.db.open_tree(second)?);
+        let _quota = self.tables_quota(&[(first, &trees.0), (second, &trees.1)])?;
+        let options = (self.table_options(first)?, self.table_options(second)?);
+        let now = self.clock.now();
+        // 一个跨两个 tree 的 sled 事务，两个 tree 的修改一起提交
+        let result = (&trees.0, &trees.1).transaction(|(tx_first, tx_second)| {
+            let conflict = Cell::new(None);
+            let (read_first, read_second) = (
+                tx_reader(tx_first, &conflict),
+                tx_reader(tx_second, &conflict),
+            );
+            let mut first = TxContext::new(&read_first, now);
+            let mut second = TxContext::new(&read_second, now);
+            let result = f(&mut first, &mut second).map_err(|e| tx_error(e, &conflict))?;
+            apply_writes(tx_first, &options.0, first.into_writes(), now)?;
+            apply_writes(tx_second, &options.1, second.into_writes(), now)?;
+            Ok(result)
+        });
+
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
     }
 }
 
-impl From<sled::Result<(IVec, IVec)>> for Kvpair {
-    fn from(v: sled::Result<(IVec, IVec)>) -> Self {
-        match v {
-            Ok((k, v)) => match v.try_into() {
-                Ok(v) => Kvpair::new(str::from_utf8(k.as_ref()).unwrap(), v),
-                Err(_) => Kvpair::default(),
-            },
-            Err(_) => Kvpair::default(),
+/// 事务中读取 key 的 reader，读的时候遇到冲突需要把冲突记下来原样交给 sled，
+/// 这样 sled 才会重试整个事务（见 tx_error）
+fn tx_reader<'a>(
+    tx_tree: &'a TransactionalTree,
+    conflict: &'a Cell<Option<UnabortableTransactionError>>,
+) -> impl Fn(&str) -> Result<Option<StoredValue>, KvError> + 'a {
+    move |key| match tx_tree.get(key) {
+        Ok(v) => flip(v.map(|v| v.try_into())),
+        Err(e) => {
+            conflict.set(Some(e));
+            Err(KvError::Internal("Transaction conflict".into()))
         }
     }
 }
+
+/// 事务中 f 返回的错误：读的时候遇到过冲突时交给 sled 重试，否则放弃事务
+fn tx_error(
+    e: KvError,
+    conflict: &Cell<Option<UnabortableTransactionError>>,
+) -> ConflictableTransactionError<KvError> {
+    match conflict.take() {
+        Some(e) => e.into(),
+        None => ConflictableTransactionError::Abort(e),
+    }
+}
+
+/// 把事务中的写操作写入 tx_tree
+fn apply_writes(
+    tx_tree: &TransactionalTree,
+    options: &TableOptions,
+    writes: Vec<(String, Option<StoredValue>)>,
+    now: u64,
+) -> ConflictableTransactionResult<(), KvError> {
+    for (key, value) in writes {
+        match value {
+            Some(mut v) => {
+                // 新写入的 value 需要分配顺序号，只修改过期时间的保留原来的
+                if v.seq == 0 {
+                    v.seq = tx_tree.generate_id()? + 1;
+                }
+                let iv = encode(options, v, now).map_err(ConflictableTransactionError::Abort)?;
+                tx_tree.insert(key.as_bytes(), iv)?;
+            }
+            None => {
+                tx_tree.remove(key.as_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dispatch, CommandRequest, MockClock};
+    use tempfile::tempdir;
+
+    fn raw(store: &SledDB, table: &str, key: &str) -> IVec {
+        store
+            .db
+            .open_tree(table)
+            .unwrap()
+            .get(key)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn compression_should_only_affect_later_writes_of_the_table() {
+        let store = SledDB::new(tempdir().unwrap());
+        let value = "v".repeat(1024);
+        store.set("t1", "old", value.as_str()).unwrap();
+        let options = TableOptions {
+            compression: true,
+            ..Default::default()
+        };
+        store.set_table_options("t1", options).unwrap();
+        store.set("t1", "new", value.as_str()).unwrap();
+        store.set("t2", "new", value.as_str()).unwrap();
+        store
+            .transaction("t1", |tx| tx.set("tx", value.as_str()))
+            .unwrap();
+
+        // 只有 t1 中开启压缩之后写入的数据被压缩了
+        let plain = raw(&store, "t2", "new").len();
+        assert!(plain > 1024);
+        assert_eq!(raw(&store, "t1", "old").len(), plain);
+        assert!(raw(&store, "t1", "new").len() < plain / 10);
+        assert!(raw(&store, "t1", "tx").len() < plain / 10);
+
+        // 读出来的 value 不受影响
+        for (table, key) in [("t1", "old"), ("t1", "new"), ("t1", "tx"), ("t2", "new")] {
+            assert_eq!(store.get(table, key).unwrap(), Some(value.as_str().into()));
+        }
+        let res = store.transaction("t1", |tx| tx.get("new")).unwrap();
+        assert_eq!(res, Some(value.as_str().into()));
+    }
+
+    #[test]
+    fn verify_should_report_undecodable_values() {
+        let store = SledDB::new(tempdir().unwrap());
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t2", "k1", "v1").unwrap();
+        let tree = store.db.open_tree("t1").unwrap();
+        tree.insert("bad", &[0xff, 0xff, 0xff]).unwrap();
+        // 以压缩标记开头，但是无法解压
+        tree.insert("bad_compressed", &[0, 1, 2]).unwrap();
+
+        assert_eq!(store.verify("t1").unwrap(), ["bad", "bad_compressed"]);
+        assert!(store.verify("t2").unwrap().is_empty());
+        // 其它的 key 不受影响
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+
+        // 检查不存在的 table 不会创建它
+        assert!(store.verify("t3").unwrap().is_empty());
+        assert_eq!(store.table_names().unwrap(), ["t1", "t2"]);
+
+        // 保存 table 配置的 table 也会被检查
+        let config = store.db.open_tree(TABLE_OPTIONS_TABLE).unwrap();
+        config.insert("t1", &[0xff, 0xff, 0xff]).unwrap();
+        let res = dispatch(CommandRequest::new_verify(""), &store);
+        assert_eq!(res.values, [3.into()]);
+        let pair = res.pairs.iter().find(|p| p.key == TABLE_OPTIONS_TABLE);
+        assert_eq!(pair.unwrap().value, Some(Value::new_list(["t1".into()])));
+    }
+
+    #[test]
+    fn legacy_values_should_be_readable() {
+        let store = SledDB::new(tempdir().unwrap());
+        // 早期的版本直接存储 encode 之后的 Value
+        let tree = store.db.open_tree("t1").unwrap();
+        for (k, v) in [
+            ("s", "v1".into()),
+            ("i", 42.into()),
+            ("e", Value::default()),
+        ] {
+            let iv: IVec = v.try_into().unwrap();
+            tree.insert(k, iv).unwrap();
+        }
+
+        assert_eq!(store.get("t1", "s").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "i").unwrap(), Some(42.into()));
+        assert_eq!(store.get("t1", "e").unwrap(), Some(Value::default()));
+        assert!(store.verify("t1").unwrap().is_empty());
+
+        // 修改过期时间之后重新写入的是新的格式
+        store.expire_table("t1", Duration::from_secs(10)).unwrap();
+        assert_eq!(store.get("t1", "s").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "i").unwrap(), Some(42.into()));
+    }
+
+    #[test]
+    fn iteration_should_report_undecodable_values() {
+        let store = SledDB::new(tempdir().unwrap());
+        store.set("t1", "k1", "v1").unwrap();
+        store
+            .db
+            .open_tree("t1")
+            .unwrap()
+            .insert("bad", &[0xff])
+            .unwrap();
+
+        assert!(store.get_all("t1").is_err());
+        let results: Vec<_> = store.get_iter("t1").unwrap().collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert_eq!(
+            results[1].as_ref().unwrap(),
+            &Kvpair::new("k1", "v1".into())
+        );
+    }
+
+    #[test]
+    fn default_ttl_should_apply_to_keys_without_ttl() {
+        let clock = MockClock::new();
+        let store = SledDB::new(tempdir().unwrap()).with_clock(clock.clone());
+        let options = TableOptions {
+            default_ttl_ms: 1000,
+            ..Default::default()
+        };
+        store.set_table_options("t1", options).unwrap();
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t2", "k1", "v1").unwrap();
+        // 指定了过期时间的 key 不受影响
+        store.set("t1", "k2", "v2").unwrap();
+        store.expire("t1", "k2", Duration::from_secs(10)).unwrap();
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t2", "k1").unwrap(), Some("v1".into()));
+    }
+
+    #[test]
+    fn compact_should_remove_expired_keys() {
+        let clock = MockClock::new();
+        let store = SledDB::new(tempdir().unwrap()).with_clock(clock.clone());
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        store.expire("t1", "k2", Duration::from_secs(1)).unwrap();
+        clock.advance(Duration::from_secs(2));
+
+        // 没有被读到的过期 key 还在 sled 里，compact 之后才被删除
+        let tree = store.db.open_tree("t1").unwrap();
+        assert_eq!(tree.len(), 2);
+        store.compact().unwrap();
+        assert_eq!(tree.len(), 1);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+    }
+
+    #[test]
+    fn create_table_should_be_atomic() {
+        let clock = MockClock::new();
+        let store = SledDB::new(tempdir().unwrap()).with_clock(clock.clone());
+        // 只有过期的 key 时 table 也算是空的
+        store.set("t1", "k1", "v1").unwrap();
+        store.expire("t1", "k1", Duration::from_secs(1)).unwrap();
+        clock.advance(Duration::from_secs(2));
+
+        // 同时创建，只有一个能成功，table 里只有它写入的数据
+        let created: Vec<bool> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    let store = &store;
+                    s.spawn(move || {
+                        let pairs = vec![Kvpair::new(format!("k{}", i), "v".into())];
+                        store.create_table("t1", pairs).unwrap()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(created.iter().filter(|c| **c).count(), 1);
+        let winner = created.iter().position(|c| *c).unwrap();
+        let keys: Vec<_> = store
+            .get_all("t1")
+            .unwrap()
+            .into_iter()
+            .map(|pair| pair.key)
+            .collect();
+        assert_eq!(keys, vec![format!("k{}", winner)]);
+    }
+}
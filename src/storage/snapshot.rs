@@ -0,0 +1,216 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{SledDB, Storage};
+use crate::{KvError, Kvpair, TableOptions, TxContext, Value};
+
+/// SledDB 在某一时刻的只读快照，由 SledDB::snapshot() 生成
+///
+/// sled 0.34 没有提供快照的接口，生成快照时会把所有的数据拷贝到磁盘上一个临时的 sled 里，
+/// 之后对 SledDB 的修改不会影响快照，快照（和它所有的 clone）被 drop 之后临时的 sled 会被删除。
+/// 所有的写操作都会返回 KvError::ReadOnly
+#[derive(Clone)]
+pub struct SledSnapshot {
+    inner: Arc<SledDB>,
+    /// 字段按声明的顺序 drop，临时的 sled 关闭之后才删除它的目录
+    _dir: Arc<SnapshotDir>,
+}
+
+/// 快照的目录，drop 时删除
+///
+/// sled 的 temporary 配置要等后台的线程都退出才删除目录，时机不确定，
+/// 这里在最后一个快照被 drop 时自己删除
+struct SnapshotDir(PathBuf);
+
+impl Drop for SnapshotDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+impl fmt::Debug for SledSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SledSnapshot").finish_non_exhaustive()
+    }
+}
+
+impl SledSnapshot {
+    pub(crate) fn new(inner: SledDB, dir: PathBuf) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            _dir: Arc::new(SnapshotDir(dir)),
+        }
+    }
+}
+
+impl Storage for SledSnapshot {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(
+        &self,
+        _table: &str,
+        _key: impl Into<String>,
+        _value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        Err(KvError::ReadOnly)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, _table: &str, _key: &str) -> Result<Option<Value>, KvError> {
+        Err(KvError::ReadOnly)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn sorted_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        self.inner.sorted_iter(table)
+    }
+
+    fn keys(&self, table: &str, prefix: &str) -> Result<Vec<String>, KvError> {
+        self.inner.keys(table, prefix)
+    }
+
+    fn drain(&self, _table: &str) -> Result<Vec<Kvpair>, KvError> {
+        Err(KvError::ReadOnly)
+    }
+
+    fn replace(&self, _table: &str, _pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        Err(KvError::ReadOnly)
+    }
+
+    fn by_insertion(&self, table: &str, n: usize, newest: bool) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.by_insertion(table, n, newest)
+    }
+
+    fn count(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        self.inner.count(table, prefix)
+    }
+
+    fn rename_table(&self, _from: &str, _to: &str, _overwrite: bool) -> Result<(), KvError> {
+        Err(KvError::ReadOnly)
+    }
+
+    fn move_keys<F>(&self, _from: &str, _to: &str, _pick: F) -> Result<usize, KvError>
+    where
+        F: Fn(&str, Option<Duration>) -> bool,
+    {
+        Err(KvError::ReadOnly)
+    }
+
+    fn table_names(&self) -> Result<Vec<String>, KvError> {
+        self.inner.table_names()
+    }
+
+    fn table_iter(&self) -> Result<Box<dyn Iterator<Item = String> + '_>, KvError> {
+        self.inner.table_iter()
+    }
+
+    fn expire_table(&self, _table: &str, _ttl: Duration) -> Result<usize, KvError> {
+        Err(KvError::ReadOnly)
+    }
+
+    fn reap_expired(&self, table: &str, remove: bool) -> Result<usize, KvError> {
+        if remove {
+            return Err(KvError::ReadOnly);
+        }
+        self.inner.reap_expired(table, false)
+    }
+
+    fn info(&self) -> Result<Vec<Kvpair>, KvError> {
+        let mut info = self.inner.info()?;
+        if let Some(backend) = info.iter_mut().find(|pair| pair.key == "backend") {
+            backend.value = Some("sled_snapshot".into());
+        }
+        Ok(info)
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<(), KvError> {
+        // 快照是临时的，用完就删除，没有必要压缩
+        Ok(())
+    }
+
+    fn transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        // 只读的事务可以正常执行，有写操作的事务会被拒绝
+        let reader = |key: &str| self.inner.get_stored(table, key);
+        let mut tx = TxContext::new(&reader, self.inner.now());
+        let result = f(&mut tx)?;
+        if !tx.into_writes().is_empty() {
+            return Err(KvError::ReadOnly);
+        }
+        Ok(result)
+    }
+
+    fn transaction_pair<F, R>(&self, first: &str, second: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext, &mut TxContext) -> Result<R, KvError>,
+    {
+        if first == second {
+            let msg = format!("Cannot run a transaction on table {} twice", first);
+            return Err(KvError::InvalidCommand(msg));
+        }
+        let read_first = |key: &str| self.inner.get_stored(first, key);
+        let read_second = |key: &str| self.inner.get_stored(second, key);
+        let now = self.inner.now();
+        let mut tx_first = TxContext::new(&read_first, now);
+        let mut tx_second = TxContext::new(&read_second, now);
+        let result = f(&mut tx_first, &mut tx_second)?;
+        if !tx_first.into_writes().is_empty() || !tx_second.into_writes().is_empty() {
+            return Err(KvError::ReadOnly);
+        }
+        Ok(result)
+    }
+
+    fn read_transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        self.transaction(table, f)
+    }
+
+    fn get_many(&self, reads: &[(String, String)]) -> Result<Vec<Option<Value>>, KvError> {
+        self.inner.get_many(reads)
+    }
+
+    fn verify(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.verify(table)
+    }
+
+    fn table_options(&self, table: &str) -> Result<TableOptions, KvError> {
+        self.inner.table_options(table)
+    }
+
+    fn set_table_options(
+        &self,
+        _table: &str,
+        _options: TableOptions,
+    ) -> Result<TableOptions, KvError> {
+        Err(KvError::ReadOnly)
+    }
+}
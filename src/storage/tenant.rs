@@ -0,0 +1,244 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{is_reserved_table, RESERVED_TABLE_PREFIX};
+use crate::{KvError, Kvpair, Storage, TableOptions, TxContext, Value};
+
+/// 多租户的存储：所有的 table 都会被改写成 "tenant/table"，不同租户之间的数据互相隔离
+///
+/// 内部使用的 table（比如 "__hits:table"）被改写成 "__hits:table/tenant"，在底层的存储里
+/// 仍然是内部使用的 table，不占用 table 的名额
+///
+/// 多个 TenantStore 可以共享同一个底层的存储，通常每个租户一个，租户是客户端证书的 CN
+/// （见 TlsServerAcceptor::peer_common_name 和 TenantServices）
+#[derive(Debug)]
+pub struct TenantStore<S> {
+    inner: Arc<S>,
+    tenant: String,
+}
+
+impl<S> Clone for TenantStore<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            tenant: self.tenant.clone(),
+        }
+    }
+}
+
+impl<S: Storage> TenantStore<S> {
+    /// 租户的名字不能为空，不能包含 '/'，否则 "tenant/table" 无法区分租户和 table；
+    /// 也不能以内部使用的前缀开头，否则租户的 table 会被当成内部的 table
+    pub fn new(inner: Arc<S>, tenant: impl Into<String>) -> Result<Self, KvError> {
+        let tenant = tenant.into();
+        if tenant.is_empty() || tenant.contains('/') || tenant.starts_with(RESERVED_TABLE_PREFIX) {
+            return Err(KvError::InvalidCommand(format!(
+                "Invalid tenant name: {:?}",
+                tenant
+            )));
+        }
+        Ok(Self { inner, tenant })
+    }
+
+    /// 当前的租户
+    pub fn tenant(&self) -> &str {
+        &self.tenant
+    }
+
+    fn table(&self, table: &str) -> String {
+        match is_reserved_table(table) {
+            true => format!("{}/{}", table, self.tenant),
+            false => format!("{}/{}", self.tenant, table),
+        }
+    }
+}
+
+impl<S: Storage> Storage for TenantStore<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(&self.table(table), key)
+    }
+
+    fn set(
+        &self,
+        table: &str,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        self.inner.set(&self.table(table), key, value)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(&self.table(table), key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.del(&self.table(table), key)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(&self.table(table))
+    }
+
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        self.inner.get_iter(&self.table(table))
+    }
+
+    fn sorted_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>> + Send>, KvError> {
+        self.inner.sorted_iter(&self.table(table))
+    }
+
+    fn keys(&self, table: &str, prefix: &str) -> Result<Vec<String>, KvError> {
+        self.inner.keys(&self.table(table), prefix)
+    }
+
+    fn drain(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.drain(&self.table(table))
+    }
+
+    fn replace(&self, table: &str, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        self.inner.replace(&self.table(table), pairs)
+    }
+
+    fn by_insertion(&self, table: &str, n: usize, newest: bool) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.by_insertion(&self.table(table), n, newest)
+    }
+
+    fn count(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        self.inner.count(&self.table(table), prefix)
+    }
+
+    fn reap_expired(&self, table: &str, remove: bool) -> Result<usize, KvError> {
+        self.inner.reap_expired(&self.table(table), remove)
+    }
+
+    fn rename_table(&self, from: &str, to: &str, overwrite: bool) -> Result<(), KvError> {
+        self.inner
+            .rename_table(&self.table(from), &self.table(to), overwrite)
+    }
+
+    fn move_keys<F>(&self, from: &str, to: &str, pick: F) -> Result<usize, KvError>
+    where
+        F: Fn(&str, Option<Duration>) -> bool,
+    {
+        self.inner
+            .move_keys(&self.table(from), &self.table(to), pick)
+    }
+
+    fn table_names(&self) -> Result<Vec<String>, KvError> {
+        // 只返回当前租户的 table，并去掉租户的前缀；租户内部使用的 table 没有这个前缀，不会返回
+        let prefix = format!("{}/", self.tenant);
+        Ok(self
+            .inner
+            .table_names()?
+            .into_iter()
+            .filter_map(|name| name.strip_prefix(&prefix).map(|v| v.to_owned()))
+            .filter(|name| !is_reserved_table(name))
+            .collect())
+    }
+
+    fn table_iter(&self) -> Result<Box<dyn Iterator<Item = String> + '_>, KvError> {
+        let prefix = format!("{}/", self.tenant);
+        let iter = self
+            .inner
+            .table_iter()?
+            .filter_map(move |name| name.strip_prefix(&prefix).map(|v| v.to_owned()))
+            .filter(|name| !is_reserved_table(name));
+        Ok(Box::new(iter))
+    }
+
+    fn create_table(&self, table: &str, pairs: Vec<Kvpair>) -> Result<bool, KvError> {
+        self.inner.create_table(&self.table(table), pairs)
+    }
+
+    fn set_with_len(&self, table: &str, key: &str, value: Value) -> Result<usize, KvError> {
+        self.inner.set_with_len(&self.table(table), key, value)
+    }
+
+    fn verify(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.verify(&self.table(table))
+    }
+
+    fn table_options(&self, table: &str) -> Result<TableOptions, KvError> {
+        self.inner.table_options(&self.table(table))
+    }
+
+    fn set_table_options(
+        &self,
+        table: &str,
+        options: TableOptions,
+    ) -> Result<TableOptions, KvError> {
+        self.inner.set_table_options(&self.table(table), options)
+    }
+
+    fn get_many(&self, reads: &[(String, String)]) -> Result<Vec<Option<Value>>, KvError> {
+        let reads: Vec<_> = reads
+            .iter()
+            .map(|(table, key)| (self.table(table), key.clone()))
+            .collect();
+        self.inner.get_many(&reads)
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.inner.flush()
+    }
+
+    fn info(&self) -> Result<Vec<Kvpair>, KvError> {
+        // 只统计当前租户的数据，底层存储的其他信息可能涉及别的租户，都不返回
+        let mut info: Vec<_> = self
+            .inner
+            .info()?
+            .into_iter()
+            .filter(|pair| pair.key == "backend")
+            .collect();
+        let names = self.table_names()?;
+        let mut entries = 0;
+        for name in &names {
+            entries += self.count(name, "")?;
+        }
+        info.push(Kvpair::new("tables", (names.len() as i64).into()));
+        info.push(Kvpair::new("entries", (entries as i64).into()));
+        info.push(Kvpair::new("tenant", self.tenant.as_str().into()));
+        Ok(info)
+    }
+
+    fn compact(&self) -> Result<(), KvError> {
+        // 底层的存储是所有租户共享的，租户只能清理自己的 table 中过期的 key；
+        // 压缩整个存储（包括内部使用的 table）需要直接调用底层存储的 compact
+        for name in self.table_names()? {
+            self.reap_expired(&name, true)?;
+        }
+        Ok(())
+    }
+
+    fn transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        self.inner.transaction(&self.table(table), f)
+    }
+
+    fn transaction_pair<F, R>(&self, first: &str, second: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext, &mut TxContext) -> Result<R, KvError>,
+    {
+        self.inner
+            .transaction_pair(&self.table(first), &self.table(second), f)
+    }
+
+    fn read_transaction<F, R>(&self, table: &str, f: F) -> Result<R, KvError>
+    where
+        F: Fn(&mut TxContext) -> Result<R, KvError>,
+    {
+        self.inner.read_transaction(&self.table(table), f)
+    }
+
+    fn expire_table(&self, table: &str, ttl: Duration) -> Result<usize, KvError> {
+        self.inner.expire_table(&self.table(table), ttl)
+    }
+}
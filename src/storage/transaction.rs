@@ -0,0 +1,181 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{KeyNormalizer, KvError, StoredValue, Value};
+
+/// 事务中的一个回滚点，由 TxContext::savepoint() 生成
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint(usize);
+
+/// 从存储中读取一个 key 的原始数据（可能已经过期）
+pub(crate) type TxReader<'a> = &'a dyn Fn(&str) -> Result<Option<StoredValue>, KvError>;
+
+/// 事务的上下文
+///
+/// 事务中所有的写操作都按顺序缓存在这里，事务提交时才真正写入存储；
+/// 读操作先查缓存的写操作，找不到再去存储里读
+pub struct TxContext<'a> {
+    reader: TxReader<'a>,
+    /// 事务开始的时间（unix 时间戳，毫秒），事务中所有过期相关的判断都以它为准
+    now: u64,
+    writes: Vec<(String, Option<StoredValue>)>,
+    /// 事务中所有的 key 在访问存储之前都会先经过它
+    normalizer: Option<Arc<dyn KeyNormalizer>>,
+}
+
+impl<'a> TxContext<'a> {
+    pub(crate) fn new(reader: TxReader<'a>, now: u64) -> Self {
+        Self {
+            reader,
+            now,
+            writes: Vec::new(),
+            normalizer: None,
+        }
+    }
+
+    /// 设置 key 的 normalizer，见 NormalizingStore
+    pub(crate) fn set_normalizer(&mut self, normalizer: Arc<dyn KeyNormalizer>) {
+        self.normalizer = Some(normalizer);
+    }
+
+    /// 获取一个 key 的 value，可以看到事务中之前的修改
+    pub fn get(&self, key: &str) -> Result<Option<Value>, KvError> {
+        Ok(self.get_stored(key)?.map(|v| v.into_value()))
+    }
+
+    /// 设置一个 key 的 value，返回旧的 value；之前设置的过期时间会被清除
+    pub fn set(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let key = self.key(&key.into());
+        let old = self.get(&key)?;
+        self.writes.push((key, Some(StoredValue::new(value))));
+        Ok(old)
+    }
+
+    /// 修改一个 key 的 value，返回旧的 value；和 set 不同，之前设置的过期时间会被保留
+    pub fn update(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let key = self.key(&key.into());
+        let old = self.get_stored(&key)?;
+        let mut stored = StoredValue::new(value);
+        stored.expire_at = old.as_ref().map(|v| v.expire_at).unwrap_or_default();
+        self.writes.push((key, Some(stored)));
+        Ok(old.map(|v| v.into_value()))
+    }
+
+    /// 查看是否有 key
+    pub fn contains(&self, key: &str) -> Result<bool, KvError> {
+        Ok(self.get_stored(key)?.is_some())
+    }
+
+    /// 删除一个 key，返回旧的 value
+    pub fn del(&mut self, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.get(key)?;
+        if old.is_some() {
+            self.writes.push((self.key(key), None));
+        }
+        Ok(old)
+    }
+
+    /// 设置 key 在 ttl 之后过期，key 不存在时返回 false
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        match self.get_stored(key)? {
+            Some(mut v) => {
+                v.expire_in(self.now, ttl);
+                self.writes.push((self.key(key), Some(v)));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// 只有新的过期时间比现在的更晚时才设置，返回是否设置了
+    ///
+    /// key 不存在，或者 key 永不过期时都不会设置
+    pub fn expire_gt(&mut self, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        match self.get_stored(key)? {
+            Some(mut v) if v.expire_at != 0 => {
+                let old = v.expire_at;
+                v.expire_in(self.now, ttl);
+                if v.expire_at <= old {
+                    return Ok(false);
+                }
+                self.writes.push((self.key(key), Some(v)));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// 获取 key 剩余的存活时间，key 不存在时返回 None，永不过期时返回 Some(None)
+    pub fn ttl(&self, key: &str) -> Result<Option<Option<Duration>>, KvError> {
+        Ok(self.get_stored(key)?.map(|v| v.ttl(self.now)))
+    }
+
+    /// 获取 key 的内容类型，key 不存在时返回 None，没有设置时返回空字符串
+    pub fn content_type(&self, key: &str) -> Result<Option<String>, KvError> {
+        Ok(self.get_stored(key)?.map(|v| v.content_type))
+    }
+
+    /// 设置 key 的内容类型，key 不存在时返回 false
+    pub fn set_content_type(
+        &mut self,
+        key: &str,
+        content_type: impl Into<String>,
+    ) -> Result<bool, KvError> {
+        match self.get_stored(key)? {
+            Some(mut v) => {
+                v.content_type = content_type.into();
+                self.writes.push((self.key(key), Some(v)));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// 创建一个回滚点
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint(self.writes.len())
+    }
+
+    /// 丢弃回滚点之后的所有修改，回滚点之前的修改不受影响
+    ///
+    /// 回滚到更早的回滚点之后，在它之后创建的回滚点也就失效了，再回滚到它们不会有任何效果
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        self.writes.truncate(savepoint.0);
+    }
+
+    /// 目前为止按顺序缓存的所有写操作，value 为 None 代表删除
+    pub(crate) fn writes(&self) -> &[(String, Option<StoredValue>)] {
+        &self.writes
+    }
+
+    /// 事务提交时按顺序拿到所有的写操作，value 为 None 代表删除
+    pub(crate) fn into_writes(self) -> Vec<(String, Option<StoredValue>)> {
+        self.writes
+    }
+
+    /// 经过 normalizer 处理之后的 key
+    fn key(&self, key: &str) -> String {
+        match &self.normalizer {
+            Some(n) => n.normalize(key),
+            None => key.to_owned(),
+        }
+    }
+
+    /// 获取 key 的原始数据，已经过期的 key 视为不存在
+    fn get_stored(&self, key: &str) -> Result<Option<StoredValue>, KvError> {
+        let key = self.key(key);
+        let v = match self.writes.iter().rev().find(|(k, _)| *k == key) {
+            Some((_, v)) => v.clone(),
+            None => (self.reader)(&key)?,
+        };
+        Ok(v.filter(|v| !v.is_expired(self.now)))
+    }
+}
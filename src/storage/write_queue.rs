@@ -0,0 +1,104 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+
+/// 一个 table 的队列：每个写请求领一个号，叫到自己的号时才能开始
+#[derive(Debug, Default)]
+struct Queue {
+    tickets: Mutex<Tickets>,
+    turn_done: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Tickets {
+    /// 下一个写请求领到的号
+    next: u64,
+    /// 正在写的请求的号
+    serving: u64,
+}
+
+/// 每个 table 一个队列，同一个 table 的写请求按到达的顺序一个一个执行，在各自的线程里写入
+///
+/// 同一个 table 的写操作不会再互相竞争 DashMap 的锁，不同 table 的写操作互不影响；
+/// 没有请求在排队的 table 的队列会被马上删除，不会随着 table 的增加一直占用内存
+#[derive(Debug, Default)]
+pub(crate) struct WriteQueues {
+    queues: DashMap<String, Arc<Queue>>,
+}
+
+/// 拿到了写 table 的权利，drop 之后下一个写请求才能开始
+pub(crate) struct WriteTurn {
+    queues: Arc<WriteQueues>,
+    table: String,
+}
+
+impl WriteQueues {
+    /// 在 table 的队列里排队，轮到之后返回；同一个线程不能在拿着 WriteTurn 的时候再次排队
+    pub(crate) fn acquire(self: &Arc<Self>, table: &str) -> WriteTurn {
+        // 拿住 entry 的时候领号，release 删除空闲的队列时也拿住 entry，领到的号不会落在被删除的队列上
+        let (queue, ticket) = {
+            let entry = self.queues.entry(table.into()).or_default();
+            let mut tickets = entry.tickets.lock().unwrap();
+            let ticket = tickets.next;
+            tickets.next += 1;
+            (entry.clone(), ticket)
+        };
+
+        let mut tickets = queue.tickets.lock().unwrap();
+        while tickets.serving != ticket {
+            tickets = queue.turn_done.wait(tickets).unwrap();
+        }
+        WriteTurn {
+            queues: self.clone(),
+            table: table.into(),
+        }
+    }
+
+    /// 叫下一个号，没有请求在排队时删除这个队列
+    fn release(&self, table: &str) {
+        if let Entry::Occupied(entry) = self.queues.entry(table.into()) {
+            let idle = {
+                let mut tickets = entry.get().tickets.lock().unwrap();
+                tickets.serving += 1;
+                tickets.serving == tickets.next
+            };
+            match idle {
+                true => {
+                    entry.remove();
+                }
+                false => entry.get().turn_done.notify_all(),
+            }
+        }
+    }
+}
+
+impl Drop for WriteTurn {
+    fn drop(&mut self) {
+        self.queues.release(&self.table);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn idle_queues_should_be_removed() {
+        let queues = Arc::new(WriteQueues::default());
+        let counter = Mutex::new(0);
+        thread::scope(|s| {
+            for i in 0..8 {
+                let (queues, counter) = (&queues, &counter);
+                s.spawn(move || {
+                    for _ in 0..100 {
+                        let _turn = queues.acquire(&format!("t{}", i % 2));
+                        *counter.lock().unwrap() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*counter.lock().unwrap(), 800);
+        assert!(queues.queues.is_empty());
+    }
+}
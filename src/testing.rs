@@ -0,0 +1,105 @@
+//! 集成测试用的辅助函数，打开 `testing` feature 之后别的 crate 也可以使用
+
+use std::net::SocketAddr;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+use tracing::warn;
+
+use crate::{
+    KvError, MemTable, ProstClientStream, ProstServerStream, Service, ServiceInner,
+    TlsClientConnector, TlsServerAcceptor, YamuxCtrl,
+};
+
+const CA_CERT: &str = include_str!("../fixtures/ca.cert");
+const SERVER_CERT: &str = include_str!("../fixtures/server.cert");
+const SERVER_KEY: &str = include_str!("../fixtures/server.key");
+const SERVER_DOMAIN: &str = "demo.simplekv.cc";
+
+/// 测试用的服务器，drop 之后不再接受新的连接
+pub struct TestServer {
+    handle: JoinHandle<()>,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// 在随机端口上启动一个使用 TLS + yamux 的服务器，数据存放在一个新的 MemTable 中
+pub async fn start_test_server() -> Result<(SocketAddr, TestServer), KvError> {
+    let acceptor = TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, None)?;
+    let service: Service = ServiceInner::new(MemTable::new()).into();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let handle = tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _addr)) => stream,
+                Err(e) => {
+                    warn!("Failed to process TCP: {:?}", e);
+                    continue;
+                }
+            };
+
+            let tls = acceptor.clone();
+            let svc = service.clone();
+            tokio::spawn(async move {
+                let stream = match tls.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => return warn!("Failed to process TLS: {:?}", e),
+                };
+                YamuxCtrl::new_server(stream, None, move |stream| {
+                    let svc = svc.clone();
+                    async move {
+                        let stream = ProstServerStream::new(stream.compat(), svc);
+                        if let Err(e) = stream.process().await {
+                            warn!("Failed to process stream: {:?}", e);
+                        }
+                        Ok(())
+                    }
+                });
+            });
+        }
+    });
+
+    Ok((addr, TestServer { handle }))
+}
+
+/// 连接到 start_test_server 启动的服务器，返回一个可以直接发送命令的客户端
+pub async fn test_client(
+    addr: SocketAddr,
+) -> Result<ProstClientStream<Compat<yamux::Stream>>, KvError> {
+    let connector = TlsClientConnector::new(SERVER_DOMAIN, None, Some(CA_CERT))?;
+    let stream = TcpStream::connect(addr).await?;
+    let stream = connector.connect(stream).await?;
+
+    let mut ctrl = YamuxCtrl::new_client(stream, None);
+    let stream = ctrl.open_stream().await?;
+    Ok(ProstClientStream::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, CommandRequest, Value};
+
+    #[tokio::test]
+    async fn test_server_round_trip_should_work() -> anyhow::Result<()> {
+        let (addr, _server) = start_test_server().await?;
+        let mut client = test_client(addr).await?;
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute_unary(&cmd).await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute_unary(&cmd).await?;
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+}